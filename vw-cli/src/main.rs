@@ -3,63 +3,349 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use colored::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io::IsTerminal;
 use std::process;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
 
+use vw_lib::mapping::SymbolKind;
 use vw_lib::{
-    add_dependency_with_token, clear_cache, extract_hostname_from_repo_url,
-    generate_deps_tcl, get_access_credentials_from_netrc, init_workspace,
-    list_dependencies, list_testbenches, load_workspace_config,
-    remove_dependency, run_testbench, update_workspace_with_token, Credentials,
-    VersionInfo, VhdlStandard,
+    add_dependency_with_token, analyze_workspace, bench_directory, cache_size,
+    check_environment, clean_workspace, clear_cache, compile_order,
+    dependency_graph, dependency_tree, dependency_usage, explain_symbol,
+    find_duplicate_symbols, find_workspace_root, gc_cache, generate_deps_make,
+    generate_deps_tcl, generate_manifest, generate_vivado_script,
+    get_access_credentials, init_workspace, is_mixed_signal_testbench,
+    list_dependencies, list_testbenches, load_workspace_config, open_waveform,
+    parse_age, parse_generic, plan_update, random_seed, regenerate_configs,
+    remove_dependency, resolve_only, run_testbench, run_testbench_passthrough,
+    scan_tagged_records, set_git_proxy, set_git_retries,
+    update_workspace_with_token, validate_config, vendor_dependencies,
+    vhdl_extensions, vhdl_standard, AnalysisStatus, AuthConfig, CoverageKind,
+    CoverageOptions, Credentials, DependencyNode, InitTemplate, NvcOutput,
+    NvcPassthrough, NvcPhase, ProgressCallback, TestOptions, TestResult,
+    TransferProgress, VersionInfo, VhdlStandard, WaveFormat, WaveOptions,
+    DEFAULT_RECORD_ATTRIBUTE, TOKEN_USERNAME,
 };
 
 #[derive(Clone, Copy, Debug, ValueEnum)]
 enum CliVhdlStandard {
+    #[value(name = "93", alias = "1993")]
+    Vhdl1993,
+    #[value(name = "2002")]
+    Vhdl2002,
     #[value(name = "2008")]
     Vhdl2008,
     #[value(name = "2019")]
     Vhdl2019,
 }
 
-impl fmt::Display for CliVhdlStandard {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            CliVhdlStandard::Vhdl2008 => write!(f, "2008"),
-            CliVhdlStandard::Vhdl2019 => write!(f, "2019"),
-        }
-    }
-}
+const STRICT_HELP: &str =
+    "Fail if a package or entity is declared in more than one file of a library";
+
+const PREFIX_OUTPUT_HELP: &str =
+    "Prefix each line of nvc output with the library or testbench name";
+
+const STD_HELP: &str =
+    "VHDL standard [default: vhdl_standard from vw.toml, else 2019]";
 
 impl From<CliVhdlStandard> for VhdlStandard {
     fn from(std: CliVhdlStandard) -> Self {
         match std {
+            CliVhdlStandard::Vhdl1993 => VhdlStandard::Vhdl1993,
+            CliVhdlStandard::Vhdl2002 => VhdlStandard::Vhdl2002,
             CliVhdlStandard::Vhdl2008 => VhdlStandard::Vhdl2008,
             CliVhdlStandard::Vhdl2019 => VhdlStandard::Vhdl2019,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CliGraphFormat {
+    Dot,
+    Json,
+}
+
+impl fmt::Display for CliGraphFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliGraphFormat::Dot => write!(f, "dot"),
+            CliGraphFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CliInitTemplate {
+    Basic,
+    Bare,
+}
+
+impl fmt::Display for CliInitTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliInitTemplate::Basic => write!(f, "basic"),
+            CliInitTemplate::Bare => write!(f, "bare"),
+        }
+    }
+}
+
+impl From<CliInitTemplate> for InitTemplate {
+    fn from(template: CliInitTemplate) -> Self {
+        match template {
+            CliInitTemplate::Basic => InitTemplate::Basic,
+            CliInitTemplate::Bare => InitTemplate::Bare,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CliCoverageKind {
+    Statement,
+    Branch,
+    Toggle,
+    Expression,
+    FsmState,
+}
+
+impl From<CliCoverageKind> for CoverageKind {
+    fn from(kind: CliCoverageKind) -> Self {
+        match kind {
+            CliCoverageKind::Statement => CoverageKind::Statement,
+            CliCoverageKind::Branch => CoverageKind::Branch,
+            CliCoverageKind::Toggle => CoverageKind::Toggle,
+            CliCoverageKind::Expression => CoverageKind::Expression,
+            CliCoverageKind::FsmState => CoverageKind::FsmState,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CliNvcPhase {
+    Analyze,
+    Elaborate,
+    Run,
+}
+
+impl From<CliNvcPhase> for NvcPhase {
+    fn from(phase: CliNvcPhase) -> Self {
+        match phase {
+            CliNvcPhase::Analyze => NvcPhase::Analyze,
+            CliNvcPhase::Elaborate => NvcPhase::Elaborate,
+            CliNvcPhase::Run => NvcPhase::Run,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CliWaveFormat {
+    Fst,
+    Vcd,
+    Ghw,
+}
+
+impl fmt::Display for CliWaveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", WaveFormat::from(*self))
+    }
+}
+
+impl From<CliWaveFormat> for WaveFormat {
+    fn from(format: CliWaveFormat) -> Self {
+        match format {
+            CliWaveFormat::Fst => WaveFormat::Fst,
+            CliWaveFormat::Vcd => WaveFormat::Vcd,
+            CliWaveFormat::Ghw => WaveFormat::Ghw,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl fmt::Display for ColorChoice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColorChoice::Auto => write!(f, "auto"),
+            ColorChoice::Always => write!(f, "always"),
+            ColorChoice::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl ColorChoice {
+    /// Apply the choice to all `colored` output. `auto` colors only when
+    /// stdout is a terminal and `NO_COLOR` is not set.
+    fn apply(self) {
+        let enabled = match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+        };
+        colored::control::set_override(enabled);
+    }
+}
+
 #[derive(Parser)]
 #[command(name = "vw")]
 #[command(about = "A VHDL workspace management tool")]
 struct Cli {
+    #[arg(
+        long,
+        global = true,
+        help = "When to use colored output",
+        default_value_t = ColorChoice::Auto
+    )]
+    color: ColorChoice,
+    #[arg(
+        long,
+        global = true,
+        value_name = "PAT",
+        help = "Access token for private repositories, offered to every host that asks; overrides [auth] sources"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        requires = "token",
+        help = "Username to send with --token (default: x-access-token)"
+    )]
+    username: Option<String>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "Print more progress messages (-vv for even more)"
+    )]
+    verbose: u8,
+    #[arg(
+        short,
+        long,
+        global = true,
+        conflicts_with = "verbose",
+        help = "Only print errors and results; nvc output is shown only if a step fails"
+    )]
+    quiet: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Print results as JSON (vw list and vw test); implies --color never"
+    )]
+    json: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        help = "Times to retry git fetches that fail with a network error (default: $VW_GIT_RETRIES or 3)"
+    )]
+    retries: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        help = "Connect to git remotes directly, ignoring http.proxy and HTTPS_PROXY"
+    )]
+    no_proxy: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Print `value` as pretty JSON on stdout, for `--json`.
+fn print_json(value: &impl serde::Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{json}"),
+        Err(e) => {
+            eprintln!(
+                "{} Failed to serialize output: {e}",
+                "error:".bright_red()
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Send progress messages to stderr at the level `--verbose` or `--quiet`
+/// selects. Without either, `RUST_LOG` is honored, defaulting to `info`.
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => Some("error"),
+        (false, 0) => None,
+        (false, 1) => Some("debug"),
+        (false, _) => Some("trace"),
+    };
+    let filter = match level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    // Messages carry their own colors and labels
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .with_level(false)
+        .with_target(false)
+        .without_time()
+        .init();
+}
+
 #[derive(Subcommand)]
 enum Commands {
     #[command(about = "Initialize a new workspace")]
     Init {
         #[arg(help = "Workspace name")]
         name: String,
+        #[arg(
+            long,
+            default_value_t = CliInitTemplate::Basic,
+            help = "Files to create: basic adds src/, bench/ and an example testbench"
+        )]
+        template: CliInitTemplate,
+        #[arg(
+            long,
+            conflicts_with = "template",
+            help = "Only create vw.toml (same as --template bare)"
+        )]
+        bare: bool,
     },
     #[command(about = "Update workspace dependencies")]
-    Update,
+    Update {
+        #[arg(long, help = "Re-download vendored dependencies into the cache")]
+        force: bool,
+        #[arg(
+            long,
+            help = "Hardlink unpatched files into patched dependency trees instead of copying them"
+        )]
+        link: bool,
+        #[arg(
+            long,
+            conflicts_with = "force",
+            help = "Resolve commits and show what would be downloaded without writing anything"
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            conflicts_with_all = ["force", "link", "dry_run"],
+            help = "Use only vendored and cached dependencies from vw.lock (same as vw sync)"
+        )]
+        offline: bool,
+    },
+    #[command(
+        about = "Resolve dependency commits and write vw.lock without downloading"
+    )]
+    Lock,
     #[command(about = "Add a new dependency")]
     Add {
         #[arg(help = "Git repository URL")]
@@ -85,18 +371,179 @@ enum Commands {
         #[arg(help = "Name of the dependency to remove")]
         name: String,
     },
+    #[command(about = "Copy dependencies into the workspace")]
+    Vendor {
+        #[arg(
+            help = "Directory to vendor dependencies into",
+            default_value = "vendor"
+        )]
+        dir: Utf8PathBuf,
+    },
     #[command(about = "Clear all cached repositories")]
     Clear,
+    #[command(
+        about = "Remove build artifacts: vw_build/, waveforms and generated Tcl scripts"
+    )]
+    Clean {
+        #[arg(long, help = "List what would be removed without deleting it")]
+        dry_run: bool,
+    },
+    #[command(about = "Manage the dependency cache shared by all workspaces")]
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
     #[command(about = "List workspace dependencies")]
     List,
+    #[command(
+        about = "Show the dependency graph, with the libraries each dependency references"
+    )]
+    Tree,
+    #[command(
+        about = "Regenerate vhdl_ls.toml, deps.tcl and deps.mk from vw.lock without network access"
+    )]
+    Sync,
+    #[command(about = "Check vw.toml for configuration mistakes")]
+    CheckConfig,
+    #[command(
+        about = "Show which dependencies the workspace's VHDL references"
+    )]
+    Deps {
+        #[arg(
+            long,
+            help = "Only list dependencies nothing references, as candidates for removal"
+        )]
+        unused: bool,
+        #[arg(
+            long,
+            requires = "unused",
+            help = "Also list sim_only dependencies, which are skipped by default"
+        )]
+        include_sim_only: bool,
+    },
     #[command(about = "Generate deps.tcl file with all dependency VHDL files")]
     DepsToTcl,
+    #[command(
+        about = "Generate deps.mk with dependency VHDL files as GNU make variables"
+    )]
+    DepsToMake,
+    #[command(
+        about = "Generate a Vivado read_vhdl script for dependencies in compile order"
+    )]
+    DepsToVivado {
+        #[arg(
+            long,
+            help = "Output script path",
+            default_value = "read_deps.tcl"
+        )]
+        out: Utf8PathBuf,
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+    },
+    #[command(
+        about = "Write a JSON manifest of dependency files per library in compile order"
+    )]
+    Manifest {
+        #[arg(
+            long,
+            help = "Output manifest path",
+            default_value = "build_manifest.json"
+        )]
+        output: Utf8PathBuf,
+    },
+    #[command(
+        about = "Show which files declare a package or entity and what pulls it in"
+    )]
+    Why {
+        #[arg(help = "Name of the package or entity")]
+        symbol: String,
+    },
+    #[command(about = "Check that nvc and the dependency cache are usable")]
+    Doctor {
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+    },
+    #[command(about = "Print the compile order for a testbench")]
+    Order {
+        #[arg(long, help = "Name of the testbench entity")]
+        testbench: String,
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+        #[arg(
+            long,
+            help = "Enable recursive search when looking for testbenches"
+        )]
+        recurse: bool,
+        #[arg(long, help = "Show why each file was included")]
+        explain: bool,
+        #[arg(long, help = STRICT_HELP)]
+        strict: bool,
+    },
+    #[command(
+        about = "Print the file dependency graph used for compile order"
+    )]
+    Graph {
+        #[arg(
+            long,
+            help = "Name of the testbench entity (default: all defaultlib files)"
+        )]
+        testbench: Option<String>,
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+        #[arg(
+            long,
+            help = "Enable recursive search when looking for testbenches"
+        )]
+        recurse: bool,
+        #[arg(
+            long,
+            help = "Output format",
+            default_value_t = CliGraphFormat::Dot
+        )]
+        format: CliGraphFormat,
+    },
+    #[command(
+        about = "Analyze dependencies and workspace files with NVC, without elaborating or simulating"
+    )]
+    Analyze {
+        #[arg(
+            long,
+            help = "Name of the testbench entity (default: all defaultlib files)"
+        )]
+        testbench: Option<String>,
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+        #[arg(
+            long,
+            help = "Enable recursive search when looking for testbenches"
+        )]
+        recurse: bool,
+        #[arg(
+            long,
+            value_name = "SIZE",
+            help = "NVC heap size, e.g. 512m or 1g (overrides [simulator] heap; default 256m)"
+        )]
+        heap_size: Option<String>,
+        #[arg(long, help = PREFIX_OUTPUT_HELP)]
+        prefix_output: bool,
+    },
+    #[command(about = "List records tagged for Rust codegen in emit order")]
+    Records {
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+        #[arg(
+            long,
+            help = "Attribute that tags a record for codegen",
+            default_value = DEFAULT_RECORD_ATTRIBUTE
+        )]
+        attribute: String,
+    },
     #[command(about = "Run testbench using NVC")]
     Test {
         #[arg(help = "Name of the testbench entity to run")]
         testbench: Option<String>,
-        #[arg(long, help = "VHDL standard", default_value_t = CliVhdlStandard::Vhdl2019)]
-        std: CliVhdlStandard,
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
         #[arg(long, help = "List all available testbenches")]
         list: bool,
         #[arg(
@@ -113,46 +560,396 @@ enum Commands {
         #[arg(
             long,
             value_delimiter = ',',
-            help = "Runtime flags to pass to NVC (comma-separated or use multiple times)",
+            help = "Runtime flags to pass to NVC (comma-separated or use multiple times; placed before any given after `--`)",
             requires = "testbench"
         )]
         runtime_flags: Vec<String>,
+        #[arg(
+            long = "generic",
+            value_name = "NAME=VALUE",
+            value_parser = parse_generic_arg,
+            help = "Set a top-level generic at elaboration (may be used multiple times)",
+            requires = "testbench"
+        )]
+        generics: Vec<(String, String)>,
+        #[arg(
+            long,
+            help = "Random seed for the simulation (default: a new one, printed so the run can be repeated)",
+            conflicts_with = "elaborate_only",
+            requires = "testbench"
+        )]
+        seed: Option<u64>,
+        #[arg(
+            long = "arch",
+            value_name = "NAME",
+            help = "Architecture of the testbench to elaborate (default: the last one analyzed)",
+            requires = "testbench"
+        )]
+        architecture: Option<String>,
+        #[arg(
+            long,
+            value_name = "SIZE",
+            help = "NVC heap size, e.g. 512m or 1g (overrides [simulator] heap; default 256m)",
+            requires = "testbench"
+        )]
+        heap_size: Option<String>,
+        #[arg(
+            long,
+            help = "Waveform format (default: fst)",
+            requires = "testbench"
+        )]
+        wave_format: Option<CliWaveFormat>,
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Directory to write the waveform to, relative to the workspace root (default: the workspace root)",
+            requires = "testbench"
+        )]
+        wave_dir: Option<Utf8PathBuf>,
+        #[arg(
+            long,
+            help = "Do not dump a waveform",
+            conflicts_with_all = ["wave_format", "wave_dir"],
+            requires = "testbench"
+        )]
+        no_wave: bool,
+        #[arg(
+            long,
+            help = "Open the waveform in a viewer (surfer or gtkwave, or [simulator] waveform_viewer) after a successful run",
+            conflicts_with_all = ["no_wave", "elaborate_only"],
+            requires = "testbench"
+        )]
+        gui: bool,
         #[arg(
             long,
             help = "Build Rust library for testbench before running",
             requires = "testbench"
         )]
         build_rust: bool,
+        #[arg(
+            long = "load",
+            value_name = "LIB",
+            help = "VHPI plugin to load into the simulation, e.g. a cocotb library (also sets GPI_USERS)",
+            conflicts_with_all = ["build_rust", "elaborate_only"],
+            requires = "testbench"
+        )]
+        plugin: Option<Utf8PathBuf>,
         #[arg(
             long,
             help = "Generate/regenerate mixed-signal scaffolding from mist.toml",
             requires = "testbench"
         )]
         scaffold: bool,
+        #[arg(long, help = STRICT_HELP, requires = "testbench")]
+        strict: bool,
+        #[arg(
+            long,
+            help = "Analyze and elaborate the testbench, but do not run it",
+            conflicts_with_all = ["wave_format", "wave_dir", "build_rust", "scaffold"],
+            requires = "testbench"
+        )]
+        elaborate_only: bool,
+        #[arg(
+            long,
+            help = "Collect code coverage and write an HTML report",
+            conflicts_with = "elaborate_only",
+            requires = "testbench"
+        )]
+        coverage: bool,
+        #[arg(
+            long,
+            value_name = "KINDS",
+            value_delimiter = ',',
+            help = "Coverage kinds to collect (default: statement,branch,toggle)",
+            requires = "coverage"
+        )]
+        cover_kinds: Vec<CliCoverageKind>,
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Directory for the coverage report, relative to the workspace root (default: vw_build/coverage/<testbench>)",
+            requires = "coverage"
+        )]
+        cover_dir: Option<Utf8PathBuf>,
+        #[arg(long, help = PREFIX_OUTPUT_HELP, requires = "testbench")]
+        prefix_output: bool,
+        #[arg(
+            last = true,
+            value_name = "SIM_ARGS",
+            help = "Extra NVC runtime arguments, passed after `--` (e.g. -- --stop-time=1ms); unlike --runtime-flags they are not split on commas, and they follow them",
+            requires = "testbench"
+        )]
+        sim_args: Vec<String>,
+    },
+    #[command(
+        about = "Build and run a testbench with raw NVC flags passed through"
+    )]
+    Run {
+        #[arg(help = "Name of the testbench entity to run")]
+        testbench: String,
+        #[arg(long, help = STD_HELP)]
+        std: Option<CliVhdlStandard>,
+        #[arg(
+            long,
+            help = "Enable recursive search when looking for testbenches"
+        )]
+        recurse: bool,
+        #[arg(
+            long = "generic",
+            value_name = "NAME=VALUE",
+            value_parser = parse_generic_arg,
+            help = "Set a top-level generic at elaboration (may be used multiple times)"
+        )]
+        generics: Vec<(String, String)>,
+        #[arg(
+            long,
+            value_name = "SIZE",
+            help = "NVC heap size, e.g. 512m or 1g (overrides [simulator] heap; default 256m)"
+        )]
+        heap_size: Option<String>,
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "Phases to run (comma-separated; default: analyze,elaborate,run)"
+        )]
+        phases: Vec<CliNvcPhase>,
+        #[arg(
+            long = "elab-flag",
+            value_name = "FLAG",
+            allow_hyphen_values = true,
+            help = "Raw flag for `nvc -e` (may be used multiple times, e.g. --elab-flag=--jit)"
+        )]
+        elab_flags: Vec<String>,
+        #[arg(long, help = PREFIX_OUTPUT_HELP)]
+        prefix_output: bool,
+        #[arg(
+            last = true,
+            value_name = "RUN_FLAGS",
+            help = "Raw flags for `nvc -r`, passed after `--` (e.g. -- --ieee-warnings=off)"
+        )]
+        run_flags: Vec<String>,
     },
 }
 
-/// Helper function to get access credentials for a repository URL from netrc if available
+#[derive(Subcommand)]
+enum CacheCommands {
+    #[command(about = "Remove cached dependencies no workspace used recently")]
+    Gc {
+        #[arg(
+            long,
+            value_name = "AGE",
+            value_parser = parse_age_arg,
+            help = "Remove entries unused for this long, e.g. 30d, 12h or 2w"
+        )]
+        older_than: std::time::Duration,
+        #[arg(long, help = "Only report what would be removed")]
+        dry_run: bool,
+    },
+    #[command(about = "Show how much disk space the cache uses")]
+    Size {
+        #[arg(
+            long,
+            value_name = "N",
+            help = "Also list the N largest entries"
+        )]
+        top: Option<usize>,
+    },
+}
+
+/// Format a byte count with binary units, e.g. `1.3 GiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Parse a `--older-than` age argument.
+fn parse_age_arg(arg: &str) -> Result<std::time::Duration, String> {
+    parse_age(arg).map_err(|e| e.to_string())
+}
+
+/// Draw a single-line download progress indicator on stderr.
+fn print_transfer_progress(name: &str, progress: TransferProgress) {
+    let percent = (progress.received_objects * 100)
+        .checked_div(progress.total_objects)
+        .unwrap_or(0);
+    eprint!(
+        "\r\x1b[2KDownloading {name}: {percent:>3}% ({}/{} objects, {:.1} MiB)",
+        progress.received_objects,
+        progress.total_objects,
+        progress.received_bytes as f64 / (1024.0 * 1024.0)
+    );
+}
+
+/// How to show nvc output for `--prefix-output` and `--quiet`; `--quiet`
+/// only shows it if a step fails.
+fn nvc_output(prefix_output: bool, quiet: bool) -> NvcOutput {
+    if quiet {
+        NvcOutput::Quiet
+    } else if prefix_output {
+        NvcOutput::Prefixed
+    } else {
+        NvcOutput::Inherit
+    }
+}
+
+/// The VHDL standard for a command: `--std` if given, else the workspace's.
+fn resolve_std(
+    workspace_dir: &camino::Utf8Path,
+    std: Option<CliVhdlStandard>,
+) -> VhdlStandard {
+    vhdl_standard(workspace_dir, std.map(Into::into)).unwrap_or_else(|e| {
+        eprintln!("{} {e}", "error:".bright_red());
+        process::exit(1);
+    })
+}
+
+/// Warn about packages and entities declared in more than one file of a
+/// library, exiting with an error instead if `strict` is set.
+fn check_duplicate_symbols(workspace_dir: &camino::Utf8Path, strict: bool) {
+    let duplicates = match find_duplicate_symbols(workspace_dir) {
+        Ok(duplicates) => duplicates,
+        Err(e) => {
+            eprintln!("{} {e}", "error:".bright_red());
+            process::exit(1);
+        }
+    };
+    for duplicate in &duplicates {
+        let mut message = format!(
+            "{} '{}' is declared in {} files of library '{}':",
+            symbol_kind_name(&duplicate.symbol.kind),
+            duplicate.symbol.name,
+            duplicate.files.len(),
+            duplicate.library
+        );
+        for file in &duplicate.files {
+            message.push_str(&format!("\n    {}", file.display()));
+        }
+        // Errors always print; warnings follow --quiet
+        if strict {
+            eprintln!("{} {message}", "error:".bright_red());
+        } else {
+            warn!("{} {message}", "warning:".bright_yellow());
+        }
+    }
+    if strict && !duplicates.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// Print `name` and, beneath it, the dependencies it references, as
+/// `cargo tree` does. A dependency already expanded is marked `(*)` rather
+/// than printed again, which also stops reference cycles.
+fn print_dependency_tree(
+    name: &str,
+    nodes: &HashMap<&str, &DependencyNode>,
+    prefix: &str,
+    last: bool,
+    expanded: &mut HashSet<String>,
+) {
+    let node = nodes[name];
+    let commit = node
+        .commit
+        .as_deref()
+        .map(|commit| format!(" ({})", &commit[..8.min(commit.len())]))
+        .unwrap_or_default();
+    let repeated =
+        !node.references.is_empty() && !expanded.insert(name.to_string());
+    println!(
+        "{prefix}{}{}{}{}",
+        if last { "└── " } else { "├── " },
+        name.cyan(),
+        commit.bright_black(),
+        if repeated { " (*)" } else { "" }
+    );
+    if repeated {
+        return;
+    }
+
+    let prefix = format!("{prefix}{}", if last { "    " } else { "│   " });
+    for (i, reference) in node.references.iter().enumerate() {
+        let last = i + 1 == node.references.len();
+        print_dependency_tree(reference, nodes, &prefix, last, expanded);
+    }
+}
+
+/// How a symbol kind reads in `vw order` and `vw why` output.
+fn symbol_kind_name(kind: &SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Package => "package",
+        SymbolKind::Entity => "entity",
+        _ => "symbol",
+    }
+}
+
+/// The `nvc -r` flags of `vw test`: `--runtime-flags` first, then the
+/// arguments after `--`, each in the order given.
+fn test_runtime_flags(
+    runtime_flags: Vec<String>,
+    sim_args: Vec<String>,
+) -> Vec<String> {
+    [runtime_flags, sim_args].concat()
+}
+
+/// Parse a `--generic NAME=VALUE` argument.
+fn parse_generic_arg(arg: &str) -> Result<(String, String), String> {
+    parse_generic(arg).map_err(|e| e.to_string())
+}
+
+/// The workspace's `[auth]` settings, or the defaults.
+fn auth_config(workspace_dir: &camino::Utf8Path) -> AuthConfig {
+    load_workspace_config(workspace_dir)
+        .ok()
+        .and_then(|config| config.auth)
+        .unwrap_or_default()
+}
+
+/// Helper function to get access credentials for a repository URL: the
+/// `--token` credentials if given, else the configured sources
 async fn get_access_credentials_for_repo(
+    workspace_dir: &camino::Utf8Path,
     repo_url: &str,
+    cli_creds: Option<&Credentials>,
 ) -> Option<Credentials> {
-    if let Ok(hostname) = extract_hostname_from_repo_url(repo_url) {
-        if let Ok(Some(creds)) = get_access_credentials_from_netrc(&hostname) {
-            return Some(creds);
+    if let Some(creds) = cli_creds {
+        return Some(creds.clone());
+    }
+    match get_access_credentials(repo_url, &auth_config(workspace_dir)) {
+        Ok(creds) => creds,
+        Err(e) => {
+            warn!(
+                "{} no credentials for {repo_url}: {e}",
+                "warning:".bright_yellow()
+            );
+            None
         }
     }
-    None
 }
 
-/// Helper function to get access credentials for workspace dependencies from netrc
+/// Helper function to get access credentials for workspace dependencies: the
+/// `--token` credentials if given, else the configured sources
 async fn get_access_credentials_for_workspace(
     workspace_dir: &camino::Utf8Path,
+    cli_creds: Option<&Credentials>,
 ) -> Option<Credentials> {
+    if let Some(creds) = cli_creds {
+        return Some(creds.clone());
+    }
     // Load workspace config and check if any dependencies might need authentication
     if let Ok(config) = load_workspace_config(workspace_dir) {
         for dep in config.dependencies.values() {
             if let Some(creds) =
-                get_access_credentials_for_repo(&dep.repo).await
+                get_access_credentials_for_repo(workspace_dir, &dep.repo, None)
+                    .await
             {
                 return Some(creds);
             }
@@ -164,6 +961,30 @@ async fn get_access_credentials_for_workspace(
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    cli.color.apply();
+    if cli.json {
+        let supported = match &cli.command {
+            Commands::List => true,
+            Commands::Test { list, .. } => !list,
+            _ => false,
+        };
+        if !supported {
+            eprintln!(
+                "{} --json is only supported by vw list and by vw test \
+                 running a testbench",
+                "error:".bright_red()
+            );
+            process::exit(1);
+        }
+        colored::control::set_override(false);
+    }
+    init_logging(cli.verbose, cli.quiet);
+    if let Some(retries) = cli.retries {
+        set_git_retries(retries);
+    }
+    if cli.no_proxy {
+        set_git_proxy(false);
+    }
     // Get current working directory
     let cwd =
         Utf8PathBuf::try_from(std::env::current_dir().unwrap_or_else(|e| {
@@ -181,37 +1002,162 @@ async fn main() {
             process::exit(1);
         });
 
+    // Credentials from --token take precedence over every configured source
+    let cli_creds = cli.token.map(|token| {
+        Credentials::new(
+            cli.username.unwrap_or_else(|| TOKEN_USERNAME.to_string()),
+            token,
+        )
+    });
+
+    // Like cargo, run from anywhere inside the workspace. Paths given on
+    // the command line stay relative to the current directory.
+    let workspace_dir = match &cli.command {
+        Commands::Init { .. } => cwd,
+        // These work outside a workspace, and `vw add` creates vw.toml
+        Commands::Add { .. }
+        | Commands::Doctor { .. }
+        | Commands::Cache { .. }
+        | Commands::Clear => find_workspace_root(&cwd).unwrap_or(cwd),
+        _ => find_workspace_root(&cwd).unwrap_or_else(|e| {
+            eprintln!("{} {e}", "error:".bright_red());
+            process::exit(1);
+        }),
+    };
+    debug!("Workspace root: {workspace_dir}");
+
     match cli.command {
-        Commands::Init { name } => {
-            if let Err(e) = init_workspace(&cwd, name.clone()) {
-                eprintln!("{} {e}", "error:".bright_red());
-                process::exit(1);
-            }
+        Commands::Init {
+            name,
+            template,
+            bare,
+        } => {
+            let template = if bare {
+                InitTemplate::Bare
+            } else {
+                template.into()
+            };
+            let created =
+                match init_workspace(&workspace_dir, name.clone(), template) {
+                    Ok(created) => created,
+                    Err(e) => {
+                        eprintln!("{} {e}", "error:".bright_red());
+                        process::exit(1);
+                    }
+                };
             println!(
                 "{} Initialized workspace: {}",
                 "✓".bright_green(),
                 name.cyan()
             );
+            for path in created {
+                info!("  {} {path}", "created".bright_black());
+            }
+        }
+        Commands::Update { offline: true, .. } | Commands::Sync => {
+            match regenerate_configs(&workspace_dir) {
+                Ok(names) => {
+                    for name in &names {
+                        info!("Synced {}", name.cyan());
+                    }
+                    println!(
+                        "{} Regenerated configuration for {} dependencies from vw.lock",
+                        "✓".bright_green(),
+                        names.len()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Update { dry_run: true, .. } => {
+            let access_creds = get_access_credentials_for_workspace(
+                &workspace_dir,
+                cli_creds.as_ref(),
+            )
+            .await;
+            match plan_update(&workspace_dir, access_creds).await {
+                Ok(plan) => {
+                    for dep in plan.dependencies {
+                        let action = if dep.vendored {
+                            format!("vendored, {} files", dep.files.len())
+                                .bright_black()
+                        } else if dep.was_cached {
+                            format!("cached, {} files", dep.files.len())
+                                .bright_black()
+                        } else {
+                            "would download".yellow()
+                        };
+                        println!(
+                            "{} at {} ({action})",
+                            dep.name.cyan(),
+                            dep.commit.cyan()
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
         }
-        Commands::Update => {
-            let access_creds = get_access_credentials_for_workspace(&cwd).await;
-            match update_workspace_with_token(&cwd, access_creds).await {
+        Commands::Update {
+            force,
+            link,
+            dry_run: false,
+            offline: false,
+        } => {
+            let access_creds = get_access_credentials_for_workspace(
+                &workspace_dir,
+                cli_creds.as_ref(),
+            )
+            .await;
+            let show_progress = std::io::stderr().is_terminal() && !cli.quiet;
+            let progress: Option<ProgressCallback> =
+                show_progress.then(|| Arc::new(print_transfer_progress) as _);
+            let result = update_workspace_with_token(
+                &workspace_dir,
+                access_creds,
+                force,
+                link,
+                progress,
+            )
+            .await;
+            if show_progress {
+                // Clear the progress line
+                eprint!("\r\x1b[2K");
+            }
+            match result {
                 Ok(result) => {
                     for dep in result.dependencies {
-                        println!("Processing dependency: {}", dep.name.cyan());
-                        if dep.was_cached {
-                            println!(
-                                "Using cached version of {} at {}",
+                        debug!("Processing dependency: {}", dep.name.cyan());
+                        if dep.vendored {
+                            info!(
+                                "Using vendored copy of {} at {} ({} files)",
                                 dep.name.cyan(),
-                                dep.commit.cyan()
+                                dep.commit.cyan(),
+                                dep.files.len()
+                            );
+                        } else if dep.was_cached {
+                            info!(
+                                "Using cached version of {} at {} ({} files)",
+                                dep.name.cyan(),
+                                dep.commit.cyan(),
+                                dep.files.len()
                             );
                         } else {
-                            println!(
-                                "Downloaded {} at {}",
+                            info!(
+                                "Downloaded {} at {} ({} files)",
                                 dep.name.cyan(),
-                                dep.commit.cyan()
+                                dep.commit.cyan(),
+                                dep.files.len()
                             );
                         }
+                        for file in &dep.files {
+                            debug!("  {}", file.display());
+                        }
                     }
                     println!(
                         "{} Workspace updated successfully!",
@@ -224,6 +1170,32 @@ async fn main() {
                 }
             }
         }
+        Commands::Lock => {
+            let access_creds = get_access_credentials_for_workspace(
+                &workspace_dir,
+                cli_creds.as_ref(),
+            )
+            .await;
+            match resolve_only(&workspace_dir, access_creds).await {
+                Ok(lock_file) => {
+                    let mut names: Vec<_> =
+                        lock_file.dependencies.keys().collect();
+                    names.sort();
+                    for name in names {
+                        info!(
+                            "Resolved {} at {}",
+                            name.cyan(),
+                            lock_file.dependencies[name].commit.cyan()
+                        );
+                    }
+                    println!("{} Wrote vw.lock", "✓".bright_green());
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
         Commands::Add {
             repo,
             branch,
@@ -233,9 +1205,14 @@ async fn main() {
             recursive,
             sim_only,
         } => {
-            let access_creds = get_access_credentials_for_repo(&repo).await;
+            let access_creds = get_access_credentials_for_repo(
+                &workspace_dir,
+                &repo,
+                cli_creds.as_ref(),
+            )
+            .await;
             match add_dependency_with_token(
-                &cwd,
+                &workspace_dir,
                 repo.clone(),
                 branch,
                 commit,
@@ -256,7 +1233,7 @@ async fn main() {
                             .to_string()
                     });
                     println!("Added dependency: {}", dep_name.cyan());
-                    println!(
+                    info!(
                         "Run {} to download and configure",
                         "vw update".cyan()
                     );
@@ -268,12 +1245,32 @@ async fn main() {
             }
         }
         Commands::Remove { name } => {
-            match remove_dependency(&cwd, name.clone()) {
+            match remove_dependency(&workspace_dir, name.clone()) {
                 Ok(()) => {
                     println!("Removed dependency: {}", name.cyan());
+                    info!("Run {} to update configuration", "vw update".cyan());
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Vendor { dir } => {
+            match vendor_dependencies(&workspace_dir, &dir) {
+                Ok(vendored) => {
+                    for dep in &vendored {
+                        info!(
+                            "Vendored {} into {} ({} files)",
+                            dep.name.cyan(),
+                            dep.path.display(),
+                            dep.file_count
+                        );
+                    }
                     println!(
-                        "Run {} to update configuration",
-                        "vw update".cyan()
+                        "{} Vendored {} dependencies",
+                        "✓".bright_green(),
+                        vendored.len()
                     );
                 }
                 Err(e) => {
@@ -282,11 +1279,11 @@ async fn main() {
                 }
             }
         }
-        Commands::Clear => match clear_cache(&cwd) {
+        Commands::Clear => match clear_cache(&workspace_dir) {
             Ok(cleared) => {
                 if !cleared.is_empty() {
                     for dep in &cleared {
-                        println!("Removing cached dependency: {}", dep.cyan());
+                        info!("Removing cached dependency: {}", dep.cyan());
                     }
                     println!(
                         "{} Cleared {} cached repositories",
@@ -302,7 +1299,91 @@ async fn main() {
                 process::exit(1);
             }
         },
-        Commands::List => match list_dependencies(&cwd) {
+        Commands::Clean { dry_run } => {
+            match clean_workspace(&workspace_dir, dry_run) {
+                Ok(result) => {
+                    let verb = if dry_run { "Would remove" } else { "Removed" };
+                    for path in &result.removed {
+                        println!("{verb} {}", path.to_string().cyan());
+                    }
+                    if result.removed.is_empty() {
+                        println!("No build artifacts found");
+                    } else {
+                        println!(
+                            "{} {verb} {} build artifacts, {}",
+                            "✓".bright_green(),
+                            result.removed.len(),
+                            format_bytes(result.reclaimed_bytes)
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Cache { command } => match command {
+            CacheCommands::Gc {
+                older_than,
+                dry_run,
+            } => match gc_cache(older_than, Some(&workspace_dir), dry_run) {
+                Ok(result) => {
+                    let verb = if dry_run { "Would remove" } else { "Removed" };
+                    for path in &result.removed {
+                        println!(
+                            "{verb} {}",
+                            path.display().to_string().cyan()
+                        );
+                    }
+                    println!(
+                        "{} {verb} {} cached dependencies, {}",
+                        "✓".bright_green(),
+                        result.removed.len(),
+                        format_bytes(result.reclaimed_bytes)
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            },
+            CacheCommands::Size { top } => match cache_size() {
+                Ok(stats) => {
+                    println!(
+                        "{} across {} cached dependencies",
+                        format_bytes(stats.total_bytes),
+                        stats.entries.len()
+                    );
+                    if stats.in_use > 0 {
+                        warn!(
+                            "{} {} of them in use by another vw process, so \
+                             their size may still change",
+                            "warning:".bright_yellow(),
+                            stats.in_use
+                        );
+                    }
+                    for entry in stats.entries.iter().take(top.unwrap_or(0)) {
+                        let name = entry
+                            .path
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        println!(
+                            "{:>10}  {}",
+                            format_bytes(entry.bytes),
+                            name.cyan()
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            },
+        },
+        Commands::List => match list_dependencies(&workspace_dir) {
+            Ok(deps) if cli.json => print_json(&deps),
             Ok(deps) => {
                 if deps.is_empty() {
                     println!("No dependencies found in workspace");
@@ -336,7 +1417,92 @@ async fn main() {
                 process::exit(1);
             }
         },
-        Commands::DepsToTcl => match generate_deps_tcl(&cwd) {
+        Commands::Tree => match dependency_tree(&workspace_dir) {
+            Ok(tree) => {
+                println!("{}", tree.workspace);
+                let nodes: HashMap<&str, &DependencyNode> = tree
+                    .dependencies
+                    .iter()
+                    .map(|dep| (dep.name.as_str(), dep))
+                    .collect();
+                let mut expanded = HashSet::new();
+                for (i, dep) in tree.dependencies.iter().enumerate() {
+                    let last = i + 1 == tree.dependencies.len();
+                    print_dependency_tree(
+                        &dep.name,
+                        &nodes,
+                        "",
+                        last,
+                        &mut expanded,
+                    );
+                }
+                if let Some(reason) = &tree.unknown_references {
+                    warn!(
+                        "{} References between dependencies are not shown: {reason}",
+                        "warning:".bright_yellow()
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("{} {e}", "error:".bright_red());
+                process::exit(1);
+            }
+        },
+        Commands::CheckConfig => {
+            match load_workspace_config(&workspace_dir)
+                .and_then(|config| validate_config(&config))
+            {
+                Ok(()) => {
+                    println!("{} vw.toml is valid", "✓".bright_green());
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Deps {
+            unused,
+            include_sim_only,
+        } => match dependency_usage(&workspace_dir) {
+            Ok(usage) if !unused => {
+                for dep in &usage {
+                    let status = match &dep.referenced_by {
+                        Some(file) => format!("used by {}", file.display())
+                            .bright_black()
+                            .to_string(),
+                        None => "unused".bright_yellow().to_string(),
+                    };
+                    let sim_only =
+                        if dep.sim_only { " (sim only)" } else { "" };
+                    println!("{}{sim_only} {status}", dep.name.cyan());
+                }
+            }
+            Ok(usage) => {
+                let (candidates, skipped): (Vec<_>, Vec<_>) = usage
+                    .iter()
+                    .filter(|dep| dep.referenced_by.is_none())
+                    .partition(|dep| include_sim_only || !dep.sim_only);
+                for dep in &candidates {
+                    println!("{}", dep.name);
+                }
+                if candidates.is_empty() {
+                    info!("{} No unused dependencies", "✓".bright_green());
+                }
+                if !skipped.is_empty() {
+                    info!(
+                        "Skipped {} unreferenced sim_only dependencies; \
+                         pass --include-sim-only to list them",
+                        skipped.len()
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("{} {e}", "error:".bright_red());
+                process::exit(1);
+            }
+        },
+        Commands::DepsToTcl => match generate_deps_tcl(&workspace_dir) {
             Ok(()) => {
                 println!(
                     "{} Generated deps.tcl with dependency VHDL files",
@@ -348,6 +1514,304 @@ async fn main() {
                 process::exit(1);
             }
         },
+        Commands::DepsToMake => match generate_deps_make(&workspace_dir) {
+            Ok(()) => {
+                println!(
+                    "{} Generated deps.mk with dependency VHDL files",
+                    "✓".bright_green()
+                );
+            }
+            Err(e) => {
+                eprintln!("{} {e}", "error:".bright_red());
+                process::exit(1);
+            }
+        },
+        Commands::Manifest { output } => {
+            match generate_manifest(&workspace_dir)
+                .and_then(|manifest| manifest.to_json())
+            {
+                Ok(json) => {
+                    if let Err(e) =
+                        std::fs::write(workspace_dir.join(&output), json + "\n")
+                    {
+                        eprintln!(
+                            "{} Failed to write {output}: {e}",
+                            "error:".bright_red()
+                        );
+                        process::exit(1);
+                    }
+                    println!(
+                        "{} Generated {output} with dependency files for each library",
+                        "✓".bright_green()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::DepsToVivado { out, std } => {
+            match generate_vivado_script(
+                &workspace_dir,
+                &out,
+                resolve_std(&workspace_dir, std),
+            ) {
+                Ok(()) => {
+                    println!(
+                        "{} Generated {out} with read_vhdl commands for dependencies",
+                        "✓".bright_green()
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Order {
+            testbench,
+            std,
+            recurse,
+            explain,
+            strict,
+        } => {
+            check_duplicate_symbols(&workspace_dir, strict);
+            match compile_order(
+                &workspace_dir,
+                &testbench,
+                resolve_std(&workspace_dir, std),
+                recurse,
+            ) {
+                Ok(sources) => {
+                    for file in &sources.files {
+                        if !explain {
+                            println!("{}", file.display());
+                            continue;
+                        }
+                        match sources.provenance.get(file) {
+                            Some(inclusion) => {
+                                let kind =
+                                    symbol_kind_name(&inclusion.symbol.kind);
+                                println!(
+                                    "{} {}",
+                                    file.display(),
+                                    format!(
+                                        "({kind} {} used by {})",
+                                        inclusion.symbol.name,
+                                        inclusion.referenced_by.display()
+                                    )
+                                    .bright_black()
+                                );
+                            }
+                            None => println!("{}", file.display()),
+                        }
+                    }
+                    if explain {
+                        println!(
+                            "{} {}",
+                            sources.testbench_file.display(),
+                            format!("(testbench {testbench})").bright_black()
+                        );
+                    } else {
+                        println!("{}", sources.testbench_file.display());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Graph {
+            testbench,
+            std,
+            recurse,
+            format,
+        } => {
+            let rendered = dependency_graph(
+                &workspace_dir,
+                testbench.as_deref(),
+                resolve_std(&workspace_dir, std),
+                recurse,
+            )
+            .and_then(|graph| match format {
+                CliGraphFormat::Dot => Ok(graph.to_dot()),
+                CliGraphFormat::Json => graph.to_json(),
+            });
+            match rendered {
+                Ok(rendered) => print!("{rendered}"),
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Analyze {
+            testbench,
+            std,
+            recurse,
+            heap_size,
+            prefix_output,
+        } => {
+            let results = match analyze_workspace(
+                &workspace_dir,
+                testbench.as_deref(),
+                std.map(Into::into),
+                recurse,
+                heap_size.as_deref(),
+                nvc_output(prefix_output, cli.quiet || cli.json),
+            )
+            .await
+            {
+                Ok(results) => results,
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            };
+            let mut failed = false;
+            for result in &results {
+                let files = format!("({} files)", result.file_count);
+                match &result.status {
+                    AnalysisStatus::Passed => println!(
+                        "{} {} {}",
+                        "✓".bright_green(),
+                        result.library.cyan(),
+                        files.bright_black()
+                    ),
+                    AnalysisStatus::Failed(e) => {
+                        failed = true;
+                        println!(
+                            "{} {} {}",
+                            "✗".bright_red(),
+                            result.library.cyan(),
+                            files.bright_black()
+                        );
+                        eprintln!("{} {e}", "error:".bright_red());
+                    }
+                    AnalysisStatus::Skipped => println!(
+                        "{} {} {}",
+                        "-".bright_black(),
+                        result.library.cyan(),
+                        "(skipped)".bright_black()
+                    ),
+                }
+            }
+            if failed {
+                process::exit(1);
+            }
+        }
+        Commands::Why { symbol } => {
+            match explain_symbol(&workspace_dir, &symbol) {
+                Ok(explanation) => {
+                    if explanation.declarations.is_empty() {
+                        eprintln!(
+                            "{} no package or entity named '{symbol}' is \
+                             declared in vhdl_ls.toml's libraries",
+                            "error:".bright_red()
+                        );
+                        process::exit(1);
+                    }
+                    if explanation.declarations.len() > 1 {
+                        println!(
+                            "{} '{symbol}' is declared in {} files:",
+                            "!".bright_yellow(),
+                            explanation.declarations.len()
+                        );
+                    }
+                    for declaration in &explanation.declarations {
+                        println!(
+                            "{} {} {}",
+                            symbol_kind_name(&declaration.symbol.kind),
+                            declaration.symbol.name.cyan(),
+                            format!(
+                                "declared in {} ({})",
+                                declaration.file.display(),
+                                declaration.library
+                            )
+                            .bright_black()
+                        );
+                    }
+                    match explanation.reached_from {
+                        Some(chain) => {
+                            println!();
+                            println!(
+                                "{} {}",
+                                chain.testbench.cyan(),
+                                chain
+                                    .testbench_file
+                                    .display()
+                                    .to_string()
+                                    .bright_black()
+                            );
+                            for (depth, step) in chain.steps.iter().enumerate()
+                            {
+                                let verb = match step.symbol.kind {
+                                    SymbolKind::Entity => "instantiates",
+                                    _ => "uses",
+                                };
+                                println!(
+                                    "{}└─ {verb} {} {}",
+                                    "   ".repeat(depth),
+                                    step.symbol.name,
+                                    step.file
+                                        .display()
+                                        .to_string()
+                                        .bright_black()
+                                );
+                            }
+                        }
+                        None => {
+                            println!();
+                            println!("No testbench references '{symbol}'");
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+        Commands::Doctor { std } => {
+            let checks = check_environment(resolve_std(&workspace_dir, std));
+            for check in &checks {
+                let mark = if check.passed {
+                    "✓".bright_green()
+                } else if check.critical {
+                    "✗".bright_red()
+                } else {
+                    "!".bright_yellow()
+                };
+                println!("{mark} {}: {}", check.name, check.detail);
+            }
+            if checks.iter().any(|check| check.critical && !check.passed) {
+                process::exit(1);
+            }
+        }
+        Commands::Records { std, attribute } => {
+            match scan_tagged_records(
+                &workspace_dir,
+                resolve_std(&workspace_dir, std),
+                &attribute,
+            )
+            .and_then(|processor| processor.tagged_records_in_order())
+            {
+                Ok(records) => {
+                    if records.is_empty() {
+                        println!("No records tagged with '{attribute}' found");
+                    }
+                    for record in records {
+                        println!("{record}");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
         Commands::Test {
             testbench,
             std,
@@ -355,11 +1819,31 @@ async fn main() {
             recurse,
             ignore,
             runtime_flags,
+            generics,
+            seed,
+            architecture,
+            heap_size,
+            wave_format,
+            wave_dir,
+            no_wave,
+            gui,
             build_rust,
+            plugin,
             scaffold,
+            strict,
+            elaborate_only,
+            coverage,
+            cover_kinds,
+            cover_dir,
+            prefix_output,
+            sim_args,
         } => {
             if list {
-                let bench_dir = cwd.join("bench");
+                let bench_dir =
+                    bench_directory(&workspace_dir).unwrap_or_else(|e| {
+                        eprintln!("{} {e}", "error:".bright_red());
+                        process::exit(1);
+                    });
                 if !bench_dir.exists() {
                     println!("No bench dir found in {:}", bench_dir.as_str());
                 } else {
@@ -372,7 +1856,18 @@ async fn main() {
                         vw_lib::sim::find_mist_configs(&bench_dir)
                             .unwrap_or_default();
 
-                    match list_testbenches(&bench_dir, &ignore_set, recurse) {
+                    let not_testbenches = load_workspace_config(&workspace_dir)
+                        .map(|config| config.workspace.not_testbenches)
+                        .unwrap_or_default();
+
+                    match list_testbenches(
+                        &bench_dir,
+                        &ignore_set,
+                        recurse,
+                        &not_testbenches,
+                        resolve_std(&workspace_dir, std),
+                        &vhdl_extensions(&workspace_dir),
+                    ) {
                         Ok(testbenches) => {
                             if testbenches.is_empty() && mist_configs.is_empty()
                             {
@@ -408,20 +1903,78 @@ async fn main() {
                     }
                 }
             } else if let Some(testbench_name) = testbench {
-                println!("Running testbench: {}", testbench_name.cyan());
-                match run_testbench(
-                    &cwd,
-                    testbench_name.clone(),
-                    std.into(),
+                check_duplicate_symbols(&workspace_dir, strict);
+                if elaborate_only {
+                    info!("Elaborating testbench: {}", testbench_name.cyan());
+                } else {
+                    info!("Running testbench: {}", testbench_name.cyan());
+                }
+                // Mixed-signal testbenches run without NVC's seed and
+                // waveform options, so only pass the ones asked for
+                let mixed_signal =
+                    is_mixed_signal_testbench(&workspace_dir, &testbench_name)
+                        .unwrap_or_else(|e| {
+                            eprintln!("{} {e}", "error:".bright_red());
+                            process::exit(1);
+                        });
+                let seed = if mixed_signal || elaborate_only || scaffold {
+                    seed
+                } else {
+                    let seed = seed.unwrap_or_else(random_seed);
+                    info!("Using seed {seed} (repeat with --seed {seed})");
+                    Some(seed)
+                };
+                let wave_requested = !mixed_signal
+                    || wave_format.is_some()
+                    || wave_dir.is_some();
+                let wave = (!no_wave && !elaborate_only && wave_requested)
+                    .then(|| WaveOptions {
+                        format: wave_format.map(Into::into).unwrap_or_default(),
+                        dir: wave_dir,
+                    });
+                let coverage = coverage.then(|| CoverageOptions {
+                    kinds: cover_kinds.into_iter().map(Into::into).collect(),
+                    report_dir: cover_dir,
+                });
+                let options = TestOptions {
+                    architecture,
+                    vhdl_std: std.map(Into::into),
                     recurse,
-                    &runtime_flags,
+                    runtime_flags: test_runtime_flags(runtime_flags, sim_args),
+                    seed,
+                    generics,
+                    heap_size,
+                    wave,
                     build_rust,
+                    plugin,
                     scaffold,
+                    elaborate_only,
+                    coverage,
+                    output: nvc_output(prefix_output, cli.quiet || cli.json),
+                };
+                match run_testbench(
+                    &workspace_dir,
+                    testbench_name.clone(),
+                    &options,
                 )
                 .await
                 {
-                    Ok(()) => {
-                        if scaffold {
+                    Ok(result) if cli.json => print_json(&result),
+                    Err(e) if cli.json => {
+                        let mut result =
+                            TestResult::failed(&testbench_name, &e);
+                        result.seed = seed;
+                        print_json(&result);
+                        process::exit(1);
+                    }
+                    Ok(result) => {
+                        if elaborate_only {
+                            println!(
+                                "{} Testbench '{}' elaborated successfully",
+                                "✓".bright_green(),
+                                testbench_name
+                            );
+                        } else if scaffold {
                             println!(
                                 "{} Scaffolding generated for '{}'",
                                 "✓".bright_green(),
@@ -433,10 +1986,33 @@ async fn main() {
                                 "✓".bright_green(),
                                 testbench_name
                             );
-                            println!(
-                                "Waveform saved to: {}",
-                                format!("{testbench_name}.fst").cyan()
-                            );
+                            if let Some(waveform) = &result.waveform {
+                                println!(
+                                    "Waveform saved to: {}",
+                                    waveform.as_str().cyan()
+                                );
+                                if gui {
+                                    match open_waveform(
+                                        &workspace_dir,
+                                        waveform,
+                                    ) {
+                                        Ok(viewer) => info!(
+                                            "Opened waveform in {}",
+                                            viewer.cyan()
+                                        ),
+                                        Err(e) => eprintln!(
+                                            "{} {e}",
+                                            "warning:".bright_yellow()
+                                        ),
+                                    }
+                                }
+                            }
+                            if let Some(coverage) = &result.coverage {
+                                println!(
+                                    "Coverage report saved to: {}",
+                                    coverage.report_dir.as_str().cyan()
+                                );
+                            }
                         }
                     }
                     Err(e) => {
@@ -452,5 +2028,101 @@ async fn main() {
                 process::exit(1);
             }
         }
+        Commands::Run {
+            testbench,
+            std,
+            recurse,
+            generics,
+            heap_size,
+            phases,
+            elab_flags,
+            prefix_output,
+            run_flags,
+        } => {
+            let passthrough = NvcPassthrough {
+                phases: phases.into_iter().map(Into::into).collect(),
+                elab_flags,
+                run_flags,
+            };
+            match run_testbench_passthrough(
+                &workspace_dir,
+                &testbench,
+                std.map(Into::into),
+                recurse,
+                &generics,
+                heap_size.as_deref(),
+                &passthrough,
+                nvc_output(prefix_output, cli.quiet),
+            )
+            .await
+            {
+                Ok(_) => {
+                    println!(
+                        "{} Testbench '{}' completed successfully!",
+                        "✓".bright_green(),
+                        testbench
+                    );
+                }
+                Err(e) => {
+                    eprintln!("{} {e}", "error:".bright_red());
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_color_writes_no_ansi_escapes() {
+        let rendered = || format!("{} {}", "error:".bright_red(), "x".bold());
+
+        ColorChoice::Always.apply();
+        assert!(rendered().contains('\x1b'));
+
+        ColorChoice::Never.apply();
+        assert_eq!(rendered(), "error: x");
+
+        // auto honors NO_COLOR even on a terminal
+        std::env::set_var("NO_COLOR", "1");
+        ColorChoice::Auto.apply();
+        std::env::remove_var("NO_COLOR");
+        assert_eq!(rendered(), "error: x");
+    }
+
+    #[test]
+    fn runtime_flags_come_before_the_arguments_after_the_separator() {
+        let cli = Cli::try_parse_from([
+            "vw",
+            "test",
+            "tb_top",
+            "--runtime-flags=--ieee-warnings=off,--stop-delta=1000",
+            "--",
+            "--stop-time=1ms,5ns",
+            "--exit-severity=error",
+        ])
+        .unwrap();
+        let Commands::Test {
+            runtime_flags,
+            sim_args,
+            ..
+        } = cli.command
+        else {
+            panic!("expected vw test");
+        };
+
+        // Only --runtime-flags is split on commas
+        assert_eq!(
+            test_runtime_flags(runtime_flags, sim_args),
+            [
+                "--ieee-warnings=off",
+                "--stop-delta=1000",
+                "--stop-time=1ms,5ns",
+                "--exit-severity=error",
+            ]
+        );
     }
 }
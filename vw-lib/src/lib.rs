@@ -12,14 +12,18 @@
 //! # Example
 //!
 //! ```no_run
-//! use vw_lib::{init_workspace, update_workspace};
+//! use vw_lib::{init_workspace, update_workspace, InitTemplate};
 //! use camino::Utf8Path;
 //!
 //! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
 //! let workspace_dir = Utf8Path::new(".");
 //!
 //! // Initialize a new workspace
-//! init_workspace(workspace_dir, "my_project".to_string())?;
+//! init_workspace(
+//!     workspace_dir,
+//!     "my_project".to_string(),
+//!     InitTemplate::Basic,
+//! )?;
 //!
 //! // Update dependencies
 //! update_workspace(workspace_dir).await?;
@@ -28,29 +32,49 @@
 //! ```
 
 use std::cell::RefCell;
-use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
-use std::path::{Path, PathBuf};
+use std::collections::{
+    btree_map, hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque,
+};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
 use std::{fmt, fs};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use vhdl_lang::ast::{ArchitectureBody, EntityDeclaration};
 use vhdl_lang::{VHDLParser, VHDLStandard};
 
 use petgraph::{
-    algo::toposort,
+    algo::{tarjan_scc, toposort},
     graph::{DiGraph, NodeIndex},
 };
 
 use crate::mapping::{FileData, SymbolKind, VwSymbol, VwSymbolFinder};
-use crate::nvc_helpers::{run_nvc_analysis, run_nvc_elab, run_nvc_sim};
-use crate::visitor::walk_design_file;
+pub use crate::nvc_helpers::NvcOutput;
+use crate::nvc_helpers::{
+    nvc_coverage_database, run_nvc_analysis, run_nvc_cover_report,
+    run_nvc_elab, run_nvc_sim,
+};
+use crate::visitor::{walk_design_file, Visitor, VisitorResult};
 
 pub mod mapping;
 pub mod nvc_helpers;
 pub mod sim;
+pub mod vhdl_printer;
 pub mod visitor;
 
 const BUILD_DIR: &str = "vw_build";
+const DEFAULT_BENCH_DIR: &str = "bench";
+const DEFAULT_SRC_DIR: &str = "src";
+
+/// File extensions always recognized as VHDL sources, compared
+/// case-insensitively. `[workspace] vhdl_extensions` adds to these.
+pub const DEFAULT_VHDL_EXTENSIONS: [&str; 2] = ["vhd", "vhdl"];
+
+/// Tcl variable `deps.tcl` uses for the workspace root of vendored files.
+const TCL_WORKSPACE_DIR_VAR: &str = "$vw_workspace_dir";
 
 // ============================================================================
 // Error Types
@@ -58,23 +82,66 @@ const BUILD_DIR: &str = "vw_build";
 
 #[derive(Debug)]
 pub enum VwError {
-    Config { message: String },
-    Dependency { message: String },
-    Git { message: String },
-    FileSystem { message: String },
-    Testbench { message: String },
-    NvcSimulation { command: String },
-    NvcElab { command: String },
-    NvcAnalysis { library: String, command: String },
-    CodeGen { message: String },
-    Simulation { message: String },
+    Config {
+        message: String,
+    },
+    Dependency {
+        message: String,
+    },
+    Git {
+        message: String,
+    },
+    FileSystem {
+        message: String,
+        path: Option<PathBuf>,
+    },
+    Testbench {
+        message: String,
+    },
+    NvcSimulation {
+        command: String,
+        /// Flags in `command` that came from the user rather than vw.
+        user_flags: Vec<String>,
+    },
+    NvcElab {
+        command: String,
+        /// Flags in `command` that came from the user rather than vw.
+        user_flags: Vec<String>,
+    },
+    NvcAnalysis {
+        library: String,
+        command: String,
+    },
+    NvcCoverage {
+        command: String,
+    },
+    NvcNotFound,
+    CodeGen {
+        message: String,
+    },
+    Simulation {
+        message: String,
+    },
     Io(std::io::Error),
     Serialization(toml::ser::Error),
     Deserialization(toml::de::Error),
     Regex(regex::Error),
+    Git2(git2::Error),
+}
+
+impl std::error::Error for VwError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VwError::Io(e) => Some(e),
+            VwError::Serialization(e) => Some(e),
+            VwError::Deserialization(e) => Some(e),
+            VwError::Regex(e) => Some(e),
+            VwError::Git2(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
-impl std::error::Error for VwError {}
 impl From<std::io::Error> for VwError {
     fn from(err: std::io::Error) -> Self {
         VwError::Io(err)
@@ -99,22 +166,47 @@ impl From<regex::Error> for VwError {
     }
 }
 
+impl From<git2::Error> for VwError {
+    fn from(err: git2::Error) -> Self {
+        VwError::Git2(err)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, VwError>;
 
+/// List the user-supplied flags of a failed NVC command, so they can be
+/// told apart from the ones vw added.
+fn write_user_flags(
+    f: &mut fmt::Formatter<'_>,
+    user_flags: &[String],
+) -> fmt::Result {
+    if !user_flags.is_empty() {
+        writeln!(f, "user flags (passed through unchanged):")?;
+        writeln!(f, "{}", user_flags.join(" "))?;
+    }
+    Ok(())
+}
+
 impl fmt::Display for VwError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            VwError::NvcSimulation { command } => {
+            VwError::NvcSimulation {
+                command,
+                user_flags,
+            } => {
                 writeln!(f, "NVC simulation failed")?;
                 writeln!(f, "command:")?;
                 writeln!(f, "{command}")?;
-                Ok(())
+                write_user_flags(f, user_flags)
             }
-            VwError::NvcElab { command } => {
+            VwError::NvcElab {
+                command,
+                user_flags,
+            } => {
                 writeln!(f, "NVC elaboration failed")?;
                 writeln!(f, "command:")?;
                 writeln!(f, "{command}")?;
-                Ok(())
+                write_user_flags(f, user_flags)
             }
             VwError::NvcAnalysis { library, command } => {
                 writeln!(f, "NVC analysis failed for library '{library}'")?;
@@ -122,6 +214,20 @@ impl fmt::Display for VwError {
                 writeln!(f, "{command}")?;
                 Ok(())
             }
+            VwError::NvcCoverage { command } => {
+                writeln!(f, "NVC coverage report failed")?;
+                writeln!(f, "command:")?;
+                writeln!(f, "{command}")?;
+                Ok(())
+            }
+            VwError::NvcNotFound => {
+                write!(
+                    f,
+                    "nvc was not found; install NVC \
+                     (https://github.com/nickg/nvc) and make sure `nvc` is \
+                     on your PATH (run `vw doctor` to check)"
+                )
+            }
             VwError::CodeGen { message } => {
                 write!(f, "Code generation failed: {message}")
             }
@@ -137,9 +243,23 @@ impl fmt::Display for VwError {
             VwError::Git { message } => {
                 write!(f, "Git operation failed: {message}")
             }
-            VwError::FileSystem { message } => {
-                write!(f, "File system error: {message}")
-            }
+            VwError::FileSystem {
+                message,
+                path: Some(path),
+            } => match Utf8Path::from_path(path) {
+                Some(path) => {
+                    write!(f, "File system error at {path}: {message}")
+                }
+                None => write!(
+                    f,
+                    "File system error at {}: {message}",
+                    path.display()
+                ),
+            },
+            VwError::FileSystem {
+                message,
+                path: None,
+            } => write!(f, "File system error: {message}"),
             VwError::Testbench { message } => {
                 write!(f, "Testbench error: {message}")
             }
@@ -149,6 +269,23 @@ impl fmt::Display for VwError {
                 write!(f, "Deserialization error: {e}")
             }
             VwError::Regex(e) => write!(f, "Regex error: {e}"),
+            VwError::Git2(e) if e.code() == git2::ErrorCode::Auth => {
+                write!(
+                    f,
+                    "Git authentication failed: {} (check the credentials \
+                     for this host in the [auth] sources of vw.toml, or the \
+                     --token given)",
+                    e.message()
+                )
+            }
+            VwError::Git2(e) => {
+                write!(
+                    f,
+                    "Git operation failed ({:?}): {}",
+                    e.class(),
+                    e.message()
+                )
+            }
         }
     }
 }
@@ -157,15 +294,30 @@ impl fmt::Display for VwError {
 // VHDL Standard
 // ============================================================================
 
-#[derive(Clone, Copy, Debug)]
+/// A VHDL language revision, written as its year in `vw.toml`.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq,
+)]
 pub enum VhdlStandard {
+    #[serde(rename = "93", alias = "1993")]
+    Vhdl1993,
+    #[serde(rename = "2002")]
+    Vhdl2002,
+    #[serde(rename = "2008")]
     Vhdl2008,
+    #[default]
+    #[serde(rename = "2019")]
     Vhdl2019,
 }
 
 impl From<VhdlStandard> for VHDLStandard {
     fn from(val: VhdlStandard) -> Self {
         match val {
+            // vhdl_lang has no 2002 mode; 2002 differs from 93 mainly in
+            // protected types, which the 1993 parser accepts anyway
+            VhdlStandard::Vhdl1993 | VhdlStandard::Vhdl2002 => {
+                VHDLStandard::VHDL1993
+            }
             VhdlStandard::Vhdl2008 => VHDLStandard::VHDL2008,
             VhdlStandard::Vhdl2019 => VHDLStandard::VHDL2019,
         }
@@ -175,12 +327,230 @@ impl From<VhdlStandard> for VHDLStandard {
 impl fmt::Display for VhdlStandard {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            VhdlStandard::Vhdl1993 => write!(f, "93"),
+            VhdlStandard::Vhdl2002 => write!(f, "2002"),
             VhdlStandard::Vhdl2008 => write!(f, "2008"),
             VhdlStandard::Vhdl2019 => write!(f, "2019"),
         }
     }
 }
 
+impl VhdlStandard {
+    /// The name vhdl_ls accepts for this standard in `vhdl_ls.toml`, which
+    /// only knows 1993, 2008 and 2019.
+    pub fn vhdl_ls_name(self) -> &'static str {
+        match self {
+            VhdlStandard::Vhdl1993 | VhdlStandard::Vhdl2002 => "1993",
+            VhdlStandard::Vhdl2008 => "2008",
+            VhdlStandard::Vhdl2019 => "2019",
+        }
+    }
+}
+
+/// Waveform file format written by `nvc -r --wave`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WaveFormat {
+    #[default]
+    Fst,
+    Vcd,
+    Ghw,
+}
+
+impl WaveFormat {
+    /// File extension for waveforms in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            WaveFormat::Fst => "fst",
+            WaveFormat::Vcd => "vcd",
+            WaveFormat::Ghw => "ghw",
+        }
+    }
+}
+
+impl fmt::Display for WaveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// Where and in which format a testbench run writes its waveform.
+#[derive(Clone, Debug, Default)]
+pub struct WaveOptions {
+    pub format: WaveFormat,
+    /// Directory for the waveform file, relative to the workspace
+    /// directory; the workspace directory if unset.
+    pub dir: Option<Utf8PathBuf>,
+}
+
+impl WaveOptions {
+    /// Directory the waveform file is written to.
+    pub fn dir(&self, workspace_dir: &Utf8Path) -> Utf8PathBuf {
+        match &self.dir {
+            Some(dir) => workspace_dir.join(dir),
+            None => workspace_dir.to_path_buf(),
+        }
+    }
+
+    /// Path of the waveform file written for `testbench_name`.
+    pub fn path(
+        &self,
+        workspace_dir: &Utf8Path,
+        testbench_name: &str,
+    ) -> Utf8PathBuf {
+        let file_name = format!("{testbench_name}.{}", self.format.extension());
+        self.dir(workspace_dir).join(file_name)
+    }
+}
+
+/// Kind of code coverage NVC collects, as passed to `nvc -e --cover`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageKind {
+    Statement,
+    Branch,
+    Toggle,
+    Expression,
+    FsmState,
+}
+
+impl CoverageKind {
+    /// Kinds collected when none are chosen.
+    pub const DEFAULT: [CoverageKind; 3] = [
+        CoverageKind::Statement,
+        CoverageKind::Branch,
+        CoverageKind::Toggle,
+    ];
+}
+
+impl fmt::Display for CoverageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageKind::Statement => write!(f, "statement"),
+            CoverageKind::Branch => write!(f, "branch"),
+            CoverageKind::Toggle => write!(f, "toggle"),
+            CoverageKind::Expression => write!(f, "expression"),
+            CoverageKind::FsmState => write!(f, "fsm-state"),
+        }
+    }
+}
+
+/// Coverage collection for a testbench run.
+#[derive(Clone, Debug)]
+pub struct CoverageOptions {
+    /// Kinds to collect; [`CoverageKind::DEFAULT`] if empty.
+    pub kinds: Vec<CoverageKind>,
+    /// Directory for the HTML report, relative to the workspace directory;
+    /// `vw_build/coverage/<testbench>` if unset.
+    pub report_dir: Option<Utf8PathBuf>,
+}
+
+impl CoverageOptions {
+    /// Directory the HTML report for `testbench_name` is written to.
+    pub fn report_dir(
+        &self,
+        workspace_dir: &Utf8Path,
+        testbench_name: &str,
+    ) -> Utf8PathBuf {
+        match &self.report_dir {
+            Some(dir) => workspace_dir.join(dir),
+            None => workspace_build_dir(workspace_dir)
+                .join("coverage")
+                .join(testbench_name),
+        }
+    }
+}
+
+/// A step of building and running a testbench with NVC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NvcPhase {
+    /// Analyze the dependency libraries and the testbench's sources.
+    Analyze,
+    /// Elaborate the testbench.
+    Elaborate,
+    /// Run the elaborated testbench.
+    Run,
+}
+
+/// Raw NVC flags and phase selection for [`run_testbench_passthrough`].
+#[derive(Clone, Debug, Default)]
+pub struct NvcPassthrough {
+    /// Phases to run, always in analyze, elaborate, run order; all of them
+    /// if empty. Skipped phases rely on the results of earlier runs in
+    /// `vw_build`.
+    pub phases: Vec<NvcPhase>,
+    /// Flags added to `nvc -e` before the testbench name.
+    pub elab_flags: Vec<String>,
+    /// Flags added to `nvc -r` after the testbench name.
+    pub run_flags: Vec<String>,
+}
+
+impl NvcPassthrough {
+    fn runs(&self, phase: NvcPhase) -> bool {
+        self.phases.is_empty() || self.phases.contains(&phase)
+    }
+}
+
+/// Coverage collected by [`run_testbench`].
+#[derive(Clone, Debug, Serialize)]
+pub struct CoverageReport {
+    /// NVC coverage database written by the simulation.
+    pub database: Utf8PathBuf,
+    /// Directory holding the HTML report generated from `database`.
+    pub report_dir: Utf8PathBuf,
+}
+
+/// Outcome of a [`run_testbench`] call.
+///
+/// The JSON form is part of `vw test --json`'s output and only gains
+/// fields: `testbench`, `passed`, `error` (the failure message, or null),
+/// `waveform` (path, or null), `coverage` (`{"database",
+/// "report_dir"}`, or null) and `seed` (number, or null).
+#[derive(Clone, Debug, Serialize)]
+pub struct TestResult {
+    pub testbench: String,
+    pub passed: bool,
+    pub error: Option<String>,
+    /// Waveform file written by the simulation.
+    pub waveform: Option<Utf8PathBuf>,
+    pub coverage: Option<CoverageReport>,
+    /// Random seed the simulation ran with.
+    pub seed: Option<u64>,
+}
+
+impl TestResult {
+    fn passed(testbench: &str) -> Self {
+        TestResult {
+            testbench: testbench.to_string(),
+            passed: true,
+            error: None,
+            waveform: None,
+            coverage: None,
+            seed: None,
+        }
+    }
+
+    /// Result for a run of `testbench` that failed with `error`.
+    pub fn failed(testbench: &str, error: &VwError) -> Self {
+        TestResult {
+            testbench: testbench.to_string(),
+            passed: false,
+            error: Some(error.to_string().trim_end().to_string()),
+            waveform: None,
+            coverage: None,
+            seed: None,
+        }
+    }
+}
+
+/// A fresh random seed for [`run_testbench`], for runs that should be
+/// reproducible later without choosing a seed up front.
+pub fn random_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    // RandomState is seeded randomly for each process and each instance
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
 // ============================================================================
 // Configuration Structures
 // ============================================================================
@@ -192,6 +562,10 @@ pub struct WorkspaceConfig {
     pub dependencies: HashMap<String, Dependency>,
     #[serde(default)]
     pub tools: Option<ToolsConfig>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub simulator: Option<SimulatorConfig>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -200,6 +574,50 @@ pub struct WorkspaceInfo {
     pub name: String,
     #[allow(dead_code)]
     pub version: String,
+    /// Entities that look like testbenches but are helpers rather than
+    /// runnable top-levels.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_testbenches: Vec<String>,
+    /// Testbench directory relative to the workspace (default `bench`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bench_dir: Option<String>,
+    /// How dependency file paths are written to `vhdl_ls.toml`
+    /// (default `home`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vhdl_ls_paths: Option<VhdlLsPaths>,
+    /// VHDL standard for simulation and for vhdl_ls (default 2019).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vhdl_standard: Option<VhdlStandard>,
+    /// File extensions to treat as VHDL in addition to `vhd` and `vhdl`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vhdl_extensions: Vec<String>,
+    /// Hardlink the files of patched dependency trees that their patch
+    /// leaves alone to the unpatched tree instead of copying them, where
+    /// the filesystem allows (default false).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_files: Option<bool>,
+    /// Descend into symlinked directories when looking for sources under
+    /// the bench directory (default false).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_symlinks: Option<bool>,
+}
+
+/// How cached dependency files are referenced in the generated
+/// `vhdl_ls.toml`.
+#[derive(
+    Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum VhdlLsPaths {
+    /// Prefix paths under the home directory with a literal `$HOME`, which
+    /// vhdl_ls expands but most other tools do not.
+    #[default]
+    Home,
+    /// Paths relative to the workspace directory, which vhdl_ls resolves
+    /// against the directory holding `vhdl_ls.toml`.
+    Relative,
+    /// Absolute paths, valid only on this machine.
+    Absolute,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -211,6 +629,11 @@ pub struct Dependency {
     pub commit: Option<String>,
     #[serde(default)]
     pub src: Vec<String>,
+    /// Files to use, in order, relative to the single `src` directory (or
+    /// the repository root). When set, these replace discovery by `src`
+    /// globs, `recursive` and `exclude`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
     #[serde(default)]
     pub recursive: bool,
     #[serde(default)]
@@ -219,6 +642,37 @@ pub struct Dependency {
     pub submodules: bool,
     #[serde(default)]
     pub exclude: Vec<String>,
+    /// Let vhdl_ls lint this dependency instead of marking it third-party.
+    #[serde(default)]
+    pub lint: bool,
+    /// Standard to analyze this dependency with, for libraries written
+    /// against an older standard than the workspace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vhdl_standard: Option<VhdlStandard>,
+    /// Extra `nvc -a` options for this dependency's library, such as
+    /// `--relaxed`, passed after vw's own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub analysis_flags: Vec<String>,
+    /// `vhdl_ls.toml` library to put this dependency's files in (default:
+    /// the dependency's name). Dependencies naming the same library share
+    /// it, and `defaultlib` adds the files to the workspace's own sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub library: Option<String>,
+    /// Patch file, relative to the workspace, applied to a copy of the
+    /// repository's cache tree that only this patch uses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+    /// Workspace files, relative to the workspace, added at the top of the
+    /// dependency's layout alongside its own files.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlay: Vec<String>,
+}
+
+impl Dependency {
+    /// The library this dependency, listed under `name`, belongs to.
+    pub fn library_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.library.as_deref().unwrap_or(name)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -229,9 +683,18 @@ pub struct LockFile {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LockedDependency {
     pub repo: String,
+    /// The branch the dependency follows, as given in `vw.toml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
     pub commit: String,
     #[serde(default)]
     pub src: Vec<String>,
+    /// The dependency's explicit file list, relative to its `src`
+    /// directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    /// The cache tree the dependency's files are selected from, relative
+    /// to the dependency cache directory.
     pub path: PathBuf,
     #[serde(default)]
     pub recursive: bool,
@@ -241,15 +704,88 @@ pub struct LockedDependency {
     pub submodules: bool,
     #[serde(default)]
     pub exclude: Vec<String>,
+    /// Workspace-relative directory holding a vendored copy of this
+    /// dependency. When set, `vw update` uses it instead of the cache.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendored: Option<PathBuf>,
+    /// The remote's default branch, recorded when the dependency names
+    /// neither a branch nor a commit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_branch: Option<String>,
+    /// The library the dependency's files belong to, if not its name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub library: Option<String>,
+    /// The patch applied to the dependency's files, as given in `vw.toml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+    /// Hash of the patch's contents, which is part of the cache tree name
+    /// so that editing the patch builds the patched tree again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch_hash: Option<String>,
+    /// The overlay files added to the dependency, as given in `vw.toml`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlay: Vec<String>,
+}
+
+impl LockedDependency {
+    /// The library this dependency, listed under `name`, belongs to.
+    pub fn library_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.library.as_deref().unwrap_or(name)
+    }
+
+    /// The first setting of `dep` that no longer matches this locked
+    /// entry, if any. `patch_hash` is the hash of `dep`'s patch file as it
+    /// is now.
+    fn changed_setting(
+        &self,
+        dep: &Dependency,
+        patch_hash: Option<&str>,
+    ) -> Option<&'static str> {
+        let pin_matches = match (&dep.branch, &dep.commit) {
+            (Some(branch), _) => self.branch.as_ref() == Some(branch),
+            (None, Some(commit)) => {
+                self.branch.is_none()
+                    && self.commit.starts_with(&commit.to_ascii_lowercase())
+            }
+            (None, None) => {
+                self.branch.is_none() && self.default_branch.is_some()
+            }
+        };
+        if self.repo != dep.repo {
+            Some("repo")
+        } else if !pin_matches {
+            Some("branch or commit")
+        } else if self.src != dep.src {
+            Some("src")
+        } else if self.files != dep.files {
+            Some("files")
+        } else if self.recursive != dep.recursive {
+            Some("recursive")
+        } else if self.submodules != dep.submodules {
+            Some("submodules")
+        } else if self.exclude != dep.exclude {
+            Some("exclude")
+        } else if self.patch != dep.patch
+            || self.patch_hash.as_deref() != patch_hash
+        {
+            Some("patch")
+        } else if self.overlay != dep.overlay {
+            Some("overlay")
+        } else {
+            None
+        }
+    }
 }
 
+/// Contents of `vhdl_ls.toml`. Maps are ordered so the file is written
+/// the same way every time.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VhdlLsConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub standard: Option<String>,
-    pub libraries: HashMap<String, VhdlLsLibrary>,
+    pub libraries: BTreeMap<String, VhdlLsLibrary>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub lint: Option<HashMap<String, serde_json::Value>>,
+    pub lint: Option<BTreeMap<String, serde_json::Value>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -269,6 +805,14 @@ pub struct VhdlLsLibrary {
     pub exclude: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_third_party: Option<bool>,
+    /// Standard to analyze this library with instead of the workspace's.
+    /// vhdl_ls itself uses one standard for every library and ignores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vhdl_standard: Option<VhdlStandard>,
+    /// Extra `nvc -a` options to analyze this library with. vhdl_ls
+    /// ignores them too.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub analysis_flags: Vec<String>,
 }
 
 // ============================================================================
@@ -330,12 +874,22 @@ pub struct PortMapping {
 // ============================================================================
 
 /// Credentials for authenticating with git repositories.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Credentials {
     pub username: String,
     pub password: String,
 }
 
+// Keep passwords and tokens out of debug output and logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 impl Credentials {
     /// Create new credentials from username and password.
     pub fn new(username: String, password: String) -> Self {
@@ -343,37 +897,216 @@ impl Credentials {
     }
 }
 
+/// A place credentials for a git host can be looked up.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialSource {
+    /// The `VW_GIT_USERNAME` and `VW_GIT_PASSWORD` environment variables.
+    Env,
+    /// An access token in an environment variable chosen per host (see
+    /// [`AuthConfig::token_env`]).
+    Token,
+    /// The user's netrc file: `$NETRC`, `~/.netrc` or `~/_netrc`.
+    Netrc,
+    /// git's configured credential helper.
+    Helper,
+}
+
+impl CredentialSource {
+    /// Look up credentials for `repo_url` from this source.
+    pub fn credentials(
+        &self,
+        repo_url: &str,
+        auth: &AuthConfig,
+    ) -> Result<Option<Credentials>> {
+        match self {
+            CredentialSource::Env => Ok(get_access_credentials_from_env()),
+            CredentialSource::Token => {
+                let host = extract_hostname_from_repo_url(repo_url)?;
+                Ok(get_access_token_from_env(&host, &auth.token_env))
+            }
+            CredentialSource::Netrc => {
+                let host = extract_hostname_from_repo_url(repo_url)?;
+                get_access_credentials_from_netrc(&host)
+            }
+            CredentialSource::Helper => {
+                Ok(get_access_credentials_from_helper(repo_url))
+            }
+        }
+    }
+}
+
+/// Credential sources tried when `[auth] sources` is not configured.
+pub const DEFAULT_CREDENTIAL_SOURCES: [CredentialSource; 4] = [
+    CredentialSource::Env,
+    CredentialSource::Token,
+    CredentialSource::Netrc,
+    CredentialSource::Helper,
+];
+
+fn default_credential_sources() -> Vec<CredentialSource> {
+    DEFAULT_CREDENTIAL_SOURCES.to_vec()
+}
+
+/// Workspace-wide `[auth]` section.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AuthConfig {
+    /// Credential sources in the order they are tried; the first one that
+    /// has credentials for a host wins.
+    #[serde(default = "default_credential_sources")]
+    pub sources: Vec<CredentialSource>,
+    /// Environment variable holding an access token, keyed by host. Hosts
+    /// not listed use `GITHUB_TOKEN` for github.com, then `VW_GIT_TOKEN`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub token_env: HashMap<String, String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            sources: default_credential_sources(),
+            token_env: HashMap::new(),
+        }
+    }
+}
+
+/// Workspace-wide `[simulator]` section.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct SimulatorConfig {
+    /// NVC heap size passed as `-M`, e.g. `"1g"` (default `256m`).
+    #[serde(default)]
+    pub heap: Option<String>,
+    /// Program that `vw test --gui` opens waveforms with (default: the
+    /// first of [`WAVEFORM_VIEWERS`] found).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waveform_viewer: Option<String>,
+}
+
 // ============================================================================
 // Authentication Helpers
 // ============================================================================
 
-/// Get access token for a given host from the netrc file.
+/// Get access credentials for a repository, trying each of the
+/// configured sources in order and returning the first credentials found.
 ///
-/// This function reads the user's .netrc file and looks for credentials
-/// for the specified host. For GitHub, it returns the password field
-/// which should contain the personal access token.
-/// Get access credentials (username, password) for a given host from the netrc file.
-pub fn get_access_credentials_from_netrc(
-    host: &str,
+/// A source that fails (say, an unreadable netrc file) is logged and
+/// skipped. Its error is returned only if no later source has credentials.
+pub fn get_access_credentials(
+    repo_url: &str,
+    auth: &AuthConfig,
 ) -> Result<Option<Credentials>> {
+    let mut first_error = None;
+    for source in &auth.sources {
+        match source.credentials(repo_url, auth) {
+            Ok(Some(creds)) => return Ok(Some(creds)),
+            Ok(None) => {}
+            Err(e) => {
+                debug!("Credential source {source:?} failed: {e}");
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+/// Get access credentials from the `VW_GIT_USERNAME` and
+/// `VW_GIT_PASSWORD` environment variables, if both are set.
+pub fn get_access_credentials_from_env() -> Option<Credentials> {
+    let username = std::env::var("VW_GIT_USERNAME").ok()?;
+    let password = std::env::var("VW_GIT_PASSWORD").ok()?;
+    Some(Credentials::new(username, password))
+}
+
+/// Username sent with access tokens; GitHub and most other hosts ignore
+/// it, but it must not be empty.
+pub const TOKEN_USERNAME: &str = "x-access-token";
+
+/// Get token credentials for `host` from the environment.
+///
+/// The variable named in `token_env` for the host is used if there is one;
+/// otherwise `GITHUB_TOKEN` for github.com and then `VW_GIT_TOKEN`.
+pub fn get_access_token_from_env(
+    host: &str,
+    token_env: &HashMap<String, String>,
+) -> Option<Credentials> {
+    let mut vars = Vec::new();
+    match token_env.get(host) {
+        Some(var) => vars.push(var.as_str()),
+        None => {
+            if host == "github.com" {
+                vars.push("GITHUB_TOKEN");
+            }
+            vars.push("VW_GIT_TOKEN");
+        }
+    }
+    vars.into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|token| !token.is_empty())
+        .map(|token| Credentials::new(TOKEN_USERNAME.to_string(), token))
+}
+
+/// Get access credentials for a repository from git's credential helper.
+pub fn get_access_credentials_from_helper(
+    repo_url: &str,
+) -> Option<Credentials> {
+    let config = git2::Config::open_default().ok()?;
+    let mut helper = git2::CredentialHelper::new(repo_url);
+    helper.config(&config);
+    let (username, password) = helper.execute()?;
+    Some(Credentials::new(username, password))
+}
+
+/// The netrc file to read credentials from: the file named by the `NETRC`
+/// environment variable, or else `.netrc` in the home directory, or else
+/// `_netrc` there (the usual name on Windows). `None` if there is no such
+/// file in the home directory; a `NETRC` naming no file is an error.
+fn netrc_path() -> Result<Option<PathBuf>> {
+    if let Some(path) = std::env::var_os("NETRC").filter(|v| !v.is_empty()) {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            return Err(VwError::FileSystem {
+                message: "$NETRC does not name a file; unset it to use \
+                          ~/.netrc"
+                    .to_string(),
+                path: Some(path),
+            });
+        }
+        return Ok(Some(path));
+    }
+
     let home_dir = dirs::home_dir().ok_or_else(|| VwError::FileSystem {
         message: "Could not determine home directory".to_string(),
+        path: None,
     })?;
+    Ok([".netrc", "_netrc"]
+        .into_iter()
+        .map(|name| home_dir.join(name))
+        .find(|path| path.is_file()))
+}
 
-    let netrc_path = home_dir.join(".netrc");
-    if !netrc_path.exists() {
+/// Get access credentials (username, password) for a given host from the
+/// netrc file (see [`netrc_path`] for where it is looked for).
+pub fn get_access_credentials_from_netrc(
+    host: &str,
+) -> Result<Option<Credentials>> {
+    let Some(netrc_path) = netrc_path()? else {
         return Ok(None);
-    }
+    };
 
     let netrc_content = std::fs::read_to_string(&netrc_path).map_err(|e| {
         VwError::FileSystem {
-            message: format!("Failed to read .netrc file: {e}"),
+            message: format!("Failed to read netrc file: {e}"),
+            path: Some(netrc_path.clone()),
         }
     })?;
 
     let netrc = netrc::Netrc::parse(netrc_content.as_bytes()).map_err(|e| {
         VwError::FileSystem {
-            message: format!("Failed to parse .netrc file: {e:?}"),
+            message: format!("Failed to parse netrc file: {e:?}"),
+            path: Some(netrc_path.clone()),
         }
     })?;
 
@@ -406,26 +1139,40 @@ pub fn get_access_token_from_netrc(host: &str) -> Result<Option<String>> {
 
 /// Extract hostname from a git repository URL.
 ///
-/// Supports both HTTPS and SSH URLs:
+/// Supports URLs with a scheme and scp-like SSH paths. Any port is dropped
+/// so the result can be matched against `.netrc` machine names:
 /// - https://github.com/user/repo.git -> github.com
+/// - https://git.example.com:8443/user/repo.git -> git.example.com
+/// - ssh://git@git.example.com:2222/org/repo.git -> git.example.com
 /// - git@github.com:user/repo.git -> github.com
+/// - git@git.example.com:2222/org/repo.git -> git.example.com
 pub fn extract_hostname_from_repo_url(repo_url: &str) -> Result<String> {
-    if repo_url.starts_with("https://") {
+    if repo_url.contains("://") {
+        // https://, http://, ssh:// and git:// URLs; host_str() never
+        // includes the port
         let url = url::Url::parse(repo_url).map_err(|e| VwError::Config {
             message: format!("Invalid repository URL '{repo_url}': {e}"),
         })?;
-        Ok(url.host_str().unwrap_or("").to_string())
-    } else if repo_url.starts_with("git@") {
-        // Parse SSH format: git@hostname:path
-        if let Some(at_pos) = repo_url.find('@') {
-            if let Some(colon_pos) = repo_url[at_pos..].find(':') {
-                let hostname = &repo_url[at_pos + 1..at_pos + colon_pos];
-                return Ok(hostname.to_string());
-            }
+        match url.host_str() {
+            Some(host) if !host.is_empty() => Ok(host.to_string()),
+            _ => Err(VwError::Config {
+                message: format!("Repository URL has no host: {repo_url}"),
+            }),
         }
-        Err(VwError::Config {
-            message: format!("Invalid SSH repository URL format: {repo_url}"),
-        })
+    } else if let Some((authority, _path)) = repo_url.split_once(':') {
+        // scp-like syntax: [user@]hostname:path, where the path may start
+        // with a port as in git@hostname:2222/org/repo.git
+        let hostname = authority
+            .rsplit_once('@')
+            .map_or(authority, |(_user, host)| host);
+        if hostname.is_empty() || hostname.contains('/') {
+            return Err(VwError::Config {
+                message: format!(
+                    "Invalid SSH repository URL format: {repo_url}"
+                ),
+            });
+        }
+        Ok(hostname.to_string())
     } else {
         Err(VwError::Config {
             message: format!("Unsupported repository URL format: {repo_url}"),
@@ -437,8 +1184,105 @@ pub fn extract_hostname_from_repo_url(repo_url: &str) -> Result<String> {
 // Public API - Workspace Management
 // ============================================================================
 
+/// What [`init_workspace`] creates besides `vw.toml`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InitTemplate {
+    /// Only `vw.toml`.
+    Bare,
+    /// `src/` and `bench/` with an example entity and its testbench, a
+    /// matching `vhdl_ls.toml`, and a `.gitignore` for build output.
+    #[default]
+    Basic,
+}
+
+const TEMPLATE_ENTITY: &str = r#"library ieee;
+use ieee.std_logic_1164.all;
+use ieee.numeric_std.all;
+
+entity counter is
+    port (
+        clk : in std_logic;
+        rst : in std_logic;
+        count : out unsigned(7 downto 0)
+    );
+end entity counter;
+
+architecture rtl of counter is
+    signal count_r : unsigned(7 downto 0) := (others => '0');
+begin
+    process (clk)
+    begin
+        if rising_edge(clk) then
+            if rst = '1' then
+                count_r <= (others => '0');
+            else
+                count_r <= count_r + 1;
+            end if;
+        end if;
+    end process;
+
+    count <= count_r;
+end architecture rtl;
+"#;
+
+// vw finds testbenches by their entity having no ports, so `vw test
+// counter_tb` runs this file. It ends by stopping the clock rather than
+// with `std.env.finish`, which VHDL-93 and 2002 lack
+const TEMPLATE_TESTBENCH: &str = r#"library ieee;
+use ieee.std_logic_1164.all;
+use ieee.numeric_std.all;
+
+entity counter_tb is
+end entity counter_tb;
+
+architecture sim of counter_tb is
+    signal clk : std_logic := '0';
+    signal rst : std_logic := '1';
+    signal done : boolean := false;
+    signal count : unsigned(7 downto 0);
+begin
+    dut : entity work.counter
+        port map (
+            clk => clk,
+            rst => rst,
+            count => count
+        );
+
+    clk <= not clk after 5 ns when not done else clk;
+
+    stimulus : process
+    begin
+        wait for 20 ns;
+        rst <= '0';
+        wait until rising_edge(clk) and count = 10;
+        report "counter reached 10" severity note;
+        done <= true;
+        wait;
+    end process;
+end architecture sim;
+"#;
+
+const TEMPLATE_GITIGNORE: &str =
+    "# Build output, waveforms and the lock file written by vw
+/vw_build/
+/work/
+vw.lock
+*.fst
+*.vcd
+*.ghw
+";
+
 /// Initialize a new workspace with the given name.
-pub fn init_workspace(workspace_dir: &Utf8Path, name: String) -> Result<()> {
+///
+/// Existing files are never overwritten: if `vw.toml` or any file the
+/// template would create already exists, nothing is written. An existing
+/// `.gitignore` is left as it is. Returns the files created, relative to
+/// the workspace.
+pub fn init_workspace(
+    workspace_dir: &Utf8Path,
+    name: String,
+    template: InitTemplate,
+) -> Result<Vec<Utf8PathBuf>> {
     let config_path = workspace_dir.join("vw.toml");
     if config_path.exists() {
         return Err(VwError::Config {
@@ -446,17 +1290,87 @@ pub fn init_workspace(workspace_dir: &Utf8Path, name: String) -> Result<()> {
         });
     }
 
+    let mut files: Vec<(Utf8PathBuf, String)> = Vec::new();
+    if template == InitTemplate::Basic {
+        let src_file = Utf8PathBuf::from(DEFAULT_SRC_DIR).join("counter.vhd");
+        let tb_file =
+            Utf8PathBuf::from(DEFAULT_BENCH_DIR).join("counter_tb.vhd");
+        let mut libraries = BTreeMap::new();
+        libraries.insert(
+            "defaultlib".to_string(),
+            VhdlLsLibrary {
+                files: vec![src_file.clone().into(), tb_file.clone().into()],
+                exclude: None,
+                is_third_party: None,
+                vhdl_standard: None,
+                analysis_flags: Vec::new(),
+            },
+        );
+        let vhdl_ls_config = VhdlLsConfig {
+            standard: Some(VhdlStandard::default().vhdl_ls_name().to_string()),
+            libraries,
+            lint: None,
+        };
+
+        files.push((src_file, TEMPLATE_ENTITY.to_string()));
+        files.push((tb_file, TEMPLATE_TESTBENCH.to_string()));
+        files.push((
+            Utf8PathBuf::from("vhdl_ls.toml"),
+            toml::to_string_pretty(&vhdl_ls_config)?,
+        ));
+        if !workspace_dir.join(".gitignore").exists() {
+            files.push((
+                Utf8PathBuf::from(".gitignore"),
+                TEMPLATE_GITIGNORE.to_string(),
+            ));
+        }
+    }
+
+    if let Some((existing, _)) = files
+        .iter()
+        .find(|(path, _)| workspace_dir.join(path).exists())
+    {
+        return Err(VwError::Config {
+            message: format!("{existing} already exists in {workspace_dir}"),
+        });
+    }
+
     let config = WorkspaceConfig {
         workspace: WorkspaceInfo {
             name,
             version: "0.1.0".to_string(),
+            not_testbenches: Vec::new(),
+            bench_dir: None,
+            vhdl_ls_paths: None,
+            vhdl_standard: None,
+            vhdl_extensions: Vec::new(),
+            link_files: None,
+            follow_symlinks: None,
         },
         dependencies: HashMap::new(),
         tools: None,
+        auth: None,
+        simulator: None,
     };
 
     save_workspace_config(workspace_dir, &config)?;
-    Ok(())
+
+    let mut created = vec![Utf8PathBuf::from("vw.toml")];
+    for (path, content) in files {
+        let full_path = workspace_dir.join(&path);
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| VwError::FileSystem {
+                message: format!("Failed to create directory: {e}"),
+                path: Some(parent.into()),
+            })?;
+        }
+        fs::write(&full_path, content).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to write {path}: {e}"),
+            path: Some(full_path.clone().into()),
+        })?;
+        created.push(path);
+    }
+    Ok(created)
 }
 
 #[derive(Debug, Clone)]
@@ -469,122 +1383,624 @@ pub struct DependencyUpdateInfo {
     pub name: String,
     pub commit: String,
     pub was_cached: bool,
+    pub vendored: bool,
+    /// The dependency's VHDL files, by their paths in its layout: relative
+    /// to the `src` directory each was found in, as `vw vendor` copies
+    /// them. Empty in a dry run for a dependency not yet downloaded.
+    pub files: Vec<PathBuf>,
+}
+
+/// Git transfer progress while a dependency is being downloaded.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferProgress {
+    pub received_objects: usize,
+    pub indexed_objects: usize,
+    pub total_objects: usize,
+    pub received_bytes: usize,
 }
 
+/// Called with the dependency name as its download makes progress.
+///
+/// Downloads run on a blocking thread, so the callback must be `Send` and
+/// `Sync`.
+pub type ProgressCallback = Arc<dyn Fn(&str, TransferProgress) + Send + Sync>;
+
 /// Update workspace dependencies by downloading them and generating configuration files.
 pub async fn update_workspace(
     workspace_dir: &Utf8Path,
 ) -> Result<UpdateResult> {
-    update_workspace_with_token(workspace_dir, None).await
+    update_workspace_with_token(workspace_dir, None, false, false, None).await
 }
 
 /// Update workspace dependencies with optional credentials for private repositories.
 ///
+/// Dependencies recorded as vendored in `vw.lock` are left alone unless
+/// `force` is set, in which case they are resolved and downloaded into the
+/// cache again like any other dependency.
+///
 /// # Arguments
 /// * `workspace_dir` - Path to the workspace directory
 /// * `credentials` - Optional credentials for authentication
+/// * `force` - Re-resolve and download vendored dependencies
+/// * `link` - Hardlink the unpatched files of patched dependency trees,
+///   as if the workspace set `link_files`
+/// * `progress` - Optional callback for download progress
 pub async fn update_workspace_with_token(
     workspace_dir: &Utf8Path,
     credentials: Option<Credentials>,
+    force: bool,
+    link: bool,
+    progress: Option<ProgressCallback>,
+) -> Result<UpdateResult> {
+    run_update(workspace_dir, credentials, force, link, progress, false).await
+}
+
+/// Work out what `update_workspace_with_token` would do without doing it.
+///
+/// Commits are resolved as usual, which contacts the remote for branch
+/// dependencies, but nothing is downloaded and neither `vw.lock` nor
+/// `vhdl_ls.toml` is written. In the returned plan, `was_cached` tells
+/// whether a dependency is already in the cache or would be downloaded.
+pub async fn plan_update(
+    workspace_dir: &Utf8Path,
+    credentials: Option<Credentials>,
+) -> Result<UpdateResult> {
+    run_update(workspace_dir, credentials, false, false, None, true).await
+}
+
+async fn run_update(
+    workspace_dir: &Utf8Path,
+    credentials: Option<Credentials>,
+    force: bool,
+    link: bool,
+    progress: Option<ProgressCallback>,
+    dry_run: bool,
 ) -> Result<UpdateResult> {
     let config = load_workspace_config(workspace_dir)?;
+    validate_config(&config)?;
+    let link = link || config.workspace.link_files.unwrap_or(false);
     let deps_dir = deps_directory()?;
+    let previous_lock = load_lock_file(workspace_dir).ok();
+    let path_style = config.workspace.vhdl_ls_paths.unwrap_or_default();
+    let vhdl_std = config.workspace.vhdl_standard.unwrap_or_default();
+    let extensions = vhdl_extensions(workspace_dir);
 
     let mut lock_file = LockFile {
         dependencies: HashMap::new(),
     };
 
     let mut vhdl_ls_config = VhdlLsConfig {
-        standard: None,
-        libraries: HashMap::new(),
+        standard: Some(vhdl_std.vhdl_ls_name().to_string()),
+        libraries: BTreeMap::new(),
         lint: None,
     };
 
     let mut update_info = Vec::new();
+    let mut resolver = CommitResolver::default();
+
+    // Go by name so dependencies sharing a library list their files in a
+    // stable order
+    let mut names: Vec<&String> = config.dependencies.keys().collect();
+    names.sort();
+
+    for name in names {
+        let dep = &config.dependencies[name];
+        let patch = read_dependency_patch(workspace_dir, name, dep)?;
+        let patch_hash = patch.as_ref().map(|patch| patch.hash.as_str());
+
+        // Keep vendored dependencies as they are unless asked to refresh them
+        let vendored = previous_lock
+            .as_ref()
+            .and_then(|lock| lock.dependencies.get(name))
+            .filter(|locked| {
+                locked.vendored.as_ref().is_some_and(|dir| {
+                    workspace_dir.as_std_path().join(dir).exists()
+                })
+            });
+        if let (Some(locked), false) = (vendored, force) {
+            let vendor_dir = locked.vendored.as_ref().unwrap();
+            // The vendored files are only what vw.toml asks for if nothing
+            // that selects them has changed since they were vendored
+            if let Some(setting) = locked.changed_setting(dep, patch_hash) {
+                return Err(VwError::Dependency {
+                    message: format!(
+                        "Dependency '{name}' is vendored in {}, but its \
+                         {setting} in vw.toml changed since; run `vw update \
+                         --force` to download it again, then `vw vendor` to \
+                         refresh the vendored copy",
+                        vendor_dir.display()
+                    ),
+                });
+            }
+            let vhdl_files = vendored_library_files(
+                workspace_dir,
+                vendor_dir,
+                locked,
+                &extensions,
+            )?;
+            let files = relative_files(&vhdl_files, vendor_dir);
+            if !vhdl_files.is_empty() {
+                insert_dependency_library(
+                    &mut vhdl_ls_config.libraries,
+                    dep.library_name(name),
+                    dependency_library(
+                        vhdl_files,
+                        std::slice::from_ref(vendor_dir),
+                        &locked.exclude,
+                        Some(dep),
+                    ),
+                )?;
+            }
+
+            update_info.push(DependencyUpdateInfo {
+                name: name.clone(),
+                commit: locked.commit.clone(),
+                was_cached: true,
+                vendored: true,
+                files,
+            });
+            lock_file.dependencies.insert(
+                name.clone(),
+                LockedDependency {
+                    vendored: Some(vendor_dir.clone()),
+                    default_branch: locked.default_branch.clone(),
+                    ..locked_dependency(
+                        dep,
+                        &locked.commit,
+                        locked.patch_hash.as_deref(),
+                    )
+                },
+            );
+            continue;
+        }
 
-    for (name, dep) in &config.dependencies {
         // Use credentials passed from caller
         let creds = credentials
             .as_ref()
             .map(|c| (c.username.as_str(), c.password.as_str()));
 
-        let commit_sha = resolve_dependency_commit(
-            &dep.repo,
-            &dep.branch,
-            &dep.commit,
+        let resolved = match &dep.commit {
+            // A cached full SHA was validated when it was downloaded
+            Some(commit)
+                if dep.branch.is_none()
+                    && is_full_commit_sha(commit)
+                    && deps_dir
+                        .join(cache_tree_name(
+                            &dep.repo,
+                            commit,
+                            dep.submodules,
+                            patch_hash,
+                        ))
+                        .exists() =>
+            {
+                ResolvedCommit {
+                    sha: commit.clone(),
+                    default_branch: None,
+                }
+            }
+            _ => resolver
+                .resolve(&dep.repo, &dep.branch, &dep.commit, creds)
+                .await
+                .map_err(|e| VwError::Dependency {
+                    message: format!(
+                        "Failed to resolve commit for dependency '{name}': {e}"
+                    ),
+                })?,
+        };
+        let commit_sha = resolved.sha;
+        let locked = LockedDependency {
+            default_branch: resolved.default_branch,
+            ..locked_dependency(dep, &commit_sha, patch_hash)
+        };
+        let dep_path = deps_dir.join(&locked.path);
+
+        if dry_run {
+            let was_cached = dep_path.is_dir();
+            let files = if was_cached {
+                tree_files(
+                    workspace_dir,
+                    name,
+                    &dep_path,
+                    &locked,
+                    &extensions,
+                )?
+                .into_iter()
+                .map(|(_, layout_path)| layout_path)
+                .collect()
+            } else {
+                Vec::new()
+            };
+            update_info.push(DependencyUpdateInfo {
+                name: name.clone(),
+                commit: commit_sha,
+                was_cached,
+                vendored: false,
+                files,
+            });
+            continue;
+        }
+
+        let was_cached = ensure_cache_entry(
+            workspace_dir,
+            name,
+            dep,
+            &commit_sha,
+            &dep_path,
+            patch.as_ref(),
+            link,
             creds,
+            progress.clone(),
         )
-        .await
-        .map_err(|e| VwError::Dependency {
-            message: format!(
-                "Failed to resolve commit for dependency '{name}': {e}"
-            ),
-        })?;
-
-        let dep_path = deps_dir.join(format!("{name}-{commit_sha}"));
-
-        let was_cached = dep_path.exists();
+        .await?;
 
-        if !was_cached {
-            download_dependency(
-                &dep.repo,
-                &commit_sha,
-                &dep.src,
-                &dep_path,
-                dep.recursive,
-                &dep.exclude,
-                dep.submodules,
-                creds,
-            )
-            .await
-            .map_err(|e| VwError::Dependency {
-                message: format!("Failed to download dependency '{name}': {e}"),
-            })?;
-        }
+        // Select the dependency's VHDL files from the cached tree
+        let vhdl_files =
+            tree_files(workspace_dir, name, &dep_path, &locked, &extensions)?;
 
         update_info.push(DependencyUpdateInfo {
             name: name.clone(),
             commit: commit_sha.clone(),
             was_cached,
+            vendored: false,
+            files: vhdl_files
+                .iter()
+                .map(|(_, layout_path)| layout_path.clone())
+                .collect(),
         });
 
+        if !vhdl_files.is_empty() {
+            let portable_files = vhdl_files
+                .into_iter()
+                .map(|(f, _)| vhdl_ls_file_path(workspace_dir, f, path_style))
+                .collect::<Result<_>>()?;
+            let roots = tree_roots(&dep_path, &locked)
+                .into_iter()
+                .map(|root| vhdl_ls_file_path(workspace_dir, root, path_style))
+                .collect::<Result<Vec<_>>>()?;
+            insert_dependency_library(
+                &mut vhdl_ls_config.libraries,
+                dep.library_name(name),
+                dependency_library(
+                    portable_files,
+                    &roots,
+                    &dep.exclude,
+                    Some(dep),
+                ),
+            )?;
+        }
+        lock_file.dependencies.insert(name.clone(), locked);
+    }
+
+    if !dry_run {
+        write_lock_file(workspace_dir, &lock_file)?;
+        write_vhdl_ls_config(workspace_dir, &vhdl_ls_config)?;
+    }
+
+    Ok(UpdateResult {
+        dependencies: update_info,
+    })
+}
+
+/// Resolve every dependency to a commit and write `vw.lock` without
+/// downloading anything.
+///
+/// No files are copied into the dependency cache and `vhdl_ls.toml` is left
+/// untouched. This is meant for air-gapped setups: resolve on a networked
+/// machine, then carry the lock file to the offline box.
+pub async fn resolve_only(
+    workspace_dir: &Utf8Path,
+    credentials: Option<Credentials>,
+) -> Result<LockFile> {
+    let config = load_workspace_config(workspace_dir)?;
+    validate_config(&config)?;
+
+    let mut lock_file = LockFile {
+        dependencies: HashMap::new(),
+    };
+    let mut resolver = CommitResolver::default();
+
+    for (name, dep) in &config.dependencies {
+        let creds = credentials
+            .as_ref()
+            .map(|c| (c.username.as_str(), c.password.as_str()));
+
+        let resolved = resolver
+            .resolve(&dep.repo, &dep.branch, &dep.commit, creds)
+            .await
+            .map_err(|e| VwError::Dependency {
+                message: format!(
+                    "Failed to resolve commit for dependency '{name}': {e}"
+                ),
+            })?;
+
+        let patch = read_dependency_patch(workspace_dir, name, dep)?;
         lock_file.dependencies.insert(
             name.clone(),
             LockedDependency {
-                repo: dep.repo.clone(),
-                commit: commit_sha.clone(),
-                src: dep.src.clone(),
-                path: PathBuf::from(format!("{name}-{commit_sha}")),
-                recursive: dep.recursive,
-                sim_only: dep.sim_only,
-                submodules: dep.submodules,
-                exclude: dep.exclude.clone(),
+                default_branch: resolved.default_branch,
+                ..locked_dependency(
+                    dep,
+                    &resolved.sha,
+                    patch.as_ref().map(|patch| patch.hash.as_str()),
+                )
             },
         );
+    }
 
-        // Find VHDL files in the cached dependency directory
-        let vhdl_files =
-            find_vhdl_files(&dep_path, dep.recursive, &dep.exclude)?;
-        if !vhdl_files.is_empty() {
-            let portable_files =
-                vhdl_files.into_iter().map(make_path_portable).collect();
-            vhdl_ls_config.libraries.insert(
-                name.clone(),
-                VhdlLsLibrary {
-                    files: portable_files,
-                    exclude: None,
-                    is_third_party: None,
-                },
-            );
+    write_lock_file(workspace_dir, &lock_file)?;
+
+    Ok(lock_file)
+}
+
+/// Rewrite `vhdl_ls.toml`, and `deps.tcl` if present, from `vw.lock`.
+///
+/// Each locked dependency's VHDL files are found again in its cache tree
+/// (or vendored copy) exactly as `vw update` finds them, but nothing is
+/// resolved or downloaded, so no network access is needed. A dependency
+/// missing from the lock or from the cache is an error pointing at
+/// `vw update`. Returns the names of the dependencies written.
+pub fn regenerate_configs(workspace_dir: &Utf8Path) -> Result<Vec<String>> {
+    let config = load_workspace_config(workspace_dir)?;
+    let lock_file = load_lock_file(workspace_dir).map_err(|e| match e {
+        VwError::Config { .. } => VwError::Config {
+            message: format!(
+                "No vw.lock file found in {workspace_dir}; run `vw update` \
+                 to create it"
+            ),
+        },
+        e => e,
+    })?;
+    let path_style = config.workspace.vhdl_ls_paths.unwrap_or_default();
+    let vhdl_std = config.workspace.vhdl_standard.unwrap_or_default();
+    let extensions = vhdl_extensions(workspace_dir);
+
+    let mut vhdl_ls_config = VhdlLsConfig {
+        standard: Some(vhdl_std.vhdl_ls_name().to_string()),
+        libraries: BTreeMap::new(),
+        lint: None,
+    };
+
+    let mut names: Vec<&String> = config.dependencies.keys().collect();
+    names.sort();
+    for name in &names {
+        let dep = &config.dependencies[*name];
+        let Some(locked) = lock_file.dependencies.get(*name) else {
+            return Err(VwError::Dependency {
+                message: format!(
+                    "Dependency '{name}' is not in vw.lock; run `vw update` \
+                     to resolve it"
+                ),
+            });
+        };
+
+        let (files, roots) = match &locked.vendored {
+            Some(vendor_dir) => {
+                let vendor_path = workspace_dir.as_std_path().join(vendor_dir);
+                if !vendor_path.exists() {
+                    return Err(VwError::Dependency {
+                        message: format!(
+                            "Vendored copy of dependency '{name}' not found \
+                             at {}; run `vw update` to download it",
+                            vendor_path.display()
+                        ),
+                    });
+                }
+                let files = vendored_library_files(
+                    workspace_dir,
+                    vendor_dir,
+                    locked,
+                    &extensions,
+                )?;
+                (files, vec![vendor_dir.clone()])
+            }
+            None => {
+                let dep_path = cached_tree(name, locked)?;
+                if !dep_path.exists() {
+                    return Err(VwError::Dependency {
+                        message: format!(
+                            "Cached files for dependency '{name}' not found \
+                             at {}; run `vw update` to download them",
+                            dep_path.display()
+                        ),
+                    });
+                }
+                let files = tree_files(
+                    workspace_dir,
+                    name,
+                    &dep_path,
+                    locked,
+                    &extensions,
+                )?
+                .into_iter()
+                .map(|(f, _)| vhdl_ls_file_path(workspace_dir, f, path_style))
+                .collect::<Result<Vec<_>>>()?;
+                let roots = tree_roots(&dep_path, locked)
+                    .into_iter()
+                    .map(|root| {
+                        vhdl_ls_file_path(workspace_dir, root, path_style)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                (files, roots)
+            }
+        };
+
+        if !files.is_empty() {
+            insert_dependency_library(
+                &mut vhdl_ls_config.libraries,
+                dep.library_name(name),
+                dependency_library(files, &roots, &locked.exclude, Some(dep)),
+            )?;
+        }
+    }
+
+    write_vhdl_ls_config(workspace_dir, &vhdl_ls_config)?;
+    if workspace_dir.join("deps.tcl").exists() {
+        generate_deps_tcl(workspace_dir)?;
+    }
+    if workspace_dir.join("deps.mk").exists() {
+        generate_deps_make(workspace_dir)?;
+    }
+
+    Ok(names.into_iter().cloned().collect())
+}
+
+#[derive(Debug, Clone)]
+pub struct VendorInfo {
+    pub name: String,
+    pub path: PathBuf,
+    pub file_count: usize,
+}
+
+/// Copy every locked dependency into a workspace-local vendor directory.
+///
+/// Each dependency's VHDL files are copied from the cache into
+/// `<vendor_dir>/<name>`, preserving their layout, and the dependency is
+/// marked as vendored in `vw.lock`. `vhdl_ls.toml` (and `deps.tcl`, if
+/// present) are rewritten to reference the vendored copies with
+/// workspace-relative paths so no per-user cache is needed afterwards.
+pub fn vendor_dependencies(
+    workspace_dir: &Utf8Path,
+    vendor_dir: &Utf8Path,
+) -> Result<Vec<VendorInfo>> {
+    let mut lock_file = load_lock_file(workspace_dir)?;
+    let config = load_workspace_config(workspace_dir).ok();
+    let vhdl_std = config
+        .as_ref()
+        .and_then(|config| config.workspace.vhdl_standard)
+        .unwrap_or_default();
+    let extensions = vhdl_extensions(workspace_dir);
+
+    let vendor_rel = if vendor_dir.is_absolute() {
+        vendor_dir
+            .strip_prefix(workspace_dir)
+            .map_err(|_| VwError::Config {
+                message: format!(
+                    "Vendor directory {vendor_dir} must be inside the workspace"
+                ),
+            })?
+            .to_path_buf()
+    } else {
+        vendor_dir.to_path_buf()
+    };
+
+    let mut vhdl_ls_config = VhdlLsConfig {
+        standard: Some(vhdl_std.vhdl_ls_name().to_string()),
+        libraries: BTreeMap::new(),
+        lint: None,
+    };
+    let mut vendored = Vec::new();
+
+    let mut names: Vec<String> =
+        lock_file.dependencies.keys().cloned().collect();
+    names.sort();
+
+    for name in names {
+        let locked = lock_file.dependencies.get_mut(&name).unwrap();
+        let dest_rel = vendor_rel.join(&name).into_std_path_buf();
+        let dest = workspace_dir.as_std_path().join(&dest_rel);
+
+        let source = match &locked.vendored {
+            Some(dir) => workspace_dir.as_std_path().join(dir),
+            None => cached_tree(&name, locked)?,
+        };
+        if !source.exists() {
+            return Err(VwError::Dependency {
+                message: format!(
+                    "Cached files for dependency '{name}' not found at {}; \
+                     run `vw update` first",
+                    source.display()
+                ),
+            });
+        }
+
+        // Files with their paths in the dependency's layout
+        let files = match &locked.vendored {
+            Some(_) => dependency_files(
+                &source,
+                &vendored_listed_files(locked),
+                true,
+                &locked.exclude,
+                &extensions,
+            )?
+            .into_iter()
+            .map(|file| {
+                let layout_path =
+                    file.strip_prefix(&source).unwrap_or(&file).to_path_buf();
+                (file, layout_path)
+            })
+            .collect(),
+            None => {
+                tree_files(workspace_dir, &name, &source, locked, &extensions)?
+            }
+        };
+
+        if source != dest {
+            if dest.exists() {
+                fs::remove_dir_all(&dest).map_err(|e| VwError::FileSystem {
+                    message: format!("Failed to remove old vendored copy: {e}"),
+                    path: Some(dest.clone()),
+                })?;
+            }
+            for (file, layout_path) in &files {
+                let dest_file = dest.join(layout_path);
+                if let Some(parent) = dest_file.parent() {
+                    fs::create_dir_all(parent).map_err(|e| {
+                        VwError::FileSystem {
+                            message: format!("Failed to create directory: {e}"),
+                            path: Some(parent.to_path_buf()),
+                        }
+                    })?;
+                }
+                fs::copy(file, &dest_file).map_err(|e| {
+                    VwError::FileSystem {
+                        message: format!("Failed to copy file: {e}"),
+                        path: Some(file.clone()),
+                    }
+                })?;
+            }
+        }
+
+        locked.vendored = Some(dest_rel.clone());
+
+        let library_files = vendored_library_files(
+            workspace_dir,
+            &dest_rel,
+            locked,
+            &extensions,
+        )?;
+        if !library_files.is_empty() {
+            let dep = config
+                .as_ref()
+                .and_then(|config| config.dependencies.get(&name));
+            insert_dependency_library(
+                &mut vhdl_ls_config.libraries,
+                locked.library_name(&name),
+                dependency_library(
+                    library_files,
+                    std::slice::from_ref(&dest_rel),
+                    &locked.exclude,
+                    dep,
+                ),
+            )?;
         }
+
+        vendored.push(VendorInfo {
+            name,
+            path: dest_rel,
+            file_count: files.len(),
+        });
     }
 
     write_lock_file(workspace_dir, &lock_file)?;
     write_vhdl_ls_config(workspace_dir, &vhdl_ls_config)?;
+    if workspace_dir.join("deps.tcl").exists() {
+        generate_deps_tcl(workspace_dir)?;
+    }
+    if workspace_dir.join("deps.mk").exists() {
+        generate_deps_make(workspace_dir)?;
+    }
 
-    Ok(UpdateResult {
-        dependencies: update_info,
-    })
+    Ok(vendored)
 }
 
 /// Add a new dependency to the workspace configuration.
@@ -643,20 +2059,42 @@ pub async fn add_dependency_with_token(
                 workspace: WorkspaceInfo {
                     name: "workspace".to_string(),
                     version: "0.1.0".to_string(),
+                    not_testbenches: Vec::new(),
+                    bench_dir: None,
+                    vhdl_ls_paths: None,
+                    vhdl_standard: None,
+                    vhdl_extensions: Vec::new(),
+                    link_files: None,
+                    follow_symlinks: None,
                 },
                 dependencies: HashMap::new(),
                 tools: None,
+                auth: None,
+                simulator: None,
             }
         });
 
-    // Validate that either branch or commit is provided
-    if branch.is_none() && commit.is_none() {
+    let dep_name = name.unwrap_or_else(|| extract_repo_name(&repo));
+    let library = nvc_library_name(&dep_name);
+    if !is_vhdl_identifier(&library) {
         return Err(VwError::Config {
-            message: "Must specify either --branch or --commit".to_string(),
+            message: format!(
+                "Dependency name '{dep_name}' doesn't make a legal VHDL \
+                 library name ('{library}'); choose another with --name"
+            ),
         });
     }
-
-    let dep_name = name.unwrap_or_else(|| extract_repo_name(&repo));
+    for (other_name, other) in &config.dependencies {
+        let other_library = nvc_library_name(other.library_name(other_name));
+        if other_name != &dep_name
+            && other_library.eq_ignore_ascii_case(&library)
+        {
+            warn!(
+                "Dependency '{dep_name}' and '{other_name}' both map to VHDL \
+                 library '{library}'; their files will share it"
+            );
+        }
+    }
     let src_paths = vec![src.unwrap_or_else(|| ".".to_string())];
 
     let dependency = Dependency {
@@ -664,13 +2102,21 @@ pub async fn add_dependency_with_token(
         branch,
         commit,
         src: src_paths,
+        files: Vec::new(),
         recursive,
         sim_only,
         submodules: false,
         exclude: Vec::new(),
+        lint: false,
+        vhdl_standard: None,
+        analysis_flags: Vec::new(),
+        library: None,
+        patch: None,
+        overlay: Vec::new(),
     };
 
     config.dependencies.insert(dep_name.clone(), dependency);
+    validate_config(&config)?;
     save_workspace_config(workspace_dir, &config)?;
 
     Ok(())
@@ -691,138 +2137,533 @@ pub fn remove_dependency(workspace_dir: &Utf8Path, name: String) -> Result<()> {
 }
 
 /// Clear all cached repositories for the current workspace.
+///
+/// This removes the cache trees locked in `vw.lock`, and any entries for
+/// the workspace's dependencies left in the old `<name>-<commit>` layout.
+/// Trees are shared, so one that another workspace has used and still
+/// locks is kept.
 pub fn clear_cache(workspace_dir: &Utf8Path) -> Result<Vec<String>> {
     let config = load_workspace_config(workspace_dir)?;
     let deps_dir = deps_directory()?;
+    let workspace = cache_use_key(workspace_dir)?;
+
+    let mut targets: HashSet<String> = load_lock_file(workspace_dir)
+        .map(|lock| {
+            lock.dependencies
+                .values()
+                .filter_map(|locked| locked.path.to_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    targets.retain(|target| {
+        let user =
+            read_cache_uses(&deps_dir.join(target))
+                .into_keys()
+                .find(|other| {
+                    *other != workspace
+                        && load_lock_file(Utf8Path::new(other)).is_ok_and(
+                            |lock| {
+                                lock.dependencies.values().any(|locked| {
+                                    locked.vendored.is_none()
+                                        && locked.path == Path::new(target)
+                                })
+                            },
+                        )
+                });
+        if let Some(user) = &user {
+            debug!("Keeping cache tree {target}, which {user} still uses");
+        }
+        user.is_none()
+    });
+
+    if let Ok(entries) = fs::read_dir(&deps_dir) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            let is_legacy_entry = config.dependencies.keys().any(|name| {
+                file_name
+                    .strip_prefix(&format!("{name}-"))
+                    .is_some_and(is_full_commit_sha)
+            });
+            if is_legacy_entry {
+                targets.insert(file_name.to_string());
+            }
+        }
+    }
 
     let mut cleared = Vec::new();
+    let mut targets: Vec<String> = targets.into_iter().collect();
+    targets.sort();
+    for target in targets {
+        let dep_path = deps_dir.join(&target);
+        // Check the entry itself rather than what it may link to, so a
+        // symlinked entry never has its target's contents removed
+        if fs::symlink_metadata(&dep_path).is_ok_and(|m| m.is_dir()) {
+            let Some(_lock) = try_lock_cache_entry(&dep_path)? else {
+                debug!(
+                    "Keeping cache tree {target}, which another vw process \
+                     holds"
+                );
+                continue;
+            };
 
-    // Get all dependencies from the current workspace
-    for name in config.dependencies.keys() {
-        if let Ok(entries) = fs::read_dir(&deps_dir) {
-            for entry in entries.flatten() {
-                let file_name = entry.file_name();
-                if let Some(file_name_str) = file_name.to_str() {
-                    if file_name_str.starts_with(&format!("{name}-")) {
-                        let dep_path = entry.path();
-                        if dep_path.is_dir() {
-                            fs::remove_dir_all(&dep_path)
-                                .map_err(|e| VwError::FileSystem {
-                                    message: format!("Failed to remove cached dependency at {dep_path:?}: {e}")
-                                })?;
-                            cleared.push(file_name_str.to_string());
-                        }
-                    }
-                }
-            }
+            fs::remove_dir_all(&dep_path).map_err(|e| VwError::FileSystem {
+                message: format!("Failed to remove cached dependency: {e}"),
+                path: Some(dep_path.clone()),
+            })?;
+            let _ = fs::remove_file(sibling_path(&dep_path, CACHE_USES_SUFFIX));
+            cleared.push(target);
         }
     }
 
     Ok(cleared)
 }
 
-/// List all dependencies in the workspace.
-pub fn list_dependencies(
-    workspace_dir: &Utf8Path,
-) -> Result<Vec<DependencyInfo>> {
-    let config = load_workspace_config(workspace_dir)?;
-    if config.dependencies.is_empty() {
-        return Ok(Vec::new());
-    }
-
-    // Try to load lock file to get resolved versions
-    let lock_file = load_lock_file(workspace_dir).ok();
-
-    let mut deps = Vec::new();
-    for (name, dep) in &config.dependencies {
-        let version_info = match &lock_file {
-            Some(lock) => {
-                if let Some(locked_dep) = lock.dependencies.get(name) {
-                    VersionInfo::Locked {
-                        commit: locked_dep.commit.clone(),
-                    }
-                } else {
-                    // Not yet resolved, show branch/commit from config
-                    match (&dep.branch, &dep.commit) {
-                        (Some(branch), None) => VersionInfo::Branch {
-                            branch: branch.clone(),
-                        },
-                        (None, Some(commit)) => VersionInfo::Commit {
-                            commit: commit.clone(),
-                        },
-                        _ => VersionInfo::Unknown,
-                    }
-                }
-            }
-            None => {
-                // No lock file, show branch/commit from config
-                match (&dep.branch, &dep.commit) {
-                    (Some(branch), None) => VersionInfo::Branch {
-                        branch: branch.clone(),
-                    },
-                    (None, Some(commit)) => VersionInfo::Commit {
-                        commit: commit.clone(),
-                    },
-                    _ => VersionInfo::Unknown,
-                }
-            }
-        };
-
-        deps.push(DependencyInfo {
-            name: name.clone(),
-            repo: dep.repo.clone(),
-            version: version_info,
-        });
-    }
-
-    Ok(deps)
+/// Result of a dependency cache garbage collection.
+#[derive(Debug, Clone, Default)]
+pub struct GcResult {
+    /// Cache directories that were (or, for a dry run, would be) removed.
+    pub removed: Vec<PathBuf>,
+    /// Total size of the removed directories in bytes.
+    pub reclaimed_bytes: u64,
 }
 
-#[derive(Debug, Clone)]
-pub struct DependencyInfo {
-    pub name: String,
-    pub repo: String,
-    pub version: VersionInfo,
+/// Parse an age such as `30d`, `12h`, or `2w` (units: s, m, h, d, w).
+pub fn parse_age(age: &str) -> Result<std::time::Duration> {
+    let invalid = || VwError::Config {
+        message: format!(
+            "Invalid age '{age}': expected a number followed by s, m, h, d, \
+             or w (e.g. 30d)"
+        ),
+    };
+    let unit_start = age
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (count, unit) = age.split_at(unit_start);
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(std::time::Duration::from_secs(
+        count.saturating_mul(seconds),
+    ))
 }
 
-#[derive(Debug, Clone)]
-pub enum VersionInfo {
-    Branch { branch: String },
+/// Remove dependency cache entries that no workspace has used for
+/// `older_than`, across all workspaces.
+///
+/// An entry was last used when `vw update` last ran in any workspace that
+/// locks it, as recorded in `<entry>.uses`; entries cached before these
+/// records existed fall back to their modification time. Entries locked by
+/// `keep_workspace`'s `vw.lock` are always kept, as are entries another vw
+/// process is creating or using. With `dry_run` nothing is deleted, but the
+/// result still lists what would have been removed.
+pub fn gc_cache(
+    older_than: std::time::Duration,
+    keep_workspace: Option<&Utf8Path>,
+    dry_run: bool,
+) -> Result<GcResult> {
+    let deps_dir = deps_directory()?;
+    let keep: HashSet<PathBuf> = keep_workspace
+        .and_then(|dir| load_lock_file(dir).ok())
+        .map(|lock| {
+            lock.dependencies
+                .values()
+                .map(|locked| deps_dir.join(&locked.path))
+                .collect()
+        })
+        .unwrap_or_default();
+    let now = std::time::SystemTime::now();
+
+    let entries = fs::read_dir(&deps_dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to read dependency cache: {e}"),
+        path: Some(deps_dir.clone()),
+    })?;
+
+    let mut result = GcResult::default();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Lock and use records are files next to their entry, and a
+        // `.partial` directory is a download still being written
+        if !path.is_dir()
+            || path.extension().is_some_and(|ext| ext == "partial")
+            || keep.contains(&path)
+        {
+            continue;
+        }
+        // Leave entries that another vw process is working on
+        let Some(_lock) = try_lock_cache_entry(&path)? else {
+            continue;
+        };
+        let last_used = match read_cache_uses(&path).into_values().max() {
+            Some(seconds) => Some(
+                std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds),
+            ),
+            None => entry.metadata().and_then(|m| m.modified()).ok(),
+        };
+        let age = last_used.and_then(|time| now.duration_since(time).ok());
+        if age.is_none_or(|age| age < older_than) {
+            continue;
+        }
+
+        let size = dir_size(&path);
+        if !dry_run {
+            fs::remove_dir_all(&path).map_err(|e| VwError::FileSystem {
+                message: format!("Failed to remove cached dependency: {e}"),
+                path: Some(path.clone()),
+            })?;
+            let _ = fs::remove_file(sibling_path(&path, CACHE_USES_SUFFIX));
+        }
+        result.reclaimed_bytes += size;
+        result.removed.push(path);
+    }
+    result.removed.sort();
+
+    Ok(result)
+}
+
+/// Disk usage of one dependency cache entry.
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Disk usage of the dependency cache.
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    pub total_bytes: u64,
+    /// One entry per cached dependency, largest first.
+    pub entries: Vec<CacheEntry>,
+    /// How many of `entries` another vw process holds, so their size may
+    /// still change.
+    pub in_use: usize,
+}
+
+/// Measure the dependency cache shared by all workspaces. Downloads in
+/// progress are left out; entries another vw process holds are measured
+/// as they are and counted in [`CacheStats::in_use`].
+pub fn cache_size() -> Result<CacheStats> {
+    let deps_dir = deps_directory()?;
+    let dir_entries =
+        fs::read_dir(&deps_dir).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read dependency cache: {e}"),
+            path: Some(deps_dir.clone()),
+        })?;
+
+    let mut entries = Vec::new();
+    let mut in_use = 0;
+    for entry in dir_entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir()
+            || path.extension().is_some_and(|ext| ext == "partial")
+        {
+            continue;
+        }
+        let lock = try_lock_cache_entry(&path)?;
+        if lock.is_none() {
+            in_use += 1;
+        }
+        entries.push(CacheEntry {
+            bytes: dir_size(&path),
+            path,
+        });
+    }
+    entries.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.path.cmp(&b.path)));
+
+    Ok(CacheStats {
+        total_bytes: entries.iter().map(|entry| entry.bytes).sum(),
+        entries,
+        in_use,
+    })
+}
+
+/// Total size in bytes of the files under `path`. Symlinks are not
+/// followed and unreadable entries are skipped.
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            Ok(file_type) if file_type.is_file() => {
+                entry.metadata().map(|m| m.len()).unwrap_or(0)
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Header vw writes at the top of every Tcl script and makefile it
+/// generates; both read it as a comment.
+const GENERATED_HEADER: &str = "# Auto-generated by vw";
+
+/// Scripts that `vw clean` removes when vw generated them.
+const GENERATED_SCRIPTS: [&str; 3] = ["deps.tcl", "read_deps.tcl", "deps.mk"];
+
+/// Build artifacts removed (or, for a dry run, found) by [`clean_workspace`].
+#[derive(Debug, Clone, Default)]
+pub struct CleanResult {
+    /// Removed files and directories, relative to the workspace.
+    pub removed: Vec<Utf8PathBuf>,
+    /// Total size of the removed artifacts in bytes.
+    pub reclaimed_bytes: u64,
+}
+
+/// Remove the build artifacts vw leaves in a workspace.
+///
+/// Only these are considered, all at the workspace root:
+/// * the NVC build directory `vw_build/`, and a `work/` directory if it
+///   is an NVC library (it contains `_NVC_LIB`)
+/// * waveform files (`*.fst`, `*.vcd`, `*.ghw`) written by `vw test`
+/// * `deps.tcl`, `read_deps.tcl` and `deps.mk`, if they start with vw's
+///   auto-generated header
+///
+/// Sources, `vw.toml`, `vw.lock` and `vhdl_ls.toml` are never touched, and
+/// waveforms written elsewhere with `--wave-dir` are left alone. With
+/// `dry_run` nothing is deleted, but the result still lists what would
+/// have been removed.
+pub fn clean_workspace(
+    workspace_dir: &Utf8Path,
+    dry_run: bool,
+) -> Result<CleanResult> {
+    let mut targets: Vec<Utf8PathBuf> = Vec::new();
+
+    if workspace_dir.join(BUILD_DIR).is_dir() {
+        targets.push(Utf8PathBuf::from(BUILD_DIR));
+    }
+    let work_dir = workspace_dir.join("work");
+    if work_dir.is_dir() && work_dir.join("_NVC_LIB").is_file() {
+        targets.push(Utf8PathBuf::from("work"));
+    }
+
+    let entries =
+        fs::read_dir(workspace_dir).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read workspace directory: {e}"),
+            path: Some(workspace_dir.into()),
+        })?;
+    let wave_extensions = [WaveFormat::Fst, WaveFormat::Vcd, WaveFormat::Ghw]
+        .map(|f| f.extension());
+    for entry in entries.flatten() {
+        if !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        let Ok(file_name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let is_waveform = Utf8Path::new(&file_name)
+            .extension()
+            .is_some_and(|ext| wave_extensions.contains(&ext));
+        if is_waveform {
+            targets.push(Utf8PathBuf::from(file_name));
+        }
+    }
+
+    for name in GENERATED_SCRIPTS {
+        let generated = fs::read_to_string(workspace_dir.join(name))
+            .is_ok_and(|content| content.starts_with(GENERATED_HEADER));
+        if generated {
+            targets.push(Utf8PathBuf::from(name));
+        }
+    }
+
+    targets.sort();
+    let mut result = CleanResult::default();
+    for target in targets {
+        let path = workspace_dir.join(&target);
+        let is_dir = path.is_dir();
+        let size = if is_dir {
+            dir_size(path.as_std_path())
+        } else {
+            fs::metadata(&path).map(|m| m.len()).unwrap_or(0)
+        };
+        if !dry_run {
+            let removed = if is_dir {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            removed.map_err(|e| VwError::FileSystem {
+                message: format!("Failed to remove {target}: {e}"),
+                path: Some(path.clone().into()),
+            })?;
+        }
+        result.reclaimed_bytes += size;
+        result.removed.push(target);
+    }
+
+    Ok(result)
+}
+
+/// List all dependencies in the workspace.
+pub fn list_dependencies(
+    workspace_dir: &Utf8Path,
+) -> Result<Vec<DependencyInfo>> {
+    let config = load_workspace_config(workspace_dir)?;
+    if config.dependencies.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Try to load lock file to get resolved versions
+    let lock_file = load_lock_file(workspace_dir).ok();
+
+    let mut deps = Vec::new();
+    for (name, dep) in &config.dependencies {
+        let version_info = match &lock_file {
+            Some(lock) => {
+                if let Some(locked_dep) = lock.dependencies.get(name) {
+                    VersionInfo::Locked {
+                        commit: locked_dep.commit.clone(),
+                    }
+                } else {
+                    // Not yet resolved, show branch/commit from config
+                    match (&dep.branch, &dep.commit) {
+                        (Some(branch), None) => VersionInfo::Branch {
+                            branch: branch.clone(),
+                        },
+                        (None, Some(commit)) => VersionInfo::Commit {
+                            commit: commit.clone(),
+                        },
+                        _ => VersionInfo::Unknown,
+                    }
+                }
+            }
+            None => {
+                // No lock file, show branch/commit from config
+                match (&dep.branch, &dep.commit) {
+                    (Some(branch), None) => VersionInfo::Branch {
+                        branch: branch.clone(),
+                    },
+                    (None, Some(commit)) => VersionInfo::Commit {
+                        commit: commit.clone(),
+                    },
+                    _ => VersionInfo::Unknown,
+                }
+            }
+        };
+
+        deps.push(DependencyInfo {
+            name: name.clone(),
+            repo: dep.repo.clone(),
+            version: version_info,
+        });
+    }
+    deps.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(deps)
+}
+
+/// A dependency as listed by [`list_dependencies`]. The JSON form is
+/// `{"name", "repo", "version": {"kind", ...}}`, see [`VersionInfo`].
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyInfo {
+    pub name: String,
+    pub repo: String,
+    pub version: VersionInfo,
+}
+
+/// Serialized with a `kind` of `branch`, `commit`, `locked` (the commit
+/// resolved in `vw.lock`) or `unknown`, next to the variant's field.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum VersionInfo {
+    Branch { branch: String },
     Commit { commit: String },
     Locked { commit: String },
     Unknown,
 }
 
+/// The kind of build a set of dependency libraries is wanted for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildTarget {
+    /// Synthesis builds driven from `deps.tcl`; sim-only libraries are left out.
+    Synthesis,
+    /// `vw test` simulation runs, which see every locked library.
+    Simulation,
+}
+
+/// Names of the locked dependency libraries a build target pulls in, in
+/// sorted order. This is the same set `deps-to-tcl` (for
+/// [`BuildTarget::Synthesis`]) or `test` (for [`BuildTarget::Simulation`])
+/// emits, so build systems can use it as a precise input list.
+pub fn libraries_for_target(
+    workspace_dir: &Utf8Path,
+    target: BuildTarget,
+) -> Result<Vec<String>> {
+    let lock_file = load_lock_file(workspace_dir)?;
+    let mut libraries: Vec<String> = lock_file
+        .dependencies
+        .iter()
+        .filter(|(_, dep)| target == BuildTarget::Simulation || !dep.sim_only)
+        .map(|(name, dep)| nvc_library_name(dep.library_name(name)))
+        .collect();
+    libraries.sort();
+    libraries.dedup();
+    Ok(libraries)
+}
+
 /// Resolve dependency VHDL files from the lock file.
 /// Returns a map of library name to list of paths relative to the
 /// per-user dependency cache directory (`$HOME/.vw/deps`), skipping
 /// sim-only dependencies. Relative paths keep this output stable across
-/// machines and platforms.
+/// machines and platforms. Files of vendored dependencies, and overlay
+/// files, are instead returned relative to the workspace, prefixed with
+/// `$vw_workspace_dir`.
 pub fn resolve_deps(
     workspace_dir: &Utf8Path,
 ) -> Result<HashMap<String, Vec<PathBuf>>> {
     let lock_file = load_lock_file(workspace_dir)?;
     let deps_dir = deps_directory()?;
-    let mut deps = HashMap::new();
+    let extensions = vhdl_extensions(workspace_dir);
+    let mut deps: HashMap<String, Vec<PathBuf>> = HashMap::new();
 
-    for (dep_name, locked_dep) in &lock_file.dependencies {
+    let mut dep_names: Vec<&String> = lock_file.dependencies.keys().collect();
+    dep_names.sort();
+    for dep_name in dep_names {
+        let locked_dep = &lock_file.dependencies[dep_name];
         if locked_dep.sim_only {
             continue;
         }
-        let abs_dep_path = resolve_dep_path(&locked_dep.path)?;
-        let vhdl_files = find_vhdl_files(
-            &abs_dep_path,
-            locked_dep.recursive,
-            &locked_dep.exclude,
-        )?;
-        let relative_files = vhdl_files
+        let library = nvc_library_name(locked_dep.library_name(dep_name));
+        if let Some(vendor_dir) = &locked_dep.vendored {
+            let files = vendored_library_files(
+                workspace_dir,
+                vendor_dir,
+                locked_dep,
+                &extensions,
+            )?
             .into_iter()
-            .map(|f| match f.strip_prefix(&deps_dir) {
-                Ok(rel) => rel.to_path_buf(),
-                Err(_) => f,
-            })
-            .collect();
-        deps.insert(dep_name.clone(), relative_files);
+            .map(|f| PathBuf::from(TCL_WORKSPACE_DIR_VAR).join(f));
+            deps.entry(library).or_default().extend(files);
+            continue;
+        }
+        let tree = cached_tree(dep_name, locked_dep)?;
+        let vhdl_files = tree_files(
+            workspace_dir,
+            dep_name,
+            &tree,
+            locked_dep,
+            &extensions,
+        )?;
+        let relative_files = vhdl_files.into_iter().map(|(f, _)| {
+            if let Ok(rel) = f.strip_prefix(&deps_dir) {
+                rel.to_path_buf()
+            } else if let Ok(rel) = f.strip_prefix(workspace_dir) {
+                PathBuf::from(TCL_WORKSPACE_DIR_VAR).join(rel)
+            } else {
+                f
+            }
+        });
+        deps.entry(library).or_default().extend(relative_files);
     }
 
     Ok(deps)
@@ -841,7 +2682,7 @@ pub fn resolve_deps(
 /// `source deps.tcl` and feed `$dep_files($lib)` straight to commands
 /// like `read_vhdl`.
 pub fn format_deps_tcl(deps: &HashMap<String, Vec<PathBuf>>) -> String {
-    let mut tcl_content = String::from("# Auto-generated by vw\n");
+    let mut tcl_content = format!("{GENERATED_HEADER}\n");
     tcl_content.push_str("# Associative array of dependency VHDL files\n");
     tcl_content
         .push_str("# Keys: library names, Values: lists of VHDL files\n");
@@ -852,22 +2693,7 @@ pub fn format_deps_tcl(deps: &HashMap<String, Vec<PathBuf>>) -> String {
         "# resulting lists contain absolute, ready-to-use file paths.\n\n",
     );
 
-    tcl_content.push_str(
-        "if {[info exists ::env(VW_DEPS_DIR)] && $::env(VW_DEPS_DIR) ne \"\"} {\n",
-    );
-    tcl_content.push_str("    set vw_deps_dir $::env(VW_DEPS_DIR)\n");
-    tcl_content.push_str("} elseif {[info exists ::env(USERPROFILE)] && $::env(USERPROFILE) ne \"\"} {\n");
-    tcl_content.push_str(
-        "    set vw_deps_dir [file join $::env(USERPROFILE) .vw deps]\n",
-    );
-    tcl_content.push_str(
-        "} elseif {[info exists ::env(HOME)] && $::env(HOME) ne \"\"} {\n",
-    );
-    tcl_content
-        .push_str("    set vw_deps_dir [file join $::env(HOME) .vw deps]\n");
-    tcl_content.push_str("} else {\n");
-    tcl_content.push_str("    error \"vw: unable to locate dependency cache; set VW_DEPS_DIR or HOME\"\n");
-    tcl_content.push_str("}\n\n");
+    push_tcl_path_preamble(&mut tcl_content);
 
     let mut dep_names: Vec<_> = deps.keys().collect();
     dep_names.sort();
@@ -880,12 +2706,7 @@ pub fn format_deps_tcl(deps: &HashMap<String, Vec<PathBuf>>) -> String {
         if !vhdl_files.is_empty() {
             tcl_content.push_str(" \\\n");
             for (i, file) in vhdl_files.iter().enumerate() {
-                // Use forward slashes so Tcl doesn't treat Windows-style
-                // backslashes as escape sequences. Vivado accepts forward
-                // slashes on both platforms. $vw_deps_dir is substituted
-                // by Tcl when the enclosing [list ...] is evaluated.
-                let path_str = file.to_string_lossy().replace('\\', "/");
-                tcl_content.push_str(&format!("    $vw_deps_dir/{path_str}"));
+                tcl_content.push_str(&format!("    {}", tcl_file_path(file)));
 
                 if i < vhdl_files.len() - 1 {
                     tcl_content.push_str(" \\");
@@ -900,6 +2721,64 @@ pub fn format_deps_tcl(deps: &HashMap<String, Vec<PathBuf>>) -> String {
     tcl_content
 }
 
+/// Append the Tcl that defines `vw_deps_dir` and `vw_workspace_dir`, the
+/// roots that portable dependency paths are written relative to.
+fn push_tcl_path_preamble(tcl_content: &mut String) {
+    tcl_content.push_str(
+        "if {[info exists ::env(VW_DEPS_DIR)] && $::env(VW_DEPS_DIR) ne \"\"} {\n",
+    );
+    tcl_content.push_str("    set vw_deps_dir $::env(VW_DEPS_DIR)\n");
+    tcl_content.push_str("} elseif {[info exists ::env(USERPROFILE)] && $::env(USERPROFILE) ne \"\"} {\n");
+    tcl_content.push_str(
+        "    set vw_deps_dir [file join $::env(USERPROFILE) .vw deps]\n",
+    );
+    tcl_content.push_str(
+        "} elseif {[info exists ::env(HOME)] && $::env(HOME) ne \"\"} {\n",
+    );
+    tcl_content
+        .push_str("    set vw_deps_dir [file join $::env(HOME) .vw deps]\n");
+    tcl_content.push_str("} else {\n");
+    tcl_content.push_str("    error \"vw: unable to locate dependency cache; set VW_DEPS_DIR or HOME\"\n");
+    tcl_content.push_str("}\n\n");
+    tcl_content.push_str(
+        "# Vendored dependencies live in the workspace next to this file\n",
+    );
+    tcl_content.push_str(
+        "set vw_workspace_dir [file dirname [file normalize [info script]]]\n\n",
+    );
+}
+
+/// Render a path from [`resolve_deps`] for Tcl: vendored files already
+/// start with `$vw_workspace_dir`, everything else is prefixed with
+/// `$vw_deps_dir/`.
+///
+/// The result is a double-quoted Tcl word, so paths with spaces stay a
+/// single list element while the leading variable is still substituted.
+fn tcl_file_path(file: &Path) -> String {
+    let (root, rel) = match file.strip_prefix(TCL_WORKSPACE_DIR_VAR) {
+        Ok(rel) => (TCL_WORKSPACE_DIR_VAR, rel),
+        Err(_) => ("$vw_deps_dir", file),
+    };
+    // Use forward slashes so Tcl doesn't treat Windows-style
+    // backslashes as escape sequences. Vivado accepts forward
+    // slashes on both platforms. The root variable is substituted
+    // by Tcl when the enclosing command is evaluated.
+    let rel = rel.to_string_lossy().replace('\\', "/");
+    format!("\"{root}/{}\"", tcl_quote_escape(&rel))
+}
+
+/// Escape the characters Tcl substitutes inside a double-quoted word.
+fn tcl_quote_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '$' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 /// Generate a TCL file containing all dependency VHDL files.
 /// Creates an associative array where keys are library names and values are lists of files.
 pub fn generate_deps_tcl(workspace_dir: &Utf8Path) -> Result<()> {
@@ -909,57 +2788,477 @@ pub fn generate_deps_tcl(workspace_dir: &Utf8Path) -> Result<()> {
     let tcl_path = workspace_dir.join("deps.tcl");
     fs::write(&tcl_path, tcl_content).map_err(|e| VwError::FileSystem {
         message: format!("Failed to write deps.tcl file: {e}"),
+        path: Some(tcl_path.clone().into()),
     })?;
 
     Ok(())
 }
 
-// ============================================================================
-// Public API - Testbench Management
-// ============================================================================
+/// Format a dependency map as a GNU make fragment.
+///
+/// Each library gets a `DEPS_VHDL_FILES_<lib>` variable listing its files,
+/// `DEPS_LIBRARIES` names the libraries, and `DEPS_VHDL_FILES` joins every
+/// library's files. Like `deps.tcl`, paths are written relative to
+/// `$(VW_DEPS_DIR)`, which defaults to the per-user cache unless set in
+/// the environment, or to `$(VW_WORKSPACE_DIR)`, the directory holding the
+/// fragment, for vendored files. Paths use forward slashes and escape
+/// the characters make would otherwise split or expand.
+pub fn format_deps_make(deps: &HashMap<String, Vec<PathBuf>>) -> String {
+    let mut make_content = format!("{GENERATED_HEADER}\n");
+    make_content
+        .push_str("# Dependency VHDL files per library, for GNU make\n\n");
+    make_content
+        .push_str("VW_DEPS_DIR ?= $(or $(USERPROFILE),$(HOME))/.vw/deps\n");
+    make_content.push_str(
+        "VW_WORKSPACE_DIR := $(patsubst %/,%,$(dir $(abspath \
+         $(lastword $(MAKEFILE_LIST)))))\n\n",
+    );
 
-/// List all available testbenches in the workspace.
-pub fn list_testbenches(
-    bench_dir: &Utf8Path,
-    ignore_dirs: &HashSet<String>,
-    recurse: bool,
-) -> Result<Vec<TestbenchInfo>> {
-    let mut entities_cache = HashMap::new();
-    list_testbenches_impl(bench_dir, ignore_dirs, recurse, &mut entities_cache)
-}
+    let mut dep_names: Vec<_> = deps.keys().collect();
+    dep_names.sort();
 
-fn list_testbenches_impl(
-    bench_dir: &Utf8Path,
-    ignore_dirs: &HashSet<String>,
-    recurse: bool,
-    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
-) -> Result<Vec<TestbenchInfo>> {
-    let mut testbenches = Vec::new();
+    make_content.push_str("DEPS_LIBRARIES :=");
+    for dep_name in &dep_names {
+        make_content.push_str(&format!(" {dep_name}"));
+    }
+    make_content.push_str("\n\n");
 
-    for entry in fs::read_dir(bench_dir).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to read bench directory: {e}"),
-    })? {
-        let entry = entry.map_err(|e| VwError::FileSystem {
-            message: format!("Failed to read directory entry: {e}"),
-        })?;
-        let path = entry.path();
+    for dep_name in dep_names {
+        make_content.push_str(&format!("DEPS_VHDL_FILES_{dep_name} :="));
+        for file in &deps[dep_name] {
+            make_content
+                .push_str(&format!(" \\\n    {}", make_file_path(file)));
+        }
+        make_content.push_str("\n\n");
+    }
 
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                if extension == "vhd" || extension == "vhdl" {
-                    let entities = get_cached_entities(&path, entities_cache)?;
-                    for entity in entities {
-                        testbenches.push(TestbenchInfo {
-                            name: entity.clone(),
-                            path: path.clone(),
-                        });
-                    }
-                }
+    make_content.push_str(
+        "DEPS_VHDL_FILES := $(foreach lib,$(DEPS_LIBRARIES),\
+         $(DEPS_VHDL_FILES_$(lib)))\n",
+    );
+    make_content
+}
+
+/// Render a portable path from [`resolve_deps`] for a makefile: rooted at
+/// `$(VW_WORKSPACE_DIR)` or `$(VW_DEPS_DIR)`, with forward slashes, and
+/// with spaces, `#` and `$` escaped.
+fn make_file_path(file: &Path) -> String {
+    let (root, rel) = match file.strip_prefix(TCL_WORKSPACE_DIR_VAR) {
+        Ok(rel) => ("$(VW_WORKSPACE_DIR)", rel),
+        Err(_) => ("$(VW_DEPS_DIR)", file),
+    };
+    let mut escaped = String::new();
+    for c in rel.to_string_lossy().replace('\\', "/").chars() {
+        match c {
+            ' ' | '#' => {
+                escaped.push('\\');
+                escaped.push(c);
             }
-        } else if recurse {
-            let dir_path: Utf8PathBuf =
+            '$' => escaped.push_str("$$"),
+            _ => escaped.push(c),
+        }
+    }
+    format!("{root}/{escaped}")
+}
+
+/// Generate `deps.mk`, the GNU make counterpart of `deps.tcl`, from the
+/// same files.
+pub fn generate_deps_make(workspace_dir: &Utf8Path) -> Result<()> {
+    let deps = resolve_deps(workspace_dir)?;
+    let make_content = format_deps_make(&deps);
+
+    let make_path = workspace_dir.join("deps.mk");
+    fs::write(&make_path, make_content).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to write deps.mk file: {e}"),
+        path: Some(make_path.clone().into()),
+    })?;
+
+    Ok(())
+}
+
+/// Write a Vivado non-project script that reads every dependency file with
+/// `read_vhdl -library <lib>`, with libraries and the files within each
+/// library in compile order.
+///
+/// The file set and portable paths match `deps.tcl`: sim-only dependencies
+/// are skipped and paths are written relative to `$vw_deps_dir` or
+/// `$vw_workspace_dir`. Like `deps.tcl`, the script locates vendored files
+/// relative to its own directory, so `out` belongs at the workspace root
+/// when dependencies are vendored.
+pub fn generate_vivado_script(
+    workspace_dir: &Utf8Path,
+    out: &Utf8Path,
+    vhdl_std: VhdlStandard,
+) -> Result<()> {
+    let deps = ordered_deps(workspace_dir, vhdl_std)?;
+
+    // Vivado reads VHDL-93 (and 2002) without a flag
+    let std_flag = match vhdl_std {
+        VhdlStandard::Vhdl1993 | VhdlStandard::Vhdl2002 => "",
+        VhdlStandard::Vhdl2008 => "-vhdl2008 ",
+        VhdlStandard::Vhdl2019 => "-vhdl2019 ",
+    };
+
+    let mut script = format!("{GENERATED_HEADER}\n");
+    script.push_str("# Reads dependency VHDL files in compile order\n\n");
+    push_tcl_path_preamble(&mut script);
+
+    for (lib_name, files) in &deps.libraries {
+        let library = nvc_library_name(lib_name);
+        for file in files {
+            let path = deps.portable_paths.get(file).unwrap_or(file);
+            script.push_str(&format!(
+                "read_vhdl {std_flag}-library {library} {}\n",
+                tcl_file_path(path)
+            ));
+        }
+    }
+
+    let out_path = workspace_dir.join(out);
+    fs::write(&out_path, script).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to write Vivado script: {e}"),
+        path: Some(out_path.clone().into()),
+    })?;
+
+    Ok(())
+}
+
+/// The dependency files of [`resolve_deps`] in compile order.
+struct OrderedDeps {
+    /// Libraries, each after the libraries it references, with their
+    /// absolute file paths.
+    libraries: Vec<(String, Vec<PathBuf>)>,
+    /// The portable form `resolve_deps` gave each absolute path.
+    portable_paths: HashMap<PathBuf, PathBuf>,
+}
+
+/// Put the files of [`resolve_deps`] in compile order, shared by the
+/// Vivado script and the build manifest.
+///
+/// Libraries come after the libraries they reference. Files within a
+/// library are sorted by their dependencies, except for libraries of
+/// dependencies with an explicit `files` list, which keep that order as
+/// they do in `vw test`.
+fn ordered_deps(
+    workspace_dir: &Utf8Path,
+    vhdl_std: VhdlStandard,
+) -> Result<OrderedDeps> {
+    let deps = resolve_deps(workspace_dir)?;
+    let deps_dir = deps_directory()?;
+    let listed: HashSet<String> = load_lock_file(workspace_dir)?
+        .dependencies
+        .iter()
+        .filter(|(_, locked)| !locked.files.is_empty())
+        .map(|(name, locked)| nvc_library_name(locked.library_name(name)))
+        .collect();
+
+    // Absolute paths are needed to parse the files; remember the portable
+    // form of each one for callers writing portable output
+    let mut portable_paths: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut lib_files: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for (lib_name, files) in &deps {
+        let mut absolute_files = Vec::new();
+        for file in files {
+            let absolute = match file.strip_prefix(TCL_WORKSPACE_DIR_VAR) {
+                Ok(rel) => workspace_dir.as_std_path().join(rel),
+                Err(_) => deps_dir.join(file),
+            };
+            portable_paths.insert(absolute.clone(), file.clone());
+            absolute_files.push(absolute);
+        }
+        lib_files.insert(lib_name.clone(), absolute_files);
+    }
+
+    let mut lib_names: Vec<String> = lib_files.keys().cloned().collect();
+    lib_names.sort();
+    let sorted_libs = sort_libraries_by_dependencies(lib_names, &lib_files);
+
+    let mut processor = RecordProcessor::new(vhdl_std);
+    let mut cache = FileCache::new();
+    let mut libraries = Vec::new();
+    for lib_name in sorted_libs {
+        let Some(mut files) = lib_files.remove(&lib_name) else {
+            continue;
+        };
+        if !listed.contains(&lib_name) {
+            sort_files_by_dependencies(&mut processor, &mut files, &mut cache)?;
+        }
+        libraries.push((lib_name, files));
+    }
+
+    Ok(OrderedDeps {
+        libraries,
+        portable_paths,
+    })
+}
+
+/// A dependency library in a [`Manifest`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestLibrary {
+    /// The library name, which keys the library in the JSON form.
+    #[serde(skip)]
+    pub name: String,
+    /// Absolute paths of the library's files, in compile order.
+    pub files: Vec<PathBuf>,
+    /// Whether no dependency in the library sets `lint`.
+    pub is_third_party: bool,
+    /// The standard to analyze the library with.
+    pub standard: VhdlStandard,
+}
+
+/// The resolved dependency files, for build systems other than Vivado.
+/// The JSON form is an object keyed by library name,
+/// `{"<library>": {"files", "is_third_party", "standard"}}`, with the
+/// libraries in compile order.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub libraries: Vec<ManifestLibrary>,
+}
+
+impl Serialize for Manifest {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.libraries.len()))?;
+        for library in &self.libraries {
+            map.serialize_entry(&library.name, library)?;
+        }
+        map.end()
+    }
+}
+
+impl Manifest {
+    /// Render the manifest as pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| VwError::CodeGen {
+            message: format!("Failed to render manifest as JSON: {e}"),
+        })
+    }
+}
+
+/// Build the manifest of dependency files from `vw.lock`.
+///
+/// This is the structured sibling of `deps.tcl`: it covers the same
+/// files, skipping sim-only dependencies, with absolute paths valid on
+/// this machine and in the compile order the Vivado script uses.
+pub fn generate_manifest(workspace_dir: &Utf8Path) -> Result<Manifest> {
+    let config = load_workspace_config(workspace_dir)?;
+    let workspace_std = config.workspace.vhdl_standard.unwrap_or_default();
+    let deps = ordered_deps(workspace_dir, workspace_std)?;
+
+    let libraries = deps
+        .libraries
+        .into_iter()
+        .map(|(name, files)| {
+            let members: Vec<&Dependency> = config
+                .dependencies
+                .iter()
+                .filter(|(dep_name, dep)| {
+                    nvc_library_name(dep.library_name(dep_name)) == name
+                })
+                .map(|(_, dep)| dep)
+                .collect();
+            ManifestLibrary {
+                is_third_party: !members.iter().any(|dep| dep.lint),
+                standard: members
+                    .iter()
+                    .find_map(|dep| dep.vhdl_standard)
+                    .unwrap_or(workspace_std),
+                name,
+                files,
+            }
+        })
+        .collect();
+    Ok(Manifest { libraries })
+}
+
+// ============================================================================
+// Public API - Environment Checks
+// ============================================================================
+
+/// Outcome of one `vw doctor` check.
+#[derive(Debug, Clone)]
+pub struct EnvironmentCheck {
+    pub name: String,
+    pub passed: bool,
+    /// Whether vw cannot build or run testbenches if this check fails.
+    pub critical: bool,
+    pub detail: String,
+}
+
+/// Oldest NVC release that accepts `--std=2019`.
+const NVC_MIN_VERSION_2019: (u32, u32) = (1, 6);
+
+/// Check that the tools and directories vw relies on are usable: `nvc` is
+/// on `PATH`, its version supports `vhdl_std`, and the dependency cache
+/// directory is writable.
+pub fn check_environment(vhdl_std: VhdlStandard) -> Vec<EnvironmentCheck> {
+    let mut checks = Vec::new();
+
+    let nvc_version = match std::process::Command::new("nvc")
+        .arg("--version")
+        .output()
+    {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            checks.push(EnvironmentCheck {
+                name: "nvc is installed".to_string(),
+                passed: true,
+                critical: true,
+                detail: stdout.lines().next().unwrap_or("").to_string(),
+            });
+            parse_nvc_version(&stdout)
+        }
+        Ok(output) => {
+            checks.push(EnvironmentCheck {
+                name: "nvc is installed".to_string(),
+                passed: false,
+                critical: true,
+                detail: format!("`nvc --version` failed ({})", output.status),
+            });
+            None
+        }
+        Err(e) => {
+            checks.push(EnvironmentCheck {
+                name: "nvc is installed".to_string(),
+                passed: false,
+                critical: true,
+                detail: format!(
+                    "could not run nvc: {e} (is it installed and on PATH?)"
+                ),
+            });
+            None
+        }
+    };
+
+    let required = match vhdl_std {
+        VhdlStandard::Vhdl1993
+        | VhdlStandard::Vhdl2002
+        | VhdlStandard::Vhdl2008 => (0, 0),
+        VhdlStandard::Vhdl2019 => NVC_MIN_VERSION_2019,
+    };
+    let (passed, detail) = match nvc_version {
+        Some(version) if version >= required => {
+            (true, format!("nvc {}.{} supports it", version.0, version.1))
+        }
+        Some(version) => (
+            false,
+            format!(
+                "nvc {}.{} is too old; {}.{} or newer is required",
+                version.0, version.1, required.0, required.1
+            ),
+        ),
+        None => (false, "nvc version could not be determined".to_string()),
+    };
+    checks.push(EnvironmentCheck {
+        name: format!("VHDL-{vhdl_std} is supported"),
+        passed,
+        critical: true,
+        detail,
+    });
+
+    let (passed, detail) = match deps_directory().and_then(|dir| {
+        let probe = dir.join(".vw-write-test");
+        fs::write(&probe, b"")
+            .and_then(|()| fs::remove_file(&probe))
+            .map_err(|e| VwError::FileSystem {
+                message: format!("Directory is not writable: {e}"),
+                path: Some(dir.clone()),
+            })?;
+        Ok(dir)
+    }) {
+        Ok(dir) => (true, dir.display().to_string()),
+        Err(e) => (false, e.to_string()),
+    };
+    checks.push(EnvironmentCheck {
+        name: "Dependency cache is writable".to_string(),
+        passed,
+        critical: true,
+        detail,
+    });
+
+    checks
+}
+
+/// Extract the major and minor version from `nvc --version` output, whose
+/// first line looks like `nvc 1.13.2 (Using LLVM 17.0.6)`.
+fn parse_nvc_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.lines().next()?.split_whitespace().nth(1)?;
+    let mut parts = version.split(['.', '-']);
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+// ============================================================================
+// Public API - Testbench Management
+// ============================================================================
+
+/// List all available testbenches in the workspace.
+///
+/// A testbench is an entity without ports (see [`testbench_entities`]).
+/// Entities named in `not_testbenches` are helpers rather than runnable
+/// top-levels and are left out of the list. Only files with one of
+/// `extensions` (see [`vhdl_extensions`]) are searched.
+pub fn list_testbenches(
+    bench_dir: &Utf8Path,
+    ignore_dirs: &HashSet<String>,
+    recurse: bool,
+    not_testbenches: &[String],
+    vhdl_std: VhdlStandard,
+    extensions: &[String],
+) -> Result<Vec<TestbenchInfo>> {
+    let mut entities_cache = HashMap::new();
+    let mut testbenches = list_testbenches_impl(
+        bench_dir,
+        ignore_dirs,
+        recurse,
+        vhdl_std,
+        extensions,
+        &mut entities_cache,
+    )?;
+    testbenches.retain(|tb| !is_listed(not_testbenches, &tb.name));
+    Ok(testbenches)
+}
+
+fn list_testbenches_impl(
+    bench_dir: &Utf8Path,
+    ignore_dirs: &HashSet<String>,
+    recurse: bool,
+    vhdl_std: VhdlStandard,
+    extensions: &[String],
+    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
+) -> Result<Vec<TestbenchInfo>> {
+    let mut testbenches = Vec::new();
+
+    for entry in fs::read_dir(bench_dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to read bench directory: {e}"),
+        path: Some(bench_dir.as_std_path().to_path_buf()),
+    })? {
+        let entry = entry.map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read directory entry: {e}"),
+            path: None,
+        })?;
+        let path = entry.path();
+
+        if path.is_file() {
+            if has_vhdl_extension(&path, extensions) {
+                let entities = get_cached_entities(&path, entities_cache)?;
+                for entity in testbench_entities(&path, entities, vhdl_std) {
+                    testbenches.push(TestbenchInfo {
+                        name: entity,
+                        path: path.clone(),
+                    });
+                }
+            }
+        } else if recurse {
+            let dir_path: Utf8PathBuf =
                 path.try_into().map_err(|e| VwError::FileSystem {
                     message: format!("Failed to get dir path: {e}"),
+                    path: None,
                 })?;
             if let Some(file_name) = dir_path.file_name() {
                 if !ignore_dirs.contains(file_name) {
@@ -967,6 +3266,8 @@ fn list_testbenches_impl(
                         &dir_path,
                         ignore_dirs,
                         recurse,
+                        vhdl_std,
+                        extensions,
                         entities_cache,
                     )?;
                     testbenches.append(&mut lower_testbenches);
@@ -993,7 +3294,9 @@ pub struct RecordProcessor {
     pub target_attr: String,
 }
 
-const RECORD_PARSE_ATTRIBUTE: &str = "serialize_rust";
+/// Attribute that tags a record for Rust codegen unless another is chosen.
+pub const DEFAULT_RECORD_ATTRIBUTE: &str = "serialize_rust";
+
 impl RecordProcessor {
     pub fn new(std: VhdlStandard) -> Self {
         Self {
@@ -1002,7 +3305,86 @@ impl RecordProcessor {
             symbol_to_file: HashMap::new(),
             tagged_names: HashSet::new(),
             file_info: HashMap::new(),
-            target_attr: RECORD_PARSE_ATTRIBUTE.to_string(),
+            target_attr: DEFAULT_RECORD_ATTRIBUTE.to_string(),
+        }
+    }
+
+    /// Create a processor that tags records carrying `target_attr` instead
+    /// of [`DEFAULT_RECORD_ATTRIBUTE`].
+    pub fn with_attribute(
+        std: VhdlStandard,
+        target_attr: &str,
+    ) -> Result<Self> {
+        if !is_vhdl_identifier(target_attr) {
+            return Err(VwError::Config {
+                message: format!(
+                    "'{target_attr}' is not a valid VHDL attribute name"
+                ),
+            });
+        }
+        Ok(Self {
+            target_attr: target_attr.to_string(),
+            ..Self::new(std)
+        })
+    }
+
+    /// Order the tagged records so each one comes after every tagged record
+    /// it embeds as a field, which is the order generated types must be
+    /// emitted in.
+    ///
+    /// Returns a `CodeGen` error naming the records involved when tagged
+    /// records reference each other in a cycle, since such types would have
+    /// unbounded size.
+    pub fn tagged_records_in_order(&self) -> Result<Vec<String>> {
+        let tagged: HashSet<String> =
+            self.tagged_names.iter().map(|n| n.to_lowercase()).collect();
+
+        let mut records: Vec<&VwSymbol> = self
+            .symbols
+            .values()
+            .filter(|s| matches!(s.kind, SymbolKind::Record(_)))
+            .filter(|s| tagged.contains(&s.name.to_lowercase()))
+            .collect();
+        records.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut graph: DiGraph<String, ()> = DiGraph::default();
+        let mut index_map: HashMap<String, NodeIndex> = HashMap::new();
+        for record in &records {
+            let index = graph.add_node(record.name.clone());
+            index_map.insert(record.name.to_lowercase(), index);
+        }
+
+        // Edges point from an embedded record to the record embedding it
+        for record in &records {
+            let record_node = index_map[&record.name.to_lowercase()];
+            for field in record.get_fields().into_iter().flatten() {
+                if let Some(&field_node) =
+                    index_map.get(&field.subtype_name.to_lowercase())
+                {
+                    graph.add_edge(field_node, record_node, ());
+                }
+            }
+        }
+
+        match toposort(&graph, None) {
+            Ok(order) => Ok(order.iter().map(|&i| graph[i].clone()).collect()),
+            Err(_) => {
+                let cycle: Vec<String> = tarjan_scc(&graph)
+                    .into_iter()
+                    .find(|scc| {
+                        scc.len() > 1 || graph.contains_edge(scc[0], scc[0])
+                    })
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|&i| graph[i].clone())
+                    .collect();
+                Err(VwError::CodeGen {
+                    message: format!(
+                        "Tagged records reference each other in a cycle: {}",
+                        cycle.join(", ")
+                    ),
+                })
+            }
         }
     }
 }
@@ -1035,7 +3417,8 @@ impl FileCache {
             Entry::Vacant(e) => {
                 let content = fs::read_to_string(path).map_err(|e| {
                     VwError::FileSystem {
-                        message: format!("Failed to read file {path:?}: {e}"),
+                        message: format!("Failed to read file: {e}"),
+                        path: Some(path.to_path_buf()),
                     }
                 })?;
                 let deps = parse_file_dependencies(&content)?;
@@ -1054,7 +3437,8 @@ impl FileCache {
             Entry::Vacant(e) => {
                 let content = fs::read_to_string(path).map_err(|e| {
                     VwError::FileSystem {
-                        message: format!("Failed to read file {path:?}: {e}"),
+                        message: format!("Failed to read file: {e}"),
+                        path: Some(path.to_path_buf()),
                     }
                 })?;
                 let symbols = parse_provided_symbols(&content)?;
@@ -1070,7 +3454,8 @@ impl FileCache {
             Entry::Vacant(e) => {
                 let content = fs::read_to_string(path).map_err(|e| {
                     VwError::FileSystem {
-                        message: format!("Failed to read file {path:?}: {e}"),
+                        message: format!("Failed to read file: {e}"),
+                        path: Some(path.to_path_buf()),
                     }
                 })?;
                 let entities = parse_entities(&content)?;
@@ -1079,6 +3464,43 @@ impl FileCache {
         }
     }
 
+    /// Read and scan a batch of files up front, filling the dependency and
+    /// provided-symbol caches.
+    ///
+    /// With the `parallel` feature the files are scanned on the rayon thread
+    /// pool; otherwise they are scanned in turn. The cached results are the
+    /// same either way. Files that can't be read are skipped here so the
+    /// lazy getters still report the error if the file is actually needed.
+    pub fn preload(&mut self, paths: &[PathBuf]) {
+        let pending: Vec<&PathBuf> = paths
+            .iter()
+            .filter(|p| {
+                !self.dependencies.contains_key(*p)
+                    || !self.provided_symbols.contains_key(*p)
+            })
+            .collect();
+
+        let scan = |path: &&PathBuf| {
+            let content = fs::read_to_string(path).ok()?;
+            let deps = parse_file_dependencies(&content).ok()?;
+            let provided = parse_provided_symbols(&content).ok()?;
+            Some(((*path).clone(), deps, provided))
+        };
+
+        #[cfg(feature = "parallel")]
+        let scanned: Vec<_> = {
+            use rayon::prelude::*;
+            pending.par_iter().filter_map(scan).collect()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let scanned: Vec<_> = pending.iter().filter_map(scan).collect();
+
+        for (path, deps, provided) in scanned {
+            self.dependencies.insert(path.clone(), deps);
+            self.provided_symbols.insert(path, provided);
+        }
+    }
+
     /// Get mutable access to the entities cache for functions that only need entity lookups.
     pub fn entities_cache_mut(&mut self) -> &mut HashMap<PathBuf, Vec<String>> {
         &mut self.entities
@@ -1179,6 +3601,24 @@ fn parse_provided_symbols(content: &str) -> Result<Vec<VwSymbol>> {
     Ok(symbols)
 }
 
+/// Parse library-qualified references (`use lib.pkg`, `entity lib.ent`,
+/// `context lib.ctx`, `configuration lib.cfg`) from file content, as
+/// lowercase `(library, name)` pairs.
+fn parse_library_references(content: &str) -> Result<Vec<(String, String)>> {
+    let reference_re = regex::Regex::new(
+        r"(?i)\b(?:use|entity|context|configuration)\s+(\w+)\.(\w+)",
+    )?;
+    let mut references = Vec::new();
+    for captures in reference_re.captures_iter(content) {
+        let reference =
+            (captures[1].to_lowercase(), captures[2].to_lowercase());
+        if !references.contains(&reference) {
+            references.push(reference);
+        }
+    }
+    Ok(references)
+}
+
 /// Parse entity declarations from file content.
 fn parse_entities(content: &str) -> Result<Vec<String>> {
     let mut entities = Vec::new();
@@ -1195,50 +3635,91 @@ fn parse_entities(content: &str) -> Result<Vec<String>> {
     Ok(entities)
 }
 
-pub async fn analyze_ext_libraries(
-    vhdl_ls_config: &VhdlLsConfig,
-    processor: &mut RecordProcessor,
+/// Collects the names of entities declared without ports, which is the
+/// signature of a testbench (or other simulation top level).
+#[derive(Default)]
+struct PortlessEntityFinder {
+    entities: Vec<String>,
+    portless: Vec<String>,
+}
+
+impl Visitor for PortlessEntityFinder {
+    fn visit_entity(&mut self, entity: &EntityDeclaration) -> VisitorResult {
+        let name = entity.ident.tree.item.name_utf8();
+        if entity
+            .port_clause
+            .as_ref()
+            .is_none_or(|ports| ports.items.is_empty())
+        {
+            self.portless.push(name.clone());
+        }
+        self.entities.push(name);
+        VisitorResult::Continue
+    }
+}
+
+/// Pick out the testbench entities among `entities`, the entity names
+/// declared in `path`.
+///
+/// Entities without a port list are testbenches. Any entity the parser
+/// could not make sense of falls back to the `_tb` naming convention.
+pub fn testbench_entities(
+    path: &Path,
+    entities: &[String],
     vhdl_std: VhdlStandard,
-    cache: &mut FileCache,
-) -> Result<()> {
-    // Collect non-defaultlib library names
-    let ext_lib_names: Vec<String> = vhdl_ls_config
-        .libraries
-        .keys()
-        .filter(|k| k.as_str() != "defaultlib")
+) -> Vec<String> {
+    let parser = VHDLParser::new(vhdl_std.into());
+    let mut diagnostics = Vec::new();
+    let mut finder = PortlessEntityFinder::default();
+    if let Ok((_, design_file)) =
+        parser.parse_design_file(path, &mut diagnostics)
+    {
+        walk_design_file(&mut finder, &design_file);
+    }
+
+    entities
+        .iter()
+        .filter(|entity| {
+            if is_listed(&finder.entities, entity) {
+                is_listed(&finder.portless, entity)
+            } else {
+                entity.to_lowercase().ends_with("_tb")
+            }
+        })
         .cloned()
-        .collect();
+        .collect()
+}
 
+/// Convert a dependency or library name into the library name VHDL code
+/// and NVC know it by: hyphens, which VHDL identifiers can't contain,
+/// become underscores. Every library name written to `vhdl_ls.toml` or
+/// `deps.tcl`, or passed to NVC, goes through this.
+fn nvc_library_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Order libraries so each one comes after the libraries its files name in
+/// `library` clauses. Falls back to the given order if the libraries depend
+/// on each other in a cycle.
+fn sort_libraries_by_dependencies(
+    ext_lib_names: Vec<String>,
+    lib_files: &HashMap<String, Vec<PathBuf>>,
+) -> Vec<String> {
     // Build inter-library dependency graph by scanning for `library <name>;`
     let ext_lib_set: HashSet<String> = ext_lib_names.iter().cloned().collect();
     let mut lib_deps: HashMap<String, Vec<String>> = HashMap::new();
     for lib_name in &ext_lib_names {
         let mut deps = Vec::new();
-        if let Some(library) = vhdl_ls_config.libraries.get(lib_name) {
-            for file_path in &library.files {
-                let expanded = if file_path.starts_with("$HOME") {
-                    if let Some(home) = dirs::home_dir() {
-                        home.join(
-                            file_path
-                                .strip_prefix("$HOME/")
-                                .unwrap_or(file_path),
-                        )
-                    } else {
-                        PathBuf::from(file_path)
-                    }
-                } else {
-                    PathBuf::from(file_path)
-                };
-                if let Ok(contents) = fs::read_to_string(&expanded) {
-                    for line in contents.lines() {
-                        let trimmed = line.trim().to_lowercase();
-                        if let Some(rest) = trimmed.strip_prefix("library ") {
-                            let dep_lib = rest.trim_end_matches(';').trim();
-                            if ext_lib_set.contains(dep_lib)
-                                && dep_lib != lib_name.to_lowercase()
-                            {
-                                deps.push(dep_lib.to_string());
-                            }
+        for file_path in lib_files.get(lib_name).into_iter().flatten() {
+            if let Ok(contents) = fs::read_to_string(file_path) {
+                for line in contents.lines() {
+                    let trimmed = line.trim().to_lowercase();
+                    if let Some(rest) = trimmed.strip_prefix("library ") {
+                        let dep_lib = rest.trim_end_matches(';').trim();
+                        if ext_lib_set.contains(dep_lib)
+                            && dep_lib != lib_name.to_lowercase()
+                        {
+                            deps.push(dep_lib.to_string());
                         }
                     }
                 }
@@ -1288,67 +3769,387 @@ pub async fn analyze_ext_libraries(
         sorted_libs = ext_lib_names;
     }
 
-    // Analyze libraries in dependency order
-    for lib_name in &sorted_libs {
-        if let Some(library) = vhdl_ls_config.libraries.get(lib_name) {
-            // Convert library name to be NVC-compatible (no hyphens)
-            let nvc_lib_name = lib_name.replace('-', "_");
-
-            let mut files = Vec::new();
-            for file_path in &library.files {
-                // Convert $HOME paths to absolute paths
-                let expanded_path = if file_path.starts_with("$HOME") {
-                    let home_dir = dirs::home_dir().ok_or_else(|| {
-                        VwError::FileSystem {
-                            message: "Could not determine home directory"
-                                .to_string(),
-                        }
-                    })?;
-                    home_dir.join(
-                        file_path.strip_prefix("$HOME/").unwrap_or(file_path),
-                    )
-                } else {
-                    PathBuf::from(file_path)
-                };
-                files.push(expanded_path);
-            }
-
-            // Sort files in dependency order (dependencies first)
-            sort_files_by_dependencies(processor, &mut files, cache)?;
-
-            let file_strings: Vec<String> = files
-                .iter()
-                .map(|p| p.to_string_lossy().to_string())
-                .collect();
-
-            run_nvc_analysis(
-                vhdl_std,
-                BUILD_DIR,
-                &nvc_lib_name,
-                &file_strings,
-                false,
-            )
-            .await?;
-        }
-    }
-
-    Ok(())
+    sorted_libs
 }
 
-/// Run a testbench using NVC simulator.
-pub async fn run_testbench(
+pub async fn analyze_ext_libraries(
     workspace_dir: &Utf8Path,
-    testbench_name: String,
+    vhdl_ls_config: &VhdlLsConfig,
+    processor: &mut RecordProcessor,
     vhdl_std: VhdlStandard,
-    recurse: bool,
-    runtime_flags: &[String],
-    build_rust: bool,
-    scaffold: bool,
+    heap_size: &str,
+    cache: &mut FileCache,
+    output: NvcOutput,
 ) -> Result<()> {
+    let builds = ext_library_builds(
+        workspace_dir,
+        vhdl_ls_config,
+        processor,
+        vhdl_std,
+        cache,
+    )?;
+    let build_dir = workspace_build_dir(workspace_dir);
+    for build in &builds {
+        run_nvc_analysis(
+            build.vhdl_std,
+            build_dir.as_str(),
+            &build.nvc_name,
+            heap_size,
+            &build.analysis_flags,
+            &build.files,
+            output,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// One library to analyze: its files in compile order and the standard and
+/// extra flags to analyze them with.
+struct LibraryBuild {
+    name: String,
+    /// Library name as passed to NVC.
+    nvc_name: String,
+    vhdl_std: VhdlStandard,
+    analysis_flags: Vec<String>,
+    files: Vec<String>,
+}
+
+/// The non-defaultlib libraries from `vhdl_ls.toml`, in the order they
+/// must be analyzed.
+fn ext_library_builds(
+    workspace_dir: &Utf8Path,
+    vhdl_ls_config: &VhdlLsConfig,
+    processor: &mut RecordProcessor,
+    vhdl_std: VhdlStandard,
+    cache: &mut FileCache,
+) -> Result<Vec<LibraryBuild>> {
+    // Collect non-defaultlib library names
+    let ext_lib_names: Vec<String> = vhdl_ls_config
+        .libraries
+        .keys()
+        .filter(|k| k.as_str() != "defaultlib")
+        .cloned()
+        .collect();
+
+    // Resolve $HOME and workspace-relative paths in each library's file list
+    let mut lib_files: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut lib_stds: HashMap<String, VhdlStandard> = HashMap::new();
+    let mut lib_flags: HashMap<String, Vec<String>> = HashMap::new();
+    for lib_name in &ext_lib_names {
+        if let Some(library) = vhdl_ls_config.libraries.get(lib_name) {
+            if let Some(lib_std) = library.vhdl_standard {
+                lib_stds.insert(lib_name.clone(), lib_std);
+            }
+            lib_flags.insert(lib_name.clone(), library.analysis_flags.clone());
+            let files = library
+                .files
+                .iter()
+                .map(|file_path| resolve_library_file(workspace_dir, file_path))
+                .collect::<Result<Vec<_>>>()?;
+            lib_files.insert(lib_name.clone(), files);
+        }
+    }
+
+    let sorted_libs = sort_libraries_by_dependencies(ext_lib_names, &lib_files);
+
+    // Dependencies with an explicit file list are compiled in that order
+    let listed: HashSet<String> = load_lock_file(workspace_dir)
+        .map(|lock| {
+            lock.dependencies
+                .into_iter()
+                .filter(|(_, locked)| !locked.files.is_empty())
+                .map(|(name, locked)| {
+                    nvc_library_name(locked.library_name(&name))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // Analyze libraries in dependency order
+    let mut builds = Vec::new();
+    for lib_name in sorted_libs {
+        if let Some(mut files) = lib_files.remove(&lib_name) {
+            if !listed.contains(&lib_name) {
+                // Sort files in dependency order (dependencies first)
+                sort_files_by_dependencies(processor, &mut files, cache)?;
+            }
+
+            builds.push(LibraryBuild {
+                // Convert library name to be NVC-compatible (no hyphens)
+                nvc_name: nvc_library_name(&lib_name),
+                vhdl_std: lib_stds.get(&lib_name).copied().unwrap_or(vhdl_std),
+                analysis_flags: lib_flags.remove(&lib_name).unwrap_or_default(),
+                files: files
+                    .iter()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .collect(),
+                name: lib_name,
+            });
+        }
+    }
+
+    Ok(builds)
+}
+
+/// Outcome of analyzing one library with [`analyze_workspace`].
+#[derive(Debug)]
+pub enum AnalysisStatus {
+    Passed,
+    Failed(VwError),
+    /// Not analyzed because an earlier library failed.
+    Skipped,
+}
+
+/// One library analyzed by [`analyze_workspace`].
+#[derive(Debug)]
+pub struct LibraryAnalysis {
+    /// Library name from `vhdl_ls.toml`, or `work` for the workspace's own
+    /// files.
+    pub library: String,
+    pub file_count: usize,
+    pub status: AnalysisStatus,
+}
+
+/// Analyze the dependency libraries and the workspace's own files with
+/// NVC, without elaborating or simulating anything.
+///
+/// Libraries and files are ordered as for [`run_testbench`]. With
+/// `testbench_name`, the work library gets the files that testbench
+/// needs; otherwise every `defaultlib` file is analyzed. Analysis stops
+/// at the first library that fails, and the libraries after it are
+/// reported as skipped, since they may depend on it.
+pub async fn analyze_workspace(
+    workspace_dir: &Utf8Path,
+    testbench_name: Option<&str>,
+    vhdl_std: Option<VhdlStandard>,
+    recurse: bool,
+    heap_size: Option<&str>,
+    output: NvcOutput,
+) -> Result<Vec<LibraryAnalysis>> {
+    let vhdl_std = vhdl_standard(workspace_dir, vhdl_std)?;
+    let heap_size = nvc_heap_size(workspace_dir, heap_size)?;
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut processor = RecordProcessor::new(vhdl_std);
+    let mut cache = FileCache::new();
+
+    let mut builds = ext_library_builds(
+        workspace_dir,
+        &vhdl_ls_config,
+        &mut processor,
+        vhdl_std,
+        &mut cache,
+    )?;
+
+    let work_files = match testbench_name {
+        Some(testbench_name) => {
+            let sources = collect_testbench_sources(
+                workspace_dir,
+                testbench_name,
+                &vhdl_ls_config,
+                recurse,
+                &mut processor,
+                &mut cache,
+            )?;
+            let mut files = sources.files;
+            files.push(sources.testbench_file);
+            files
+        }
+        None => {
+            let mut files = defaultlib_files(workspace_dir, &vhdl_ls_config)?;
+            sort_files_by_dependencies(&mut processor, &mut files, &mut cache)?;
+            files
+        }
+    };
+    builds.push(LibraryBuild {
+        name: "work".to_string(),
+        nvc_name: "work".to_string(),
+        vhdl_std,
+        analysis_flags: Vec::new(),
+        files: work_files
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+    });
+
+    let build_dir = workspace_build_dir(workspace_dir);
+    fs::create_dir_all(&build_dir)?;
+
+    let mut results = Vec::new();
+    let mut failed = false;
+    for build in builds {
+        let status = if failed {
+            AnalysisStatus::Skipped
+        } else {
+            match run_nvc_analysis(
+                build.vhdl_std,
+                build_dir.as_str(),
+                &build.nvc_name,
+                &heap_size,
+                &build.analysis_flags,
+                &build.files,
+                output,
+            )
+            .await
+            {
+                Ok(_) => AnalysisStatus::Passed,
+                Err(e) => {
+                    failed = true;
+                    AnalysisStatus::Failed(e)
+                }
+            }
+        };
+        results.push(LibraryAnalysis {
+            library: build.name,
+            file_count: build.files.len(),
+            status,
+        });
+    }
+
+    Ok(results)
+}
+
+/// Parse a `NAME=VALUE` top-level generic assignment.
+pub fn parse_generic(arg: &str) -> Result<(String, String)> {
+    match arg.split_once('=') {
+        Some((name, value)) if is_vhdl_identifier(name) && !value.is_empty() => {
+            Ok((name.to_string(), value.to_string()))
+        }
+        _ => Err(VwError::Config {
+            message: format!(
+                "Invalid generic '{arg}': expected NAME=VALUE with NAME a VHDL identifier"
+            ),
+        }),
+    }
+}
+
+/// How [`run_testbench`] builds and runs a testbench. The default runs it
+/// with the VHDL standard and heap size from `vw.toml`, NVC's default
+/// seed, and no waveform or coverage.
+#[derive(Clone, Debug, Default)]
+pub struct TestOptions {
+    /// Architecture of the testbench entity to elaborate, instead of the
+    /// one NVC analyzed last; it must be declared in one of the analyzed
+    /// files.
+    pub architecture: Option<String>,
+    /// Overrides the VHDL standard from `vw.toml` (see [`vhdl_standard`]).
+    pub vhdl_std: Option<VhdlStandard>,
+    /// Look for the testbench in subdirectories of the bench directory.
+    pub recurse: bool,
+    /// Flags appended to the `nvc -r` invocation after the testbench name
+    /// and before vw's waveform options, so a user flag cannot replace the
+    /// `--wave` setup.
+    pub runtime_flags: Vec<String>,
+    /// Passed to the simulation as `--seed` and recorded in the result
+    /// (see [`random_seed`]).
+    pub seed: Option<u64>,
+    /// Passed to the elaboration step as `-gNAME=VALUE`; each name may
+    /// only appear once.
+    pub generics: Vec<(String, String)>,
+    /// Overrides the NVC heap size from `vw.toml` (see [`nvc_heap_size`]).
+    pub heap_size: Option<String>,
+    /// Waveform to dump, if any.
+    pub wave: Option<WaveOptions>,
+    /// Build the testbench's Rust crate and load the library into the
+    /// simulation like `plugin`.
+    pub build_rust: bool,
+    /// A VHPI library, such as a cocotb or other GPI-based cosimulation
+    /// library, loaded into the simulation with `--load` and named in the
+    /// `GPI_USERS` environment variable. It is checked to be a readable
+    /// file before anything runs, and can't be combined with `build_rust`.
+    pub plugin: Option<Utf8PathBuf>,
+    /// Generate the files a mixed-signal testbench needs instead of
+    /// running it.
+    pub scaffold: bool,
+    /// Stop once the testbench has been elaborated, so binding and generic
+    /// errors surface as [`VwError::NvcElab`] without simulating.
+    pub elaborate_only: bool,
+    /// Elaborate with `--cover` and generate an HTML report after the
+    /// simulation.
+    pub coverage: Option<CoverageOptions>,
+    /// How NVC's output is shown (see [`NvcOutput`]).
+    pub output: NvcOutput,
+}
+
+/// Whether `testbench_name` is a mixed-signal testbench, described by a
+/// `mist.toml` in its own directory under the bench directory.
+pub fn is_mixed_signal_testbench(
+    workspace_dir: &Utf8Path,
+    testbench_name: &str,
+) -> Result<bool> {
+    Ok(bench_directory(workspace_dir)?
+        .join(testbench_name)
+        .join("mist.toml")
+        .exists())
+}
+
+/// Run a testbench using NVC simulator, as set up by `options`. The
+/// returned [`TestResult`] names the waveform and coverage files written.
+///
+/// Mixed-signal testbenches (see [`is_mixed_signal_testbench`]) are run
+/// by [`sim::run_analog_test`], which takes none of the NVC-specific
+/// options: asking for an architecture, runtime flags, a seed, generics,
+/// a waveform, coverage, a VHPI plugin or elaboration alone is an error.
+pub async fn run_testbench(
+    workspace_dir: &Utf8Path,
+    testbench_name: String,
+    options: &TestOptions,
+) -> Result<TestResult> {
+    let vhdl_std = vhdl_standard(workspace_dir, options.vhdl_std)?;
+    let heap_size = nvc_heap_size(workspace_dir, options.heap_size.as_deref())?;
+    let output = options.output;
+    check_generics(&options.generics)?;
+    if let Some(plugin) = &options.plugin {
+        if options.build_rust {
+            return Err(VwError::Config {
+                message: "Cannot load both a VHPI plugin and the \
+                          testbench's Rust library"
+                    .to_string(),
+            });
+        }
+        let readable = plugin.is_file() && fs::File::open(plugin).is_ok();
+        if !readable {
+            return Err(VwError::FileSystem {
+                message: "VHPI plugin is not a readable file".to_string(),
+                path: Some(plugin.into()),
+            });
+        }
+    }
+
     // Check for mixed-signal test (mist.toml in bench/<name>/)
-    let bench_test_dir = workspace_dir.join("bench").join(&testbench_name);
+    let bench_dir = bench_directory(workspace_dir)?;
+    let bench_test_dir = bench_dir.join(&testbench_name);
     let mist_toml = bench_test_dir.join("mist.toml");
     if mist_toml.exists() {
+        if options.elaborate_only {
+            return Err(VwError::Config {
+                message: format!(
+                    "'{testbench_name}' is a mixed-signal testbench, which \
+                     cannot be elaborated on its own"
+                ),
+            });
+        }
+        // The analog flow runs its own simulation, which takes none of these
+        let unsupported = [
+            (options.architecture.is_some(), "architecture selection"),
+            (options.coverage.is_some(), "coverage"),
+            (!options.runtime_flags.is_empty(), "runtime flags"),
+            (options.seed.is_some(), "a seed"),
+            (!options.generics.is_empty(), "generics"),
+            (options.wave.is_some(), "waveform output"),
+            (
+                options.plugin.is_some() || options.build_rust,
+                "a VHPI library",
+            ),
+        ];
+        if let Some((_, what)) = unsupported.iter().find(|(given, _)| *given) {
+            return Err(VwError::Config {
+                message: format!(
+                    "Mixed-signal testbench '{testbench_name}' does not \
+                     support {what}"
+                ),
+            });
+        }
         let ws_config = load_workspace_config(workspace_dir)?;
         let mist_content =
             fs::read_to_string(&mist_toml).map_err(|e| VwError::Config {
@@ -1358,12 +4159,9 @@ pub async fn run_testbench(
             toml::from_str(&mist_content).map_err(|e| VwError::Config {
                 message: format!("Failed to parse mist.toml: {e}"),
             })?;
-        if scaffold {
-            return sim::scaffold(
-                &bench_test_dir,
-                &mist_config,
-                &ws_config.tools,
-            );
+        if options.scaffold {
+            sim::scaffold(&bench_test_dir, &mist_config, &ws_config.tools)?;
+            return Ok(TestResult::passed(&testbench_name));
         }
         return sim::run_analog_test(
             workspace_dir,
@@ -1372,124 +4170,64 @@ pub async fn run_testbench(
             &mist_config,
             &ws_config.tools,
             vhdl_std,
+            &heap_size,
+            output,
         )
-        .await;
+        .await
+        .map(|()| TestResult::passed(&testbench_name));
     }
 
-    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
-    let mut processor = RecordProcessor::new(vhdl_std);
-    let mut cache = FileCache::new();
-
-    fs::create_dir_all(BUILD_DIR)?;
-
-    // First, analyze all non-defaultlib libraries
-    analyze_ext_libraries(
-        &vhdl_ls_config,
-        &mut processor,
+    let sources = analyze_testbench(
+        workspace_dir,
+        &testbench_name,
         vhdl_std,
-        &mut cache,
+        options.recurse,
+        &heap_size,
+        output,
     )
     .await?;
-
-    // Get defaultlib files for later use
-    let defaultlib_files = vhdl_ls_config
-        .libraries
-        .get("defaultlib")
-        .map(|lib| lib.files.clone())
-        .unwrap_or_default();
-
-    // Look for the testbench file in bench folder
-    let bench_dir = workspace_dir.join("bench");
-    if !bench_dir.exists() {
-        return Err(VwError::Testbench {
-            message: format!("No 'bench' directory found in {workspace_dir}"),
-        });
-    }
-
-    let testbench_file = find_testbench_file(
-        &testbench_name,
-        &bench_dir,
-        recurse,
-        cache.entities_cache_mut(),
-    )?;
-
-    // Filter defaultlib files to exclude OTHER testbenches but allow common bench code
-    let bench_dir_abs = workspace_dir.as_std_path().join("bench");
-
-    // Pre-compute entities for bench files to avoid mutable borrow in closure
-    let mut bench_file_entities: HashMap<PathBuf, Vec<String>> = HashMap::new();
-    for file_path in &defaultlib_files {
-        let absolute_path = if file_path.is_relative() {
-            workspace_dir.as_std_path().join(file_path)
-        } else {
-            file_path.clone()
-        };
-        if absolute_path.starts_with(&bench_dir_abs) {
-            if let Ok(entities) = cache.get_entities(&absolute_path) {
-                bench_file_entities.insert(absolute_path, entities.clone());
-            }
+    let elab_unit = match options.architecture.as_deref() {
+        Some(architecture) => {
+            check_architecture(
+                &sources,
+                &testbench_name,
+                architecture,
+                vhdl_std,
+            )?;
+            format!("{testbench_name}({architecture})")
         }
-    }
-
-    let filtered_defaultlib_files: Vec<PathBuf> = defaultlib_files
-        .into_iter()
-        .filter(|file_path| {
-            // Convert to absolute path for comparison
-            let absolute_path = if file_path.is_relative() {
-                workspace_dir.as_std_path().join(file_path)
-            } else {
-                file_path.clone()
-            };
-
-            // If it's not in the bench directory, include it
-            if !absolute_path.starts_with(&bench_dir_abs) {
-                return true;
-            }
-
-            // If it's in the bench directory, check if it's a different testbench
-            if let Some(entities) = bench_file_entities.get(&absolute_path) {
-                // Exclude files that contain testbench entities other than the one we're running
-                for entity in entities {
-                    if entity.to_lowercase().ends_with("_tb")
-                        && entity != &testbench_name
-                    {
-                        return false; // This is a different testbench, exclude it
-                    }
-                }
-            }
-
-            // Include this file (it's either the current testbench or common bench code)
-            true
-        })
-        .collect();
-
-    // Find only the defaultlib files that are actually referenced by this testbench
-    let mut referenced_files = find_referenced_files(
-        &testbench_file,
-        &filtered_defaultlib_files,
-        &mut cache,
-    )?;
-
-    // Sort files in dependency order (dependencies first)
-    sort_files_by_dependencies(
-        &mut processor,
-        &mut referenced_files,
-        &mut cache,
-    )?;
-
-    let mut files: Vec<String> = referenced_files
-        .iter()
-        .map(|s| s.to_string_lossy().to_string())
-        .collect();
+        None => testbench_name.clone(),
+    };
+    let testbench_file = sources.testbench_file;
+    let build_dir = workspace_build_dir(workspace_dir);
 
-    files.push(testbench_file.to_string_lossy().to_string());
+    let coverage_kinds: Vec<CoverageKind> = match &options.coverage {
+        Some(coverage) if coverage.kinds.is_empty() => {
+            CoverageKind::DEFAULT.to_vec()
+        }
+        Some(coverage) => coverage.kinds.clone(),
+        None => Vec::new(),
+    };
 
-    run_nvc_analysis(vhdl_std, BUILD_DIR, "work", &files, false).await?;
+    run_nvc_elab(
+        vhdl_std,
+        build_dir.as_str(),
+        "work",
+        &heap_size,
+        &elab_unit,
+        &options.generics,
+        &coverage_kinds,
+        &[],
+        output,
+    )
+    .await?;
 
-    run_nvc_elab(vhdl_std, BUILD_DIR, "work", &testbench_name, false).await?;
+    if options.elaborate_only {
+        return Ok(TestResult::passed(&testbench_name));
+    }
 
     // Build Rust library if requested
-    let rust_lib_path = if build_rust {
+    let rust_lib_path = if options.build_rust {
         Some(
             build_rust_library(&bench_dir, &testbench_file)
                 .await?
@@ -1497,1124 +4235,5594 @@ pub async fn run_testbench(
                 .to_string(),
         )
     } else {
-        None
+        options.plugin.as_ref().map(|plugin| plugin.to_string())
+    };
+
+    let wave_path = match &options.wave {
+        Some(wave) => {
+            if wave.dir.is_some() {
+                let dir = wave.dir(workspace_dir);
+                fs::create_dir_all(&dir).map_err(|e| VwError::FileSystem {
+                    message: format!(
+                        "Failed to create waveform directory: {e}"
+                    ),
+                    path: Some(dir.into()),
+                })?;
+            }
+            Some((wave.format, wave.path(workspace_dir, &testbench_name)))
+        }
+        None => None,
     };
 
     // Run NVC simulation
     run_nvc_sim(
         vhdl_std,
-        BUILD_DIR,
+        build_dir.as_str(),
         "work",
+        &heap_size,
         &testbench_name,
         rust_lib_path,
-        &runtime_flags.to_vec(),
-        false,
+        options.seed,
+        &options.runtime_flags,
+        wave_path
+            .as_ref()
+            .map(|(format, path)| (*format, path.as_str())),
+        output,
     )
     .await?;
 
-    Ok(())
-}
+    let mut result = TestResult::passed(&testbench_name);
+    result.waveform = wave_path.map(|(_, path)| path);
+    result.seed = options.seed;
+    let Some(coverage) = &options.coverage else {
+        return Ok(result);
+    };
+    let database = Utf8PathBuf::from(nvc_coverage_database(
+        build_dir.as_str(),
+        "work",
+        &testbench_name,
+    ));
+    let report_dir = coverage.report_dir(workspace_dir, &testbench_name);
+    fs::create_dir_all(&report_dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to create coverage report directory: {e}"),
+        path: Some(report_dir.clone().into()),
+    })?;
+    run_nvc_cover_report(database.as_str(), report_dir.as_str(), output)
+        .await?;
 
-pub fn find_referenced_files(
-    testbench_file: &Path,
-    available_files: &[PathBuf],
-    cache: &mut FileCache,
-) -> Result<Vec<PathBuf>> {
-    let mut referenced_files = Vec::new();
-    let mut processed_files = HashSet::new();
-    let mut files_to_process = vec![testbench_file.to_path_buf()];
+    result.coverage = Some(CoverageReport {
+        database,
+        report_dir,
+    });
+    Ok(result)
+}
 
-    while let Some(current_file) = files_to_process.pop() {
-        if processed_files.contains(&current_file) {
-            continue;
-        }
-        processed_files.insert(current_file.clone());
+/// Build and run a testbench like [`run_testbench`], with raw NVC flags
+/// that vw doesn't model passed through unchanged.
+///
+/// The testbench's files are found and ordered exactly as for
+/// [`run_testbench`]. `passthrough` picks the phases to run and the
+/// flags to add to the elaboration and run steps; the `user_flags` of a
+/// failure list the flags that came from it. No waveform or coverage is
+/// set up, and mixed-signal testbenches are not supported.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_testbench_passthrough(
+    workspace_dir: &Utf8Path,
+    testbench_name: &str,
+    vhdl_std: Option<VhdlStandard>,
+    recurse: bool,
+    generics: &[(String, String)],
+    heap_size: Option<&str>,
+    passthrough: &NvcPassthrough,
+    output: NvcOutput,
+) -> Result<TestResult> {
+    let vhdl_std = vhdl_standard(workspace_dir, vhdl_std)?;
+    let heap_size = nvc_heap_size(workspace_dir, heap_size)?;
+    check_generics(generics)?;
+
+    if is_mixed_signal_testbench(workspace_dir, testbench_name)? {
+        return Err(VwError::Config {
+            message: format!(
+                "'{testbench_name}' is a mixed-signal testbench, which vw run \
+                 does not support; use vw test"
+            ),
+        });
+    }
+    let build_dir = workspace_build_dir(workspace_dir);
 
-        // Don't include the testbench file itself in the referenced files
-        // (it will be added separately)
-        if current_file != testbench_file {
-            referenced_files.push(current_file.clone());
-        }
+    if passthrough.runs(NvcPhase::Analyze) {
+        analyze_testbench(
+            workspace_dir,
+            testbench_name,
+            vhdl_std,
+            recurse,
+            &heap_size,
+            output,
+        )
+        .await?;
+    }
 
-        let dependencies = cache.get_dependencies(&current_file)?.clone();
+    if passthrough.runs(NvcPhase::Elaborate) {
+        run_nvc_elab(
+            vhdl_std,
+            build_dir.as_str(),
+            "work",
+            &heap_size,
+            testbench_name,
+            generics,
+            &[],
+            &passthrough.elab_flags,
+            output,
+        )
+        .await?;
+    }
 
-        // Find corresponding files for each dependency
-        for dep in dependencies {
-            for available_file in available_files {
-                if file_provides_symbol(available_file, &dep, cache)? {
-                    if !processed_files.contains(available_file) {
-                        files_to_process.push(available_file.clone());
-                    }
-                    break;
-                }
-            }
-        }
+    if passthrough.runs(NvcPhase::Run) {
+        run_nvc_sim(
+            vhdl_std,
+            build_dir.as_str(),
+            "work",
+            &heap_size,
+            testbench_name,
+            None,
+            None,
+            &passthrough.run_flags,
+            None,
+            output,
+        )
+        .await?;
     }
 
-    Ok(referenced_files)
+    Ok(TestResult::passed(testbench_name))
 }
 
-pub fn sort_files_by_dependencies(
-    processor: &mut RecordProcessor,
-    files: &mut Vec<PathBuf>,
-    cache: &mut FileCache,
-) -> Result<()> {
-    // Build dependency graph
-    let mut dependencies: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
-    let mut all_symbols: HashMap<String, PathBuf> = HashMap::new();
-
-    // First pass: collect all symbols provided by each file
-    for file in files.iter() {
-        let symbols = analyze_file(processor, file)?;
-        for symbol in symbols {
-            match &symbol.kind {
-                SymbolKind::Package => {
-                    all_symbols.insert(symbol.name.clone(), file.clone());
-                    let entry = processor
-                        .file_info
-                        .entry(file.to_string_lossy().to_string())
-                        .or_default();
-                    entry.add_defined_pkg(&symbol.name);
-
-                    // Use cache to get package imports only
-                    let deps = cache.get_dependencies(file)?;
-                    for dep in deps {
-                        if let SymbolKind::Package = dep.kind {
-                            entry.add_imported_pkg(&dep.name);
-                        }
-                    }
-                }
-                SymbolKind::Entity => {
-                    all_symbols.insert(symbol.name, file.clone());
-                }
-                _ => {}
-            }
+/// Check that each generic is well formed and set at most once.
+fn check_generics(generics: &[(String, String)]) -> Result<()> {
+    let mut seen_generics: Vec<String> = Vec::new();
+    for (name, value) in generics {
+        parse_generic(&format!("{name}={value}"))?;
+        if is_listed(&seen_generics, name) {
+            return Err(VwError::Config {
+                message: format!("Generic '{name}' is set more than once"),
+            });
         }
+        seen_generics.push(name.clone());
     }
+    Ok(())
+}
 
-    // Second pass: find dependencies for each file
-    for file in files.iter() {
-        let deps = cache.get_dependencies(file)?.clone();
-        let mut file_deps = Vec::new();
+/// Analyze the dependency libraries and then the `work` library files
+/// `testbench_name` needs, in compile order. Returns the analyzed `work`
+/// sources.
+async fn analyze_testbench(
+    workspace_dir: &Utf8Path,
+    testbench_name: &str,
+    vhdl_std: VhdlStandard,
+    recurse: bool,
+    heap_size: &str,
+    output: NvcOutput,
+) -> Result<TestbenchSources> {
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut processor = RecordProcessor::new(vhdl_std);
+    let mut cache = FileCache::new();
 
-        for dep in deps {
-            let dep_name = match &dep.kind {
-                SymbolKind::Package | SymbolKind::Entity => &dep.name,
-                _ => continue,
-            };
-            if let Some(provider_file) = all_symbols.get(dep_name) {
-                if provider_file != file {
-                    file_deps.push(provider_file.clone());
-                }
-            }
-        }
+    let build_dir = workspace_build_dir(workspace_dir);
+    fs::create_dir_all(&build_dir)?;
 
-        dependencies.insert(file.clone(), file_deps);
-    }
+    // First, analyze all non-defaultlib libraries
+    analyze_ext_libraries(
+        workspace_dir,
+        &vhdl_ls_config,
+        &mut processor,
+        vhdl_std,
+        heap_size,
+        &mut cache,
+        output,
+    )
+    .await?;
 
-    // Topological sort using Kahn's algorithm
-    let sorted = topological_sort_files(files.clone(), dependencies)?;
-    *files = sorted;
+    // Select the work library files this testbench needs
+    let sources = collect_testbench_sources(
+        workspace_dir,
+        testbench_name,
+        &vhdl_ls_config,
+        recurse,
+        &mut processor,
+        &mut cache,
+    )?;
 
-    Ok(())
-}
+    let mut files: Vec<String> = sources
+        .files
+        .iter()
+        .map(|s| s.to_string_lossy().to_string())
+        .collect();
 
-pub fn load_existing_vhdl_ls_config(
-    workspace_dir: &Utf8Path,
-) -> Result<VhdlLsConfig> {
-    let config_path = workspace_dir.join("vhdl_ls.toml");
-    if config_path.exists() {
-        let config_content = fs::read_to_string(&config_path).map_err(|e| {
-            VwError::FileSystem {
-                message: format!("Failed to read existing vhdl_ls.toml: {e}"),
-            }
-        })?;
+    files.push(sources.testbench_file.to_string_lossy().to_string());
 
-        let config: VhdlLsConfig = toml::from_str(&config_content)?;
+    run_nvc_analysis(
+        vhdl_std,
+        build_dir.as_str(),
+        "work",
+        heap_size,
+        &[],
+        &files,
+        output,
+    )
+    .await?;
 
-        Ok(config)
-    } else {
-        Ok(VhdlLsConfig {
-            standard: None,
-            libraries: HashMap::new(),
-            lint: None,
-        })
-    }
+    Ok(sources)
 }
 
-// ============================================================================
-// Internal Helper Functions
-// ============================================================================
+/// Check that one of the analyzed `sources` declares `architecture` of
+/// `testbench_name`, listing the architectures that are declared if not.
+fn check_architecture(
+    sources: &TestbenchSources,
+    testbench_name: &str,
+    architecture: &str,
+    vhdl_std: VhdlStandard,
+) -> Result<()> {
+    let parser = VHDLParser::new(vhdl_std.into());
+    let mut finder = ArchitectureFinder {
+        entity: testbench_name.to_string(),
+        architectures: Vec::new(),
+    };
+    for path in sources.files.iter().chain([&sources.testbench_file]) {
+        let mut diagnostics = Vec::new();
+        if let Ok((_, design_file)) =
+            parser.parse_design_file(path, &mut diagnostics)
+        {
+            walk_design_file(&mut finder, &design_file);
+        }
+    }
 
-fn get_package_imports(content: &str) -> Result<Vec<String>> {
-    // Find 'use work.package_name' statements
-    let use_work_pattern = r"(?i)use\s+work\.(\w+)";
-    let use_work_re = regex::Regex::new(use_work_pattern)?;
-    let mut imports = Vec::new();
+    if is_listed(&finder.architectures, architecture) {
+        return Ok(());
+    }
+    let available = if finder.architectures.is_empty() {
+        "none found".to_string()
+    } else {
+        finder.architectures.join(", ")
+    };
+    Err(VwError::Testbench {
+        message: format!(
+            "Architecture '{architecture}' of testbench '{testbench_name}' \
+             not found in the analyzed files (available: {available})"
+        ),
+    })
+}
 
-    for captures in use_work_re.captures_iter(content) {
-        if let Some(package_name) = captures.get(1) {
-            imports.push(package_name.as_str().to_string());
+/// Collects the names of the architectures of one entity.
+struct ArchitectureFinder {
+    entity: String,
+    architectures: Vec<String>,
+}
+
+impl Visitor for ArchitectureFinder {
+    fn visit_architecture(&mut self, arch: &ArchitectureBody) -> VisitorResult {
+        let entity = arch.entity_name.item.item.name_utf8();
+        if entity.eq_ignore_ascii_case(&self.entity) {
+            self.architectures.push(arch.ident.tree.item.name_utf8());
         }
+        VisitorResult::Continue
     }
-    Ok(imports)
 }
 
-fn file_provides_symbol(
-    file_path: &Path,
-    needed: &VwSymbol,
-    cache: &mut FileCache,
-) -> Result<bool> {
-    let provided = cache.get_provided_symbols(file_path)?;
-    Ok(provided.iter().any(|s| match (&needed.kind, &s.kind) {
-        // Package dependency matches package declaration
-        (SymbolKind::Package, SymbolKind::Package) => {
-            needed.name.eq_ignore_ascii_case(&s.name)
-        }
-        // Entity dependency matches entity declaration
-        (SymbolKind::Entity, SymbolKind::Entity) => {
-            needed.name.eq_ignore_ascii_case(&s.name)
-        }
-        _ => false,
-    }))
+/// Why a file was pulled into a testbench's compile set.
+#[derive(Debug, Clone)]
+pub struct Inclusion {
+    /// File whose reference caused this one to be included.
+    pub referenced_by: PathBuf,
+    /// Package or entity the referencing file needed.
+    pub symbol: VwSymbol,
 }
 
-fn analyze_file(
-    processor: &mut RecordProcessor,
-    file: &Path,
-) -> Result<Vec<VwSymbol>> {
-    let parser = VHDLParser::new(processor.vhdl_std.into());
-    let mut diagnostics = Vec::new();
-    let (_, design_file) = parser.parse_design_file(file, &mut diagnostics)?;
+/// The `work` library sources needed to build a single testbench.
+#[derive(Debug, Clone)]
+pub struct TestbenchSources {
+    /// File declaring the testbench entity; always compiled last.
+    pub testbench_file: PathBuf,
+    /// Referenced defaultlib files in compile order, excluding the testbench.
+    pub files: Vec<PathBuf>,
+    /// The reference that first pulled in each entry of `files`.
+    pub provenance: HashMap<PathBuf, Inclusion>,
+}
 
-    let mut file_finder = VwSymbolFinder::new(&processor.target_attr);
-    walk_design_file(&mut file_finder, &design_file);
+/// How one file depends on another in a [`FileGraph`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileEdgeKind {
+    /// A `use` of a package the other file declares.
+    Use,
+    /// An instantiation of an entity the other file declares.
+    Instantiation,
+}
 
-    let file_str = file.to_string_lossy().to_string();
+/// A dependency of one file on another.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileEdge {
+    /// The file with the reference.
+    pub from: PathBuf,
+    /// The file declaring the referenced package or entity.
+    pub to: PathBuf,
+    pub kind: FileEdgeKind,
+    /// Name of the referenced package or entity.
+    pub symbol: String,
+    /// Whether this edge is part of a circular dependency.
+    pub in_cycle: bool,
+}
 
-    // Add symbols to the map
+/// The file dependency graph [`sort_files_by_dependencies`] orders files by.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileGraph {
+    pub files: Vec<PathBuf>,
+    pub edges: Vec<FileEdge>,
+}
 
-    for symbol in file_finder.get_symbols() {
-        match symbol.kind {
-            SymbolKind::Enum(_)
-            | SymbolKind::Record(_)
-            | SymbolKind::Constant(_) => {
-                let name = symbol.get_name().to_string();
-                processor.symbols.insert(name.clone(), symbol.clone());
-                processor.symbol_to_file.insert(name, file_str.clone());
-            }
-            _ => {}
+impl FileGraph {
+    /// Render the graph as Graphviz DOT, with edges pointing from a file to
+    /// the file it depends on. Edges in a cycle are drawn in red.
+    pub fn to_dot(&self) -> String {
+        fn quote(path: &Path) -> String {
+            let text = path.to_string_lossy();
+            let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("\"{escaped}\"")
         }
-    }
 
-    for tagged_type in file_finder.get_tagged_types() {
-        processor.tagged_names.insert(tagged_type.clone());
+        let mut dot = String::from("digraph vw {\n    rankdir=LR;\n");
+        dot.push_str("    node [shape=box];\n");
+        for file in &self.files {
+            dot.push_str(&format!("    {};\n", quote(file)));
+        }
+        for edge in &self.edges {
+            let verb = match edge.kind {
+                FileEdgeKind::Use => "use",
+                FileEdgeKind::Instantiation => "entity",
+            };
+            let color = if edge.in_cycle { ", color=red" } else { "" };
+            dot.push_str(&format!(
+                "    {} -> {} [label=\"{verb} {}\"{color}];\n",
+                quote(&edge.from),
+                quote(&edge.to),
+                edge.symbol
+            ));
+        }
+        dot.push_str("}\n");
+        dot
     }
 
-    Ok(file_finder.get_symbols().clone())
+    /// Render the graph as pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| VwError::CodeGen {
+            message: format!("Failed to render dependency graph as JSON: {e}"),
+        })
+    }
 }
 
-fn topological_sort_files(
-    files: Vec<PathBuf>,
-    dependencies: HashMap<PathBuf, Vec<PathBuf>>,
-) -> Result<Vec<PathBuf>> {
-    let mut dep_graph: DiGraph<PathBuf, ()> = DiGraph::default();
-    let mut index_map: HashMap<PathBuf, NodeIndex> = HashMap::new();
+/// Scan the workspace's VHDL sources for records tagged for Rust codegen.
+///
+/// Every file listed in `vhdl_ls.toml` is parsed: the `defaultlib` sources
+/// plus the files of each dependency library. The returned processor holds
+/// the discovered symbols and the names of types carrying `target_attr`,
+/// which must be a basic VHDL identifier.
+pub fn scan_tagged_records(
+    workspace_dir: &Utf8Path,
+    vhdl_std: VhdlStandard,
+    target_attr: &str,
+) -> Result<RecordProcessor> {
+    let mut processor = RecordProcessor::with_attribute(vhdl_std, target_attr)?;
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
 
-    // initialize the nodes
-    for file in &files {
-        let index = dep_graph.add_node(file.clone());
-        index_map.insert(file.clone(), index);
-    }
+    let mut lib_names: Vec<&String> = vhdl_ls_config.libraries.keys().collect();
+    lib_names.sort();
 
-    // now add edges from files to their dependencies
-    for (file, deps) in &dependencies {
-        let source_node = index_map.get(file).ok_or(VwError::Dependency {
-            message: format!(
-                "Index map somehow didn't contain file {:?}",
-                file
-            ),
-        })?;
-        // file depends on every dep in deps
-        for dep in deps {
-            let dst_node = index_map.get(dep).ok_or(VwError::Dependency {
-                message: format!(
-                    "Index map somehow didn't contain dep {:?}",
-                    dep
-                ),
-            })?;
-            dep_graph.add_edge(*source_node, *dst_node, ());
+    for lib_name in lib_names {
+        for file_path in &vhdl_ls_config.libraries[lib_name].files {
+            let path = resolve_library_file(workspace_dir, file_path)?;
+            analyze_file(&mut processor, &path)?;
         }
     }
 
-    // ok now topological sort
-    let ordered_files =
-        toposort(&dep_graph, None).map_err(|_| VwError::Dependency {
-            message: "Got circular dependency".to_string(),
-        })?;
-
-    let result: Vec<PathBuf> = ordered_files
-        .iter()
-        .map(|&idx| dep_graph[idx].clone())
-        .rev()
-        .collect();
-    Ok(result)
+    Ok(processor)
 }
 
-fn find_testbench_file_recurse(
+/// Compute the compile order of a testbench without running NVC.
+///
+/// This performs the same file selection and dependency sort that
+/// [`run_testbench`] uses for the `work` library.
+pub fn compile_order(
+    workspace_dir: &Utf8Path,
     testbench_name: &str,
-    bench_dir: &Utf8Path,
+    vhdl_std: VhdlStandard,
     recurse: bool,
-    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
-) -> Result<Vec<PathBuf>> {
-    let mut found_files = Vec::new();
+) -> Result<TestbenchSources> {
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut processor = RecordProcessor::new(vhdl_std);
+    let mut cache = FileCache::new();
 
-    for entry in fs::read_dir(bench_dir).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to read bench directory: {e}"),
-    })? {
-        let entry = entry.map_err(|e| VwError::FileSystem {
-            message: format!("Failed to read directory entry: {e}"),
-        })?;
-        let path = entry.path();
+    collect_testbench_sources(
+        workspace_dir,
+        testbench_name,
+        &vhdl_ls_config,
+        recurse,
+        &mut processor,
+        &mut cache,
+    )
+}
 
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                if extension == "vhd" || extension == "vhdl" {
-                    // Check if this file contains the entity we're looking for
-                    if file_contains_entity(
-                        &path,
-                        testbench_name,
-                        entities_cache,
-                    )? {
-                        found_files.push(path);
-                    }
-                }
-            }
-        } else if recurse {
-            let dir_path: Utf8PathBuf =
-                path.try_into().map_err(|e| VwError::FileSystem {
-                    message: format!("Failed to get dir path: {e}"),
-                })?;
-            let mut lower_testbenches = find_testbench_file_recurse(
+/// Build the file dependency graph of a testbench's `work` library
+/// sources, including the testbench itself, or of every `defaultlib` file
+/// when `testbench_name` is `None`.
+///
+/// Unlike [`compile_order`], a circular dependency is not an error: the
+/// edges forming it are marked [`FileEdge::in_cycle`]. Paths inside the
+/// workspace are reported relative to it.
+pub fn dependency_graph(
+    workspace_dir: &Utf8Path,
+    testbench_name: Option<&str>,
+    vhdl_std: VhdlStandard,
+    recurse: bool,
+) -> Result<FileGraph> {
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut processor = RecordProcessor::new(vhdl_std);
+    let mut cache = FileCache::new();
+
+    let files = match testbench_name {
+        Some(testbench_name) => {
+            let sources = select_testbench_sources(
+                workspace_dir,
                 testbench_name,
-                &dir_path,
+                &vhdl_ls_config,
                 recurse,
-                entities_cache,
+                &mut processor,
+                &mut cache,
             )?;
-            found_files.append(&mut lower_testbenches);
+            let mut files = sources.files;
+            files.push(sources.testbench_file);
+            files
         }
-    }
-    Ok(found_files)
-}
+        None => defaultlib_files(workspace_dir, &vhdl_ls_config)?,
+    };
 
-fn find_testbench_file(
-    testbench_name: &str,
-    bench_dir: &Utf8Path,
-    recurse: bool,
-    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
-) -> Result<PathBuf> {
-    let found_files = find_testbench_file_recurse(
-        testbench_name,
-        bench_dir,
-        recurse,
-        entities_cache,
-    )?;
+    let mut graph = build_file_graph(&mut processor, &files, &mut cache)?;
 
-    match found_files.len() {
-        0 => Err(VwError::Testbench {
-            message: format!("Testbench entity '{testbench_name}' not found in bench directory")
-        }),
-        1 => Ok(found_files.into_iter().next().unwrap()),
-        _ => Err(VwError::Testbench {
-            message: format!("Multiple files contain entity '{testbench_name}': {found_files:?}")
-        }),
+    let relative = |path: &mut PathBuf| {
+        if let Ok(rel) = path.strip_prefix(workspace_dir) {
+            *path = rel.to_path_buf();
+        }
+    };
+    graph.files.iter_mut().for_each(relative);
+    for edge in &mut graph.edges {
+        relative(&mut edge.from);
+        relative(&mut edge.to);
     }
+    Ok(graph)
 }
 
-fn file_contains_entity(
-    file_path: &Path,
-    entity_name: &str,
-    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
-) -> Result<bool> {
-    let entities = get_cached_entities(file_path, entities_cache)?;
-    Ok(entities.iter().any(|e| e.eq_ignore_ascii_case(entity_name)))
+/// A file declaring the symbol looked up by [`explain_symbol`].
+#[derive(Debug, Clone)]
+pub struct SymbolDeclaration {
+    /// `vhdl_ls.toml` library containing the file.
+    pub library: String,
+    pub file: PathBuf,
+    pub symbol: VwSymbol,
 }
 
-/// Get entities from cache, parsing and caching if not present.
-fn get_cached_entities<'a>(
-    path: &Path,
-    entities_cache: &'a mut HashMap<PathBuf, Vec<String>>,
-) -> Result<&'a Vec<String>> {
-    match entities_cache.entry(path.to_path_buf()) {
-        Entry::Occupied(e) => Ok(e.into_mut()),
-        Entry::Vacant(e) => {
-            let content =
-                fs::read_to_string(path).map_err(|e| VwError::FileSystem {
-                    message: format!("Failed to read file {path:?}: {e}"),
-                })?;
-            let entities = parse_entities(&content)?;
-            Ok(e.insert(entities))
-        }
-    }
+/// One reference in a [`ReferenceChain`]: the previous file in the chain
+/// uses or instantiates `symbol`, which `file` declares.
+#[derive(Debug, Clone)]
+pub struct ReferenceStep {
+    pub symbol: VwSymbol,
+    pub file: PathBuf,
 }
 
-fn make_path_portable(path: PathBuf) -> PathBuf {
-    if let Some(home_dir) = dirs::home_dir() {
-        if let Ok(relative_path) = path.strip_prefix(&home_dir) {
-            return PathBuf::from("$HOME").join(relative_path);
-        }
-    }
-    path
+/// The references leading from a testbench to a file.
+#[derive(Debug, Clone)]
+pub struct ReferenceChain {
+    pub testbench: String,
+    pub testbench_file: PathBuf,
+    pub steps: Vec<ReferenceStep>,
 }
 
-fn extract_repo_name(repo_url: &str) -> String {
-    repo_url
-        .trim_end_matches(".git")
-        .split('/')
-        .next_back()
-        .unwrap_or("dependency")
-        .to_string()
+/// Where a package or entity comes from and why testbenches compile it.
+#[derive(Debug, Clone)]
+pub struct SymbolExplanation {
+    /// Every file in `vhdl_ls.toml` declaring the symbol, in any library.
+    pub declarations: Vec<SymbolDeclaration>,
+    /// The shortest chain of `work` references from a testbench to the
+    /// symbol, or `None` if no testbench needs it.
+    pub reached_from: Option<ReferenceChain>,
 }
 
-fn save_workspace_config(
+/// Explain a package or entity: which files declare it, and the shortest
+/// chain of references by which a testbench pulls it in.
+///
+/// References are followed the same way [`run_testbench`] selects files,
+/// so the chain shows why `vw test` compiles the symbol's file. Paths
+/// inside the workspace are reported relative to it.
+pub fn explain_symbol(
     workspace_dir: &Utf8Path,
-    config: &WorkspaceConfig,
-) -> Result<()> {
-    let toml_content = toml::to_string_pretty(config)?;
-    let config_path = workspace_dir.join("vw.toml");
-
-    fs::write(&config_path, toml_content).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to write vw.toml file: {e}"),
-    })?;
+    symbol: &str,
+) -> Result<SymbolExplanation> {
+    let vhdl_std = vhdl_standard(workspace_dir, None)?;
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut cache = FileCache::new();
 
-    Ok(())
-}
+    let is_target = |candidate: &VwSymbol| {
+        matches!(candidate.kind, SymbolKind::Package | SymbolKind::Entity)
+            && candidate.name.eq_ignore_ascii_case(symbol)
+    };
+    let relative = |path: &Path| {
+        path.strip_prefix(workspace_dir)
+            .unwrap_or(path)
+            .to_path_buf()
+    };
 
-pub fn load_workspace_config(
-    workspace_dir: &Utf8Path,
-) -> Result<WorkspaceConfig> {
-    let config_path = workspace_dir.join("vw.toml");
-    if !config_path.exists() {
-        return Err(VwError::Config {
-            message: format!("No vw.toml file found in {workspace_dir}"),
-        });
+    let mut declarations = Vec::new();
+    let mut defaultlib_files = Vec::new();
+    for (library, lib) in &vhdl_ls_config.libraries {
+        let files = lib
+            .files
+            .iter()
+            .map(|file| resolve_library_file(workspace_dir, file))
+            .collect::<Result<Vec<_>>>()?;
+        cache.preload(&files);
+        for file in &files {
+            for provided in cache.get_provided_symbols(file)? {
+                if is_target(provided) {
+                    declarations.push(SymbolDeclaration {
+                        library: library.clone(),
+                        file: relative(file),
+                        symbol: provided.clone(),
+                    });
+                }
+            }
+        }
+        if library == "defaultlib" {
+            defaultlib_files = files;
+        }
     }
 
-    let config_content =
-        fs::read_to_string(&config_path).map_err(|e| VwError::FileSystem {
-            message: format!("Failed to read vw.toml: {e}"),
-        })?;
-
-    let config: WorkspaceConfig = toml::from_str(&config_content)?;
-
-    Ok(config)
-}
+    let bench_dir = bench_directory(workspace_dir)?;
+    let testbenches = if bench_dir.exists() {
+        let not_testbenches = load_workspace_config(workspace_dir)
+            .map(|config| config.workspace.not_testbenches)
+            .unwrap_or_default();
+        list_testbenches(
+            &bench_dir,
+            &HashSet::new(),
+            true,
+            &not_testbenches,
+            vhdl_std,
+            &vhdl_extensions(workspace_dir),
+        )?
+    } else {
+        Vec::new()
+    };
 
-fn load_lock_file(workspace_dir: &Utf8Path) -> Result<LockFile> {
-    let lock_path = workspace_dir.join("vw.lock");
-    if !lock_path.exists() {
-        return Err(VwError::Config {
-            message: format!("No vw.lock file found in {workspace_dir}"),
-        });
+    // Breadth-first search from every testbench at once, so the first
+    // chain found starts at the nearest one
+    let mut reached: HashMap<PathBuf, Option<(PathBuf, VwSymbol)>> =
+        HashMap::new();
+    let mut origin: HashMap<PathBuf, usize> = HashMap::new();
+    let mut queue = VecDeque::new();
+    for (index, tb) in testbenches.iter().enumerate() {
+        if reached.insert(tb.path.clone(), None).is_none() {
+            origin.insert(tb.path.clone(), index);
+            queue.push_back(tb.path.clone());
+        }
     }
 
-    let lock_content =
-        fs::read_to_string(&lock_path).map_err(|e| VwError::FileSystem {
-            message: format!("Failed to read vw.lock: {e}"),
-        })?;
+    let mut found = None;
+    'search: while let Some(file) = queue.pop_front() {
+        let dependencies = cache.get_dependencies(&file)?.clone();
+        for dep in dependencies {
+            let mut provider = None;
+            for available in &defaultlib_files {
+                if file_provides_symbol(available, &dep, &mut cache)? {
+                    provider = Some(available.clone());
+                    break;
+                }
+            }
+            let Some(provider) = provider else {
+                continue;
+            };
+            // The file declaring the symbol may already have been reached
+            // through another of its declarations, so check for the target
+            // first
+            if is_target(&dep) {
+                found = Some((provider, file, dep));
+                break 'search;
+            }
+            if reached.contains_key(&provider) {
+                continue;
+            }
+            reached.insert(provider.clone(), Some((file.clone(), dep.clone())));
+            origin.insert(provider.clone(), origin[&file]);
+            queue.push_back(provider);
+        }
+    }
 
-    let lock_file: LockFile = toml::from_str(&lock_content)?;
+    let reached_from = found.map(|(target, referenced_by, dep)| {
+        let testbench = &testbenches[origin[&referenced_by]];
+        let mut steps = vec![ReferenceStep {
+            symbol: dep,
+            file: relative(&target),
+        }];
+        let mut current = referenced_by;
+        while let Some(Some((referenced_by, dep))) = reached.get(&current) {
+            steps.push(ReferenceStep {
+                symbol: dep.clone(),
+                file: relative(&current),
+            });
+            current = referenced_by.clone();
+        }
+        steps.reverse();
+        ReferenceChain {
+            testbench: testbench.name.clone(),
+            testbench_file: relative(&testbench.path),
+            steps,
+        }
+    });
 
-    Ok(lock_file)
+    Ok(SymbolExplanation {
+        declarations,
+        reached_from,
+    })
 }
 
-/// Return the per-user dependency cache directory used by vw.
+/// A package or entity declared by more than one file of a library.
 ///
-/// Resolved from `$VW_DEPS_DIR` if set, otherwise `$HOME/.vw/deps`.
-/// The directory is created if it does not exist. Callers holding
-/// relative paths from [`resolve_deps`] or `vw.lock` should join against
-/// the value returned here to obtain absolute paths.
-pub fn deps_directory() -> Result<PathBuf> {
-    let deps_dir = if let Some(override_dir) =
-        std::env::var_os("VW_DEPS_DIR").filter(|v| !v.is_empty())
-    {
-        PathBuf::from(override_dir)
-    } else {
-        let home_dir = dirs::home_dir().ok_or_else(|| VwError::FileSystem {
-            message: "Could not determine home directory".to_string(),
-        })?;
-        home_dir.join(".vw").join("deps")
-    };
+/// When ordering files, a reference to the symbol resolves to just one of
+/// them, so which file a design actually uses depends on file order.
+#[derive(Debug, Clone)]
+pub struct DuplicateSymbol {
+    /// `vhdl_ls.toml` library containing the files.
+    pub library: String,
+    pub symbol: VwSymbol,
+    /// Every file declaring the symbol, in the library's file order.
+    pub files: Vec<PathBuf>,
+}
 
-    fs::create_dir_all(&deps_dir).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to create dependencies directory: {e}"),
-    })?;
+/// Find packages and entities declared in more than one file of the same
+/// `vhdl_ls.toml` library. Names are compared case-insensitively, as in
+/// VHDL. Paths inside the workspace are reported relative to it.
+pub fn find_duplicate_symbols(
+    workspace_dir: &Utf8Path,
+) -> Result<Vec<DuplicateSymbol>> {
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut cache = FileCache::new();
+    let mut duplicates = Vec::new();
 
-    Ok(deps_dir)
-}
+    for (library, lib) in &vhdl_ls_config.libraries {
+        let files = lib
+            .files
+            .iter()
+            .map(|file| resolve_library_file(workspace_dir, file))
+            .collect::<Result<Vec<_>>>()?;
+        cache.preload(&files);
+
+        let mut declared: Vec<(VwSymbol, Vec<PathBuf>)> = Vec::new();
+        let mut index: HashMap<(bool, String), usize> = HashMap::new();
+        for file in &files {
+            for symbol in cache.get_provided_symbols(file)? {
+                let is_package = match symbol.kind {
+                    SymbolKind::Package => true,
+                    SymbolKind::Entity => false,
+                    _ => continue,
+                };
+                let key = (is_package, symbol.name.to_lowercase());
+                match index.entry(key) {
+                    Entry::Occupied(e) => {
+                        let files = &mut declared[*e.get()].1;
+                        if !files.contains(file) {
+                            files.push(file.clone());
+                        }
+                    }
+                    Entry::Vacant(e) => {
+                        e.insert(declared.len());
+                        declared.push((symbol.clone(), vec![file.clone()]));
+                    }
+                }
+            }
+        }
 
-/// Resolve a path stored in `vw.lock` against the local dependency cache.
-///
-/// Lock-file dep paths are stored as `<name>-<sha>` (relative to the
-/// per-user `$HOME/.vw/deps` directory) so the file is identical across
-/// machines. Absolute paths are returned unchanged to remain compatible
-/// with lock files written by older versions of vw.
-fn resolve_dep_path(path: &Path) -> Result<PathBuf> {
-    if path.is_absolute() {
-        return Ok(path.to_path_buf());
+        for (symbol, files) in declared {
+            if files.len() > 1 {
+                duplicates.push(DuplicateSymbol {
+                    library: library.clone(),
+                    symbol,
+                    files: files
+                        .iter()
+                        .map(|f| {
+                            f.strip_prefix(workspace_dir)
+                                .unwrap_or(f)
+                                .to_path_buf()
+                        })
+                        .collect(),
+                });
+            }
+        }
     }
-    let deps_dir = deps_directory()?;
-    Ok(deps_dir.join(path))
+
+    Ok(duplicates)
 }
 
-async fn resolve_dependency_commit(
-    repo_url: &str,
-    branch: &Option<String>,
-    commit: &Option<String>,
-    credentials: Option<(&str, &str)>, // (username, password)
-) -> Result<String> {
-    match (branch, commit) {
-        (Some(_), Some(_)) => Err(VwError::Config {
-            message: "Cannot specify both branch and commit for dependency"
-                .to_string(),
-        }),
-        (None, None) => Err(VwError::Config {
-            message: "Must specify either branch or commit for dependency"
-                .to_string(),
-        }),
-        (None, Some(commit)) => Ok(commit.clone()),
-        (Some(branch), None) => {
-            get_branch_head_commit(repo_url, branch, credentials).await
-        }
-    }
+/// Whether a dependency's library is referenced by the workspace, as found
+/// by [`dependency_usage`].
+#[derive(Debug, Clone)]
+pub struct DependencyUsage {
+    pub name: String,
+    pub sim_only: bool,
+    /// The first file found referencing the library, or `None` if nothing
+    /// reachable from the workspace sources does. Paths inside the
+    /// workspace are relative to it.
+    pub referenced_by: Option<PathBuf>,
 }
 
-async fn get_branch_head_commit(
-    repo_url: &str,
-    branch: &str,
-    credentials: Option<(&str, &str)>, // (username, password)
-) -> Result<String> {
-    // Normalize repository URL to ensure it ends with .git for GitHub
-    let normalized_repo_url =
-        if repo_url.contains("github.com") && !repo_url.ends_with(".git") {
-            format!("{repo_url}.git")
-        } else {
-            repo_url.to_string()
-        };
+/// Find which dependency libraries the workspace actually references.
+///
+/// References are followed from every `defaultlib` file and every file in
+/// the bench directory: a library-qualified `use`, `entity`, `context` or
+/// `configuration` naming a dependency library marks it as used, and the
+/// files in it declaring the referenced package or entity are followed in
+/// turn, along with their own `work` references. A dependency only reached
+/// through another unused dependency is therefore unused too.
+///
+/// This uses the same textual scan as [`compile_order`], so an unused
+/// dependency is a candidate for removal rather than a certainty.
+/// Dependencies sharing a library are used or unused together, and those
+/// placed in `defaultlib` are left out, since their files count as
+/// workspace sources here. Dependencies are returned sorted by name.
+pub fn dependency_usage(
+    workspace_dir: &Utf8Path,
+) -> Result<Vec<DependencyUsage>> {
+    let config = load_workspace_config(workspace_dir)?;
+    let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    let mut cache = FileCache::new();
 
-    let branch = branch.to_string();
-    let credentials = credentials.map(|(u, p)| (u.to_string(), p.to_string()));
+    let relative = |path: &Path| {
+        path.strip_prefix(workspace_dir)
+            .unwrap_or(path)
+            .to_path_buf()
+    };
 
-    tokio::time::timeout(
-        std::time::Duration::from_secs(30),
-        tokio::task::spawn_blocking(move || {
-            // Create a temporary directory for the operation
-            let temp_dir =
-                tempfile::tempdir().map_err(|e| VwError::FileSystem {
-                    message: format!(
-                        "Failed to create temporary directory: {e}"
-                    ),
-                })?;
+    // Dependency libraries by the lowercase name VHDL code refers to them by
+    let mut libraries: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let mut file_library: HashMap<PathBuf, String> = HashMap::new();
+    for (name, dep) in &config.dependencies {
+        let library = dep.library_name(name);
+        let key = nvc_library_name(library).to_lowercase();
+        if library == "defaultlib" || libraries.contains_key(&key) {
+            continue;
+        }
+        let files = match vhdl_ls_config
+            .libraries
+            .get(&nvc_library_name(library))
+            .or_else(|| vhdl_ls_config.libraries.get(library))
+        {
+            Some(lib) => lib
+                .files
+                .iter()
+                .map(|file| resolve_library_file(workspace_dir, file))
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        cache.preload(&files);
+        for file in &files {
+            file_library.insert(file.clone(), key.clone());
+        }
+        libraries.insert(key, files);
+    }
 
-            // Create an empty repository to work with remotes
-            let repo =
-                git2::Repository::init_bare(temp_dir.path()).map_err(|e| {
-                    VwError::Git {
-                        message: format!(
-                            "Failed to initialize temporary repository: {e}"
-                        ),
-                    }
-                })?;
+    let mut roots = defaultlib_files(workspace_dir, &vhdl_ls_config)?;
+    let bench_dir = bench_directory(workspace_dir)?;
+    if bench_dir.exists() {
+        for file in find_vhdl_files(
+            bench_dir.as_std_path(),
+            true,
+            follow_symlinks(workspace_dir),
+            &[],
+            &vhdl_extensions(workspace_dir),
+        )? {
+            if !roots.contains(&file) {
+                roots.push(file);
+            }
+        }
+    }
 
-            // Create a remote
-            let mut remote = repo
-                .remote_anonymous(&normalized_repo_url)
-                .map_err(|e| VwError::Git {
-                    message: format!("Failed to create remote: {e}"),
-                })?;
+    let mut referenced_by: HashMap<String, PathBuf> = HashMap::new();
+    let mut visited: HashSet<PathBuf> = roots.iter().cloned().collect();
+    let mut queue: VecDeque<PathBuf> = roots.into();
+    while let Some(file) = queue.pop_front() {
+        let content =
+            fs::read_to_string(&file).map_err(|e| VwError::FileSystem {
+                message: format!("Failed to read file: {e}"),
+                path: Some(file.clone()),
+            })?;
+        let current = file_library.get(&file).cloned();
 
-            // Connect and list references
-            // Always set a credentials callback so git2 doesn't fail with "no callback set".
-            // The callback will try explicit credentials first, then fall back to git's
-            // credential helper system (which includes .netrc support).
-            let mut callbacks = git2::RemoteCallbacks::new();
-            let attempt_count = RefCell::new(0);
-
-            callbacks.credentials(
-                move |url, username_from_url, allowed_types| {
-                    let mut attempts = attempt_count.borrow_mut();
-                    *attempts += 1;
-
-                    // Limit attempts to prevent infinite loops
-                    if *attempts > 1 {
-                        return git2::Cred::default();
-                    }
+        let mut needed = Vec::new();
+        for (library, name) in parse_library_references(&content)? {
+            let library = match (library.as_str(), &current) {
+                ("work", Some(current)) => current.clone(),
+                _ => library,
+            };
+            if !libraries.contains_key(&library) {
+                continue;
+            }
+            if current.as_ref() != Some(&library) {
+                referenced_by
+                    .entry(library.clone())
+                    .or_insert_with(|| relative(&file));
+            }
+            needed.push((library, name));
+        }
+        // Unqualified `work` references (components, `use work.pkg`) of
+        // dependency files stay within their own library
+        if let Some(current) = &current {
+            for dep in cache.get_dependencies(&file)?.clone() {
+                needed.push((current.clone(), dep.name.to_lowercase()));
+            }
+        }
 
-                    // First, try explicit credentials from netrc if available
-                    if allowed_types
-                        .contains(git2::CredentialType::USER_PASS_PLAINTEXT)
-                    {
-                        if let Some((ref username, ref password)) = credentials
-                        {
-                            // Use both username and password from netrc
-                            return git2::Cred::userpass_plaintext(
-                                username, password,
-                            );
-                        }
-                    }
+        for (library, name) in needed {
+            for candidate in &libraries[&library] {
+                if visited.contains(candidate) {
+                    continue;
+                }
+                let provides =
+                    cache.get_provided_symbols(candidate)?.iter().any(|s| {
+                        matches!(
+                            s.kind,
+                            SymbolKind::Package | SymbolKind::Entity
+                        ) && s.name.eq_ignore_ascii_case(&name)
+                    });
+                if provides {
+                    visited.insert(candidate.clone());
+                    queue.push_back(candidate.clone());
+                }
+            }
+        }
+    }
 
-                    // Try SSH key if available
-                    if allowed_types.contains(git2::CredentialType::SSH_KEY) {
-                        if let Some(username) = username_from_url {
-                            if let Ok(cred) =
-                                git2::Cred::ssh_key_from_agent(username)
-                            {
-                                return Ok(cred);
-                            }
-                        }
-                    }
+    let mut usage: Vec<DependencyUsage> = config
+        .dependencies
+        .iter()
+        .filter(|(name, dep)| dep.library_name(name) != "defaultlib")
+        .map(|(name, dep)| DependencyUsage {
+            name: name.clone(),
+            sim_only: dep.sim_only,
+            referenced_by: referenced_by
+                .get(&nvc_library_name(dep.library_name(name)).to_lowercase())
+                .cloned(),
+        })
+        .collect();
+    usage.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(usage)
+}
 
-                    // Fall back to git's credential helper system (includes .netrc)
-                    if let Ok(config) = git2::Config::open_default() {
-                        if let Ok(cred) = git2::Cred::credential_helper(
-                            &config,
-                            url,
-                            username_from_url,
-                        ) {
-                            return Ok(cred);
-                        }
-                    }
+/// A dependency in the graph built by [`dependency_tree`].
+#[derive(Debug, Clone)]
+pub struct DependencyNode {
+    pub name: String,
+    /// The commit resolved in `vw.lock`, if the dependency is locked.
+    pub commit: Option<String>,
+    /// Other dependencies whose libraries this one's files reference,
+    /// sorted by name.
+    pub references: Vec<String>,
+}
 
-                    git2::Cred::default()
-                },
-            );
+/// The references between a workspace's dependencies, as found by
+/// [`dependency_tree`].
+#[derive(Debug, Clone)]
+pub struct DependencyTree {
+    /// The workspace's name, at the root of the tree.
+    pub workspace: String,
+    /// Every dependency in `vw.toml`, sorted by name.
+    pub dependencies: Vec<DependencyNode>,
+    /// Why references could not be found, in which case no dependency
+    /// has any.
+    pub unknown_references: Option<String>,
+}
 
-            remote
-                .connect_auth(git2::Direction::Fetch, Some(callbacks), None)
-                .map_err(|e| VwError::Git {
-                    message: format!("Failed to connect to remote: {e}"),
-                })?;
+/// Find which other dependencies each dependency of the workspace
+/// references.
+///
+/// The files locked for a dependency in `vw.lock` are scanned for
+/// library-qualified `use`, `entity`, `context` and `configuration`
+/// references, the same textual scan [`dependency_usage`] uses. Naming
+/// another dependency's library references that dependency, or each of
+/// them when several share the library.
+///
+/// This needs the lock file and the files of every locked dependency in
+/// the cache or vendor directory. Without them the dependencies are still
+/// returned, with the reason in [`DependencyTree::unknown_references`].
+pub fn dependency_tree(workspace_dir: &Utf8Path) -> Result<DependencyTree> {
+    let config = load_workspace_config(workspace_dir)?;
+    let lock_file = load_lock_file(workspace_dir).ok();
+    let extensions = vhdl_extensions(workspace_dir);
 
-            let refs = remote.list().map_err(|e| VwError::Git {
-                message: format!("Failed to list remote references: {e}"),
-            })?;
+    let mut names: Vec<&String> = config.dependencies.keys().collect();
+    names.sort();
+
+    // Dependencies by the lowercase name VHDL code refers to their library by
+    let library_key = |name: &str| {
+        nvc_library_name(config.dependencies[name].library_name(name))
+            .to_lowercase()
+    };
+    let mut libraries: HashMap<String, Vec<&String>> = HashMap::new();
+    for name in &names {
+        libraries.entry(library_key(name)).or_default().push(name);
+    }
+
+    let mut references: HashMap<&String, Vec<String>> = HashMap::new();
+    let mut unknown_references = None;
+    for name in &names {
+        let Some(lock_file) = &lock_file else {
+            unknown_references =
+                Some("no vw.lock found; run vw update".to_string());
+            break;
+        };
+        let Some(locked) = lock_file.dependencies.get(*name) else {
+            unknown_references =
+                Some(format!("'{name}' is not in vw.lock; run vw update"));
+            break;
+        };
+        let dir = match &locked.vendored {
+            Some(vendor_dir) => workspace_dir.as_std_path().join(vendor_dir),
+            None => cached_tree(name, locked)?,
+        };
+        if !dir.exists() {
+            unknown_references = Some(format!(
+                "files of '{name}' are missing from {}; run vw update",
+                dir.display()
+            ));
+            break;
+        }
 
-            // Look for the specific branch reference
-            let ref_name = format!("refs/heads/{branch}");
-            for remote_head in refs {
-                if remote_head.name() == ref_name {
-                    return Ok(remote_head.oid().to_string());
+        let own = library_key(name);
+        let mut referenced = Vec::new();
+        let files = match &locked.vendored {
+            Some(vendor_dir) => vendored_library_files(
+                workspace_dir,
+                vendor_dir,
+                locked,
+                &extensions,
+            )?
+            .into_iter()
+            .map(|file| workspace_dir.as_std_path().join(file))
+            .collect(),
+            None => tree_files(workspace_dir, name, &dir, locked, &extensions)?
+                .into_iter()
+                .map(|(file, _)| file)
+                .collect::<Vec<_>>(),
+        };
+        for file in files {
+            let content =
+                fs::read_to_string(&file).map_err(|e| VwError::FileSystem {
+                    message: format!("Failed to read file: {e}"),
+                    path: Some(file.clone()),
+                })?;
+            for (library, _) in parse_library_references(&content)? {
+                if library == "work" || library == own {
+                    continue;
+                }
+                if let Some(deps) = libraries.get(&library) {
+                    referenced.extend(deps.iter().map(|dep| dep.to_string()));
                 }
             }
+        }
+        referenced.sort();
+        referenced.dedup();
+        references.insert(name, referenced);
+    }
+    if unknown_references.is_some() {
+        references.clear();
+    }
 
-            Err(VwError::Git {
-                message: format!(
-                    "Branch '{branch}' not found in remote repository"
-                ),
-            })
-        }),
-    )
-    .await
-    .map_err(|_| VwError::Git {
-        message: "Git ls-remote timed out after 30 seconds".to_string(),
-    })?
-    .map_err(|e| VwError::Git {
-        message: format!("Failed to execute git ls-remote task: {e}"),
-    })?
+    let lock_dependencies = lock_file.as_ref().map(|lock| &lock.dependencies);
+    let dependencies = names
+        .iter()
+        .map(|name| DependencyNode {
+            name: name.to_string(),
+            commit: lock_dependencies
+                .and_then(|deps| deps.get(*name))
+                .map(|locked| locked.commit.clone()),
+            references: references.remove(name).unwrap_or_default(),
+        })
+        .collect();
+    Ok(DependencyTree {
+        workspace: config.workspace.name.clone(),
+        dependencies,
+        unknown_references,
+    })
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn download_dependency(
-    repo_url: &str,
-    commit: &str,
-    src_paths: &[String],
-    dest_path: &Path,
-    recursive: bool,
-    exclude: &[String],
-    submodules: bool,
-    credentials: Option<(&str, &str)>, // (username, password)
-) -> Result<()> {
-    let temp_dir = tempfile::tempdir().map_err(|e| VwError::FileSystem {
-        message: format!("Failed to create temporary directory: {e}"),
-    })?;
+/// Select and order the defaultlib files a testbench needs.
+fn collect_testbench_sources(
+    workspace_dir: &Utf8Path,
+    testbench_name: &str,
+    vhdl_ls_config: &VhdlLsConfig,
+    recurse: bool,
+    processor: &mut RecordProcessor,
+    cache: &mut FileCache,
+) -> Result<TestbenchSources> {
+    let mut sources = select_testbench_sources(
+        workspace_dir,
+        testbench_name,
+        vhdl_ls_config,
+        recurse,
+        processor,
+        cache,
+    )?;
+    // Sort files in dependency order (dependencies first)
+    sort_files_by_dependencies(processor, &mut sources.files, cache)?;
+    Ok(sources)
+}
 
-    // Normalize repository URL to ensure it ends with .git for GitHub
-    let normalized_repo_url =
-        if repo_url.contains("github.com") && !repo_url.ends_with(".git") {
-            format!("{repo_url}.git")
-        } else {
-            repo_url.to_string()
-        };
+/// Select the defaultlib files a testbench needs, in no particular order.
+fn select_testbench_sources(
+    workspace_dir: &Utf8Path,
+    testbench_name: &str,
+    vhdl_ls_config: &VhdlLsConfig,
+    recurse: bool,
+    processor: &mut RecordProcessor,
+    cache: &mut FileCache,
+) -> Result<TestbenchSources> {
+    let not_testbenches = load_workspace_config(workspace_dir)
+        .map(|config| config.workspace.not_testbenches)
+        .unwrap_or_default();
 
-    let commit = commit.to_string();
-    let temp_path = temp_dir.path().to_path_buf();
-    let src_paths = src_paths.to_vec();
-    let credentials = credentials.map(|(u, p)| (u.to_string(), p.to_string()));
+    // Get defaultlib files for later use
+    let defaultlib_files = defaultlib_files(workspace_dir, vhdl_ls_config)?;
 
-    tokio::time::timeout(
-        std::time::Duration::from_secs(120),
-        tokio::task::spawn_blocking(move || {
-            // Set up clone options with authentication
-            let mut builder = git2::build::RepoBuilder::new();
-
-            // Always set a credentials callback so git2 doesn't fail with "no callback set".
-            // The callback will try explicit credentials first, then fall back to git's
-            // credential helper system (which includes .netrc support).
-            let mut callbacks = git2::RemoteCallbacks::new();
-            let attempt_count = RefCell::new(0);
-
-            callbacks.credentials(
-                move |url, username_from_url, allowed_types| {
-                    let mut attempts = attempt_count.borrow_mut();
-                    *attempts += 1;
-
-                    // Limit attempts to prevent infinite loops
-                    if *attempts > 1 {
-                        return git2::Cred::default();
-                    }
+    // Look for the testbench file in bench folder
+    let bench_dir = bench_directory(workspace_dir)?;
+    if !bench_dir.exists() {
+        return Err(VwError::Testbench {
+            message: format!("No bench directory found at {bench_dir}"),
+        });
+    }
+
+    let testbench_file = find_testbench_file(
+        testbench_name,
+        &bench_dir,
+        recurse,
+        &not_testbenches,
+        processor.vhdl_std,
+        &vhdl_extensions(workspace_dir),
+        cache.entities_cache_mut(),
+    )?;
+
+    // Filter defaultlib files to exclude OTHER testbenches but allow common bench code
+    let bench_dir_abs = bench_dir.as_std_path().to_path_buf();
+
+    // Pre-compute testbench entities for bench files to avoid mutable borrow
+    // in closure
+    let mut bench_file_entities: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    for file_path in &defaultlib_files {
+        if file_path.starts_with(&bench_dir_abs) {
+            if let Ok(entities) = cache.get_entities(file_path) {
+                let testbenches =
+                    testbench_entities(file_path, entities, processor.vhdl_std);
+                bench_file_entities.insert(file_path.clone(), testbenches);
+            }
+        }
+    }
+
+    let filtered_defaultlib_files: Vec<PathBuf> = defaultlib_files
+        .into_iter()
+        .filter(|file_path| {
+            // If it's not in the bench directory, include it
+            if !file_path.starts_with(&bench_dir_abs) {
+                return true;
+            }
 
-                    // First, try explicit credentials from netrc if available
-                    if allowed_types
-                        .contains(git2::CredentialType::USER_PASS_PLAINTEXT)
+            // If it's in the bench directory, check if it's a different testbench
+            if let Some(entities) = bench_file_entities.get(file_path) {
+                // Exclude files that contain testbench entities other than the one we're running
+                for entity in entities {
+                    if entity != testbench_name
+                        && !is_listed(&not_testbenches, entity)
                     {
-                        if let Some((ref username, ref password)) = credentials
-                        {
-                            // Use both username and password from netrc
-                            return git2::Cred::userpass_plaintext(
-                                username, password,
-                            );
-                        }
+                        return false; // This is a different testbench, exclude it
                     }
+                }
+            }
 
-                    // Try SSH key if available
-                    if allowed_types.contains(git2::CredentialType::SSH_KEY) {
-                        if let Some(username) = username_from_url {
-                            if let Ok(cred) =
-                                git2::Cred::ssh_key_from_agent(username)
-                            {
-                                return Ok(cred);
-                            }
-                        }
-                    }
+            // Include this file (it's either the current testbench or common bench code)
+            true
+        })
+        .collect();
 
-                    // Fall back to git's credential helper system (includes .netrc)
-                    if let Ok(config) = git2::Config::open_default() {
-                        if let Ok(cred) = git2::Cred::credential_helper(
-                            &config,
-                            url,
-                            username_from_url,
-                        ) {
-                            return Ok(cred);
-                        }
-                    }
+    // Find only the defaultlib files that are actually referenced by this testbench
+    let (referenced_files, provenance) = find_referenced_files_with_provenance(
+        &testbench_file,
+        &filtered_defaultlib_files,
+        cache,
+    )?;
 
-                    git2::Cred::default()
-                },
-            );
+    Ok(TestbenchSources {
+        testbench_file,
+        files: referenced_files,
+        provenance,
+    })
+}
 
-            let mut fetch_options = git2::FetchOptions::new();
-            fetch_options.depth(1); // shallow clone — only need one commit
-            fetch_options.remote_callbacks(callbacks);
-            builder.fetch_options(fetch_options);
+pub fn find_referenced_files(
+    testbench_file: &Path,
+    available_files: &[PathBuf],
+    cache: &mut FileCache,
+) -> Result<Vec<PathBuf>> {
+    find_referenced_files_with_provenance(
+        testbench_file,
+        available_files,
+        cache,
+    )
+    .map(|(files, _)| files)
+}
 
-            // Clone the repository
-            let repo = builder
-                .clone(&normalized_repo_url, &temp_path)
-                .map_err(|e| VwError::Git {
-                    message: format!("Failed to clone repository: {e}"),
-                })?;
+/// Like [`find_referenced_files`], but also report which reference first
+/// pulled in each file.
+pub fn find_referenced_files_with_provenance(
+    testbench_file: &Path,
+    available_files: &[PathBuf],
+    cache: &mut FileCache,
+) -> Result<(Vec<PathBuf>, HashMap<PathBuf, Inclusion>)> {
+    let mut referenced_files = Vec::new();
+    let mut provenance = HashMap::new();
+    let mut processed_files = HashSet::new();
+    let mut files_to_process = vec![testbench_file.to_path_buf()];
 
-            // Parse the commit SHA
-            let commit_oid =
-                git2::Oid::from_str(&commit).map_err(|e| VwError::Git {
-                    message: format!("Invalid commit SHA '{commit}': {e}"),
-                })?;
+    // Every available file may be probed for the symbols it provides, so
+    // scan them all in one batch rather than one at a time
+    cache.preload(available_files);
 
-            // Find the commit object
-            let commit_obj =
-                repo.find_commit(commit_oid).map_err(|e| VwError::Git {
-                    message: format!("Commit '{commit}' not found: {e}"),
-                })?;
+    while let Some(current_file) = files_to_process.pop() {
+        if processed_files.contains(&current_file) {
+            continue;
+        }
+        processed_files.insert(current_file.clone());
 
-            // Checkout the specific commit
-            repo.checkout_tree(commit_obj.as_object(), None)
-                .map_err(|e| VwError::Git {
-                    message: format!(
-                        "Failed to checkout commit '{commit}': {e}"
-                    ),
-                })?;
+        // Don't include the testbench file itself in the referenced files
+        // (it will be added separately)
+        if current_file != testbench_file {
+            referenced_files.push(current_file.clone());
+        }
 
-            // Set HEAD to the commit
-            repo.set_head_detached(commit_oid)
-                .map_err(|e| VwError::Git {
-                    message: format!(
-                        "Failed to set HEAD to commit '{commit}': {e}"
-                    ),
-                })?;
+        let dependencies = cache.get_dependencies(&current_file)?.clone();
 
-            // Initialize and update submodules if requested
-            if submodules {
-                for mut submodule in
-                    repo.submodules().map_err(|e| VwError::Git {
-                        message: format!("Failed to list submodules: {e}"),
-                    })?
-                {
-                    submodule.init(false).map_err(|e| VwError::Git {
-                        message: format!(
-                            "Failed to init submodule '{}': {e}",
-                            submodule.name().unwrap_or("unknown")
-                        ),
-                    })?;
-                    submodule.update(true, None).map_err(|e| VwError::Git {
-                        message: format!(
-                            "Failed to update submodule '{}': {e}",
-                            submodule.name().unwrap_or("unknown")
-                        ),
-                    })?;
+        // Find corresponding files for each dependency
+        for dep in dependencies {
+            for available_file in available_files {
+                if file_provides_symbol(available_file, &dep, cache)? {
+                    if !processed_files.contains(available_file) {
+                        provenance
+                            .entry(available_file.clone())
+                            .or_insert_with(|| Inclusion {
+                                referenced_by: current_file.clone(),
+                                symbol: dep.clone(),
+                            });
+                        files_to_process.push(available_file.clone());
+                    }
+                    break;
                 }
             }
-
-            Ok::<(), VwError>(())
-        }),
-    )
-    .await
-    .map_err(|_| VwError::Git {
-        message: "Git clone timed out after 120 seconds".to_string(),
-    })?
-    .map_err(|e| VwError::Git {
-        message: format!("Failed to execute git operations: {e}"),
-    })??;
-
-    fs::create_dir_all(dest_path).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to create destination directory: {e}"),
-    })?;
-
-    // Treat all src values as globs (handles files, directories, and patterns)
-    for src_path in &src_paths {
-        copy_vhdl_files_glob(
-            temp_dir.path(),
-            src_path,
-            dest_path,
-            recursive,
-            exclude,
-        )?;
+        }
     }
 
-    Ok(())
+    Ok((referenced_files, provenance))
 }
 
-fn copy_vhdl_files_glob(
-    repo_root: &Path,
-    src_pattern: &str,
-    dest: &Path,
-    recursive: bool,
-    exclude: &[String],
+pub fn sort_files_by_dependencies(
+    processor: &mut RecordProcessor,
+    files: &mut Vec<PathBuf>,
+    cache: &mut FileCache,
 ) -> Result<()> {
-    // Build patterns to match
-    let src_path = repo_root.join(src_pattern);
-    let mut patterns = Vec::new();
-    let strip_prefix: PathBuf;
+    let graph = build_file_graph(processor, files, cache)?;
 
-    // Compile exclude patterns
-    let exclude_patterns: Vec<glob::Pattern> = exclude
+    let mut dependencies: HashMap<PathBuf, Vec<PathBuf>> = files
         .iter()
-        .filter_map(|p| glob::Pattern::new(p).ok())
+        .map(|file| (file.clone(), Vec::new()))
         .collect();
+    for edge in graph.edges {
+        dependencies.entry(edge.from).or_default().push(edge.to);
+    }
 
-    // Check if src_pattern points to a directory
-    if src_path.is_dir() {
-        // It's a directory - create appropriate glob patterns
-        let base_pattern =
-            src_path.to_str().ok_or_else(|| VwError::FileSystem {
-                message: "Invalid UTF-8 in path".to_string(),
-            })?;
+    // Topological sort using Kahn's algorithm
+    let sorted = topological_sort_files(files.clone(), dependencies)?;
+    *files = sorted;
 
-        if recursive {
-            // Recursively find all VHDL files
-            patterns.push(format!("{base_pattern}/**/*.vhd"));
-            patterns.push(format!("{base_pattern}/**/*.vhdl"));
-        } else {
-            // Only files directly in the directory
-            patterns.push(format!("{base_pattern}/*.vhd"));
-            patterns.push(format!("{base_pattern}/*.vhdl"));
-        }
-        // For directories, strip the src directory from paths
-        strip_prefix = src_path;
-    } else if src_path.is_file() {
-        // It's a single file - use as-is
-        patterns.push(
-            src_path
-                .to_str()
+    Ok(())
+}
+
+/// Build the dependency graph between `files`: an edge for every package
+/// or entity a file references that another of the files declares.
+fn build_file_graph(
+    processor: &mut RecordProcessor,
+    files: &[PathBuf],
+    cache: &mut FileCache,
+) -> Result<FileGraph> {
+    let mut all_symbols: HashMap<String, PathBuf> = HashMap::new();
+
+    // First pass: collect all symbols provided by each file
+    for file in files.iter() {
+        let symbols = analyze_file(processor, file)?;
+        for symbol in symbols {
+            match &symbol.kind {
+                SymbolKind::Package => {
+                    all_symbols.insert(symbol.name.clone(), file.clone());
+                    let entry = processor
+                        .file_info
+                        .entry(file.to_string_lossy().to_string())
+                        .or_default();
+                    entry.add_defined_pkg(&symbol.name);
+
+                    // Use cache to get package imports only
+                    let deps = cache.get_dependencies(file)?;
+                    for dep in deps {
+                        if let SymbolKind::Package = dep.kind {
+                            entry.add_imported_pkg(&dep.name);
+                        }
+                    }
+                }
+                SymbolKind::Entity => {
+                    all_symbols.insert(symbol.name, file.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Second pass: find dependencies for each file
+    let mut edges = Vec::new();
+    for file in files.iter() {
+        let deps = cache.get_dependencies(file)?.clone();
+
+        for dep in deps {
+            let kind = match &dep.kind {
+                SymbolKind::Package => FileEdgeKind::Use,
+                SymbolKind::Entity => FileEdgeKind::Instantiation,
+                _ => continue,
+            };
+            if let Some(provider_file) = all_symbols.get(&dep.name) {
+                if provider_file != file {
+                    edges.push(FileEdge {
+                        from: file.clone(),
+                        to: provider_file.clone(),
+                        kind,
+                        symbol: dep.name.clone(),
+                        in_cycle: false,
+                    });
+                }
+            }
+        }
+    }
+
+    // Mark edges within a strongly connected component: those are exactly
+    // the edges that lie on some cycle
+    let mut graph: DiGraph<(), ()> = DiGraph::default();
+    let mut index_map: HashMap<&PathBuf, NodeIndex> = HashMap::new();
+    for file in files {
+        index_map.insert(file, graph.add_node(()));
+    }
+    for edge in &edges {
+        graph.add_edge(index_map[&edge.from], index_map[&edge.to], ());
+    }
+    let mut component: HashMap<NodeIndex, usize> = HashMap::new();
+    for (id, scc) in tarjan_scc(&graph).into_iter().enumerate() {
+        if scc.len() > 1 {
+            component.extend(scc.into_iter().map(|node| (node, id)));
+        }
+    }
+    for edge in &mut edges {
+        let from = component.get(&index_map[&edge.from]);
+        edge.in_cycle =
+            from.is_some() && from == component.get(&index_map[&edge.to]);
+    }
+
+    Ok(FileGraph {
+        files: files.to_vec(),
+        edges,
+    })
+}
+
+pub fn load_existing_vhdl_ls_config(
+    workspace_dir: &Utf8Path,
+) -> Result<VhdlLsConfig> {
+    let config_path = workspace_dir.join("vhdl_ls.toml");
+    if config_path.exists() {
+        let config_content = fs::read_to_string(&config_path).map_err(|e| {
+            VwError::FileSystem {
+                message: format!("Failed to read existing vhdl_ls.toml: {e}"),
+                path: Some(config_path.clone().into()),
+            }
+        })?;
+
+        let config: VhdlLsConfig = toml::from_str(&config_content)?;
+
+        Ok(config)
+    } else {
+        Ok(VhdlLsConfig {
+            standard: None,
+            libraries: BTreeMap::new(),
+            lint: None,
+        })
+    }
+}
+
+// ============================================================================
+// Internal Helper Functions
+// ============================================================================
+
+fn get_package_imports(content: &str) -> Result<Vec<String>> {
+    // Find 'use work.package_name' statements
+    let use_work_pattern = r"(?i)use\s+work\.(\w+)";
+    let use_work_re = regex::Regex::new(use_work_pattern)?;
+    let mut imports = Vec::new();
+
+    for captures in use_work_re.captures_iter(content) {
+        if let Some(package_name) = captures.get(1) {
+            imports.push(package_name.as_str().to_string());
+        }
+    }
+    Ok(imports)
+}
+
+fn file_provides_symbol(
+    file_path: &Path,
+    needed: &VwSymbol,
+    cache: &mut FileCache,
+) -> Result<bool> {
+    let provided = cache.get_provided_symbols(file_path)?;
+    Ok(provided.iter().any(|s| match (&needed.kind, &s.kind) {
+        // Package dependency matches package declaration
+        (SymbolKind::Package, SymbolKind::Package) => {
+            needed.name.eq_ignore_ascii_case(&s.name)
+        }
+        // Entity dependency matches entity declaration
+        (SymbolKind::Entity, SymbolKind::Entity) => {
+            needed.name.eq_ignore_ascii_case(&s.name)
+        }
+        _ => false,
+    }))
+}
+
+fn analyze_file(
+    processor: &mut RecordProcessor,
+    file: &Path,
+) -> Result<Vec<VwSymbol>> {
+    let parser = VHDLParser::new(processor.vhdl_std.into());
+    let mut diagnostics = Vec::new();
+    let (_, design_file) = parser.parse_design_file(file, &mut diagnostics)?;
+
+    let mut file_finder = VwSymbolFinder::new(&processor.target_attr);
+    walk_design_file(&mut file_finder, &design_file);
+    if let Some(e) = file_finder.take_error() {
+        return Err(e);
+    }
+
+    let file_str = file.to_string_lossy().to_string();
+
+    // Add symbols to the map
+
+    for symbol in file_finder.get_symbols() {
+        match symbol.kind {
+            SymbolKind::Enum(_)
+            | SymbolKind::Record(_)
+            | SymbolKind::Constant(_) => {
+                let name = symbol.get_name().to_string();
+                processor.symbols.insert(name.clone(), symbol.clone());
+                processor.symbol_to_file.insert(name, file_str.clone());
+            }
+            _ => {}
+        }
+    }
+
+    for tagged_type in file_finder.get_tagged_types() {
+        processor.tagged_names.insert(tagged_type.clone());
+    }
+
+    Ok(file_finder.get_symbols().clone())
+}
+
+fn topological_sort_files(
+    files: Vec<PathBuf>,
+    dependencies: HashMap<PathBuf, Vec<PathBuf>>,
+) -> Result<Vec<PathBuf>> {
+    let mut dep_graph: DiGraph<PathBuf, ()> = DiGraph::default();
+    let mut index_map: HashMap<PathBuf, NodeIndex> = HashMap::new();
+
+    // initialize the nodes
+    for file in &files {
+        let index = dep_graph.add_node(file.clone());
+        index_map.insert(file.clone(), index);
+    }
+
+    // now add edges from files to their dependencies
+    for (file, deps) in &dependencies {
+        let source_node = index_map.get(file).ok_or(VwError::Dependency {
+            message: format!(
+                "Index map somehow didn't contain file {:?}",
+                file
+            ),
+        })?;
+        // file depends on every dep in deps
+        for dep in deps {
+            let dst_node = index_map.get(dep).ok_or(VwError::Dependency {
+                message: format!(
+                    "Index map somehow didn't contain dep {:?}",
+                    dep
+                ),
+            })?;
+            dep_graph.add_edge(*source_node, *dst_node, ());
+        }
+    }
+
+    // ok now topological sort
+    let ordered_files = toposort(&dep_graph, None).map_err(|_| {
+        let cycle: Vec<String> = find_cycle(&dep_graph)
+            .iter()
+            .map(|&idx| dep_graph[idx].display().to_string())
+            .collect();
+        VwError::Dependency {
+            message: format!(
+                "Circular dependency between VHDL files: {}",
+                cycle.join(" -> ")
+            ),
+        }
+    })?;
+
+    let result: Vec<PathBuf> = ordered_files
+        .iter()
+        .map(|&idx| dep_graph[idx].clone())
+        .rev()
+        .collect();
+    Ok(result)
+}
+
+/// Find one concrete cycle in `graph`, as the nodes along it with the
+/// first node repeated at the end. Empty if the graph is acyclic.
+fn find_cycle<N>(graph: &DiGraph<N, ()>) -> Vec<NodeIndex> {
+    let Some(scc) = tarjan_scc(graph)
+        .into_iter()
+        .find(|scc| scc.len() > 1 || graph.contains_edge(scc[0], scc[0]))
+    else {
+        return Vec::new();
+    };
+    let members: HashSet<NodeIndex> = scc.iter().copied().collect();
+    let start = scc[0];
+
+    // Breadth-first search within the component for the shortest path from
+    // `start` back to itself
+    let mut parent: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(node) = queue.pop_front() {
+        for next in graph.neighbors(node) {
+            if next == start {
+                let mut cycle = vec![start, node];
+                let mut current = node;
+                while current != start {
+                    current = parent[&current];
+                    cycle.push(current);
+                }
+                cycle.reverse();
+                return cycle;
+            }
+            if members.contains(&next) && !parent.contains_key(&next) {
+                parent.insert(next, node);
+                queue.push_back(next);
+            }
+        }
+    }
+    Vec::new()
+}
+
+fn find_testbench_file_recurse(
+    testbench_name: &str,
+    bench_dir: &Utf8Path,
+    recurse: bool,
+    extensions: &[String],
+    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
+) -> Result<Vec<PathBuf>> {
+    let mut found_files = Vec::new();
+
+    for entry in fs::read_dir(bench_dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to read bench directory: {e}"),
+        path: Some(bench_dir.as_std_path().to_path_buf()),
+    })? {
+        let entry = entry.map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read directory entry: {e}"),
+            path: None,
+        })?;
+        let path = entry.path();
+
+        if path.is_file() {
+            // Check if this file contains the entity we're looking for
+            if has_vhdl_extension(&path, extensions)
+                && file_contains_entity(&path, testbench_name, entities_cache)?
+            {
+                found_files.push(path);
+            }
+        } else if recurse {
+            let dir_path: Utf8PathBuf =
+                path.try_into().map_err(|e| VwError::FileSystem {
+                    message: format!("Failed to get dir path: {e}"),
+                    path: None,
+                })?;
+            let mut lower_testbenches = find_testbench_file_recurse(
+                testbench_name,
+                &dir_path,
+                recurse,
+                extensions,
+                entities_cache,
+            )?;
+            found_files.append(&mut lower_testbenches);
+        }
+    }
+    Ok(found_files)
+}
+
+fn find_testbench_file(
+    testbench_name: &str,
+    bench_dir: &Utf8Path,
+    recurse: bool,
+    not_testbenches: &[String],
+    vhdl_std: VhdlStandard,
+    extensions: &[String],
+    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
+) -> Result<PathBuf> {
+    let found_files = find_testbench_file_recurse(
+        testbench_name,
+        bench_dir,
+        recurse,
+        extensions,
+        entities_cache,
+    )?;
+
+    match found_files.len() {
+        0 => {
+            let mut available: Vec<String> = list_testbenches_impl(
+                bench_dir,
+                &HashSet::new(),
+                recurse,
+                vhdl_std,
+                extensions,
+                entities_cache,
+            )?
+            .into_iter()
+            .map(|tb| tb.name)
+            .filter(|name| !is_listed(not_testbenches, name))
+            .collect();
+            available.sort();
+            available.dedup();
+
+            let message = if available.is_empty() {
+                format!(
+                    "Testbench entity '{testbench_name}' not found: \
+                     bench directory {bench_dir} contains no testbenches"
+                )
+            } else {
+                format!(
+                    "Testbench entity '{testbench_name}' not found in bench \
+                     directory. Available testbenches: {}",
+                    available.join(", ")
+                )
+            };
+            Err(VwError::Testbench { message })
+        }
+        1 => Ok(found_files.into_iter().next().unwrap()),
+        _ => Err(VwError::Testbench {
+            message: format!("Multiple files contain entity '{testbench_name}': {found_files:?}")
+        }),
+    }
+}
+
+/// Check that `name` is a VHDL basic identifier: a letter followed by
+/// letters, digits, and single underscores, not ending in an underscore.
+pub fn is_vhdl_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    if !chars.next().is_some_and(|c| c.is_ascii_alphabetic()) {
+        return false;
+    }
+    !name.ends_with('_')
+        && !name.contains("__")
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Case-insensitive membership test for VHDL identifiers.
+fn is_listed(names: &[String], name: &str) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+fn file_contains_entity(
+    file_path: &Path,
+    entity_name: &str,
+    entities_cache: &mut HashMap<PathBuf, Vec<String>>,
+) -> Result<bool> {
+    let entities = get_cached_entities(file_path, entities_cache)?;
+    Ok(entities.iter().any(|e| e.eq_ignore_ascii_case(entity_name)))
+}
+
+/// Get entities from cache, parsing and caching if not present.
+fn get_cached_entities<'a>(
+    path: &Path,
+    entities_cache: &'a mut HashMap<PathBuf, Vec<String>>,
+) -> Result<&'a Vec<String>> {
+    match entities_cache.entry(path.to_path_buf()) {
+        Entry::Occupied(e) => Ok(e.into_mut()),
+        Entry::Vacant(e) => {
+            let content =
+                fs::read_to_string(path).map_err(|e| VwError::FileSystem {
+                    message: format!("Failed to read file: {e}"),
+                    path: Some(path.to_path_buf()),
+                })?;
+            let entities = parse_entities(&content)?;
+            Ok(e.insert(entities))
+        }
+    }
+}
+
+/// Turn a file path from `vhdl_ls.toml` into one usable from the workspace,
+/// expanding a leading `$HOME` and anchoring relative paths at the
+/// workspace directory.
+fn resolve_library_file(
+    workspace_dir: &Utf8Path,
+    file_path: &Path,
+) -> Result<PathBuf> {
+    if let Ok(rest) = file_path.strip_prefix("$HOME") {
+        let home_dir = dirs::home_dir().ok_or_else(|| VwError::FileSystem {
+            message: "Could not determine home directory".to_string(),
+            path: None,
+        })?;
+        Ok(home_dir.join(rest))
+    } else if file_path.is_relative() {
+        Ok(workspace_dir.as_std_path().join(file_path))
+    } else {
+        Ok(file_path.to_path_buf())
+    }
+}
+
+/// The workspace's own sources, the `defaultlib` files of `vhdl_ls.toml`,
+/// resolved with [`resolve_library_file`].
+pub(crate) fn defaultlib_files(
+    workspace_dir: &Utf8Path,
+    vhdl_ls_config: &VhdlLsConfig,
+) -> Result<Vec<PathBuf>> {
+    match vhdl_ls_config.libraries.get("defaultlib") {
+        Some(lib) => lib
+            .files
+            .iter()
+            .map(|file| resolve_library_file(workspace_dir, file))
+            .collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// The `vhdl_ls.toml` entry for a dependency's files. Dependencies are
+/// marked third-party so vhdl_ls doesn't lint them, unless `dep` sets
+/// `lint`, and carry `dep`'s VHDL standard override if it has one.
+///
+/// The dependency's `exclude` patterns are relative to the directories its
+/// files are selected from, so they are anchored at each of `roots`
+/// (written the same way as `files`) to match the same files when vhdl_ls
+/// applies them.
+fn dependency_library(
+    files: Vec<PathBuf>,
+    roots: &[PathBuf],
+    exclude: &[String],
+    dep: Option<&Dependency>,
+) -> VhdlLsLibrary {
+    let lint = dep.is_some_and(|dep| dep.lint);
+    let exclude = roots
+        .iter()
+        .flat_map(|root| {
+            exclude.iter().map(move |pattern| {
+                root.join(pattern).to_string_lossy().into_owned()
+            })
+        })
+        .collect::<Vec<_>>();
+    VhdlLsLibrary {
+        files,
+        exclude: (!exclude.is_empty()).then_some(exclude),
+        is_third_party: (!lint).then_some(true),
+        vhdl_standard: dep.and_then(|dep| dep.vhdl_standard),
+        analysis_flags: dep
+            .map(|dep| dep.analysis_flags.clone())
+            .unwrap_or_default(),
+    }
+}
+
+/// Add a dependency's `vhdl_ls.toml` entry under `library`, merging it
+/// into the entry of any other dependency already placed there.
+fn insert_dependency_library(
+    libraries: &mut BTreeMap<String, VhdlLsLibrary>,
+    library: &str,
+    entry: VhdlLsLibrary,
+) -> Result<()> {
+    let library = nvc_library_name(library);
+    let merged = match libraries.entry(library.clone()) {
+        btree_map::Entry::Vacant(vacant) => {
+            vacant.insert(entry);
+            return Ok(());
+        }
+        btree_map::Entry::Occupied(occupied) => occupied.into_mut(),
+    };
+    if merged.vhdl_standard != entry.vhdl_standard {
+        return Err(VwError::Dependency {
+            message: format!(
+                "Dependencies in library '{library}' set different \
+                 vhdl_standard values"
+            ),
+        });
+    }
+    if merged.analysis_flags != entry.analysis_flags {
+        return Err(VwError::Dependency {
+            message: format!(
+                "Dependencies in library '{library}' set different \
+                 analysis_flags values"
+            ),
+        });
+    }
+    merged.files.extend(entry.files);
+    if let Some(exclude) = entry.exclude {
+        merged.exclude.get_or_insert_with(Vec::new).extend(exclude);
+    }
+    // Lint the library if any of its dependencies asks for it
+    if entry.is_third_party.is_none() {
+        merged.is_third_party = None;
+    }
+    Ok(())
+}
+
+/// Find the VHDL files of a vendored dependency, relative to the workspace.
+fn vendored_library_files(
+    workspace_dir: &Utf8Path,
+    vendor_dir: &Path,
+    locked: &LockedDependency,
+    extensions: &[String],
+) -> Result<Vec<PathBuf>> {
+    let workspace_dir = workspace_dir.as_std_path();
+    // The vendored copy holds just the dependency's layout, which glob
+    // `src` patterns can nest even when it isn't `recursive`
+    let files = dependency_files(
+        &workspace_dir.join(vendor_dir),
+        &vendored_listed_files(locked),
+        true,
+        &locked.exclude,
+        extensions,
+    )?;
+    Ok(files
+        .into_iter()
+        .map(|f| match f.strip_prefix(workspace_dir) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => f,
+        })
+        .collect())
+}
+
+/// `files` relative to `root`, for those under it.
+fn relative_files(files: &[PathBuf], root: &Path) -> Vec<PathBuf> {
+    files
+        .iter()
+        .map(|f| f.strip_prefix(root).unwrap_or(f).to_path_buf())
+        .collect()
+}
+
+fn make_path_portable(path: PathBuf) -> PathBuf {
+    if let Some(home_dir) = dirs::home_dir() {
+        if let Ok(relative_path) = path.strip_prefix(&home_dir) {
+            return PathBuf::from("$HOME").join(relative_path);
+        }
+    }
+    path
+}
+
+/// Write an absolute dependency file path in the form `vhdl_ls.toml` is
+/// configured to use. [`resolve_library_file`] undoes any of these forms.
+fn vhdl_ls_file_path(
+    workspace_dir: &Utf8Path,
+    path: PathBuf,
+    style: VhdlLsPaths,
+) -> Result<PathBuf> {
+    match style {
+        VhdlLsPaths::Home => Ok(make_path_portable(path)),
+        VhdlLsPaths::Absolute => Ok(path),
+        VhdlLsPaths::Relative => {
+            let workspace_dir = std::path::absolute(workspace_dir)?;
+            Ok(relative_path(&workspace_dir, &path).unwrap_or(path))
+        }
+    }
+}
+
+/// Express `path` relative to the directory `base`, walking up with `..`
+/// as needed. Both paths must be absolute; `None` is returned when they
+/// share no common root (e.g. different Windows drives).
+fn relative_path(base: &Path, path: &Path) -> Option<PathBuf> {
+    let base: Vec<_> = base.components().collect();
+    let target: Vec<_> = path.components().collect();
+    let common = base.iter().zip(&target).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &target[common..] {
+        relative.push(component);
+    }
+    Some(relative)
+}
+
+/// Name of the cache tree holding a repository's files at a commit:
+/// `<repo>-<hash>-<commit>`, where `<hash>` identifies the repository URL.
+/// Trees with submodules checked out end in `-submodules`, and trees with
+/// a dependency's patch applied in `-patch-<hash of the patch>`.
+///
+/// A tree is the whole checkout without `.git`. Dependencies select their
+/// files from it when they are read, so every dependency on the same
+/// repository and commit shares one tree, whatever its name or `src`.
+fn cache_tree_name(
+    repo_url: &str,
+    commit_sha: &str,
+    submodules: bool,
+    patch_hash: Option<&str>,
+) -> String {
+    let repo = repo_url.trim_end_matches('/').trim_end_matches(".git");
+    let mut name = format!(
+        "{}-{:016x}-{commit_sha}",
+        extract_repo_name(repo_url),
+        fnv1a_hash(repo.as_bytes())
+    );
+    if submodules {
+        name.push_str("-submodules");
+    }
+    if let Some(patch_hash) = patch_hash {
+        name.push_str("-patch-");
+        name.push_str(patch_hash);
+    }
+    name
+}
+
+/// 64-bit FNV-1a; used instead of `DefaultHasher` because cache directory
+/// names must be stable across Rust releases.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn locked_dependency(
+    dep: &Dependency,
+    commit_sha: &str,
+    patch_hash: Option<&str>,
+) -> LockedDependency {
+    LockedDependency {
+        repo: dep.repo.clone(),
+        branch: dep.branch.clone(),
+        commit: commit_sha.to_string(),
+        src: dep.src.clone(),
+        files: dep.files.clone(),
+        path: PathBuf::from(cache_tree_name(
+            &dep.repo,
+            commit_sha,
+            dep.submodules,
+            patch_hash,
+        )),
+        recursive: dep.recursive,
+        sim_only: dep.sim_only,
+        submodules: dep.submodules,
+        exclude: dep.exclude.clone(),
+        vendored: None,
+        default_branch: None,
+        library: dep.library.clone(),
+        patch: dep.patch.clone(),
+        patch_hash: patch_hash.map(str::to_string),
+        overlay: dep.overlay.clone(),
+    }
+}
+
+/// The cache tree of the locked dependency `name`.
+///
+/// Lock files written before trees were shared point at per-dependency
+/// `<name>-<commit>` directories holding only the selected files, which
+/// can't be read as a tree; those dependencies have to be downloaded
+/// again.
+fn cached_tree(name: &str, locked: &LockedDependency) -> Result<PathBuf> {
+    let tree_name = cache_tree_name(
+        &locked.repo,
+        &locked.commit,
+        locked.submodules,
+        locked.patch_hash.as_deref(),
+    );
+    if locked.path != Path::new(&tree_name) {
+        return Err(VwError::Dependency {
+            message: format!(
+                "Dependency '{name}' was cached by an older version of vw; \
+                 run `vw update` to download it again"
+            ),
+        });
+    }
+    Ok(deps_directory()?.join(tree_name))
+}
+
+/// The VHDL files the locked dependency `name` selects from its cache tree
+/// at `tree`, each with its path in the dependency's layout: relative to
+/// the `src` directory it was found in, as `vw vendor` lays it out.
+///
+/// Files found through `src` come in layout order. An explicit `files`
+/// list keeps its order and is followed by the overlay files, which come
+/// from the workspace and sit at the top of the layout.
+fn tree_files(
+    workspace_dir: &Utf8Path,
+    name: &str,
+    tree: &Path,
+    locked: &LockedDependency,
+    extensions: &[String],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if !tree.is_dir() {
+        return Err(VwError::Dependency {
+            message: format!(
+                "Cached files for dependency '{name}' not found at {}; run \
+                 `vw update` to download them",
+                tree.display()
+            ),
+        });
+    }
+    let mut files = if locked.files.is_empty() {
+        let mut layout = BTreeMap::new();
+        for src in &locked.src {
+            // A file selected by several `src` entries is taken from the
+            // last of them
+            for (file, layout_path) in glob_vhdl_files(
+                tree,
+                src,
+                locked.recursive,
+                &locked.exclude,
+                extensions,
+            )? {
+                layout.insert(layout_path, file);
+            }
+        }
+        layout
+            .into_iter()
+            .map(|(layout_path, file)| (file, layout_path))
+            .collect()
+    } else {
+        listed_files(tree, &locked.src, &locked.files)?
+    };
+
+    for overlay in &locked.overlay {
+        let file = workspace_dir.as_std_path().join(overlay);
+        if !file.is_file() {
+            return Err(VwError::FileSystem {
+                message: format!(
+                    "Overlay file for dependency '{name}' not found"
+                ),
+                path: Some(file),
+            });
+        }
+        let layout_path = PathBuf::from(file.file_name().ok_or_else(|| {
+            VwError::Dependency {
+                message: format!(
+                    "Overlay path {} has no file name",
+                    file.display()
+                ),
+            }
+        })?);
+        if files.iter().any(|(_, existing)| *existing == layout_path) {
+            return Err(VwError::Dependency {
+                message: format!(
+                    "Overlay file {} would replace the dependency's own {}",
+                    file.display(),
+                    layout_path.display()
+                ),
+            });
+        }
+        files.push((file, layout_path));
+    }
+    if locked.files.is_empty() {
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+    }
+    Ok(files)
+}
+
+/// The files of a vendored dependency's layout that it lists explicitly:
+/// its `files`, followed by its overlay files at the top. Empty when the
+/// dependency's files are found through `src` instead, which finds the
+/// overlay files as well.
+fn vendored_listed_files(locked: &LockedDependency) -> Vec<String> {
+    if locked.files.is_empty() {
+        return Vec::new();
+    }
+    let overlay = locked.overlay.iter().filter_map(|file| {
+        Some(Path::new(file).file_name()?.to_string_lossy().into_owned())
+    });
+    locked.files.iter().cloned().chain(overlay).collect()
+}
+
+/// The directories in the cache tree at `tree` that `locked` selects its
+/// files from, where its `exclude` patterns are anchored.
+fn tree_roots(tree: &Path, locked: &LockedDependency) -> Vec<PathBuf> {
+    if !locked.files.is_empty() {
+        return vec![locked
+            .src
+            .first()
+            .map_or(tree.to_path_buf(), |src| tree.join(src))];
+    }
+    let mut roots: Vec<PathBuf> = Vec::new();
+    for src in &locked.src {
+        let path = tree.join(src);
+        let root = if path.is_dir() {
+            path
+        } else if path.is_file() {
+            path.parent().unwrap_or(tree).to_path_buf()
+        } else {
+            tree.to_path_buf()
+        };
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+    roots
+}
+
+/// A dependency's patch file, read from the workspace.
+struct DependencyPatch {
+    path: PathBuf,
+    contents: Vec<u8>,
+    /// Hex FNV-1a hash of `contents`.
+    hash: String,
+}
+
+/// Read the patch file `dep`, listed under `name`, names, if any.
+fn read_dependency_patch(
+    workspace_dir: &Utf8Path,
+    name: &str,
+    dep: &Dependency,
+) -> Result<Option<DependencyPatch>> {
+    let Some(patch) = &dep.patch else {
+        return Ok(None);
+    };
+    let path = workspace_dir.as_std_path().join(patch);
+    let contents = fs::read(&path).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to read patch for dependency '{name}': {e}"),
+        path: Some(path.clone()),
+    })?;
+    let hash = format!("{:016x}", fnv1a_hash(&contents));
+    Ok(Some(DependencyPatch {
+        path,
+        contents,
+        hash,
+    }))
+}
+
+/// Build the cache tree at `dest_path`, with `patch` applied, from the
+/// unpatched tree at `base_path`. With `link`, the files the patch leaves
+/// alone are hardlinked to the base tree; the ones it touches are always
+/// copied, so applying it never changes the base tree.
+fn build_patched_tree(
+    base_path: &Path,
+    dest_path: &Path,
+    patch: &DependencyPatch,
+    link: bool,
+) -> Result<()> {
+    let diff = git2::Diff::from_buffer(&patch.contents).map_err(|e| {
+        VwError::Dependency {
+            message: format!(
+                "Failed to parse patch {}: {}",
+                patch.path.display(),
+                e.message()
+            ),
+        }
+    })?;
+    let mut touched = HashSet::new();
+    for delta in diff.deltas() {
+        touched.extend(delta.old_file().path().map(Path::to_path_buf));
+        touched.extend(delta.new_file().path().map(Path::to_path_buf));
+    }
+
+    let staging_path = create_staging_dir(dest_path)?;
+    let built =
+        copy_tree(base_path, &staging_path, Path::new(""), &touched, link)
+            .and_then(|()| {
+                let repo = git2::Repository::init(&staging_path)?;
+                repo.apply(&diff, git2::ApplyLocation::WorkDir, None)
+                    .map_err(|e| VwError::Dependency {
+                        message: format!(
+                            "Patch {} does not apply: {}",
+                            patch.path.display(),
+                            e.message()
+                        ),
+                    })
+            })
+            .and_then(|()| install_staged_tree(&staging_path, dest_path));
+    if built.is_err() {
+        let _ = fs::remove_dir_all(&staging_path);
+    }
+    built
+}
+
+/// Copy the directory `source` into `dest`, hardlinking its files if
+/// `link` is set, except for the files in `copied`, which are given
+/// relative to the top of the tree being copied (`relative` is where
+/// `source` sits within it). Symlinked files are copied as regular files.
+fn copy_tree(
+    source: &Path,
+    dest: &Path,
+    relative: &Path,
+    copied: &HashSet<PathBuf>,
+    link: bool,
+) -> Result<()> {
+    fs::create_dir_all(dest).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to create directory: {e}"),
+        path: Some(dest.to_path_buf()),
+    })?;
+    for entry in fs::read_dir(source).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to read directory: {e}"),
+        path: Some(source.to_path_buf()),
+    })? {
+        let entry = entry.map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read directory entry: {e}"),
+            path: None,
+        })?;
+        let path = entry.path();
+        let relative = relative.join(entry.file_name());
+        let dest = dest.join(entry.file_name());
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            copy_tree(&path, &dest, &relative, copied, link)?;
+        } else if path.is_file() {
+            install_file(&path, &dest, link && !copied.contains(&relative))?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_repo_name(repo_url: &str) -> String {
+    repo_url
+        .trim_end_matches(".git")
+        .split('/')
+        .next_back()
+        .unwrap_or("dependency")
+        .to_string()
+}
+
+fn save_workspace_config(
+    workspace_dir: &Utf8Path,
+    config: &WorkspaceConfig,
+) -> Result<()> {
+    let toml_content = toml::to_string_pretty(config)?;
+    let config_path = workspace_dir.join("vw.toml");
+
+    fs::write(&config_path, toml_content).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to write vw.toml file: {e}"),
+        path: Some(config_path.clone().into()),
+    })?;
+
+    Ok(())
+}
+
+/// Find the workspace containing `start`: the nearest of `start` and its
+/// ancestors that holds a `vw.toml`.
+pub fn find_workspace_root(start: &Utf8Path) -> Result<Utf8PathBuf> {
+    start
+        .ancestors()
+        .find(|dir| dir.join("vw.toml").is_file())
+        .map(Utf8Path::to_path_buf)
+        .ok_or_else(|| VwError::Config {
+            message: format!(
+                "No vw.toml found in {start} or any of its parent directories"
+            ),
+        })
+}
+
+pub fn load_workspace_config(
+    workspace_dir: &Utf8Path,
+) -> Result<WorkspaceConfig> {
+    let config_path = workspace_dir.join("vw.toml");
+    if !config_path.exists() {
+        return Err(VwError::Config {
+            message: format!("No vw.toml file found in {workspace_dir}"),
+        });
+    }
+
+    let config_content =
+        fs::read_to_string(&config_path).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read vw.toml: {e}"),
+            path: Some(config_path.clone().into()),
+        })?;
+
+    let config: WorkspaceConfig = toml::from_str(&config_content)?;
+
+    Ok(config)
+}
+
+/// Check a workspace configuration for mistakes that would otherwise only
+/// surface partway through an update.
+///
+/// Each dependency must name a repository and at most one of `branch` and
+/// `commit`, select files through `src` or `files`, and map to a legal
+/// VHDL library name. The error names the first offending dependency and
+/// field, in name order.
+pub fn validate_config(config: &WorkspaceConfig) -> Result<()> {
+    let mut names: Vec<&String> = config.dependencies.keys().collect();
+    names.sort();
+
+    for name in names {
+        let dep = &config.dependencies[name];
+        let invalid = |message: String| VwError::Config {
+            message: format!("Dependency '{name}': {message}"),
+        };
+
+        if dep.repo.trim().is_empty() {
+            return Err(invalid("`repo` is empty".to_string()));
+        }
+        match (&dep.branch, &dep.commit) {
+            (Some(_), Some(_)) => {
+                return Err(invalid(
+                    "`branch` and `commit` are both set; use one of them"
+                        .to_string(),
+                ))
+            }
+            (Some(branch), None) if branch.trim().is_empty() => {
+                return Err(invalid("`branch` is empty".to_string()))
+            }
+            (None, Some(commit)) if commit.trim().is_empty() => {
+                return Err(invalid("`commit` is empty".to_string()))
+            }
+            _ => {}
+        }
+
+        if dep.files.is_empty() && dep.src.is_empty() {
+            return Err(invalid(
+                "`src` is empty; list the directories or globs to use, \
+                 or \".\" for the whole repository"
+                    .to_string(),
+            ));
+        }
+        if let Some(src) = dep.src.iter().find(|src| src.trim().is_empty()) {
+            return Err(invalid(format!("`src` has an empty entry ({src:?})")));
+        }
+        if !dep.files.is_empty() && dep.src.len() > 1 {
+            return Err(invalid(
+                "`files` is relative to `src`, which must then name a \
+                 single directory"
+                    .to_string(),
+            ));
+        }
+        if let Some(file) =
+            dep.files.iter().find(|file| !is_contained_path(file))
+        {
+            return Err(invalid(format!(
+                "`files` entry '{file}' must be a relative path inside `src`"
+            )));
+        }
+        if let Some(flag) = dep
+            .analysis_flags
+            .iter()
+            .find(|flag| flag.trim().is_empty())
+        {
+            return Err(invalid(format!(
+                "`analysis_flags` has an empty entry ({flag:?})"
+            )));
+        }
+        if let Some(patch) = &dep.patch {
+            if patch.trim().is_empty() || !is_contained_path(patch) {
+                return Err(invalid(format!(
+                    "`patch` '{patch}' must be a relative path inside the \
+                     workspace"
+                )));
+            }
+        }
+        let mut overlay_names = HashSet::new();
+        for file in &dep.overlay {
+            let file_name = Path::new(file).file_name();
+            if !is_contained_path(file) || file_name.is_none() {
+                return Err(invalid(format!(
+                    "`overlay` entry '{file}' must be a relative path to a \
+                     file inside the workspace"
+                )));
+            }
+            if !overlay_names.insert(file_name) {
+                return Err(invalid(format!(
+                    "`overlay` has more than one file named {:?}; they would \
+                     overwrite each other",
+                    file_name.unwrap_or_default()
+                )));
+            }
+        }
+
+        let library = nvc_library_name(dep.library_name(name));
+        if !is_vhdl_identifier(&library) {
+            let field = if dep.library.is_some() {
+                "`library`"
+            } else {
+                "the name"
+            };
+            return Err(invalid(format!(
+                "{field} doesn't make a legal VHDL library name \
+                 ('{library}')"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `path` is relative and stays inside the directory it is
+/// relative to.
+fn is_contained_path(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// The directory NVC libraries are built in, `vw_build` in the workspace.
+pub(crate) fn workspace_build_dir(workspace_dir: &Utf8Path) -> Utf8PathBuf {
+    workspace_dir.join(BUILD_DIR)
+}
+
+/// The workspace's testbench directory: `[workspace] bench_dir` from
+/// `vw.toml`, or `bench`.
+///
+/// A configured directory that does not exist is an error, as is a
+/// `vw.toml` that can't be read; the default is returned as-is so callers
+/// can report a missing `bench` themselves.
+pub fn bench_directory(workspace_dir: &Utf8Path) -> Result<Utf8PathBuf> {
+    let configured = if workspace_dir.join("vw.toml").exists() {
+        load_workspace_config(workspace_dir)?.workspace.bench_dir
+    } else {
+        None
+    };
+    workspace_subdir(workspace_dir, "bench_dir", configured, DEFAULT_BENCH_DIR)
+}
+
+/// Whether symlinked directories under the bench directory are searched:
+/// `[workspace] follow_symlinks` from `vw.toml`, or false.
+pub fn follow_symlinks(workspace_dir: &Utf8Path) -> bool {
+    load_workspace_config(workspace_dir)
+        .ok()
+        .and_then(|config| config.workspace.follow_symlinks)
+        .unwrap_or(false)
+}
+
+/// File extensions recognized as VHDL sources: [`DEFAULT_VHDL_EXTENSIONS`]
+/// plus any listed in `[workspace] vhdl_extensions`, lowercased and without
+/// a leading dot.
+pub fn vhdl_extensions(workspace_dir: &Utf8Path) -> Vec<String> {
+    let mut extensions: Vec<String> = DEFAULT_VHDL_EXTENSIONS
+        .iter()
+        .map(|ext| ext.to_string())
+        .collect();
+    let configured = load_workspace_config(workspace_dir)
+        .map(|config| config.workspace.vhdl_extensions)
+        .unwrap_or_default();
+    for ext in configured {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+        if !ext.is_empty() && !extensions.contains(&ext) {
+            extensions.push(ext);
+        }
+    }
+    extensions
+}
+
+/// The VHDL standard to use: `std_override` if given, else
+/// `[workspace] vhdl_standard` from `vw.toml`, else 2019.
+///
+/// Outside a workspace the default is used; an invalid `vw.toml` is an
+/// error so a mistyped standard isn't silently ignored.
+pub fn vhdl_standard(
+    workspace_dir: &Utf8Path,
+    std_override: Option<VhdlStandard>,
+) -> Result<VhdlStandard> {
+    if let Some(std) = std_override {
+        return Ok(std);
+    }
+    if !workspace_dir.join("vw.toml").exists() {
+        return Ok(VhdlStandard::default());
+    }
+    let config = load_workspace_config(workspace_dir)?;
+    Ok(config.workspace.vhdl_standard.unwrap_or_default())
+}
+
+/// Waveform viewers tried, in order, when `[simulator] waveform_viewer` is
+/// not set.
+pub const WAVEFORM_VIEWERS: &[&str] = &["surfer", "gtkwave"];
+
+/// Open `waveform` in the workspace's waveform viewer and return the
+/// viewer's name.
+///
+/// The viewer is `[simulator] waveform_viewer` from `vw.toml`, or else the
+/// first of [`WAVEFORM_VIEWERS`] that can be started. It runs detached,
+/// in its own process group where supported, so vw can exit while it
+/// stays open.
+pub fn open_waveform(
+    workspace_dir: &Utf8Path,
+    waveform: &Utf8Path,
+) -> Result<String> {
+    let configured = load_workspace_config(workspace_dir)
+        .ok()
+        .and_then(|config| config.simulator)
+        .and_then(|simulator| simulator.waveform_viewer);
+    let candidates: Vec<String> = match configured {
+        Some(viewer) => vec![viewer],
+        None => WAVEFORM_VIEWERS.iter().map(|v| v.to_string()).collect(),
+    };
+
+    for viewer in &candidates {
+        let mut command = std::process::Command::new(viewer);
+        command
+            .arg(waveform.as_str())
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            // Keep Ctrl-C in the terminal from closing the viewer
+            command.process_group(0);
+        }
+        match command.spawn() {
+            Ok(_) => return Ok(viewer.clone()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(VwError::Simulation {
+                    message: format!(
+                        "Failed to start waveform viewer '{viewer}': {e}"
+                    ),
+                })
+            }
+        }
+    }
+
+    Err(VwError::Simulation {
+        message: format!(
+            "No waveform viewer found (tried {}); install one or set \
+             `waveform_viewer` under [simulator] in vw.toml",
+            candidates.join(", ")
+        ),
+    })
+}
+
+/// The NVC heap size to use: `heap_override` if given, else
+/// `[simulator] heap` from `vw.toml`, else 256m.
+///
+/// Sizes are a number with an optional `k`, `m`, or `g` suffix.
+pub fn nvc_heap_size(
+    workspace_dir: &Utf8Path,
+    heap_override: Option<&str>,
+) -> Result<String> {
+    let heap = match heap_override {
+        Some(heap) => heap.to_string(),
+        None => load_workspace_config(workspace_dir)
+            .ok()
+            .and_then(|config| config.simulator)
+            .and_then(|simulator| simulator.heap)
+            .unwrap_or_else(|| nvc_helpers::DEFAULT_HEAP_SIZE.to_string()),
+    };
+
+    let digits = heap.trim_end_matches(['k', 'm', 'g', 'K', 'M', 'G']);
+    let suffix_len = heap.len() - digits.len();
+    if digits.is_empty()
+        || suffix_len > 1
+        || !digits.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(VwError::Config {
+            message: format!(
+                "Invalid heap size '{heap}': expected a number with an \
+                 optional k, m, or g suffix (e.g. 512m)"
+            ),
+        });
+    }
+    Ok(heap)
+}
+
+fn workspace_subdir(
+    workspace_dir: &Utf8Path,
+    setting: &str,
+    configured: Option<String>,
+    default: &str,
+) -> Result<Utf8PathBuf> {
+    match configured {
+        Some(dir) => {
+            let path = workspace_dir.join(&dir);
+            if !path.is_dir() {
+                return Err(VwError::Config {
+                    message: format!(
+                        "{setting} '{dir}' in vw.toml is not a directory \
+                         (looked for {path})"
+                    ),
+                });
+            }
+            Ok(path)
+        }
+        None => Ok(workspace_dir.join(default)),
+    }
+}
+
+fn load_lock_file(workspace_dir: &Utf8Path) -> Result<LockFile> {
+    let lock_path = workspace_dir.join("vw.lock");
+    if !lock_path.exists() {
+        return Err(VwError::Config {
+            message: format!("No vw.lock file found in {workspace_dir}"),
+        });
+    }
+
+    let lock_content =
+        fs::read_to_string(&lock_path).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read vw.lock: {e}"),
+            path: Some(lock_path.clone().into()),
+        })?;
+
+    let lock_file: LockFile = toml::from_str(&lock_content)?;
+
+    Ok(lock_file)
+}
+
+/// Return the per-user dependency cache directory used by vw.
+///
+/// Resolved from `$VW_DEPS_DIR` if set, otherwise `$HOME/.vw/deps`.
+/// The directory is created if it does not exist. Callers holding
+/// relative paths from [`resolve_deps`] or `vw.lock` should join against
+/// the value returned here to obtain absolute paths.
+pub fn deps_directory() -> Result<PathBuf> {
+    let deps_dir = if let Some(override_dir) =
+        std::env::var_os("VW_DEPS_DIR").filter(|v| !v.is_empty())
+    {
+        PathBuf::from(override_dir)
+    } else {
+        let home_dir = dirs::home_dir().ok_or_else(|| VwError::FileSystem {
+            message: "Could not determine home directory".to_string(),
+            path: None,
+        })?;
+        home_dir.join(".vw").join("deps")
+    };
+
+    fs::create_dir_all(&deps_dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to create dependencies directory: {e}"),
+        path: Some(deps_dir.clone()),
+    })?;
+
+    Ok(deps_dir)
+}
+
+/// A dependency's branch or commit resolved to a full commit SHA.
+struct ResolvedCommit {
+    sha: String,
+    /// The remote's default branch, when neither a branch nor a commit was
+    /// given.
+    default_branch: Option<String>,
+}
+
+/// Resolves dependency branches and commits, listing each remote's
+/// references at most once.
+///
+/// Dependencies that split one repository into several libraries would
+/// otherwise connect to the same remote once per dependency.
+#[derive(Default)]
+struct CommitResolver {
+    /// Listed references by normalized repository URL.
+    remotes: HashMap<String, RemoteRefs>,
+}
+
+impl CommitResolver {
+    async fn resolve(
+        &mut self,
+        repo_url: &str,
+        branch: &Option<String>,
+        commit: &Option<String>,
+        credentials: Option<(&str, &str)>, // (username, password)
+    ) -> Result<ResolvedCommit> {
+        match (branch, commit) {
+            (Some(_), Some(_)) => Err(VwError::Config {
+                message: "Cannot specify both branch and commit for dependency"
+                    .to_string(),
+            }),
+            (None, None) => {
+                let (branch, sha) = self
+                    .remote_refs(repo_url, credentials)
+                    .await?
+                    .branch_head(None)?;
+                Ok(ResolvedCommit {
+                    sha,
+                    default_branch: Some(branch),
+                })
+            }
+            (None, Some(commit)) => {
+                // A branch or tag tip needs no further network access
+                let tip = self
+                    .remote_refs(repo_url, credentials)
+                    .await?
+                    .unique_tip(commit);
+                let sha = match tip {
+                    Some(sha) => sha,
+                    None => {
+                        resolve_commit_sha(repo_url, commit, credentials)
+                            .await?
+                    }
+                };
+                Ok(ResolvedCommit {
+                    sha,
+                    default_branch: None,
+                })
+            }
+            (Some(branch), None) => {
+                let (_, sha) = self
+                    .remote_refs(repo_url, credentials)
+                    .await?
+                    .branch_head(Some(branch))?;
+                Ok(ResolvedCommit {
+                    sha,
+                    default_branch: None,
+                })
+            }
+        }
+    }
+
+    /// The references of `repo_url`, listed on first use.
+    async fn remote_refs(
+        &mut self,
+        repo_url: &str,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<&RemoteRefs> {
+        match self.remotes.entry(normalized_repo_url(repo_url)) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let refs = list_remote_refs(entry.key(), credentials).await?;
+                Ok(entry.insert(refs))
+            }
+        }
+    }
+}
+
+/// The references a remote advertises, as `git ls-remote` lists them.
+struct RemoteRefs {
+    /// The branch the remote's `HEAD` points at, if it has one.
+    default_branch: Option<String>,
+    /// Reference names, such as `refs/heads/main`, and their commit SHAs.
+    /// Annotated tags are peeled to the commit they point at.
+    heads: Vec<(String, String)>,
+}
+
+impl RemoteRefs {
+    /// The commit at the tip of `branch`, or of the default branch when
+    /// `branch` is `None`, along with the branch name.
+    fn branch_head(&self, branch: Option<&str>) -> Result<(String, String)> {
+        let branch = match branch {
+            Some(branch) => branch,
+            None => {
+                self.default_branch.as_deref().ok_or_else(|| VwError::Git {
+                    message: "Remote repository has no default branch"
+                        .to_string(),
+                })?
+            }
+        };
+
+        let ref_name = format!("refs/heads/{branch}");
+        self.heads
+            .iter()
+            .find(|(name, _)| *name == ref_name)
+            .map(|(_, oid)| (branch.to_string(), oid.clone()))
+            .ok_or_else(|| VwError::Git {
+                message: format!(
+                    "Branch '{branch}' not found in remote repository"
+                ),
+            })
+    }
+
+    /// The single advertised commit that `commit` abbreviates, if exactly
+    /// one matches.
+    fn unique_tip(&self, commit: &str) -> Option<String> {
+        let commit = commit.to_ascii_lowercase();
+        if commit.len() < 4 || !commit.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut tips: Vec<&String> = self
+            .heads
+            .iter()
+            .map(|(_, oid)| oid)
+            .filter(|oid| oid.starts_with(&commit))
+            .collect();
+        tips.sort();
+        tips.dedup();
+        match tips.as_slice() {
+            [tip] => Some((*tip).clone()),
+            _ => None,
+        }
+    }
+}
+
+/// The advertised references as (name, commit SHA) pairs.
+///
+/// An annotated tag is advertised twice: `refs/tags/<tag>` with the OID of
+/// the tag object and `refs/tags/<tag>^{}` with the commit it points at.
+/// Tag objects cannot be checked out, so the tag keeps the peeled commit and
+/// the `^{}` entry is dropped. Lightweight tags already point at a commit.
+fn peeled_heads(heads: &[git2::RemoteHead<'_>]) -> Vec<(String, String)> {
+    let peeled: HashMap<&str, String> = heads
+        .iter()
+        .filter_map(|head| {
+            let tag = head.name().strip_suffix("^{}")?;
+            Some((tag, head.oid().to_string()))
+        })
+        .collect();
+    heads
+        .iter()
+        .filter(|head| !head.name().ends_with("^{}"))
+        .map(|head| {
+            let oid = peeled
+                .get(head.name())
+                .cloned()
+                .unwrap_or_else(|| head.oid().to_string());
+            (head.name().to_string(), oid)
+        })
+        .collect()
+}
+
+/// `repo_url` with `.git` appended for GitHub repositories that lack it.
+fn normalized_repo_url(repo_url: &str) -> String {
+    if repo_url.contains("github.com") && !repo_url.ends_with(".git") {
+        format!("{repo_url}.git")
+    } else {
+        repo_url.to_string()
+    }
+}
+
+/// Whether `commit` is a full 40-character hex commit SHA.
+fn is_full_commit_sha(commit: &str) -> bool {
+    commit.len() == 40 && commit.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Confirm that `commit` exists in the remote repository and expand an
+/// abbreviated SHA to the full 40-character form.
+///
+/// Branch and tag tips are matched without fetching anything. Other commits
+/// are looked for with a shallow fetch (full SHAs only) and then a full
+/// fetch of the remote's branches and tags.
+async fn resolve_commit_sha(
+    repo_url: &str,
+    commit: &str,
+    credentials: Option<(&str, &str)>, // (username, password)
+) -> Result<String> {
+    let commit = commit.to_ascii_lowercase();
+    if commit.len() < 4
+        || commit.len() > 40
+        || !commit.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return Err(VwError::Git {
+            message: format!("'{commit}' is not a commit SHA"),
+        });
+    }
+
+    let repo_url = normalized_repo_url(repo_url);
+
+    let credentials = credentials.map(|(u, p)| (u.to_string(), p.to_string()));
+
+    let deadline = GitDeadline::after("fetch", 120);
+    tokio::task::spawn_blocking(move || {
+        let temp_dir =
+            tempfile::tempdir().map_err(|e| VwError::FileSystem {
+                message: format!("Failed to create temporary directory: {e}"),
+                path: None,
+            })?;
+        let repo = git2::Repository::init_bare(temp_dir.path())?;
+        let mut remote = repo.remote_anonymous(&repo_url)?;
+
+        // Most pinned commits are the tip of some branch or tag
+        let mut tips: Vec<String> =
+            with_git_retries(&repo_url, deadline, || {
+                remote.connect_auth(
+                    git2::Direction::Fetch,
+                    Some(dependency_callbacks(
+                        credentials.clone(),
+                        None,
+                        deadline,
+                    )),
+                    Some(proxy_options()),
+                )?;
+                let tips = peeled_heads(remote.list()?)
+                    .into_iter()
+                    .map(|(_, oid)| oid)
+                    .filter(|oid| oid.starts_with(&commit))
+                    .collect();
+                remote.disconnect()?;
+                Ok(tips)
+            })?;
+        tips.sort();
+        tips.dedup();
+        if let [tip] = tips.as_slice() {
+            return Ok(tip.clone());
+        }
+
+        if is_full_commit_sha(&commit) {
+            let mut fetch_options = git2::FetchOptions::new();
+            fetch_options.proxy_options(proxy_options());
+            fetch_options.depth(1);
+            fetch_options.remote_callbacks(dependency_callbacks(
+                credentials.clone(),
+                None,
+                deadline,
+            ));
+            let fetched = remote.fetch(
+                &[commit.as_str()],
+                Some(&mut fetch_options),
+                None,
+            );
+            // The SHA may name an annotated tag rather than a commit
+            let peeled = fetched.ok().and_then(|()| {
+                let oid = git2::Oid::from_str(&commit).ok()?;
+                repo.find_object(oid, None)
+                    .and_then(|object| object.peel_to_commit())
+                    .ok()
+            });
+            if let Some(peeled) = peeled {
+                return Ok(peeled.id().to_string());
+            }
+        }
+
+        with_git_retries(&repo_url, deadline, || {
+            let mut fetch_options = git2::FetchOptions::new();
+            fetch_options.proxy_options(proxy_options());
+            fetch_options.remote_callbacks(dependency_callbacks(
+                credentials.clone(),
+                None,
+                deadline,
+            ));
+            remote.fetch(
+                &[
+                    "+refs/heads/*:refs/remotes/origin/*",
+                    "+refs/tags/*:refs/tags/*",
+                ],
+                Some(&mut fetch_options),
+                None,
+            )?;
+            Ok(())
+        })?;
+
+        let full_sha = repo
+            .revparse_single(&commit)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| VwError::Git {
+                message: format!(
+                    "Commit '{commit}' not found in remote repository: {}",
+                    e.message()
+                ),
+            })?
+            .id()
+            .to_string();
+        Ok(full_sha)
+    })
+    .await
+    .map_err(|e| VwError::Git {
+        message: format!("Failed to execute git fetch task: {e}"),
+    })?
+}
+
+/// List the references of the remote at `repo_url`, which must already be
+/// normalized, along with its default branch.
+async fn list_remote_refs(
+    repo_url: &str,
+    credentials: Option<(&str, &str)>, // (username, password)
+) -> Result<RemoteRefs> {
+    let repo_url = repo_url.to_string();
+    let credentials = credentials.map(|(u, p)| (u.to_string(), p.to_string()));
+
+    let deadline = GitDeadline::after("ls-remote", 30);
+    tokio::task::spawn_blocking(move || {
+        // Create a temporary directory for the operation
+        let temp_dir =
+            tempfile::tempdir().map_err(|e| VwError::FileSystem {
+                message: format!("Failed to create temporary directory: {e}"),
+                path: None,
+            })?;
+
+        // Create an empty repository to work with remotes
+        let repo = git2::Repository::init_bare(temp_dir.path())?;
+
+        // Create a remote
+        let mut remote = repo.remote_anonymous(&repo_url)?;
+
+        // Connect and list references
+        with_git_retries(&repo_url, deadline, || {
+            remote.connect_auth(
+                git2::Direction::Fetch,
+                Some(dependency_callbacks(credentials.clone(), None, deadline)),
+                Some(proxy_options()),
+            )?;
+
+            let default_branch =
+                remote.default_branch().ok().and_then(|default_ref| {
+                    let default_ref = default_ref.as_str()?;
+                    Some(
+                        default_ref
+                            .strip_prefix("refs/heads/")
+                            .unwrap_or(default_ref)
+                            .to_string(),
+                    )
+                });
+            let heads = peeled_heads(remote.list()?);
+
+            Ok(RemoteRefs {
+                default_branch,
+                heads,
+            })
+        })
+    })
+    .await
+    .map_err(|e| VwError::Git {
+        message: format!("Failed to execute git ls-remote task: {e}"),
+    })?
+}
+
+/// Download the cache tree of `repo_url` at `commit` to `dest_path`: the
+/// checkout of the commit, and of its submodules if `submodules` is set,
+/// without `.git`. `name` is the dependency reported to `progress`.
+async fn download_dependency(
+    name: &str,
+    repo_url: &str,
+    commit: &str,
+    dest_path: &Path,
+    submodules: bool,
+    credentials: Option<(&str, &str)>, // (username, password)
+    progress: Option<ProgressCallback>,
+) -> Result<()> {
+    // Clone into a staging directory and move it into place at the end, so
+    // an interrupted download never leaves a half-filled tree that later
+    // runs would trust
+    let staging_path = create_staging_dir(dest_path)?;
+
+    let repo_url = normalized_repo_url(repo_url);
+
+    let commit = commit.to_string();
+    let checkout_path = staging_path.clone();
+    let credentials = credentials.map(|(u, p)| (u.to_string(), p.to_string()));
+    let progress = progress.map(|callback| (name.to_string(), callback));
+
+    let deadline = GitDeadline::after("clone", 120);
+    let downloaded = tokio::task::spawn_blocking(move || {
+        // Parse the commit SHA
+        let commit_oid =
+            git2::Oid::from_str(&commit).map_err(|e| VwError::Git {
+                message: format!("Invalid commit SHA '{commit}': {e}"),
+            })?;
+
+        let repo = git2::Repository::init(&checkout_path)?;
+        let mut remote = repo.remote("origin", &repo_url)?;
+
+        // Fetch only the pinned commit. Servers that refuse to serve a
+        // commit by SHA get a full fetch of their branches and tags
+        // instead, which also covers commits behind a branch tip.
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.proxy_options(proxy_options());
+        fetch_options.depth(1);
+        fetch_options.remote_callbacks(dependency_callbacks(
+            credentials.clone(),
+            progress.clone(),
+            deadline,
+        ));
+        let shallow =
+            remote.fetch(&[commit.as_str()], Some(&mut fetch_options), None);
+        if shallow.is_err() || repo.find_commit(commit_oid).is_err() {
+            with_git_retries(&repo_url, deadline, || {
+                let mut fetch_options = git2::FetchOptions::new();
+                fetch_options.proxy_options(proxy_options());
+                fetch_options.remote_callbacks(dependency_callbacks(
+                    credentials.clone(),
+                    progress.clone(),
+                    deadline,
+                ));
+                remote.fetch(
+                    &[
+                        "+refs/heads/*:refs/remotes/origin/*",
+                        "+refs/tags/*:refs/tags/*",
+                    ],
+                    Some(&mut fetch_options),
+                    None,
+                )?;
+                Ok(())
+            })?;
+        }
+
+        // Find the commit object. Resolution peels annotated tags, so
+        // anything else here is a bug rather than a bad pin.
+        let object =
+            repo.find_object(commit_oid, None)
+                .map_err(|e| VwError::Git {
+                    message: format!(
+                        "Commit '{commit}' not found in repository \
+                     {repo_url}: {e}"
+                    ),
+                })?;
+        if object.kind() != Some(git2::ObjectType::Commit) {
+            return Err(VwError::Git {
+                message: format!(
+                    "'{commit}' is not a commit (found {})",
+                    object
+                        .kind()
+                        .map(|kind| kind.to_string())
+                        .unwrap_or_else(|| "unknown object".to_string())
+                ),
+            });
+        }
+        let commit_obj = object.peel_to_commit()?;
+
+        // Checkout the specific commit
+        repo.checkout_tree(
+            commit_obj.as_object(),
+            Some(git2::build::CheckoutBuilder::new().force()),
+        )?;
+
+        // Set HEAD to the commit
+        repo.set_head_detached(commit_oid)?;
+
+        // Verify the working tree actually matches the requested
+        // commit before it goes into the cache
+        verify_checkout(&repo, commit_oid)?;
+
+        // Initialize and update submodules if requested
+        if submodules {
+            update_submodules(
+                &repo,
+                &repo_url,
+                &credentials,
+                &progress,
+                deadline,
+            )?;
+        }
+
+        Ok::<(), VwError>(())
+    })
+    .await
+    .map_err(|e| VwError::Git {
+        message: format!("Failed to execute git operations: {e}"),
+    })
+    .and_then(|joined| joined)
+    .and_then(|()| install_staged_tree(&staging_path, dest_path));
+    if downloaded.is_err() {
+        let _ = fs::remove_dir_all(&staging_path);
+    }
+    downloaded
+}
+
+/// Create an empty staging directory, `<entry>.partial`, to build the
+/// cache entry at `entry_path` in, removing what an interrupted run may
+/// have left there.
+fn create_staging_dir(entry_path: &Path) -> Result<PathBuf> {
+    let staging_path = sibling_path(entry_path, ".partial");
+    if staging_path.exists() {
+        fs::remove_dir_all(&staging_path).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to remove incomplete download: {e}"),
+            path: Some(staging_path.clone()),
+        })?;
+    }
+    fs::create_dir_all(&staging_path).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to create destination directory: {e}"),
+        path: Some(staging_path.clone()),
+    })?;
+    Ok(staging_path)
+}
+
+/// Drop the git repository from a finished staging directory and move it
+/// into place as the cache tree at `dest_path`.
+fn install_staged_tree(staging_path: &Path, dest_path: &Path) -> Result<()> {
+    let git_dir = staging_path.join(".git");
+    if git_dir.exists() {
+        fs::remove_dir_all(&git_dir).map_err(|e| VwError::FileSystem {
+            message: format!("Failed to remove git repository: {e}"),
+            path: Some(git_dir.clone()),
+        })?;
+    }
+    fs::rename(staging_path, dest_path).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to move download into the cache: {e}"),
+        path: Some(dest_path.to_path_buf()),
+    })
+}
+
+/// Make sure the cache tree at `dep_path` exists, downloading `dep` at
+/// `commit_sha` into it (or, with `patch`, building it from the unpatched
+/// tree) if not, and record that `workspace_dir` uses it. Another vw
+/// process may be creating the same tree, so the entry is locked
+/// throughout. Returns whether the tree was already cached.
+#[allow(clippy::too_many_arguments)]
+async fn ensure_cache_entry(
+    workspace_dir: &Utf8Path,
+    name: &str,
+    dep: &Dependency,
+    commit_sha: &str,
+    dep_path: &Path,
+    patch: Option<&DependencyPatch>,
+    link: bool,
+    creds: Option<(&str, &str)>,
+    progress: Option<ProgressCallback>,
+) -> Result<bool> {
+    let _entry_lock = lock_cache_entry(dep_path).await?;
+    let was_cached = dep_path.exists();
+
+    if !was_cached {
+        let downloaded = match patch {
+            None => {
+                download_dependency(
+                    name,
+                    &dep.repo,
+                    commit_sha,
+                    dep_path,
+                    dep.submodules,
+                    creds,
+                    progress,
+                )
+                .await
+            }
+            // A patched tree is built from the unpatched one, which other
+            // dependencies on the commit can share
+            Some(patch) => {
+                let base_path = dep_path.with_file_name(cache_tree_name(
+                    &dep.repo,
+                    commit_sha,
+                    dep.submodules,
+                    None,
+                ));
+                let _base_lock = lock_cache_entry(&base_path).await?;
+                let base_downloaded = if base_path.exists() {
+                    Ok(())
+                } else {
+                    download_dependency(
+                        name,
+                        &dep.repo,
+                        commit_sha,
+                        &base_path,
+                        dep.submodules,
+                        creds,
+                        progress,
+                    )
+                    .await
+                };
+                base_downloaded.and_then(|()| {
+                    build_patched_tree(&base_path, dep_path, patch, link)
+                })
+            }
+        };
+        downloaded.map_err(|e| VwError::Dependency {
+            message: format!("Failed to download dependency '{name}': {e}"),
+        })?;
+    }
+    record_cache_use(dep_path, workspace_dir)?;
+    Ok(was_cached)
+}
+
+/// Check that the working tree of `repo` is exactly `commit`: HEAD points
+/// at it and every tracked file hashes to the blob recorded in its tree,
+/// so an interrupted or altered checkout never goes into the cache.
+fn verify_checkout(repo: &git2::Repository, commit: git2::Oid) -> Result<()> {
+    let head_oid = repo.head()?.target();
+    if head_oid != Some(commit) {
+        return Err(VwError::Git {
+            message: format!(
+                "HEAD does not point at requested commit '{commit}' after \
+                 checkout (found {})",
+                head_oid
+                    .map(|oid| oid.to_string())
+                    .unwrap_or_else(|| "no target".to_string())
+            ),
+        });
+    }
+
+    // Submodules are checked out afterwards, and untracked files can't
+    // come from the commit
+    let mut options = git2::StatusOptions::new();
+    options
+        .include_untracked(false)
+        .include_ignored(false)
+        .exclude_submodules(true);
+    let statuses = repo.statuses(Some(&mut options))?;
+    if let Some(entry) = statuses.iter().next() {
+        return Err(VwError::Git {
+            message: format!(
+                "Checked-out file '{}' does not match commit '{commit}'",
+                entry.path().unwrap_or("<non-UTF-8 path>")
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// How long to wait for another vw process working on the same cache entry.
+const CACHE_LOCK_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(600);
+
+/// Suffix of the file next to a cache entry that holds the entry's lock.
+const CACHE_LOCK_SUFFIX: &str = ".lock";
+
+/// Exclusive lock on one dependency cache entry, held while it is checked,
+/// created or removed. The lock is released when this is dropped, on every
+/// error path, and by the OS if the process dies.
+struct CacheEntryLock {
+    _file: fs::File,
+    entry_path: PathBuf,
+    lock_path: PathBuf,
+}
+
+impl Drop for CacheEntryLock {
+    fn drop(&mut self) {
+        // Don't leave a lock file behind once its entry is removed or
+        // failed to download. This runs while the lock is still held, and
+        // a process waiting on the removed file locks a new one instead.
+        if !self.entry_path.exists() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
+
+/// Lock the cache entry at `entry_path` through an advisory lock on
+/// `<entry>.lock`, waiting up to [`CACHE_LOCK_TIMEOUT`] for other vw
+/// processes to finish with it.
+async fn lock_cache_entry(entry_path: &Path) -> Result<CacheEntryLock> {
+    let started = std::time::Instant::now();
+    loop {
+        if let Some(lock) = try_lock_cache_entry(entry_path)? {
+            return Ok(lock);
+        }
+        if started.elapsed() >= CACHE_LOCK_TIMEOUT {
+            return Err(VwError::Dependency {
+                message: format!(
+                    "Timed out after {}s waiting for another vw process to \
+                     finish with cache entry {}",
+                    CACHE_LOCK_TIMEOUT.as_secs(),
+                    entry_path.display()
+                ),
+            });
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    }
+}
+
+/// Lock the cache entry at `entry_path` like [`lock_cache_entry`], or
+/// return `None` at once if another process holds the lock.
+fn try_lock_cache_entry(entry_path: &Path) -> Result<Option<CacheEntryLock>> {
+    let lock_path = sibling_path(entry_path, CACHE_LOCK_SUFFIX);
+    loop {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|e| VwError::FileSystem {
+                message: format!("Failed to open cache lock file: {e}"),
+                path: Some(lock_path.clone()),
+            })?;
+        match file.try_lock() {
+            // The holder may have removed the file before we locked it,
+            // leaving us a lock nobody else can see
+            Ok(()) if !is_same_file(&file, &lock_path) => continue,
+            Ok(()) => {
+                return Ok(Some(CacheEntryLock {
+                    _file: file,
+                    entry_path: entry_path.to_path_buf(),
+                    lock_path,
+                }))
+            }
+            Err(fs::TryLockError::WouldBlock) => return Ok(None),
+            Err(fs::TryLockError::Error(e)) => {
+                return Err(VwError::FileSystem {
+                    message: format!("Failed to lock cache entry: {e}"),
+                    path: Some(lock_path),
+                });
+            }
+        }
+    }
+}
+
+/// Whether the open `file` is still the file at `path`.
+#[cfg(unix)]
+fn is_same_file(file: &fs::File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(open), Ok(current)) => {
+            open.dev() == current.dev() && open.ino() == current.ino()
+        }
+        _ => false,
+    }
+}
+
+/// Whether the open `file` is still the file at `path`. Without inode
+/// numbers to compare, assume it is if the path exists.
+#[cfg(not(unix))]
+fn is_same_file(_file: &fs::File, path: &Path) -> bool {
+    path.exists()
+}
+
+/// Suffix of the file next to a cache entry that records which workspaces
+/// use the entry.
+const CACHE_USES_SUFFIX: &str = ".uses";
+
+/// The workspaces using a cache entry, by absolute path, each with the
+/// Unix time at which `vw update` last ran in it.
+type CacheUses = BTreeMap<String, u64>;
+
+/// Read the use record of the cache entry at `entry_path`. A missing or
+/// unreadable record counts as no recorded uses.
+fn read_cache_uses(entry_path: &Path) -> CacheUses {
+    fs::read_to_string(sibling_path(entry_path, CACHE_USES_SUFFIX))
+        .ok()
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// How `workspace_dir` is named in cache use records: its absolute path.
+fn cache_use_key(workspace_dir: &Utf8Path) -> Result<String> {
+    let workspace = fs::canonicalize(workspace_dir)
+        .or_else(|_| std::path::absolute(workspace_dir))?;
+    Ok(workspace.to_string_lossy().into_owned())
+}
+
+/// Record that `workspace_dir` used the cache entry at `entry_path` just
+/// now. The caller holds the entry's lock, so concurrent updates don't
+/// lose each other's records.
+fn record_cache_use(entry_path: &Path, workspace_dir: &Utf8Path) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let mut uses = read_cache_uses(entry_path);
+    uses.insert(cache_use_key(workspace_dir)?, now);
+
+    let uses_path = sibling_path(entry_path, CACHE_USES_SUFFIX);
+    fs::write(&uses_path, toml::to_string(&uses)?).map_err(|e| {
+        VwError::FileSystem {
+            message: format!("Failed to record cache entry use: {e}"),
+            path: Some(uses_path.clone()),
+        }
+    })
+}
+
+/// `path` with `suffix` appended to its last component.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(suffix);
+    PathBuf::from(sibling)
+}
+
+/// Retries used for git network operations when neither
+/// [`set_git_retries`] nor `VW_GIT_RETRIES` says otherwise.
+pub const DEFAULT_GIT_RETRIES: u32 = 3;
+
+/// Retry count set with [`set_git_retries`]; `u32::MAX` means unset.
+static GIT_RETRIES: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Set how many times a git connect or fetch that fails with a transient
+/// network error is retried before giving up. This takes precedence over
+/// the `VW_GIT_RETRIES` environment variable.
+pub fn set_git_retries(retries: u32) {
+    GIT_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Whether git network operations look for a proxy; see [`set_git_proxy`].
+static GIT_PROXY: AtomicBool = AtomicBool::new(true);
+
+/// Set whether git connects and fetches go through the proxy configured
+/// in git's `http.proxy` setting or the `HTTPS_PROXY`/`HTTP_PROXY`
+/// environment variables (honoring `NO_PROXY`), as they do by default.
+/// Disabling it connects directly, bypassing an inherited setting.
+pub fn set_git_proxy(enabled: bool) {
+    GIT_PROXY.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether git network operations look for a proxy, as set with
+/// [`set_git_proxy`].
+fn git_proxy_enabled() -> bool {
+    GIT_PROXY.load(Ordering::Relaxed)
+}
+
+/// Proxy options for a git connect or fetch: detected automatically if
+/// [`git_proxy_enabled`], else none.
+fn proxy_options() -> git2::ProxyOptions<'static> {
+    let mut options = git2::ProxyOptions::new();
+    if git_proxy_enabled() {
+        options.auto();
+    }
+    options
+}
+
+fn git_retries() -> u32 {
+    match GIT_RETRIES.load(Ordering::Relaxed) {
+        u32::MAX => match std::env::var("VW_GIT_RETRIES") {
+            Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                warn!(
+                    "ignoring VW_GIT_RETRIES={value:?}, which is not a \
+                     retry count; using {DEFAULT_GIT_RETRIES}"
+                );
+                DEFAULT_GIT_RETRIES
+            }),
+            Err(_) => DEFAULT_GIT_RETRIES,
+        },
+        retries => retries,
+    }
+}
+
+/// When a blocking git network operation gives up, retries included.
+///
+/// A timeout around `spawn_blocking` can't stop the thread, so the
+/// operation enforces this itself: its remote callbacks abort a transfer
+/// once the deadline passes, and no retry starts after it.
+#[derive(Clone, Copy)]
+struct GitDeadline {
+    what: &'static str,
+    started: std::time::Instant,
+    limit: std::time::Duration,
+}
+
+impl GitDeadline {
+    fn after(what: &'static str, seconds: u64) -> Self {
+        Self {
+            what,
+            started: std::time::Instant::now(),
+            limit: std::time::Duration::from_secs(seconds),
+        }
+    }
+
+    fn remaining(&self) -> std::time::Duration {
+        self.limit.saturating_sub(self.started.elapsed())
+    }
+
+    fn passed(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    fn error(&self) -> VwError {
+        VwError::Git {
+            message: format!(
+                "Git {} timed out after {} seconds",
+                self.what,
+                self.limit.as_secs()
+            ),
+        }
+    }
+}
+
+/// The HTTP status in a libgit2 error message such as "unexpected http
+/// status code: 503".
+fn http_status(message: &str) -> Option<u16> {
+    let message = message.to_ascii_lowercase();
+    let (_, rest) = message.split_once("status code: ")?;
+    rest.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Whether `err` looks like a network hiccup worth retrying, as opposed to
+/// a failure that would happen again (bad credentials, missing repository
+/// or commit, certificate problems).
+fn is_transient_git_error(err: &VwError) -> bool {
+    let VwError::Git2(e) = err else {
+        return false;
+    };
+    if matches!(
+        e.code(),
+        git2::ErrorCode::Auth
+            | git2::ErrorCode::NotFound
+            | git2::ErrorCode::Certificate
+    ) {
+        return false;
+    }
+    match e.class() {
+        git2::ErrorClass::Net => true,
+        // Server errors, timeouts and rate limiting; other responses
+        // (not found, forbidden, ...) won't go away on retry
+        git2::ErrorClass::Http => {
+            matches!(http_status(e.message()), Some(500..=599 | 408 | 429))
+        }
+        _ => false,
+    }
+}
+
+/// Run the blocking git network operation `op` against `repo_url`,
+/// retrying with exponential backoff while it fails with a transient
+/// network error and `deadline` leaves time for another attempt.
+fn with_git_retries<T>(
+    repo_url: &str,
+    deadline: GitDeadline,
+    mut op: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let retries = git_retries();
+    let mut delay = std::time::Duration::from_millis(500);
+    let mut attempt = 0;
+    loop {
+        if deadline.passed() {
+            return Err(deadline.error());
+        }
+        match op() {
+            Err(_) if deadline.passed() => return Err(deadline.error()),
+            Err(e)
+                if attempt < retries
+                    && is_transient_git_error(&e)
+                    && delay < deadline.remaining() =>
+            {
+                attempt += 1;
+                debug!(
+                    "retrying {repo_url} in {}ms (attempt {attempt} of \
+                     {retries}): {e}",
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Remote callbacks used when fetching a dependency.
+///
+/// Always set a credentials callback so git2 doesn't fail with "no callback
+/// set". The callback tries explicit credentials first, then falls back to
+/// git's credential helper system (which includes .netrc support). Transfer
+/// progress is forwarded to `progress` along with the dependency name, and
+/// the transfer is aborted once `deadline` passes.
+fn dependency_callbacks(
+    credentials: Option<(String, String)>,
+    progress: Option<(String, ProgressCallback)>,
+    deadline: GitDeadline,
+) -> git2::RemoteCallbacks<'static> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+
+    callbacks.transfer_progress(move |stats| {
+        if let Some((name, callback)) = &progress {
+            callback(
+                name,
+                TransferProgress {
+                    received_objects: stats.received_objects(),
+                    indexed_objects: stats.indexed_objects(),
+                    total_objects: stats.total_objects(),
+                    received_bytes: stats.received_bytes(),
+                },
+            );
+        }
+        !deadline.passed()
+    });
+    callbacks.sideband_progress(move |_| !deadline.passed());
+
+    let attempt_count = RefCell::new(0);
+
+    callbacks.credentials(move |url, username_from_url, allowed_types| {
+        let mut attempts = attempt_count.borrow_mut();
+        *attempts += 1;
+
+        // Limit attempts to prevent infinite loops
+        if *attempts > 1 {
+            return git2::Cred::default();
+        }
+
+        // First, try explicit credentials from netrc if available
+        if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some((ref username, ref password)) = credentials {
+                // Use both username and password from netrc
+                return git2::Cred::userpass_plaintext(username, password);
+            }
+        }
+
+        // Try SSH key if available
+        if allowed_types.contains(git2::CredentialType::SSH_KEY) {
+            if let Some(username) = username_from_url {
+                if let Ok(cred) = git2::Cred::ssh_key_from_agent(username) {
+                    return Ok(cred);
+                }
+            }
+        }
+
+        // Fall back to git's credential helper system (includes .netrc)
+        if let Ok(config) = git2::Config::open_default() {
+            if let Ok(cred) =
+                git2::Cred::credential_helper(&config, url, username_from_url)
+            {
+                return Ok(cred);
+            }
+        }
+
+        git2::Cred::default()
+    });
+
+    callbacks
+}
+
+/// Check out every submodule of `repo`, and theirs in turn, using the same
+/// credentials as the parent repository.
+fn update_submodules(
+    repo: &git2::Repository,
+    repo_url: &str,
+    credentials: &Option<(String, String)>,
+    progress: &Option<(String, ProgressCallback)>,
+    deadline: GitDeadline,
+) -> Result<()> {
+    for mut submodule in repo.submodules()? {
+        let submodule_name =
+            submodule.name().unwrap_or("<unnamed>").to_string();
+        let submodule_path = submodule.path().display().to_string();
+        let submodule_error = |e: git2::Error| VwError::Git {
+            message: format!(
+                "Failed to update submodule '{submodule_name}' \
+                 ({submodule_path}): {}",
+                e.message()
+            ),
+        };
+
+        with_git_retries(repo_url, deadline, || {
+            let mut fetch_options = git2::FetchOptions::new();
+            fetch_options.proxy_options(proxy_options());
+            fetch_options.remote_callbacks(dependency_callbacks(
+                credentials.clone(),
+                progress.clone(),
+                deadline,
+            ));
+            let mut update_options = git2::SubmoduleUpdateOptions::new();
+            update_options.fetch(fetch_options);
+            Ok(submodule.update(true, Some(&mut update_options))?)
+        })
+        .map_err(|e| match e {
+            VwError::Git2(e) => submodule_error(e),
+            e => e,
+        })?;
+        let sub_repo = submodule.open().map_err(submodule_error)?;
+        update_submodules(
+            &sub_repo,
+            repo_url,
+            credentials,
+            progress,
+            deadline,
+        )?;
+    }
+    Ok(())
+}
+
+/// The VHDL files one `src` entry selects from the tree at `repo_root`,
+/// each with its path in the dependency's layout: relative to the entry
+/// if it names a directory, its file name if it names a file, and relative
+/// to `repo_root` if it is a glob pattern.
+fn glob_vhdl_files(
+    repo_root: &Path,
+    src_pattern: &str,
+    recursive: bool,
+    exclude: &[String],
+    extensions: &[String],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    // Build patterns to match
+    let src_path = repo_root.join(src_pattern);
+    let mut patterns = Vec::new();
+    let mut match_options = glob::MatchOptions::new();
+    let strip_prefix: PathBuf;
+
+    // Compile exclude patterns
+    let exclude_patterns: Vec<glob::Pattern> = exclude
+        .iter()
+        .filter_map(|p| glob::Pattern::new(p).ok())
+        .collect();
+
+    // Check if src_pattern points to a directory
+    if src_path.is_dir() {
+        // It's a directory - create appropriate glob patterns
+        let base_pattern =
+            src_path.to_str().ok_or_else(|| VwError::FileSystem {
+                message: "Invalid UTF-8 in path".to_string(),
+                path: None,
+            })?;
+
+        // Recursively, or only files directly in the directory
+        let depth = if recursive { "/**" } else { "" };
+        for extension in extensions {
+            patterns.push(format!("{base_pattern}{depth}/*.{extension}"));
+        }
+        // Match extensions such as .VHD as well
+        match_options.case_sensitive = false;
+        // For directories, strip the src directory from paths
+        strip_prefix = src_path;
+    } else if src_path.is_file() {
+        // It's a single file - use as-is
+        patterns.push(
+            src_path
+                .to_str()
                 .ok_or_else(|| VwError::FileSystem {
                     message: "Invalid UTF-8 in path".to_string(),
+                    path: None,
+                })?
+                .to_string(),
+        );
+        // For single files, strip the parent directory
+        strip_prefix = src_path
+            .parent()
+            .ok_or_else(|| VwError::FileSystem {
+                message: "File has no parent directory".to_string(),
+                path: None,
+            })?
+            .to_path_buf();
+    } else {
+        // It's a glob pattern or doesn't exist yet - use as-is
+        patterns.push(
+            src_path
+                .to_str()
+                .ok_or_else(|| VwError::FileSystem {
+                    message: "Invalid UTF-8 in glob pattern path".to_string(),
+                    path: None,
                 })?
                 .to_string(),
         );
-        // For single files, strip the parent directory
-        strip_prefix = src_path
-            .parent()
-            .ok_or_else(|| VwError::FileSystem {
-                message: "File has no parent directory".to_string(),
-            })?
-            .to_path_buf();
+        // For glob patterns, strip the repo root to preserve relative structure
+        strip_prefix = repo_root.to_path_buf();
+    }
+
+    let mut selected = Vec::new();
+    for pattern_str in &patterns {
+        // Use glob to find matching files
+        let entries =
+            glob::glob_with(pattern_str, match_options).map_err(|e| {
+                VwError::FileSystem {
+                    message: format!(
+                        "Invalid glob pattern '{pattern_str}': {e}"
+                    ),
+                    path: None,
+                }
+            })?;
+
+        for entry in entries {
+            let path = entry.map_err(|e| VwError::FileSystem {
+                message: format!("Error reading glob entry: {e}"),
+                path: None,
+            })?;
+
+            // Only select VHDL files
+            if path.is_file() && has_vhdl_extension(&path, extensions) {
+                // Compute relative path based on strip_prefix
+                let relative_path =
+                    path.strip_prefix(&strip_prefix).map_err(|e| {
+                        VwError::FileSystem {
+                            message: format!(
+                                "Failed to compute relative path: {e}"
+                            ),
+                            path: Some(path.clone()),
+                        }
+                    })?;
+
+                // Check if file matches any exclude pattern
+                let path_str = relative_path.to_string_lossy();
+                if exclude_patterns.iter().any(|p| p.matches(&path_str)) {
+                    continue; // Skip excluded files
+                }
+
+                let layout_path = relative_path.to_path_buf();
+                selected.push((path, layout_path));
+            }
+        }
+    }
+
+    if selected.is_empty() {
+        return Err(VwError::Dependency {
+            message: format!("No VHDL files matched pattern '{src_pattern}'"),
+        });
+    }
+
+    Ok(selected)
+}
+
+/// Copy `source` to `dest`, or hardlink it if `link` is set. A file that
+/// can't be linked, e.g. because `dest` is on another filesystem, is copied
+/// instead.
+fn install_file(source: &Path, dest: &Path, link: bool) -> Result<()> {
+    if link && fs::hard_link(source, dest).is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, dest).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to copy file: {e}"),
+        path: Some(source.to_path_buf()),
+    })?;
+    Ok(())
+}
+
+/// A dependency's explicit `files` list in the tree at `repo_root`, with
+/// each file's path in the dependency's layout. The files are relative to
+/// its `src` directory, or to the repository root if it has none, and must
+/// all exist.
+fn listed_files(
+    repo_root: &Path,
+    src_paths: &[String],
+    files: &[String],
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let src_dir = match src_paths {
+        [] => repo_root.to_path_buf(),
+        [src] => repo_root.join(src),
+        _ => {
+            return Err(VwError::Dependency {
+                message: "`files` is relative to `src`, which must then name \
+                          a single directory"
+                    .to_string(),
+            })
+        }
+    };
+
+    let mut listed = Vec::new();
+    for file in files {
+        let relative = Path::new(file);
+        if !is_contained_path(relative) {
+            return Err(VwError::Dependency {
+                message: format!(
+                    "Listed file '{file}' must be a relative path inside `src`"
+                ),
+            });
+        }
+        let source = src_dir.join(relative);
+        if !source.is_file() {
+            return Err(VwError::Dependency {
+                message: format!(
+                    "Listed file '{file}' not found at {}",
+                    source.strip_prefix(repo_root).unwrap_or(&source).display()
+                ),
+            });
+        }
+
+        listed.push((source, relative.to_path_buf()));
+    }
+    Ok(listed)
+}
+
+/// The VHDL files of a dependency stored in `dir`: its explicit `files`
+/// list, in order, if it has one, otherwise the files found by
+/// [`find_vhdl_files`].
+fn dependency_files(
+    dir: &Path,
+    files: &[String],
+    recursive: bool,
+    exclude: &[String],
+    extensions: &[String],
+) -> Result<Vec<PathBuf>> {
+    if files.is_empty() {
+        return find_vhdl_files(dir, recursive, false, exclude, extensions);
+    }
+    files
+        .iter()
+        .map(|file| {
+            let path = dir.join(file);
+            if path.is_file() {
+                Ok(path)
+            } else {
+                Err(VwError::Dependency {
+                    message: format!(
+                        "Listed file '{file}' not found in {}",
+                        dir.display()
+                    ),
+                })
+            }
+        })
+        .collect()
+}
+
+/// Find the VHDL files in `dir`, and in its subdirectories if `recursive`.
+///
+/// Symlinked directories are only descended into if `follow_symlinks`;
+/// symlinked files are always included. Each directory is scanned at most
+/// once, so a symlink loop can't make the walk recurse forever.
+fn find_vhdl_files(
+    dir: &Path,
+    recursive: bool,
+    follow_symlinks: bool,
+    exclude: &[String],
+    extensions: &[String],
+) -> Result<Vec<PathBuf>> {
+    let mut vhdl_files = Vec::new();
+    find_vhdl_files_impl(
+        dir,
+        &mut vhdl_files,
+        recursive,
+        follow_symlinks,
+        extensions,
+        &mut HashSet::new(),
+    )?;
+    // read_dir order is unspecified; sort for stable generated files
+    vhdl_files.sort();
+
+    // Filter out excluded files
+    if !exclude.is_empty() {
+        let exclude_patterns: Vec<glob::Pattern> = exclude
+            .iter()
+            .filter_map(|p| glob::Pattern::new(p).ok())
+            .collect();
+
+        vhdl_files.retain(|file| {
+            // Match against path relative to the base directory
+            let relative = file.strip_prefix(dir).unwrap_or(file);
+            let path_str = relative.to_string_lossy();
+            !exclude_patterns
+                .iter()
+                .any(|pattern| pattern.matches(&path_str))
+        });
+    }
+
+    Ok(vhdl_files)
+}
+
+fn find_vhdl_files_impl(
+    dir: &Path,
+    vhdl_files: &mut Vec<PathBuf>,
+    recursive: bool,
+    follow_symlinks: bool,
+    extensions: &[String],
+    visited: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    // Canonical paths of the directories scanned so far
+    let canonical = fs::canonicalize(dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to resolve directory: {e}"),
+        path: Some(dir.to_path_buf()),
+    })?;
+    if !visited.insert(canonical) {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to read directory: {e}"),
+        path: Some(dir.to_path_buf()),
+    })? {
+        let entry = entry.map_err(|e| VwError::FileSystem {
+            message: format!("Failed to read directory entry: {e}"),
+            path: None,
+        })?;
+        let path = entry.path();
+        let is_symlink = entry
+            .file_type()
+            .map_err(|e| VwError::FileSystem {
+                message: format!("Failed to read file type: {e}"),
+                path: Some(path.clone()),
+            })?
+            .is_symlink();
+
+        if path.is_dir() {
+            if recursive && (follow_symlinks || !is_symlink) {
+                find_vhdl_files_impl(
+                    &path,
+                    vhdl_files,
+                    recursive,
+                    follow_symlinks,
+                    extensions,
+                    visited,
+                )?;
+            }
+        } else if has_vhdl_extension(&path, extensions) {
+            vhdl_files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` ends in one of `extensions`, ignoring case.
+fn has_vhdl_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| is_listed(extensions, ext))
+}
+
+fn write_lock_file(
+    workspace_dir: &Utf8Path,
+    lock_file: &LockFile,
+) -> Result<()> {
+    let toml_content = toml::to_string_pretty(lock_file)?;
+    let lock_path = workspace_dir.join("vw.lock");
+
+    fs::write(&lock_path, toml_content).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to write vw.lock file: {e}"),
+        path: Some(lock_path.clone().into()),
+    })?;
+
+    Ok(())
+}
+
+fn write_vhdl_ls_config(
+    workspace_dir: &Utf8Path,
+    managed_config: &VhdlLsConfig,
+) -> Result<()> {
+    let mut existing_config = load_existing_vhdl_ls_config(workspace_dir)?;
+
+    if managed_config.standard.is_some() {
+        existing_config.standard = managed_config.standard.clone();
+    }
+
+    // Dependency files placed in `defaultlib` by earlier runs are replaced
+    // like any other managed library, keeping the workspace's own sources
+    let managed_dirs = managed_source_dirs(workspace_dir)?;
+    if let Some(defaultlib) = existing_config.libraries.get_mut("defaultlib") {
+        let had_files = !defaultlib.files.is_empty();
+        defaultlib.files.retain(|file| {
+            let resolved = resolve_library_file(workspace_dir, file)
+                .and_then(|file| Ok(std::path::absolute(file)?));
+            !resolved.is_ok_and(|file| {
+                let file = clean_path(&file);
+                managed_dirs.iter().any(|dir| file.starts_with(dir))
+            })
+        });
+        if had_files && defaultlib.files.is_empty() {
+            existing_config.libraries.remove("defaultlib");
+        }
+    }
+
+    // Remove any existing managed dependencies and add the new ones. File
+    // lists are kept as given: discovered files are already sorted, and an
+    // explicit `files` list is in compile order
+    for (name, library) in &managed_config.libraries {
+        // Drop entries written before library names were normalized, such
+        // as `my-lib` for what is now `my_lib`
+        existing_config.libraries.retain(|existing, _| {
+            existing == name || nvc_library_name(existing) != *name
+        });
+        match existing_config.libraries.get_mut(name) {
+            // Dependencies placed in `defaultlib` join the workspace's own
+            // sources rather than replacing them
+            Some(existing) if name == "defaultlib" => {
+                for file in &library.files {
+                    if !existing.files.contains(file) {
+                        existing.files.push(file.clone());
+                    }
+                }
+            }
+            _ => {
+                existing_config
+                    .libraries
+                    .insert(name.clone(), library.clone());
+            }
+        }
+    }
+
+    let toml_content = toml::to_string_pretty(&existing_config)?;
+    let config_path = workspace_dir.join("vhdl_ls.toml");
+
+    fs::write(&config_path, toml_content).map_err(|e| VwError::FileSystem {
+        message: format!("Failed to write vhdl_ls.toml file: {e}"),
+        path: Some(config_path.clone().into()),
+    })?;
+
+    Ok(())
+}
+
+/// Directories whose files vw writes into `vhdl_ls.toml` for dependencies:
+/// the dependency cache and the vendored copies recorded in `vw.lock`.
+fn managed_source_dirs(workspace_dir: &Utf8Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![deps_directory()?];
+    if let Ok(lock_file) = load_lock_file(workspace_dir) {
+        dirs.extend(
+            lock_file
+                .dependencies
+                .values()
+                .filter_map(|locked| locked.vendored.as_ref())
+                .map(|vendored| workspace_dir.as_std_path().join(vendored)),
+        );
+    }
+    dirs.into_iter()
+        .map(|dir| Ok(clean_path(&std::path::absolute(dir)?)))
+        .collect()
+}
+
+/// `path` with `.` components dropped and each `..` applied to the
+/// component before it, without consulting the filesystem.
+fn clean_path(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                cleaned.pop();
+            }
+            component => cleaned.push(component),
+        }
+    }
+    cleaned
+}
+
+/// Build a Rust library for a testbench.
+/// Looks for Cargo.toml in the testbench directory, builds it, and returns the path to the .so file.
+async fn build_rust_library(
+    bench_dir: &Utf8Path,
+    testbench_file: &Path,
+) -> Result<PathBuf> {
+    // Get the testbench directory
+    let testbench_dir =
+        testbench_file.parent().ok_or_else(|| VwError::Testbench {
+            message: format!(
+                "Testbench file {:?} has no parent directory???",
+                testbench_file
+            ),
+        })?;
+
+    // Look for Cargo.toml in the testbench directory
+    let cargo_toml_path = testbench_dir.join("Cargo.toml");
+    if !cargo_toml_path.exists() {
+        return Err(VwError::Testbench {
+            message: format!(
+                "Cargo.toml not found in testbench directory: {:?}",
+                testbench_dir
+            ),
+        });
+    }
+
+    // Parse Cargo.toml to get the package name
+    let cargo_toml_content =
+        fs::read_to_string(&cargo_toml_path).map_err(|e| {
+            VwError::FileSystem {
+                message: format!("Failed to read Cargo.toml: {e}"),
+                path: Some(cargo_toml_path.clone()),
+            }
+        })?;
+
+    let cargo_toml: CargoToml = toml::from_str(&cargo_toml_content)?;
+    let package_name = cargo_toml.package.name;
+
+    // Run cargo build in the testbench directory
+    let testbench_dir_owned = testbench_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let output = std::process::Command::new("cargo")
+            .arg("build")
+            .current_dir(&testbench_dir_owned)
+            .output()
+            .map_err(|e| VwError::Testbench {
+                message: format!("Failed to execute cargo build: {e}"),
+            })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(VwError::Testbench {
+                message: format!("cargo build failed:\n{stderr}"),
+            });
+        }
+
+        Ok::<(), VwError>(())
+    })
+    .await
+    .map_err(|e| VwError::Testbench {
+        message: format!("Failed to execute cargo build task: {e}"),
+    })??;
+
+    // Find the .so file in the workspace target directory (parent of testbench dir)
+    let ext = if cfg!(target_os = "macos") {
+        "dylib"
     } else {
-        // It's a glob pattern or doesn't exist yet - use as-is
-        patterns.push(
-            src_path
-                .to_str()
-                .ok_or_else(|| VwError::FileSystem {
-                    message: "Invalid UTF-8 in glob pattern path".to_string(),
-                })?
-                .to_string(),
+        "so"
+    };
+    let lib_name = format!("lib{}.{ext}", package_name.replace('-', "_"));
+    let workspace_target = bench_dir.join("target").join("debug");
+
+    let lib_path = workspace_target.join(&lib_name);
+
+    if !lib_path.exists() {
+        return Err(VwError::Testbench {
+            message: format!(
+                "Built Rust library not found at expected path: {:?}",
+                lib_path
+            ),
+        });
+    }
+
+    Ok(lib_path.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `files` into the working tree of `repo` and commit them on top
+    /// of HEAD, returning the new commit.
+    fn commit_files(
+        repo: &git2::Repository,
+        files: &[(&str, &str)],
+        message: &str,
+    ) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, contents) in files {
+            let file = workdir.join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, contents).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("vw", "vw@example.com").unwrap();
+        let parent =
+            repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_checkout_accepts_clean_checkout() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let commit = commit_files(
+            &repo,
+            &[("hdl/uart_pkg.vhd", "package uart_pkg is\nend package;\n")],
+            "Add uart_pkg",
+        );
+        repo.set_head_detached(commit).unwrap();
+
+        verify_checkout(&repo, commit).unwrap();
+    }
+
+    #[test]
+    fn verify_checkout_rejects_tampered_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let commit = commit_files(
+            &repo,
+            &[("hdl/uart_pkg.vhd", "package uart_pkg is\nend package;\n")],
+            "Add uart_pkg",
+        );
+        repo.set_head_detached(commit).unwrap();
+        fs::write(
+            dir.path().join("hdl/uart_pkg.vhd"),
+            "package uart_pkg is\n  constant evil : integer := 1;\nend package;\n",
+        )
+        .unwrap();
+
+        let err = verify_checkout(&repo, commit).unwrap_err();
+        assert!(err.to_string().contains("hdl/uart_pkg.vhd"), "{err}");
+    }
+
+    #[test]
+    fn verify_checkout_rejects_other_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let first = commit_files(&repo, &[("a.vhd", "-- a\n")], "First");
+        let second = commit_files(&repo, &[("b.vhd", "-- b\n")], "Second");
+        repo.set_head_detached(second).unwrap();
+
+        let err = verify_checkout(&repo, first).unwrap_err();
+        assert!(err.to_string().contains("HEAD does not point"), "{err}");
+    }
+
+    /// A bare repository in `dir` with two commits of `hdl/counter.vhd`,
+    /// returning its path and the two commits, oldest first.
+    fn bare_remote(dir: &Path) -> (PathBuf, git2::Oid, git2::Oid) {
+        let work = git2::Repository::init(dir.join("work")).unwrap();
+        let first =
+            commit_files(&work, &[("hdl/counter.vhd", "-- first\n")], "first");
+        let second = commit_files(
+            &work,
+            &[("hdl/counter.vhd", "-- second\n")],
+            "second",
+        );
+        let remote = dir.join("remote.git");
+        git2::build::RepoBuilder::new()
+            .bare(true)
+            .clone(work.path().to_str().unwrap(), &remote)
+            .unwrap();
+        (remote, first, second)
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_of_one_entry_download_it_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let workspace =
+            Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let dep: Dependency = toml::from_str(&format!(
+            "repo = '{}'\ncommit = \"{first}\"",
+            remote.display()
+        ))
+        .unwrap();
+        let commit = first.to_string();
+        let cache = dir.path().join("cache");
+        fs::create_dir_all(&cache).unwrap();
+        let dep_path =
+            cache.join(cache_tree_name(&dep.repo, &commit, false, None));
+
+        let update = || {
+            ensure_cache_entry(
+                &workspace, "counter", &dep, &commit, &dep_path, None, false,
+                None, None,
+            )
+        };
+        let (a, b) = tokio::join!(update(), update());
+        let mut was_cached = [a.unwrap(), b.unwrap()];
+        was_cached.sort();
+        assert_eq!(was_cached, [false, true]);
+        assert_eq!(
+            fs::read_to_string(dep_path.join("hdl/counter.vhd")).unwrap(),
+            "-- first\n"
+        );
+        assert!(!sibling_path(&dep_path, ".partial").exists());
+        assert_eq!(read_cache_uses(&dep_path).len(), 1);
+
+        // A failed download leaves neither a tree nor its lock file
+        let missing = "0".repeat(40);
+        let missing_path =
+            cache.join(cache_tree_name(&dep.repo, &missing, false, None));
+        ensure_cache_entry(
+            &workspace,
+            "counter",
+            &dep,
+            &missing,
+            &missing_path,
+            None,
+            false,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(!missing_path.exists());
+        assert!(!sibling_path(&missing_path, CACHE_LOCK_SUFFIX).exists());
+    }
+
+    #[test]
+    fn only_network_errors_and_retryable_http_statuses_are_transient() {
+        let error = |code, class, message| {
+            VwError::Git2(git2::Error::new(code, class, message))
+        };
+        let generic = git2::ErrorCode::GenericError;
+        let http = git2::ErrorClass::Http;
+
+        assert!(is_transient_git_error(&error(
+            generic,
+            git2::ErrorClass::Net,
+            "failed to connect: Connection refused"
+        )));
+        assert!(is_transient_git_error(&error(
+            generic,
+            http,
+            "unexpected http status code: 503"
+        )));
+        assert!(is_transient_git_error(&error(
+            generic,
+            http,
+            "unexpected HTTP status code: 429"
+        )));
+        assert!(!is_transient_git_error(&error(
+            generic,
+            http,
+            "unexpected http status code: 404"
+        )));
+        assert!(!is_transient_git_error(&error(
+            generic,
+            http,
+            "too many redirects or authentication replays"
+        )));
+        assert!(!is_transient_git_error(&error(
+            generic,
+            git2::ErrorClass::Os,
+            "failed to make directory: Permission denied"
+        )));
+        assert!(!is_transient_git_error(&error(
+            git2::ErrorCode::Auth,
+            git2::ErrorClass::Net,
+            "authentication required"
+        )));
+    }
+
+    #[tokio::test]
+    async fn lightweight_and_annotated_tags_resolve_to_their_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let repo = git2::Repository::open_bare(&remote).unwrap();
+        let target = repo.find_object(first, None).unwrap();
+        repo.tag_lightweight("v1-light", &target, false).unwrap();
+        let signature = git2::Signature::now("vw", "vw@example.com").unwrap();
+        let tag = repo.tag("v1", &target, &signature, "v1", false).unwrap();
+        assert_ne!(tag, first);
+        let url = remote.to_str().unwrap();
+
+        let refs = list_remote_refs(url, None).await.unwrap();
+        let oid_of = |name: &str| {
+            refs.heads
+                .iter()
+                .find(|(head, _)| head == name)
+                .map(|(_, oid)| oid.clone())
+        };
+        assert_eq!(oid_of("refs/tags/v1-light"), Some(first.to_string()));
+        assert_eq!(oid_of("refs/tags/v1"), Some(first.to_string()));
+        assert_eq!(oid_of("refs/tags/v1^{}"), None);
+
+        for sha in [first.to_string(), tag.to_string()] {
+            assert_eq!(
+                resolve_commit_sha(url, &sha, None).await.unwrap(),
+                first.to_string()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn download_dependency_fetches_a_commit_behind_the_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let dest = dir.path().join("cache").join("counter");
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+
+        download_dependency(
+            "counter",
+            remote.to_str().unwrap(),
+            &first.to_string(),
+            &dest,
+            false,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("hdl/counter.vhd")).unwrap(),
+            "-- first\n"
+        );
+        assert!(!dest.join(".git").exists());
+    }
+
+    #[test]
+    fn extract_hostname_drops_ports() {
+        for (url, host) in [
+            (
+                "https://git.example.com:8443/org/repo.git",
+                "git.example.com",
+            ),
+            (
+                "ssh://git@git.example.com:2222/org/repo.git",
+                "git.example.com",
+            ),
+            ("git@git.example.com:2222/org/repo.git", "git.example.com"),
+            ("git@github.com:org/repo.git", "github.com"),
+        ] {
+            assert_eq!(extract_hostname_from_repo_url(url).unwrap(), host);
+        }
+    }
+
+    #[tokio::test]
+    async fn generated_configs_are_byte_identical_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = Utf8Path::from_path(dir.path()).unwrap();
+        let names = ["uart", "spi", "i2c", "axi", "fifo", "osvvm"];
+        let managed = VhdlLsConfig {
+            standard: Some("2019".to_string()),
+            libraries: names
+                .iter()
+                .map(|name| {
+                    let library = VhdlLsLibrary {
+                        files: vec![
+                            PathBuf::from(format!("{name}/b.vhd")),
+                            PathBuf::from(format!("{name}/a.vhd")),
+                        ],
+                        exclude: None,
+                        is_third_party: Some(true),
+                        vhdl_standard: None,
+                        analysis_flags: Vec::new(),
+                    };
+                    (name.to_string(), library)
+                })
+                .collect(),
+            lint: None,
+        };
+
+        // Writing the config looks up the dependency cache
+        let _env = ENV_LOCK.lock().await;
+        std::env::set_var("VW_DEPS_DIR", dir.path().join("cache"));
+        let write = || -> Result<Vec<u8>> {
+            write_vhdl_ls_config(workspace, &managed)?;
+            Ok(fs::read(workspace.join("vhdl_ls.toml"))?)
+        };
+        let (first, second) = (write(), write());
+        std::env::remove_var("VW_DEPS_DIR");
+        assert_eq!(first.unwrap(), second.unwrap());
+
+        // Each map is built in its own order with its own hash seed
+        let deps = |names: &mut dyn Iterator<Item = &&str>| {
+            names
+                .map(|name| {
+                    (
+                        name.to_string(),
+                        vec![PathBuf::from(format!("{name}.vhd"))],
+                    )
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            format_deps_tcl(&deps(&mut names.iter())),
+            format_deps_tcl(&deps(&mut names.iter().rev()))
+        );
+    }
+
+    #[test]
+    fn deps_tcl_quotes_paths_with_spaces() {
+        let deps = HashMap::from([(
+            "uart".to_string(),
+            vec![
+                PathBuf::from("uart-0123/my ip/uart pkg.vhd"),
+                PathBuf::from(TCL_WORKSPACE_DIR_VAR).join("vendor/$x [y].vhd"),
+            ],
+        )]);
+        let tcl = format_deps_tcl(&deps);
+        assert!(
+            tcl.contains(
+                "    \"$vw_deps_dir/uart-0123/my ip/uart pkg.vhd\" \\\n"
+            ),
+            "{tcl}"
+        );
+        assert!(
+            tcl.contains("    \"$vw_workspace_dir/vendor/\\$x \\[y\\].vhd\"\n"),
+            "{tcl}"
+        );
+    }
+
+    #[test]
+    fn circular_package_use_names_both_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a_pkg.vhd");
+        let b = dir.path().join("b_pkg.vhd");
+        fs::write(
+            &a,
+            "use work.b_pkg.all;\n\
+             package a_pkg is\n  constant A : integer := 1;\nend package;\n",
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            "use work.a_pkg.all;\n\
+             package b_pkg is\n  constant B : integer := 2;\nend package;\n",
+        )
+        .unwrap();
+
+        let mut files = vec![a.clone(), b.clone()];
+        let err = sort_files_by_dependencies(
+            &mut RecordProcessor::new(VhdlStandard::Vhdl2019),
+            &mut files,
+            &mut FileCache::new(),
+        )
+        .unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("Circular dependency"), "{message}");
+        assert!(message.contains(&a.display().to_string()), "{message}");
+        assert!(message.contains(&b.display().to_string()), "{message}");
+    }
+
+    #[test]
+    fn preloaded_scans_match_file_by_file_scans() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..32)
+            .map(|i| {
+                let path = dir.path().join(format!("unit_{i}.vhd"));
+                let next = (i + 1) % 32;
+                fs::write(
+                    &path,
+                    format!(
+                        "use work.pkg_{next}.all;\n\
+                         package pkg_{i} is\nend package;\n\
+                         entity ent_{i} is\nend entity;\n\
+                         architecture rtl of ent_{i} is\nbegin\n\
+                         u : entity work.ent_{next};\nend architecture;\n"
+                    ),
+                )
+                .unwrap();
+                path
+            })
+            .collect();
+
+        // With the `parallel` feature, preload scans on the rayon pool
+        let mut preloaded = FileCache::new();
+        preloaded.preload(&files);
+        let mut serial = FileCache::new();
+        for file in &files {
+            assert_eq!(
+                format!("{:?}", preloaded.get_dependencies(file).unwrap()),
+                format!("{:?}", serial.get_dependencies(file).unwrap())
+            );
+            assert_eq!(
+                format!("{:?}", preloaded.get_provided_symbols(file).unwrap()),
+                format!("{:?}", serial.get_provided_symbols(file).unwrap())
+            );
+        }
+        assert_eq!(preloaded.dependencies.len(), files.len());
+        assert_eq!(preloaded.provided_symbols.len(), files.len());
+    }
+
+    #[test]
+    fn every_referenced_file_names_the_symbol_that_pulled_it_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        };
+        let tb = write(
+            "tb_top.vhd",
+            "use work.util_pkg.all;\n\
+             entity tb_top is\nend entity;\n\
+             architecture sim of tb_top is\nbegin\n\
+             dut : entity work.fifo;\nend architecture;\n",
+        );
+        let util = write("util_pkg.vhd", "package util_pkg is\nend package;\n");
+        let fifo = write(
+            "fifo.vhd",
+            "use work.mem_pkg.all;\nentity fifo is\nend entity;\n",
+        );
+        let mem = write("mem_pkg.vhd", "package mem_pkg is\nend package;\n");
+        let unused =
+            write("unused_pkg.vhd", "package unused_pkg is\nend package;\n");
+
+        let (mut files, provenance) = find_referenced_files_with_provenance(
+            &tb,
+            &[util.clone(), fifo.clone(), mem.clone(), unused],
+            &mut FileCache::new(),
+        )
+        .unwrap();
+        files.sort();
+        let mut expected = vec![util.clone(), fifo.clone(), mem.clone()];
+        expected.sort();
+        assert_eq!(files, expected);
+
+        for (file, symbol, referenced_by) in [
+            (&util, "util_pkg", &tb),
+            (&fifo, "fifo", &tb),
+            (&mem, "mem_pkg", &fifo),
+        ] {
+            let inclusion = &provenance[file];
+            assert_eq!(inclusion.symbol.name, symbol);
+            assert_eq!(&inclusion.referenced_by, referenced_by);
+        }
+        assert_eq!(provenance.len(), 3);
+    }
+
+    #[test]
+    fn testbenches_are_found_under_the_configured_bench_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = Utf8Path::from_path(dir.path()).unwrap();
+        fs::write(
+            workspace.join("vw.toml"),
+            "[workspace]\nname = \"scratch\"\nversion = \"0.1.0\"\n\
+             bench_dir = \"sim\"\n[dependencies]\n",
+        )
+        .unwrap();
+
+        // Configured but not created yet
+        let err = bench_directory(workspace).unwrap_err();
+        assert!(matches!(err, VwError::Config { .. }), "{err}");
+        assert!(err.to_string().contains("bench_dir 'sim'"), "{err}");
+
+        let sim = workspace.join("sim");
+        fs::create_dir_all(&sim).unwrap();
+        fs::write(
+            sim.join("tb_top.vhd"),
+            "entity tb_top is\nend entity;\n\
+             architecture sim of tb_top is\nbegin\nend architecture;\n",
+        )
+        .unwrap();
+        let bench_dir = bench_directory(workspace).unwrap();
+        assert_eq!(bench_dir, sim);
+
+        let extensions: Vec<String> = DEFAULT_VHDL_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect();
+        let names: Vec<String> = list_testbenches(
+            &bench_dir,
+            &HashSet::new(),
+            false,
+            &[],
+            VhdlStandard::Vhdl2008,
+            &extensions,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|tb| tb.name)
+        .collect();
+        assert_eq!(names, ["tb_top"]);
+    }
+
+    #[test]
+    fn a_listed_helper_is_neither_a_testbench_nor_filtered_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = Utf8Path::from_path(dir.path()).unwrap();
+        let bench = workspace.join("bench");
+        fs::create_dir_all(&bench).unwrap();
+        fs::write(
+            workspace.join("vw.toml"),
+            "[workspace]\nname = \"scratch\"\nversion = \"0.1.0\"\n\
+             not_testbenches = [\"stimulus_gen_tb\"]\n[dependencies]\n",
+        )
+        .unwrap();
+        fs::write(
+            workspace.join("vhdl_ls.toml"),
+            "[libraries.defaultlib]\nfiles = [\"bench/tb_top.vhd\", \
+             \"bench/stimulus_gen_tb.vhd\", \"bench/tb_other.vhd\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            bench.join("tb_top.vhd"),
+            "entity tb_top is\nend entity;\n\
+             architecture sim of tb_top is\nbegin\n\
+             stim : entity work.stimulus_gen_tb;\nend architecture;\n",
+        )
+        .unwrap();
+        fs::write(
+            bench.join("stimulus_gen_tb.vhd"),
+            "entity stimulus_gen_tb is\nend entity;\n\
+             architecture sim of stimulus_gen_tb is\nbegin\nend architecture;\n",
+        )
+        .unwrap();
+        fs::write(
+            bench.join("tb_other.vhd"),
+            "entity tb_other is\nend entity;\n\
+             architecture sim of tb_other is\nbegin\n\
+             stim : entity work.stimulus_gen_tb;\nend architecture;\n",
+        )
+        .unwrap();
+        let not_testbenches = vec!["stimulus_gen_tb".to_string()];
+        let extensions: Vec<String> = DEFAULT_VHDL_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect();
+
+        let mut names: Vec<String> = list_testbenches(
+            &bench,
+            &HashSet::new(),
+            false,
+            &not_testbenches,
+            VhdlStandard::Vhdl2008,
+            &extensions,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|tb| tb.name)
+        .collect();
+        names.sort();
+        assert_eq!(names, ["tb_other", "tb_top"]);
+
+        let sources =
+            compile_order(workspace, "tb_top", VhdlStandard::Vhdl2008, false)
+                .unwrap();
+        assert_eq!(sources.testbench_file, bench.join("tb_top.vhd"));
+        assert_eq!(
+            sources.files,
+            vec![bench.join("stimulus_gen_tb.vhd").into_std_path_buf()]
+        );
+    }
+
+    #[test]
+    fn a_missing_testbench_is_explained_by_what_the_bench_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let bench = Utf8Path::from_path(dir.path()).unwrap().join("bench");
+        fs::create_dir_all(&bench).unwrap();
+        let extensions: Vec<String> = DEFAULT_VHDL_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect();
+        let find = |name: &str| {
+            find_testbench_file(
+                name,
+                &bench,
+                false,
+                &[],
+                VhdlStandard::Vhdl2008,
+                &extensions,
+                &mut HashMap::new(),
+            )
+            .unwrap_err()
+            .to_string()
+        };
+
+        let empty = find("tb_uart");
+        assert!(empty.contains("contains no testbenches"), "{empty}");
+
+        for name in ["tb_uart", "tb_spi"] {
+            fs::write(
+                bench.join(format!("{name}.vhd")),
+                format!("entity {name} is\nend entity;\n"),
+            )
+            .unwrap();
+        }
+        let wrong_name = find("tb_i2c");
+        assert!(
+            wrong_name.contains("Available testbenches: tb_spi, tb_uart"),
+            "{wrong_name}"
+        );
+        assert!(!wrong_name.contains("no testbenches"), "{wrong_name}");
+    }
+
+    #[test]
+    fn a_relative_cover_dir_is_resolved_against_the_workspace() {
+        let workspace = Utf8Path::new("/work/ws");
+        let coverage = |dir: Option<&str>| CoverageOptions {
+            kinds: Vec::new(),
+            report_dir: dir.map(Utf8PathBuf::from),
+        };
+
+        assert_eq!(
+            coverage(None).report_dir(workspace, "tb_top"),
+            Utf8Path::new("/work/ws/vw_build/coverage/tb_top")
+        );
+        assert_eq!(
+            coverage(Some("cov")).report_dir(workspace, "tb_top"),
+            Utf8Path::new("/work/ws/cov")
+        );
+        assert_eq!(
+            coverage(Some("/tmp/cov")).report_dir(workspace, "tb_top"),
+            Utf8Path::new("/tmp/cov")
         );
-        // For glob patterns, strip the repo root to preserve relative structure
-        strip_prefix = repo_root.to_path_buf();
     }
 
-    let mut copied_count = 0;
-    for pattern_str in &patterns {
-        // Use glob to find matching files
-        let entries =
-            glob::glob(pattern_str).map_err(|e| VwError::FileSystem {
-                message: format!("Invalid glob pattern '{pattern_str}': {e}"),
-            })?;
+    #[test]
+    fn a_relative_wave_dir_is_resolved_against_the_workspace() {
+        let workspace = Utf8Path::new("/work/ws");
+        let wave = |dir: Option<&str>| WaveOptions {
+            format: WaveFormat::Vcd,
+            dir: dir.map(Utf8PathBuf::from),
+        };
 
-        for entry in entries {
-            let path = entry.map_err(|e| VwError::FileSystem {
-                message: format!("Error reading glob entry: {e}"),
-            })?;
+        assert_eq!(
+            wave(None).path(workspace, "tb_top"),
+            Utf8Path::new("/work/ws/tb_top.vcd")
+        );
+        assert_eq!(
+            wave(Some("waves")).path(workspace, "tb_top"),
+            Utf8Path::new("/work/ws/waves/tb_top.vcd")
+        );
+        assert_eq!(
+            wave(Some("/tmp/waves")).path(workspace, "tb_top"),
+            Utf8Path::new("/tmp/waves/tb_top.vcd")
+        );
+    }
 
-            // Only copy VHDL files
-            if path.is_file() {
-                if let Some(ext) = path.extension() {
-                    if ext == "vhd" || ext == "vhdl" {
-                        // Compute relative path based on strip_prefix
-                        let relative_path =
-                            path.strip_prefix(&strip_prefix).map_err(|e| {
-                                VwError::FileSystem {
-                                    message: format!(
-                                    "Failed to compute relative path for {path:?}: {e}"
-                                ),
-                                }
-                            })?;
-
-                        // Check if file matches any exclude pattern
-                        let path_str = relative_path.to_string_lossy();
-                        if exclude_patterns.iter().any(|p| p.matches(&path_str))
-                        {
-                            continue; // Skip excluded files
-                        }
+    #[test]
+    fn uppercase_extensions_are_discovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let hdl = dir.path().join("hdl");
+        fs::create_dir_all(hdl.join("sub")).unwrap();
+        fs::write(hdl.join("Foo.VHD"), "").unwrap();
+        fs::write(hdl.join("sub/Bar.Vhdl"), "").unwrap();
+        fs::write(hdl.join("README.TXT"), "").unwrap();
+        let extensions: Vec<String> = DEFAULT_VHDL_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect();
 
-                        let dest_file = dest.join(relative_path);
-
-                        // Create parent directories if needed
-                        if let Some(parent) = dest_file.parent() {
-                            fs::create_dir_all(parent).map_err(|e| {
-                                VwError::FileSystem {
-                                    message: format!(
-                                        "Failed to create directory {parent:?}: {e}"
-                                    ),
-                                }
-                            })?;
-                        }
+        let mut found =
+            find_vhdl_files(&hdl, true, false, &[], &extensions).unwrap();
+        found.sort();
+        assert_eq!(found, vec![hdl.join("Foo.VHD"), hdl.join("sub/Bar.Vhdl")]);
 
-                        fs::copy(&path, &dest_file).map_err(|e| {
-                            VwError::FileSystem {
-                                message: format!(
-                                    "Failed to copy file {path:?}: {e}"
-                                ),
-                            }
-                        })?;
-                        copied_count += 1;
-                    }
-                }
-            }
-        }
+        let mut globbed: Vec<PathBuf> =
+            glob_vhdl_files(dir.path(), "hdl", true, &[], &extensions)
+                .unwrap()
+                .into_iter()
+                .map(|(_, layout)| layout)
+                .collect();
+        globbed.sort();
+        assert_eq!(
+            globbed,
+            vec![PathBuf::from("Foo.VHD"), PathBuf::from("sub/Bar.Vhdl")]
+        );
     }
 
-    if copied_count == 0 {
-        return Err(VwError::Dependency {
-            message: format!("No VHDL files matched pattern '{src_pattern}'"),
-        });
+    #[cfg(unix)]
+    #[test]
+    fn a_self_referential_symlink_is_scanned_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let bench = dir.path().join("bench");
+        let shared = dir.path().join("shared");
+        fs::create_dir_all(bench.join("sub")).unwrap();
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(bench.join("tb_top.vhd"), "").unwrap();
+        fs::write(bench.join("sub/tb_sub.vhd"), "").unwrap();
+        fs::write(shared.join("util.vhd"), "").unwrap();
+        std::os::unix::fs::symlink("..", bench.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink(&shared, bench.join("shared")).unwrap();
+        let extensions: Vec<String> = DEFAULT_VHDL_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect();
+        let workspace = Utf8Path::from_path(dir.path()).unwrap();
+        fs::write(
+            workspace.join("vw.toml"),
+            "[workspace]\nname = \"scratch\"\nversion = \"0.1.0\"\n\
+             follow_symlinks = true\n[dependencies]\n",
+        )
+        .unwrap();
+        assert!(follow_symlinks(workspace));
+
+        let followed = find_vhdl_files(
+            &bench,
+            true,
+            follow_symlinks(workspace),
+            &[],
+            &extensions,
+        )
+        .unwrap();
+        assert_eq!(
+            followed,
+            vec![
+                bench.join("shared/util.vhd"),
+                bench.join("sub/tb_sub.vhd"),
+                bench.join("tb_top.vhd"),
+            ]
+        );
+
+        // Symlinks are skipped unless the option is set
+        fs::remove_file(workspace.join("vw.toml")).unwrap();
+        let unfollowed = find_vhdl_files(
+            &bench,
+            true,
+            follow_symlinks(workspace),
+            &[],
+            &extensions,
+        )
+        .unwrap();
+        assert_eq!(
+            unfollowed,
+            vec![bench.join("sub/tb_sub.vhd"), bench.join("tb_top.vhd")]
+        );
     }
 
-    Ok(())
-}
+    #[cfg(unix)]
+    #[test]
+    fn linked_patched_trees_share_only_unpatched_files() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("base");
+        let dest = dir.path().join("patched");
+        fs::create_dir_all(base.join("hdl")).unwrap();
+        fs::write(base.join("hdl/kept.vhd"), "-- kept\n").unwrap();
+        fs::write(base.join("hdl/fixed.vhd"), "-- broken\n").unwrap();
+        let contents = b"diff --git a/hdl/fixed.vhd b/hdl/fixed.vhd
+--- a/hdl/fixed.vhd
++++ b/hdl/fixed.vhd
+@@ -1 +1 @@
+--- broken
++-- fixed
+"
+        .to_vec();
+        let patch = DependencyPatch {
+            path: PathBuf::from("fix.patch"),
+            hash: format!("{:016x}", fnv1a_hash(&contents)),
+            contents,
+        };
 
-fn find_vhdl_files(
-    dir: &Path,
-    recursive: bool,
-    exclude: &[String],
-) -> Result<Vec<PathBuf>> {
-    let mut vhdl_files = Vec::new();
-    find_vhdl_files_impl(dir, &mut vhdl_files, recursive)?;
+        build_patched_tree(&base, &dest, &patch, true).unwrap();
 
-    // Filter out excluded files
-    if !exclude.is_empty() {
-        let exclude_patterns: Vec<glob::Pattern> = exclude
-            .iter()
-            .filter_map(|p| glob::Pattern::new(p).ok())
-            .collect();
+        let inode = |path: PathBuf| {
+            let metadata = fs::metadata(path).unwrap();
+            (metadata.dev(), metadata.ino())
+        };
+        assert_eq!(
+            inode(dest.join("hdl/kept.vhd")),
+            inode(base.join("hdl/kept.vhd"))
+        );
+        assert_ne!(
+            inode(dest.join("hdl/fixed.vhd")),
+            inode(base.join("hdl/fixed.vhd"))
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("hdl/fixed.vhd")).unwrap(),
+            "-- fixed\n"
+        );
+        assert_eq!(
+            fs::read_to_string(base.join("hdl/fixed.vhd")).unwrap(),
+            "-- broken\n"
+        );
+    }
 
-        vhdl_files.retain(|file| {
-            // Match against path relative to the base directory
-            let relative = file.strip_prefix(dir).unwrap_or(file);
-            let path_str = relative.to_string_lossy();
-            !exclude_patterns
-                .iter()
-                .any(|pattern| pattern.matches(&path_str))
-        });
+    /// Held by tests that set environment variables or other process-wide
+    /// settings, such as pointing `VW_DEPS_DIR` at a cache of their own.
+    static ENV_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Update a workspace under `dir` whose `vw.toml` holds `dependencies`,
+    /// caching trees in `dir/cache`.
+    async fn update_scratch_workspace(
+        dir: &Path,
+        dependencies: &str,
+    ) -> (Utf8PathBuf, Result<UpdateResult>) {
+        let _env = ENV_LOCK.lock().await;
+        let workspace =
+            Utf8PathBuf::from_path_buf(dir.join("workspace")).unwrap();
+        fs::create_dir_all(&workspace).unwrap();
+        fs::write(
+            workspace.join("vw.toml"),
+            format!(
+                "[workspace]\nname = \"scratch\"\nversion = \"0.1.0\"\n\
+                 {dependencies}"
+            ),
+        )
+        .unwrap();
+
+        std::env::set_var("VW_DEPS_DIR", dir.join("cache"));
+        let result = update_workspace(&workspace).await;
+        std::env::remove_var("VW_DEPS_DIR");
+        (workspace, result)
     }
 
-    Ok(vhdl_files)
-}
+    fn read_vhdl_ls_config(workspace: &Utf8Path) -> VhdlLsConfig {
+        let toml = fs::read_to_string(workspace.join("vhdl_ls.toml")).unwrap();
+        toml::from_str(&toml).unwrap()
+    }
 
-fn find_vhdl_files_impl(
-    dir: &Path,
-    vhdl_files: &mut Vec<PathBuf>,
-    recursive: bool,
-) -> Result<()> {
-    for entry in fs::read_dir(dir).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to read directory: {e}"),
-    })? {
-        let entry = entry.map_err(|e| VwError::FileSystem {
-            message: format!("Failed to read directory entry: {e}"),
-        })?;
-        let path = entry.path();
+    #[tokio::test]
+    async fn dependencies_on_the_same_commit_share_one_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"
+[dependencies.counter]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+
+[dependencies.counter-file]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl/counter.vhd"]
+"#,
+                remote = remote.display()
+            ),
+        )
+        .await;
+        let result = result.unwrap();
 
-        if path.is_dir() {
-            if recursive {
-                find_vhdl_files_impl(&path, vhdl_files, recursive)?;
-            }
-        } else if let Some(extension) =
-            path.extension().and_then(|ext| ext.to_str())
-        {
-            if extension == "vhd" || extension == "vhdl" {
-                vhdl_files.push(path);
-            }
+        let cache = dir.path().join("cache");
+        let trees: Vec<PathBuf> = fs::read_dir(&cache)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect();
+        assert_eq!(trees.len(), 1, "{trees:?}");
+        let lock = load_lock_file(&workspace).unwrap();
+        assert_eq!(
+            lock.dependencies["counter"].path,
+            lock.dependencies["counter-file"].path
+        );
+        assert!(trees[0].ends_with(&lock.dependencies["counter"].path));
+        for info in &result.dependencies {
+            assert_eq!(info.files, vec![PathBuf::from("counter.vhd")]);
         }
     }
-    Ok(())
-}
 
-fn write_lock_file(
-    workspace_dir: &Utf8Path,
-    lock_file: &LockFile,
-) -> Result<()> {
-    let toml_content = toml::to_string_pretty(lock_file)?;
-    let lock_path = workspace_dir.join("vw.lock");
+    #[tokio::test]
+    async fn resolve_only_locks_every_dependency_without_downloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, second) = bare_remote(dir.path());
+        let branch = git2::Repository::open_bare(&remote)
+            .unwrap()
+            .head()
+            .unwrap()
+            .shorthand()
+            .unwrap()
+            .to_string();
+        let workspace =
+            Utf8PathBuf::from_path_buf(dir.path().join("workspace")).unwrap();
+        fs::create_dir_all(&workspace).unwrap();
+        fs::write(
+            workspace.join("vw.toml"),
+            format!(
+                r#"
+[workspace]
+name = "scratch"
+version = "0.1.0"
+
+[dependencies.counter]
+repo = '{remote}'
+branch = "{branch}"
+src = ["hdl"]
+
+[dependencies.counter-old]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+"#,
+                remote = remote.display()
+            ),
+        )
+        .unwrap();
+
+        let _env = ENV_LOCK.lock().await;
+        let cache = dir.path().join("cache");
+        std::env::set_var("VW_DEPS_DIR", &cache);
+        let result = resolve_only(&workspace, None).await;
+        std::env::remove_var("VW_DEPS_DIR");
+        let lock = result.unwrap();
+
+        assert_eq!(lock.dependencies.len(), 2);
+        assert_eq!(lock.dependencies["counter"].commit, second.to_string());
+        assert_eq!(
+            lock.dependencies["counter"].branch.as_deref(),
+            Some(branch.as_str())
+        );
+        assert_eq!(lock.dependencies["counter-old"].commit, first.to_string());
+        assert_eq!(load_lock_file(&workspace).unwrap().dependencies.len(), 2);
+        assert!(!cache.exists());
+        assert!(!workspace.join("vhdl_ls.toml").exists());
+    }
 
-    fs::write(&lock_path, toml_content).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to write vw.lock file: {e}"),
-    })?;
+    #[tokio::test]
+    async fn vendoring_keeps_the_standard_and_writes_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"vhdl_standard = "2008"
+
+[dependencies.counter]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+"#,
+                remote = remote.display()
+            ),
+        )
+        .await;
+        result.unwrap();
+
+        let _env = ENV_LOCK.lock().await;
+        std::env::set_var("VW_DEPS_DIR", dir.path().join("cache"));
+        let result = vendor_dependencies(&workspace, Utf8Path::new("vendor"));
+        std::env::remove_var("VW_DEPS_DIR");
+        let vendored = result.unwrap();
+
+        assert_eq!(vendored.len(), 1);
+        assert_eq!(vendored[0].path, PathBuf::from("vendor/counter"));
+        assert_eq!(
+            fs::read_to_string(workspace.join("vendor/counter/counter.vhd"))
+                .unwrap(),
+            "-- first\n"
+        );
+        let config = read_vhdl_ls_config(&workspace);
+        assert_eq!(config.standard.as_deref(), Some("2008"));
+        assert_eq!(
+            config.libraries["counter"].files,
+            vec![PathBuf::from("vendor/counter/counter.vhd")]
+        );
+        assert_eq!(
+            load_lock_file(&workspace).unwrap().dependencies["counter"]
+                .vendored,
+            Some(PathBuf::from("vendor/counter"))
+        );
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn vivado_script_reads_dependencies_in_compile_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = git2::Repository::init(dir.path().join("work")).unwrap();
+        let commit = commit_files(
+            &work,
+            &[
+                (
+                    "alpha/a_user.vhd",
+                    "library zeta;\nuse zeta.zeta_pkg.all;\n\
+                     use work.z_types.all;\n\
+                     entity a_user is\nend entity;\n",
+                ),
+                ("alpha/z_types.vhd", "package z_types is\nend package;\n"),
+                ("zeta/zeta_pkg.vhd", "package zeta_pkg is\nend package;\n"),
+            ],
+            "alpha and zeta",
+        );
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"
+[dependencies.alpha]
+repo = '{remote}'
+commit = "{commit}"
+src = ["alpha"]
+
+[dependencies.zeta]
+repo = '{remote}'
+commit = "{commit}"
+src = ["zeta"]
+"#,
+                remote = work.path().parent().unwrap().display()
+            ),
+        )
+        .await;
+        result.unwrap();
+
+        let _env = ENV_LOCK.lock().await;
+        std::env::set_var("VW_DEPS_DIR", dir.path().join("cache"));
+        let scripts =
+            [VhdlStandard::Vhdl2008, VhdlStandard::Vhdl1993].map(|vhdl_std| {
+                let out = Utf8Path::new("read_deps.tcl");
+                generate_vivado_script(&workspace, out, vhdl_std).unwrap();
+                fs::read_to_string(workspace.join(out)).unwrap()
+            });
+        std::env::remove_var("VW_DEPS_DIR");
+
+        // zeta is read first because alpha uses it, and within alpha the
+        // package comes before the entity that uses it
+        let reads = |script: &str| -> Vec<String> {
+            script
+                .lines()
+                .filter(|line| line.starts_with("read_vhdl "))
+                .map(|line| {
+                    let (flags, path) = line.rsplit_once(' ').unwrap();
+                    let file = path.trim_matches('"').rsplit('/').next();
+                    format!("{flags} {}", file.unwrap())
+                })
+                .collect()
+        };
+        assert_eq!(
+            reads(&scripts[0]),
+            [
+                "read_vhdl -vhdl2008 -library zeta zeta_pkg.vhd",
+                "read_vhdl -vhdl2008 -library alpha z_types.vhd",
+                "read_vhdl -vhdl2008 -library alpha a_user.vhd",
+            ]
+        );
+        assert_eq!(
+            reads(&scripts[1]),
+            [
+                "read_vhdl -library zeta zeta_pkg.vhd",
+                "read_vhdl -library alpha z_types.vhd",
+                "read_vhdl -library alpha a_user.vhd",
+            ]
+        );
+        assert!(scripts[0].contains("\"$vw_deps_dir/"), "{}", scripts[0]);
+    }
 
-fn write_vhdl_ls_config(
-    workspace_dir: &Utf8Path,
-    managed_config: &VhdlLsConfig,
-) -> Result<()> {
-    let mut existing_config = load_existing_vhdl_ls_config(workspace_dir)?;
+    #[tokio::test]
+    async fn dependency_libraries_are_marked_third_party() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"
+[dependencies.counter]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+
+[dependencies.counter-dev]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+lint = true
+"#,
+                remote = remote.display()
+            ),
+        )
+        .await;
+        result.unwrap();
 
-    // Remove any existing managed dependencies and add the new ones
-    for (name, library) in &managed_config.libraries {
-        existing_config
-            .libraries
-            .insert(name.clone(), library.clone());
+        let config = read_vhdl_ls_config(&workspace);
+        assert_eq!(config.libraries["counter"].is_third_party, Some(true));
+        assert_eq!(config.libraries["counter_dev"].is_third_party, None);
     }
 
-    let toml_content = toml::to_string_pretty(&existing_config)?;
-    let config_path = workspace_dir.join("vhdl_ls.toml");
-
-    fs::write(&config_path, toml_content).map_err(|e| VwError::FileSystem {
-        message: format!("Failed to write vhdl_ls.toml file: {e}"),
-    })?;
+    #[tokio::test]
+    async fn excluded_files_stay_out_of_the_dependency_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = git2::Repository::init(dir.path().join("work")).unwrap();
+        let commit = commit_files(
+            &work,
+            &[
+                ("hdl/counter.vhd", "-- counter\n"),
+                ("hdl/sim/tb_counter.vhd", "-- bench\n"),
+            ],
+            "counter",
+        );
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"
+[dependencies.counter]
+repo = '{remote}'
+commit = "{commit}"
+src = ["hdl"]
+recursive = true
+exclude = ["**/tb_*.vhd"]
+"#,
+                remote = work.path().parent().unwrap().display()
+            ),
+        )
+        .await;
+        let result = result.unwrap();
 
-    Ok(())
-}
+        assert_eq!(
+            result.dependencies[0].files,
+            vec![PathBuf::from("counter.vhd")]
+        );
+        let library = &read_vhdl_ls_config(&workspace).libraries["counter"];
+        assert_eq!(library.files.len(), 1, "{:?}", library.files);
+        assert!(library.files[0].ends_with("hdl/counter.vhd"));
+        let exclude = library.exclude.as_deref().unwrap();
+        assert_eq!(exclude.len(), 1);
+        assert!(exclude[0].ends_with("hdl/**/tb_*.vhd"), "{exclude:?}");
+    }
 
-/// Build a Rust library for a testbench.
-/// Looks for Cargo.toml in the testbench directory, builds it, and returns the path to the .so file.
-async fn build_rust_library(
-    bench_dir: &Utf8Path,
-    testbench_file: &Path,
-) -> Result<PathBuf> {
-    // Get the testbench directory
-    let testbench_dir =
-        testbench_file.parent().ok_or_else(|| VwError::Testbench {
-            message: format!(
-                "Testbench file {:?} has no parent directory???",
-                testbench_file
+    #[tokio::test]
+    async fn a_vhdl_93_dependency_keeps_its_standard_under_a_2019_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, _second) = bare_remote(dir.path());
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"
+[dependencies.legacy]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+files = ["counter.vhd"]
+vhdl_standard = "93"
+
+[dependencies.counter]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+files = ["counter.vhd"]
+"#,
+                remote = remote.display()
             ),
-        })?;
+        )
+        .await;
+        result.unwrap();
 
-    // Look for Cargo.toml in the testbench directory
-    let cargo_toml_path = testbench_dir.join("Cargo.toml");
-    if !cargo_toml_path.exists() {
-        return Err(VwError::Testbench {
-            message: format!(
-                "Cargo.toml not found in testbench directory: {:?}",
-                testbench_dir
-            ),
-        });
+        let config = read_vhdl_ls_config(&workspace);
+        assert_eq!(
+            config.libraries["legacy"].vhdl_standard,
+            Some(VhdlStandard::Vhdl1993)
+        );
+        assert_eq!(config.libraries["counter"].vhdl_standard, None);
+
+        let default_std = vhdl_standard(&workspace, None).unwrap();
+        assert_eq!(default_std, VhdlStandard::Vhdl2019);
+        let builds = ext_library_builds(
+            &workspace,
+            &config,
+            &mut RecordProcessor::new(default_std),
+            default_std,
+            &mut FileCache::new(),
+        )
+        .unwrap();
+        let std_of = |name: &str| {
+            builds
+                .iter()
+                .find(|build| build.name == name)
+                .unwrap()
+                .vhdl_std
+        };
+        assert_eq!(std_of("legacy"), VhdlStandard::Vhdl1993);
+        assert_eq!(std_of("counter"), VhdlStandard::Vhdl2019);
     }
 
-    // Parse Cargo.toml to get the package name
-    let cargo_toml_content =
-        fs::read_to_string(&cargo_toml_path).map_err(|e| {
-            VwError::FileSystem {
-                message: format!("Failed to read Cargo.toml: {e}"),
-            }
-        })?;
+    #[tokio::test]
+    async fn dependencies_naming_one_library_are_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, second) = bare_remote(dir.path());
+        let (workspace, result) = update_scratch_workspace(
+            dir.path(),
+            &format!(
+                r#"
+[dependencies.counter-old]
+repo = '{remote}'
+commit = "{first}"
+src = ["hdl"]
+library = "shared-ip"
+
+[dependencies.counter-new]
+repo = '{remote}'
+commit = "{second}"
+src = ["hdl"]
+library = "shared-ip"
+"#,
+                remote = remote.display()
+            ),
+        )
+        .await;
+        result.unwrap();
 
-    let cargo_toml: CargoToml = toml::from_str(&cargo_toml_content)?;
-    let package_name = cargo_toml.package.name;
+        let config = read_vhdl_ls_config(&workspace);
+        let names: Vec<&String> = config.libraries.keys().collect();
+        assert_eq!(names, ["shared_ip"]);
+        let mut contents: Vec<String> = config.libraries["shared_ip"]
+            .files
+            .iter()
+            .map(|file| {
+                let file = resolve_library_file(&workspace, file).unwrap();
+                fs::read_to_string(file).unwrap()
+            })
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["-- first\n", "-- second\n"]);
+    }
 
-    // Run cargo build in the testbench directory
-    let testbench_dir_owned = testbench_dir.to_path_buf();
-    tokio::task::spawn_blocking(move || {
-        let output = std::process::Command::new("cargo")
-            .arg("build")
-            .current_dir(&testbench_dir_owned)
-            .output()
-            .map_err(|e| VwError::Testbench {
-                message: format!("Failed to execute cargo build: {e}"),
-            })?;
+    #[tokio::test]
+    async fn defaultlib_dependency_files_are_replaced_on_update() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, first, second) = bare_remote(dir.path());
+        let dependency = |commit: git2::Oid| {
+            format!(
+                r#"
+[dependencies.counter]
+repo = '{remote}'
+commit = "{commit}"
+src = ["hdl"]
+library = "defaultlib"
+"#,
+                remote = remote.display()
+            )
+        };
+        let workspace = dir.path().join("workspace");
+        fs::create_dir_all(&workspace).unwrap();
+        fs::write(
+            workspace.join("vhdl_ls.toml"),
+            "[libraries.defaultlib]\nfiles = [\"src/top.vhd\"]\n",
+        )
+        .unwrap();
+
+        let (workspace, result) =
+            update_scratch_workspace(dir.path(), &dependency(first)).await;
+        result.unwrap();
+        let (_, result) =
+            update_scratch_workspace(dir.path(), &dependency(second)).await;
+        result.unwrap();
+
+        let files =
+            &read_vhdl_ls_config(&workspace).libraries["defaultlib"].files;
+        assert_eq!(files.len(), 2, "{files:?}");
+        assert_eq!(files[0], PathBuf::from("src/top.vhd"));
+        let counter = resolve_library_file(&workspace, &files[1]).unwrap();
+        assert_eq!(fs::read_to_string(counter).unwrap(), "-- second\n");
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(VwError::Testbench {
-                message: format!("cargo build failed:\n{stderr}"),
-            });
+    #[tokio::test]
+    async fn the_first_configured_source_with_credentials_wins() {
+        let _env = ENV_LOCK.lock().await;
+        let dir = tempfile::tempdir().unwrap();
+        let netrc = dir.path().join("netrc");
+        fs::write(
+            &netrc,
+            "machine example.com login from-netrc password secret\n",
+        )
+        .unwrap();
+        let url = "https://example.com/org/repo.git";
+        let login = |sources: Vec<CredentialSource>| {
+            let auth = AuthConfig {
+                sources,
+                ..AuthConfig::default()
+            };
+            get_access_credentials(url, &auth)
+                .unwrap()
+                .map(|creds| creds.username)
+        };
+
+        let old_netrc = std::env::var_os("NETRC");
+        std::env::set_var("NETRC", &netrc);
+        std::env::set_var("VW_GIT_USERNAME", "from-env");
+        std::env::set_var("VW_GIT_PASSWORD", "secret");
+        let env_first =
+            login(vec![CredentialSource::Env, CredentialSource::Netrc]);
+        let netrc_first =
+            login(vec![CredentialSource::Netrc, CredentialSource::Env]);
+        std::env::remove_var("VW_GIT_USERNAME");
+        std::env::remove_var("VW_GIT_PASSWORD");
+        match old_netrc {
+            Some(old_netrc) => std::env::set_var("NETRC", old_netrc),
+            None => std::env::remove_var("NETRC"),
         }
 
-        Ok::<(), VwError>(())
-    })
-    .await
-    .map_err(|e| VwError::Testbench {
-        message: format!("Failed to execute cargo build task: {e}"),
-    })??;
+        assert_eq!(env_first.as_deref(), Some("from-env"));
+        assert_eq!(netrc_first.as_deref(), Some("from-netrc"));
+    }
 
-    // Find the .so file in the workspace target directory (parent of testbench dir)
-    let ext = if cfg!(target_os = "macos") {
-        "dylib"
-    } else {
-        "so"
-    };
-    let lib_name = format!("lib{}.{ext}", package_name.replace('-', "_"));
-    let workspace_target = bench_dir.join("target").join("debug");
+    #[tokio::test]
+    async fn cache_size_counts_entries_held_by_another_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        for (name, contents) in [("held", "0123456789"), ("free", "0123")] {
+            fs::create_dir_all(cache.join(name)).unwrap();
+            fs::write(cache.join(name).join("pkg.vhd"), contents).unwrap();
+        }
+        fs::create_dir_all(cache.join("download.partial")).unwrap();
+        let _held = try_lock_cache_entry(&cache.join("held")).unwrap().unwrap();
+
+        let _env = ENV_LOCK.lock().await;
+        std::env::set_var("VW_DEPS_DIR", &cache);
+        let stats = cache_size();
+        std::env::remove_var("VW_DEPS_DIR");
+        let stats = stats.unwrap();
+
+        assert_eq!(stats.total_bytes, 14);
+        assert_eq!(stats.in_use, 1);
+        let paths: Vec<&PathBuf> =
+            stats.entries.iter().map(|entry| &entry.path).collect();
+        assert_eq!(paths, [&cache.join("held"), &cache.join("free")]);
+    }
 
-    let lib_path = workspace_target.join(&lib_name);
+    #[tokio::test]
+    async fn netrc_env_var_takes_precedence_over_the_home_directory() {
+        let _env = ENV_LOCK.lock().await;
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        fs::create_dir_all(&home).unwrap();
+        let netrc = |login: &str| {
+            format!("machine example.com login {login} password secret\n")
+        };
+        fs::write(home.join(".netrc"), netrc("dot")).unwrap();
+        fs::write(home.join("_netrc"), netrc("underscore")).unwrap();
+        let custom = dir.path().join("custom-netrc");
+        fs::write(&custom, netrc("custom")).unwrap();
+
+        let login = || {
+            get_access_credentials_from_netrc("example.com")
+                .unwrap()
+                .map(|creds| creds.username)
+        };
+        let old_home = std::env::var_os("HOME");
+        std::env::set_var("HOME", &home);
+        std::env::set_var("NETRC", &custom);
+        let from_env = login();
+        std::env::set_var("NETRC", dir.path().join("missing-netrc"));
+        let missing = get_access_credentials_from_netrc("example.com");
+        std::env::remove_var("NETRC");
+        let from_dot = login();
+        fs::remove_file(home.join(".netrc")).unwrap();
+        let from_underscore = login();
+        match old_home {
+            Some(old_home) => std::env::set_var("HOME", old_home),
+            None => std::env::remove_var("HOME"),
+        }
 
-    if !lib_path.exists() {
-        return Err(VwError::Testbench {
-            message: format!(
-                "Built Rust library not found at expected path: {:?}",
-                lib_path
-            ),
-        });
+        assert_eq!(from_env.as_deref(), Some("custom"));
+        let err = missing.unwrap_err();
+        assert!(err.to_string().contains("$NETRC"), "{err}");
+        assert!(err.to_string().contains("missing-netrc"), "{err}");
+        assert_eq!(from_dot.as_deref(), Some("dot"));
+        assert_eq!(from_underscore.as_deref(), Some("underscore"));
     }
 
-    Ok(lib_path.into())
+    #[tokio::test]
+    async fn no_proxy_turns_off_proxy_detection() {
+        let _env = ENV_LOCK.lock().await;
+        assert!(git_proxy_enabled());
+        set_git_proxy(false);
+        let disabled = git_proxy_enabled();
+        set_git_proxy(true);
+        assert!(!disabled);
+        assert!(git_proxy_enabled());
+    }
+
+    #[test]
+    fn libraries_for_target_leaves_sim_only_out_of_synthesis() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = Utf8Path::from_path(dir.path()).unwrap();
+        fs::write(
+            workspace.join("vw.lock"),
+            r#"
+[dependencies.uart]
+repo = "https://github.com/example/uart"
+commit = "1111111111111111111111111111111111111111"
+path = "uart-1111111"
+
+[dependencies.osvvm]
+repo = "https://github.com/OSVVM/OSVVM"
+commit = "2222222222222222222222222222222222222222"
+path = "osvvm-2222222"
+sim_only = true
+
+[dependencies.vunit-lib]
+repo = "https://github.com/VUnit/vunit"
+commit = "3333333333333333333333333333333333333333"
+path = "vunit-3333333"
+sim_only = true
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            libraries_for_target(workspace, BuildTarget::Synthesis).unwrap(),
+            vec!["uart"]
+        );
+        assert_eq!(
+            libraries_for_target(workspace, BuildTarget::Simulation).unwrap(),
+            vec!["osvvm", "uart", "vunit_lib"]
+        );
+    }
 }
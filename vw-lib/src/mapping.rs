@@ -1,12 +1,13 @@
 use vhdl_lang::ast::{
     AnyDesignUnit, AnyPrimaryUnit, AttributeSpecification, Designator,
     DiscreteRange, ElementDeclaration, EntityClass, EntityDeclaration,
-    EntityName, Expression, Name, ObjectClass, ObjectDeclaration,
-    PackageDeclaration, PackageInstantiation, Range, RangeConstraint,
-    SubtypeConstraint, TypeDeclaration, TypeDefinition,
+    EntityName, EnumerationLiteral, Expression, Name, ObjectClass,
+    ObjectDeclaration, PackageDeclaration, PackageInstantiation, Range,
+    RangeConstraint, SubtypeConstraint, TypeDeclaration, TypeDefinition,
 };
 
 use crate::visitor::{Visitor, VisitorResult};
+use crate::VwError;
 
 #[derive(Debug, Clone)]
 pub struct ConstantExpr {
@@ -22,6 +23,35 @@ pub struct RecordFields {
 #[derive(Debug, Clone)]
 pub struct EnumAttrs {
     pub has_custom_encoding: bool,
+    /// Literals in declaration order. Character literals keep their quotes
+    /// (e.g. `'0'`) so they can be told apart from identifiers.
+    pub literals: Vec<String>,
+}
+
+impl EnumAttrs {
+    /// Number of bits needed to encode every literal, `ceil(log2(n))`.
+    pub fn bit_width(&self) -> u32 {
+        match self.literals.len() {
+            0 | 1 => 0,
+            n => usize::BITS - (n - 1).leading_zeros(),
+        }
+    }
+
+    /// Literals usable as Rust enum variants.
+    ///
+    /// Errors if any literal is a character literal rather than a plain
+    /// identifier, since those have no sensible variant name.
+    pub fn variant_names(&self, enum_name: &str) -> Result<&[String], VwError> {
+        if let Some(lit) = self.literals.iter().find(|l| l.starts_with('\'')) {
+            return Err(VwError::CodeGen {
+                message: format!(
+                    "Enum '{enum_name}' has literal {lit}, but only plain \
+                     identifiers can be mapped to Rust enum variants"
+                ),
+            });
+        }
+        Ok(&self.literals)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -101,6 +131,8 @@ impl FileData {
 pub struct FieldData {
     pub name: String,
     pub subtype_name: String,
+    /// Index range of an array field, or value range of a scalar field
+    /// such as `integer range 0 to 255`.
     pub constraint: Option<RangeConstraint>,
 }
 
@@ -109,6 +141,7 @@ pub struct VwSymbolFinder {
     symbols: Vec<VwSymbol>,
     tagged_types: Vec<String>,
     target_attr: String,
+    error: Option<VwError>,
 }
 
 impl VwSymbolFinder {
@@ -117,6 +150,7 @@ impl VwSymbolFinder {
             symbols: Vec::new(),
             tagged_types: Vec::new(),
             target_attr: target_attr.to_string(),
+            error: None,
         }
     }
 
@@ -127,6 +161,15 @@ impl VwSymbolFinder {
     pub fn get_tagged_types(&self) -> &Vec<String> {
         &self.tagged_types
     }
+
+    /// Take the error that stopped traversal, if any.
+    ///
+    /// Visitor callbacks can't return a `Result`, so the finder records the
+    /// first failure and stops the walk; callers should check this after
+    /// `walk_design_file` returns.
+    pub fn take_error(&mut self) -> Option<VwError> {
+        self.error.take()
+    }
 }
 
 impl Visitor for VwSymbolFinder {
@@ -160,7 +203,7 @@ impl Visitor for VwSymbolFinder {
         }
 
         // if we found the attribute with the right name
-        if attr_name == self.target_attr {
+        if attr_name.eq_ignore_ascii_case(&self.target_attr) {
             // if we tagged a type (like a record)
             match spec.entity_class {
                 EntityClass::Type | EntityClass::Constant => {
@@ -188,7 +231,6 @@ impl Visitor for VwSymbolFinder {
     ) -> VisitorResult {
         // if this is a constant Declaration
         if let ObjectClass::Constant = decl.class {
-            let const_name = decl.idents[0].tree.item.name_utf8();
             // where was this constant defined
             let def_pkg_name = if let AnyDesignUnit::Primary(
                 AnyPrimaryUnit::Package(package),
@@ -203,14 +245,18 @@ impl Visitor for VwSymbolFinder {
             let expr = decl.expression.as_ref().map(|span| span.item.clone());
             let type_name = decl.subtype_indication.type_mark.item.clone();
 
-            self.symbols.push(VwSymbol::new(
-                def_pkg_name,
-                &const_name,
-                SymbolKind::Constant(ConstantExpr {
-                    type_name,
-                    expression: expr,
-                }),
-            ));
+            // `constant a, b : t := x` declares each name with the same
+            // type and value
+            for ident in &decl.idents {
+                self.symbols.push(VwSymbol::new(
+                    def_pkg_name.clone(),
+                    &ident.tree.item.name_utf8(),
+                    SymbolKind::Constant(ConstantExpr {
+                        type_name: type_name.clone(),
+                        expression: expr.clone(),
+                    }),
+                ));
+            }
         }
 
         VisitorResult::Continue
@@ -238,19 +284,35 @@ impl Visitor for VwSymbolFinder {
 
         match &decl.def {
             TypeDefinition::Record(elements) => {
-                let fields = get_fields(elements);
+                let fields = match get_fields(&name, elements) {
+                    Ok(fields) => fields,
+                    Err(e) => {
+                        self.error = Some(e);
+                        return VisitorResult::Stop;
+                    }
+                };
                 self.symbols.push(VwSymbol::new(
                     defining_pkg_name,
                     &name,
                     SymbolKind::Record(RecordFields { fields }),
                 ));
             }
-            TypeDefinition::Enumeration(_) => {
+            TypeDefinition::Enumeration(enum_literals) => {
+                let literals = enum_literals
+                    .iter()
+                    .map(|literal| match &literal.tree.item {
+                        EnumerationLiteral::Identifier(id) => id.name_utf8(),
+                        EnumerationLiteral::Character(c) => {
+                            format!("'{}'", *c as char)
+                        }
+                    })
+                    .collect();
                 self.symbols.push(VwSymbol::new(
                     defining_pkg_name,
                     &name,
                     SymbolKind::Enum(EnumAttrs {
                         has_custom_encoding: false,
+                        literals,
                     }),
                 ));
             }
@@ -284,10 +346,15 @@ impl Visitor for VwSymbolFinder {
     }
 }
 
-fn get_fields(elements: &Vec<ElementDeclaration>) -> Vec<FieldData> {
+fn get_fields(
+    record_name: &str,
+    elements: &Vec<ElementDeclaration>,
+) -> Result<Vec<FieldData>, VwError> {
     let mut fields = Vec::new();
 
     for element in elements {
+        // A declaration like `a, b, c : t` introduces several fields that
+        // share one subtype; name the first in any error we report
         let element_name = element.idents[0].tree.item.name_utf8();
         let element_subtype = if let Name::Designator(designator) =
             &element.subtype.type_mark.item
@@ -299,39 +366,132 @@ fn get_fields(elements: &Vec<ElementDeclaration>) -> Vec<FieldData> {
             }
         } else {
             None
-            // panic here for now, because i want to see what struct differences there
-            // might be
         }
-        .unwrap();
+        .ok_or_else(|| VwError::CodeGen {
+            message: format!(
+                "Unsupported subtype for field '{element_name}' of record \
+                 '{record_name}': only simple type names are supported"
+            ),
+        })?;
 
         let element_constraint = element
             .subtype
             .constraint
             .as_ref()
-            .map(|constraint| get_range_constraint(&constraint.item));
-
-        fields.push(FieldData {
-            name: element_name,
-            subtype_name: element_subtype,
-            constraint: element_constraint,
-        });
+            .map(|constraint| {
+                get_range_constraint(
+                    record_name,
+                    &element_name,
+                    &constraint.item,
+                )
+            })
+            .transpose()?;
+
+        for ident in &element.idents {
+            fields.push(FieldData {
+                name: ident.tree.item.name_utf8(),
+                subtype_name: element_subtype.clone(),
+                constraint: element_constraint.clone(),
+            });
+        }
     }
 
-    fields
+    Ok(fields)
 }
 
-fn get_range_constraint(constraint: &SubtypeConstraint) -> RangeConstraint {
-    if let SubtypeConstraint::Array(array_range, _) = constraint {
-        if let DiscreteRange::Range(discrete_range) = &array_range[0].item {
-            if let Range::Range(constraint) = discrete_range {
-                constraint.clone()
-            } else {
-                panic!("We don't handle other range types")
-            }
-        } else {
-            panic!("We don't handle other DiscreteRange types");
+fn get_range_constraint(
+    record_name: &str,
+    field_name: &str,
+    constraint: &SubtypeConstraint,
+) -> Result<RangeConstraint, VwError> {
+    let unsupported = |what: &str| VwError::CodeGen {
+        message: format!(
+            "Unsupported constraint on field '{field_name}' of record \
+             '{record_name}': {what}"
+        ),
+    };
+
+    let range = match constraint {
+        // `integer range 0 to 255`
+        SubtypeConstraint::Range(range) => range,
+        // `std_logic_vector(7 downto 0)`
+        SubtypeConstraint::Array(array_range, _) => {
+            let Some(first_range) = array_range.first() else {
+                return Err(unsupported("array constraint has no ranges"));
+            };
+            let DiscreteRange::Range(discrete_range) = &first_range.item else {
+                return Err(unsupported(
+                    "only explicit discrete ranges are supported",
+                ));
+            };
+            discrete_range
+        }
+        _ => {
+            return Err(unsupported(
+                "only range and array constraints are supported",
+            ))
+        }
+    };
+    let Range::Range(constraint) = range else {
+        return Err(unsupported(
+            "only 'to'/'downto' ranges are supported, not range attributes",
+        ));
+    };
+
+    Ok(constraint.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use vhdl_lang::VHDLParser;
+
+    use super::*;
+    use crate::vhdl_printer::range_constraint_to_string;
+    use crate::visitor::walk_design_file;
+    use crate::VhdlStandard;
+
+    #[test]
+    fn each_identifier_of_an_element_declaration_is_a_field() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("regs_pkg.vhd");
+        std::fs::write(
+            &path,
+            "library ieee;
+            use ieee.std_logic_1164.all;
+            package regs_pkg is
+                type regs_t is record
+                    a, b, c : std_logic_vector(7 downto 0);
+                    valid : std_logic;
+                end record;
+            end package;",
+        )
+        .unwrap();
+        let parser = VHDLParser::new(VhdlStandard::Vhdl2008.into());
+        let mut diagnostics = Vec::new();
+        let (_, design_file) =
+            parser.parse_design_file(&path, &mut diagnostics).unwrap();
+
+        let mut finder = VwSymbolFinder::new("serialize_rust");
+        walk_design_file(&mut finder, &design_file);
+        assert!(finder.take_error().is_none());
+
+        let record = finder
+            .get_symbols()
+            .iter()
+            .find(|symbol| symbol.get_name() == "regs_t")
+            .unwrap();
+        let fields = record.get_fields().unwrap();
+        let names: Vec<&str> =
+            fields.iter().map(|field| field.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c", "valid"]);
+        for field in &fields[..3] {
+            assert_eq!(field.subtype_name, "std_logic_vector");
+            let constraint = field.constraint.as_ref().unwrap();
+            assert_eq!(
+                range_constraint_to_string(constraint).unwrap(),
+                "7 downto 0"
+            );
         }
-    } else {
-        panic!("We don't handle other constraint types");
+        assert!(fields[3].constraint.is_none());
     }
 }
@@ -1,29 +1,62 @@
-use crate::{VhdlStandard, VwError};
+use crate::{CoverageKind, VhdlStandard, VwError, WaveFormat};
 
-use tokio::process::Command;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+};
 
 use std::{
     io::Write,
-    process::{ExitStatus, Output},
+    process::{ExitStatus, Output, Stdio},
 };
 
+/// NVC heap size (`-M`) used when none is configured.
+pub const DEFAULT_HEAP_SIZE: &str = "256m";
+
+/// How the output of an `nvc` invocation is shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NvcOutput {
+    /// Pass output straight through to the terminal.
+    #[default]
+    Inherit,
+    /// Stream output line by line, each line prefixed with the library or
+    /// testbench name in brackets.
+    Prefixed,
+    /// Buffer output and only print it, to stderr, if the command fails.
+    Quiet,
+    /// Buffer output and return it to the caller; it is also printed if
+    /// the command fails.
+    Capture,
+}
+
 fn get_base_nvc_cmd_args(
     std: VhdlStandard,
     build_dir: &str,
     lib_name: &str,
+    heap_size: &str,
 ) -> Vec<String> {
     let lib_dir = build_dir.to_owned() + "/" + lib_name;
     let args = vec![
         format!("--std={std}"),
         format!("--work={lib_dir}"),
         "-M".to_string(),
-        "256m".to_string(),
+        heap_size.to_owned(),
         "-L".to_string(),
         build_dir.to_owned(),
     ];
     args
 }
 
+fn nvc_spawn_error(e: std::io::Error) -> VwError {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        VwError::NvcNotFound
+    } else {
+        VwError::Testbench {
+            message: format!("nvc command failed : {e}"),
+        }
+    }
+}
+
 async fn run_cmd_w_output(
     args: &Vec<String>,
     envs: Option<&Vec<(String, String)>>,
@@ -39,9 +72,7 @@ async fn run_cmd_w_output(
         }
     }
 
-    nvc_cmd.output().await.map_err(|e| VwError::Testbench {
-        message: format!("nvc command failed : {e}"),
-    })
+    nvc_cmd.output().await.map_err(nvc_spawn_error)
 }
 
 async fn run_cmd(
@@ -59,150 +90,311 @@ async fn run_cmd(
         }
     }
 
-    nvc_cmd.status().await.map_err(|e| VwError::Testbench {
-        message: format!("nvc command failed : {e}"),
-    })
+    nvc_cmd.status().await.map_err(nvc_spawn_error)
 }
 
-pub async fn run_nvc_analysis(
-    std: VhdlStandard,
-    build_dir: &str,
-    lib_name: &str,
-    referenced_files: &Vec<String>,
-    capture_output: bool,
-) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
-    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name);
-    args.push("-a".to_string());
+/// Copy `reader` to stdout or stderr line by line, prefixing each line.
+async fn prefix_lines(
+    reader: impl AsyncRead + Unpin,
+    prefix: &str,
+    to_stderr: bool,
+) -> std::io::Result<()> {
+    let mut lines = BufReader::new(reader).split(b'\n');
+    while let Some(line) = lines.next_segment().await? {
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\r');
+        if to_stderr {
+            eprintln!("[{prefix}] {line}");
+        } else {
+            println!("[{prefix}] {line}");
+        }
+    }
+    Ok(())
+}
 
-    for file in referenced_files {
-        args.push(file.clone());
+async fn run_cmd_prefixed(
+    args: &Vec<String>,
+    envs: Option<&Vec<(String, String)>>,
+    prefix: &str,
+) -> Result<ExitStatus, VwError> {
+    let mut nvc_cmd = Command::new("nvc");
+    for arg in args {
+        nvc_cmd.arg(arg);
     }
 
-    if capture_output {
-        let output = run_cmd_w_output(&args, None).await?;
+    if let Some(vars) = envs {
+        for (env_var, value) in vars {
+            nvc_cmd.env(env_var, value);
+        }
+    }
+
+    let mut child = nvc_cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(nvc_spawn_error)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let (stdout_result, stderr_result) = tokio::join!(
+        prefix_lines(stdout, prefix, false),
+        prefix_lines(stderr, prefix, true)
+    );
+    if let Err(e) = stdout_result.and(stderr_result) {
+        // Don't leave nvc running, or unreaped, when its output can't be
+        // read; `kill` also waits for it to exit
+        let _ = child.kill().await;
+        return Err(e.into());
+    }
+
+    child.wait().await.map_err(nvc_spawn_error)
+}
 
-        if !output.status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            std::io::stdout().write_all(&output.stdout)?;
-            std::io::stderr().write_all(&output.stderr)?;
-            return Err(VwError::NvcAnalysis {
-                library: lib_name.to_owned(),
-                command: cmd_str,
-            });
+/// Run nvc with `args`, showing its output as `output` asks. `prefix`
+/// labels the lines in [`NvcOutput::Prefixed`] mode. Returns whether nvc
+/// succeeded, and the buffered output in [`NvcOutput::Capture`] mode.
+async fn run_nvc(
+    args: &Vec<String>,
+    envs: Option<&Vec<(String, String)>>,
+    output: NvcOutput,
+    prefix: &str,
+) -> Result<(bool, Option<(Vec<u8>, Vec<u8>)>), VwError> {
+    match output {
+        NvcOutput::Inherit => {
+            let status = run_cmd(args, envs).await?;
+            Ok((status.success(), None))
         }
-        Ok(Some((output.stdout, output.stderr)))
-    } else {
-        let status = run_cmd(&args, None).await?;
-
-        if !status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            return Err(VwError::NvcAnalysis {
-                library: lib_name.to_owned(),
-                command: cmd_str,
-            });
+        NvcOutput::Prefixed => {
+            let status = run_cmd_prefixed(args, envs, prefix).await?;
+            Ok((status.success(), None))
+        }
+        NvcOutput::Quiet | NvcOutput::Capture => {
+            let Output {
+                status,
+                stdout,
+                stderr,
+            } = run_cmd_w_output(args, envs).await?;
+            // Diagnostics go to stderr, keeping stdout for vw's own results
+            if !status.success() {
+                std::io::stderr().write_all(&stdout)?;
+                std::io::stderr().write_all(&stderr)?;
+            }
+            let captured =
+                (output == NvcOutput::Capture).then_some((stdout, stderr));
+            Ok((status.success(), captured))
         }
-        Ok(None)
     }
 }
 
+/// Analyze `referenced_files` into `lib_name`. `analysis_flags` are extra
+/// `-a` options, placed before the files.
+pub async fn run_nvc_analysis(
+    std: VhdlStandard,
+    build_dir: &str,
+    lib_name: &str,
+    heap_size: &str,
+    analysis_flags: &[String],
+    referenced_files: &[String],
+    output: NvcOutput,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
+    let args = nvc_analysis_args(
+        std,
+        build_dir,
+        lib_name,
+        heap_size,
+        analysis_flags,
+        referenced_files,
+    );
+    let (success, captured) = run_nvc(&args, None, output, lib_name).await?;
+    if !success {
+        return Err(VwError::NvcAnalysis {
+            library: lib_name.to_owned(),
+            command: format!("nvc {}", args.join(" ")),
+        });
+    }
+    Ok(captured)
+}
+
+/// Arguments for `nvc -a`: vw's options, then `analysis_flags` after the
+/// `-a`, which they modify, then the files.
+fn nvc_analysis_args(
+    std: VhdlStandard,
+    build_dir: &str,
+    lib_name: &str,
+    heap_size: &str,
+    analysis_flags: &[String],
+    referenced_files: &[String],
+) -> Vec<String> {
+    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name, heap_size);
+    args.push("-a".to_string());
+    args.extend(analysis_flags.iter().cloned());
+    args.extend(referenced_files.iter().cloned());
+    args
+}
+
+/// Elaborate `testbench_name`. `user_flags` go after vw's own options,
+/// just before the testbench name.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_nvc_elab(
     std: VhdlStandard,
     build_dir: &str,
     lib_name: &str,
+    heap_size: &str,
     testbench_name: &str,
-    capture_output: bool,
+    generics: &[(String, String)],
+    coverage: &[CoverageKind],
+    user_flags: &[String],
+    output: NvcOutput,
 ) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
-    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name);
+    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name, heap_size);
     args.push("-e".to_string());
+    for (name, value) in generics {
+        args.push(format!("-g{name}={value}"));
+    }
+    if !coverage.is_empty() {
+        let kinds: Vec<String> =
+            coverage.iter().map(|kind| kind.to_string()).collect();
+        args.push(format!("--cover={}", kinds.join(",")));
+    }
+    args.extend(user_flags.iter().cloned());
     args.push(testbench_name.to_owned());
 
-    if capture_output {
-        let output = run_cmd_w_output(&args, None).await?;
-        if !output.status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            std::io::stdout().write_all(&output.stdout)?;
-            std::io::stdout().write_all(&output.stderr)?;
-
-            return Err(VwError::NvcElab { command: cmd_str });
-        }
-        Ok(Some((output.stdout, output.stderr)))
-    } else {
-        let status = run_cmd(&args, None).await?;
-
-        if !status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            return Err(VwError::NvcElab { command: cmd_str });
-        }
-
-        Ok(None)
+    let (success, captured) =
+        run_nvc(&args, None, output, testbench_name).await?;
+    if !success {
+        return Err(VwError::NvcElab {
+            command: format!("nvc {}", args.join(" ")),
+            user_flags: user_flags.to_vec(),
+        });
     }
+    Ok(captured)
 }
 
-pub async fn run_nvc_sim(
+/// Arguments for `nvc -r`: vw's options, then `runtime_flags`, then the
+/// waveform and `--load` options.
+#[allow(clippy::too_many_arguments)]
+fn nvc_sim_args(
     std: VhdlStandard,
     build_dir: &str,
     lib_name: &str,
-    testbench_name: &String,
-    rust_lib_path: Option<String>,
-    runtime_flags: &Vec<String>,
-    capture_output: bool,
-) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
-    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name);
+    heap_size: &str,
+    testbench_name: &str,
+    rust_lib_path: Option<&str>,
+    seed: Option<u64>,
+    runtime_flags: &[String],
+    wave: Option<(WaveFormat, &str)>,
+) -> Vec<String> {
+    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name, heap_size);
     args.push("-r".to_string());
-    args.push(testbench_name.clone());
+    args.push(testbench_name.to_string());
+    if let Some(seed) = seed {
+        args.push(format!("--seed={seed}"));
+    }
 
+    // User flags go before the waveform options so the latter win
     for flag in runtime_flags {
         args.push(flag.clone());
     }
 
-    args.push("--dump-arrays".to_string());
-    args.push("--format=fst".to_string());
-    args.push(format!("--wave={testbench_name}.fst"));
+    if let Some((format, path)) = wave {
+        args.push("--dump-arrays".to_string());
+        args.push(format!("--format={format}"));
+        args.push(format!("--wave={path}"));
+    }
 
-    let envs = match rust_lib_path {
-        Some(path) => {
-            args.push(format!("--load={path}"));
-            let envs_vec = vec![("GPI_USERS".to_string(), path.clone())];
-            Some(envs_vec)
-        }
-        None => None,
-    };
+    if let Some(path) = rust_lib_path {
+        args.push(format!("--load={path}"));
+    }
+    args
+}
 
-    if capture_output {
-        let output = run_cmd_w_output(&args, envs.as_ref()).await?;
+#[allow(clippy::too_many_arguments)]
+pub async fn run_nvc_sim(
+    std: VhdlStandard,
+    build_dir: &str,
+    lib_name: &str,
+    heap_size: &str,
+    testbench_name: &str,
+    rust_lib_path: Option<String>,
+    seed: Option<u64>,
+    runtime_flags: &[String],
+    wave: Option<(WaveFormat, &str)>,
+    output: NvcOutput,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
+    let args = nvc_sim_args(
+        std,
+        build_dir,
+        lib_name,
+        heap_size,
+        testbench_name,
+        rust_lib_path.as_deref(),
+        seed,
+        runtime_flags,
+        wave,
+    );
+    let envs = rust_lib_path.map(|path| vec![("GPI_USERS".to_string(), path)]);
+
+    let (success, captured) =
+        run_nvc(&args, envs.as_ref(), output, testbench_name).await?;
+    if !success {
+        return Err(VwError::NvcSimulation {
+            command: format!("nvc {}", args.join(" ")),
+            user_flags: runtime_flags.to_vec(),
+        });
+    }
+    Ok(captured)
+}
 
-        if !output.status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            std::io::stdout().write_all(&output.stdout)?;
-            std::io::stdout().write_all(&output.stderr)?;
+/// Path of the coverage database NVC writes when simulating a design
+/// elaborated with `--cover`: `_<LIB>.<TOP>.covdb` in the work library.
+pub fn nvc_coverage_database(
+    build_dir: &str,
+    lib_name: &str,
+    testbench_name: &str,
+) -> String {
+    format!(
+        "{build_dir}/{lib_name}/_{}.{}.covdb",
+        lib_name.to_uppercase(),
+        testbench_name.to_uppercase()
+    )
+}
 
-            return Err(VwError::NvcSimulation { command: cmd_str });
-        }
-        Ok(Some((output.stdout, output.stderr)))
-    } else {
-        let status = run_cmd(&args, envs.as_ref()).await?;
+/// Generate an HTML coverage report in `report_dir` from `database`.
+pub async fn run_nvc_cover_report(
+    database: &str,
+    report_dir: &str,
+    output: NvcOutput,
+) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
+    let args = vec![
+        "--cover-report".to_string(),
+        "-o".to_string(),
+        report_dir.to_string(),
+        database.to_string(),
+    ];
 
-        if !status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            return Err(VwError::NvcSimulation { command: cmd_str });
-        }
-        Ok(None)
+    let (success, captured) = run_nvc(&args, None, output, "coverage").await?;
+    if !success {
+        return Err(VwError::NvcCoverage {
+            command: format!("nvc {}", args.join(" ")),
+        });
     }
+    Ok(captured)
 }
 
 /// Run a mixed-signal co-simulation via NVC with a bridge library loaded.
 ///
 /// Unlike `run_nvc_sim`, this sets `COCOTB_RUST_MODE=1` and does not
-/// generate FST waveform output (analog output comes from Xyce `.prn`).
+/// generate waveform output (analog output comes from Xyce `.prn`).
 pub async fn run_nvc_cosim(
     std: VhdlStandard,
     build_dir: &str,
     lib_name: &str,
+    heap_size: &str,
     entity_name: &str,
     bridge_lib_path: &str,
-    capture_output: bool,
+    output: NvcOutput,
 ) -> Result<Option<(Vec<u8>, Vec<u8>)>, VwError> {
-    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name);
+    let mut args = get_base_nvc_cmd_args(std, build_dir, lib_name, heap_size);
     args.push("-r".to_string());
     args.push(entity_name.to_string());
     args.push(format!("--load={bridge_lib_path}"));
@@ -212,24 +404,70 @@ pub async fn run_nvc_cosim(
         ("COCOTB_RUST_MODE".to_string(), "1".to_string()),
     ];
 
-    if capture_output {
-        let output = run_cmd_w_output(&args, Some(&envs)).await?;
+    let (success, captured) =
+        run_nvc(&args, Some(&envs), output, entity_name).await?;
+    if !success {
+        return Err(VwError::NvcSimulation {
+            command: format!("nvc {}", args.join(" ")),
+            user_flags: Vec::new(),
+        });
+    }
+    Ok(captured)
+}
 
-        if !output.status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            std::io::stdout().write_all(&output.stdout)?;
-            std::io::stderr().write_all(&output.stderr)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sim_args_put_runtime_flags_before_waveform_options() {
+        let args = nvc_sim_args(
+            VhdlStandard::Vhdl2008,
+            "vw_build",
+            "work",
+            DEFAULT_HEAP_SIZE,
+            "counter_tb",
+            None,
+            None,
+            &["--stop-time=10us".to_string()],
+            Some((WaveFormat::Fst, "counter_tb.fst")),
+        );
+        let position = |arg: &str| {
+            args.iter()
+                .position(|a| a == arg)
+                .unwrap_or_else(|| panic!("{arg} missing from {args:?}"))
+        };
+        assert_eq!(position("-r") + 1, position("counter_tb"));
+        assert!(position("counter_tb") < position("--stop-time=10us"));
+        assert!(position("--stop-time=10us") < position("--format=fst"));
+    }
 
-            return Err(VwError::NvcSimulation { command: cmd_str });
-        }
-        Ok(Some((output.stdout, output.stderr)))
-    } else {
-        let status = run_cmd(&args, Some(&envs)).await?;
+    #[test]
+    fn analysis_flags_follow_the_analyze_command() {
+        let args = nvc_analysis_args(
+            VhdlStandard::Vhdl2008,
+            "vw_build",
+            "uart",
+            DEFAULT_HEAP_SIZE,
+            &["--relaxed".to_string()],
+            &["uart_pkg.vhd".to_string(), "uart.vhd".to_string()],
+        );
+        let position = |arg: &str| {
+            args.iter()
+                .position(|a| a == arg)
+                .unwrap_or_else(|| panic!("{arg} missing from {args:?}"))
+        };
+        assert!(position("-a") < position("--relaxed"));
+        assert!(position("--relaxed") < position("uart_pkg.vhd"));
+        assert_eq!(args.last().map(String::as_str), Some("uart.vhd"));
+    }
 
-        if !status.success() {
-            let cmd_str = format!("nvc {}", args.join(" "));
-            return Err(VwError::NvcSimulation { command: cmd_str });
-        }
-        Ok(None)
+    #[tokio::test]
+    async fn missing_binary_is_nvc_not_found() {
+        let e = Command::new("vw-test-no-such-nvc-binary")
+            .status()
+            .await
+            .unwrap_err();
+        assert!(matches!(nvc_spawn_error(e), VwError::NvcNotFound));
     }
 }
@@ -16,11 +16,14 @@ use std::{fs, io};
 
 use camino::Utf8Path;
 
-use crate::nvc_helpers::{run_nvc_analysis, run_nvc_cosim, run_nvc_elab};
+use crate::nvc_helpers::{
+    run_nvc_analysis, run_nvc_cosim, run_nvc_elab, NvcOutput,
+};
 use crate::{
-    analyze_ext_libraries, find_referenced_files, load_existing_vhdl_ls_config,
-    sort_files_by_dependencies, FileCache, MistConfig, RecordProcessor,
-    ToolsConfig, VhdlStandard, VwError,
+    analyze_ext_libraries, defaultlib_files, find_referenced_files,
+    load_existing_vhdl_ls_config, sort_files_by_dependencies,
+    workspace_build_dir, FileCache, MistConfig, RecordProcessor, ToolsConfig,
+    VhdlStandard, VwError,
 };
 
 /// Information about an available mixed-signal test.
@@ -41,10 +44,8 @@ pub fn find_mist_configs(
         Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(configs),
         Err(e) => {
             return Err(VwError::FileSystem {
-                message: format!(
-                    "Failed to read bench directory {}: {e}",
-                    bench_dir
-                ),
+                message: format!("Failed to read bench directory: {e}"),
+                path: Some(bench_dir.as_std_path().to_path_buf()),
             })
         }
     };
@@ -99,6 +100,7 @@ pub fn scaffold(
 }
 
 /// Run a mixed-signal co-simulation test.
+#[allow(clippy::too_many_arguments)]
 pub async fn run_analog_test(
     workspace_dir: &Utf8Path,
     name: &str,
@@ -106,37 +108,35 @@ pub async fn run_analog_test(
     mist_config: &MistConfig,
     _tools: &Option<ToolsConfig>,
     vhdl_std: VhdlStandard,
+    heap_size: &str,
+    output: NvcOutput,
 ) -> crate::Result<()> {
     let vhdl_ls_config = load_existing_vhdl_ls_config(workspace_dir)?;
     let mut processor = RecordProcessor::new(vhdl_std);
     let mut cache = FileCache::new();
 
-    fs::create_dir_all(crate::BUILD_DIR)?;
+    let build_dir = workspace_build_dir(workspace_dir);
+    fs::create_dir_all(&build_dir)?;
 
     // Analyze external libraries
     analyze_ext_libraries(
+        workspace_dir,
         &vhdl_ls_config,
         &mut processor,
         vhdl_std,
+        heap_size,
         &mut cache,
+        output,
     )
     .await?;
 
     // Get all defaultlib files
-    let defaultlib_files = vhdl_ls_config
-        .libraries
-        .get("defaultlib")
-        .map(|lib| lib.files.clone())
-        .unwrap_or_default();
+    let defaultlib_files = defaultlib_files(workspace_dir, &vhdl_ls_config)?;
 
     // Find the entity source file in defaultlib
     let entity_name = &mist_config.entity;
-    let entity_file = find_entity_file(
-        workspace_dir.as_std_path(),
-        &defaultlib_files,
-        entity_name,
-        &mut cache,
-    )?;
+    let entity_file =
+        find_entity_file(&defaultlib_files, entity_name, &mut cache)?;
 
     // Find referenced files
     let mut referenced_files =
@@ -156,9 +156,28 @@ pub async fn run_analog_test(
     files.push(entity_file.to_string_lossy().to_string());
 
     // Compile VHDL
-    run_nvc_analysis(vhdl_std, crate::BUILD_DIR, "work", &files, false).await?;
-    run_nvc_elab(vhdl_std, crate::BUILD_DIR, "work", entity_name, false)
-        .await?;
+    run_nvc_analysis(
+        vhdl_std,
+        build_dir.as_str(),
+        "work",
+        heap_size,
+        &[],
+        &files,
+        output,
+    )
+    .await?;
+    run_nvc_elab(
+        vhdl_std,
+        build_dir.as_str(),
+        "work",
+        heap_size,
+        entity_name,
+        &[],
+        &[],
+        &[],
+        output,
+    )
+    .await?;
 
     // Build the bridge crate
     let bridge_lib =
@@ -168,11 +187,12 @@ pub async fn run_analog_test(
     // Run co-simulation
     run_nvc_cosim(
         vhdl_std,
-        crate::BUILD_DIR,
+        build_dir.as_str(),
         "work",
+        heap_size,
         entity_name,
         &bridge_lib_str,
-        false,
+        output,
     )
     .await?;
 
@@ -219,24 +239,18 @@ pub async fn run_analog_test(
 
 /// Find a VHDL entity source file by searching through defaultlib files.
 fn find_entity_file(
-    workspace_dir: &Path,
     defaultlib_files: &[PathBuf],
     entity_name: &str,
     cache: &mut FileCache,
 ) -> crate::Result<PathBuf> {
     for file_path in defaultlib_files {
-        let absolute_path = if file_path.is_relative() {
-            workspace_dir.join(file_path)
-        } else {
-            file_path.clone()
-        };
-        if !absolute_path.exists() {
+        if !file_path.exists() {
             continue;
         }
-        let entities = cache.get_entities(&absolute_path)?.clone();
+        let entities = cache.get_entities(file_path)?.clone();
         for entity in &entities {
             if entity.eq_ignore_ascii_case(entity_name) {
-                return Ok(absolute_path);
+                return Ok(file_path.clone());
             }
         }
     }
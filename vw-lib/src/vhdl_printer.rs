@@ -0,0 +1,480 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Render vhdl_lang AST fragments back to VHDL text.
+//!
+//! Only the node kinds vw needs (constraint ranges, simple expressions and
+//! names) are supported. Anything else is reported as a `CodeGen` error
+//! rather than rendered as text that would not compile. The whole-entity
+//! renderers, [`entity_to_string`] and [`port_to_string`], instead write a
+//! `/* ... */` placeholder in place of such a node, so the rest of the
+//! declaration can still be used.
+
+use vhdl_lang::ast::{
+    AbstractLiteral, AttributeDesignator, AttributeName, BaseSpecifier, Choice,
+    Designator, Direction, DiscreteRange, ElementAssociation,
+    EntityDeclaration, Expression, InterfaceDeclaration, InterfaceList,
+    Literal, Mode, ModeIndication, Name, Operator, Range, RangeAttribute,
+    RangeConstraint, SubtypeConstraint, SubtypeIndication,
+};
+
+use crate::visitor::{declared_objects, InterfaceObject};
+use crate::{Result, VwError};
+
+fn unsupported(what: &str) -> VwError {
+    VwError::CodeGen {
+        message: format!("Cannot render {what} as VHDL"),
+    }
+}
+
+/// `rendered`, or a VHDL-2008 block comment saying why it couldn't be
+/// rendered.
+fn or_placeholder(rendered: Result<String>) -> String {
+    match rendered {
+        Ok(text) => text,
+        Err(VwError::CodeGen { message }) => format!("/* {message} */"),
+        Err(e) => format!("/* {e} */"),
+    }
+}
+
+/// Render an entity declaration's header: its name and its generic and
+/// port lists. Declarations and statements in the entity are left out.
+pub fn entity_to_string(entity: &EntityDeclaration) -> String {
+    let name = entity.ident.tree.item.name_utf8();
+    let mut rendered = format!("entity {name} is\n");
+    for (keyword, list) in [
+        ("generic", entity.generic_clause.as_ref()),
+        ("port", entity.port_clause.as_ref()),
+    ] {
+        if let Some(list) = list {
+            rendered.push_str(&interface_list_to_string(keyword, list));
+        }
+    }
+    rendered.push_str(&format!("end entity {name};\n"));
+    rendered
+}
+
+/// Render a `generic (...);` or `port (...);` clause, one object per line.
+fn interface_list_to_string(keyword: &str, list: &InterfaceList) -> String {
+    let lines: Vec<String> = list
+        .items
+        .iter()
+        .flat_map(|item| match item {
+            InterfaceDeclaration::Object(decl) => {
+                declared_objects(decl).iter().map(port_to_string).collect()
+            }
+            _ => vec![or_placeholder(Err(unsupported(
+                "a generic type, subprogram or package",
+            )))],
+        })
+        .collect();
+    let mut rendered = format!("    {keyword} (\n");
+    for (index, line) in lines.iter().enumerate() {
+        let separator = if index + 1 < lines.len() { ";" } else { "" };
+        rendered.push_str(&format!("        {line}{separator}\n"));
+    }
+    rendered.push_str("    );\n");
+    rendered
+}
+
+/// Render one generic or port as it appears in an interface list, such as
+/// `data : out std_logic_vector(7 downto 0)` or `WIDTH : natural := 8`,
+/// without a trailing semicolon.
+pub fn port_to_string(port: &InterfaceObject) -> String {
+    let mut rendered = format!("{} : ", port.name);
+    if let Some(mode) = port.mode {
+        rendered.push_str(mode_to_string(mode));
+        rendered.push(' ');
+    }
+    let ModeIndication::Simple(simple) = &port.decl.mode else {
+        rendered.push_str(&or_placeholder(Err(unsupported("a mode view"))));
+        return rendered;
+    };
+    rendered.push_str(&or_placeholder(subtype_to_string(
+        &simple.subtype_indication,
+    )));
+    if simple.bus {
+        rendered.push_str(" bus");
+    }
+    if let Some(default) = &simple.expression {
+        rendered.push_str(" := ");
+        rendered.push_str(&or_placeholder(expr_to_string(&default.item)));
+    }
+    rendered
+}
+
+fn mode_to_string(mode: Mode) -> &'static str {
+    match mode {
+        Mode::In => "in",
+        Mode::Out => "out",
+        Mode::InOut => "inout",
+        Mode::Buffer => "buffer",
+        Mode::Linkage => "linkage",
+    }
+}
+
+/// Render a subtype indication such as `std_logic_vector(7 downto 0)` or
+/// `integer range 0 to 15`.
+pub fn subtype_to_string(subtype: &SubtypeIndication) -> Result<String> {
+    if subtype.resolution.is_some() {
+        return Err(unsupported("a resolution indication"));
+    }
+    let mut rendered = name_to_string(&subtype.type_mark.item)?;
+    if let Some(constraint) = &subtype.constraint {
+        rendered.push_str(&subtype_constraint_to_string(&constraint.item)?);
+    }
+    Ok(rendered)
+}
+
+/// Render a constraint as it follows a type mark: ` range 0 to 15` or
+/// `(7 downto 0)`, with any element constraint appended.
+fn subtype_constraint_to_string(
+    constraint: &SubtypeConstraint,
+) -> Result<String> {
+    match constraint {
+        SubtypeConstraint::Range(range) => {
+            Ok(format!(" range {}", range_to_string(range)?))
+        }
+        SubtypeConstraint::Array(ranges, element) => {
+            let ranges = ranges
+                .iter()
+                .map(|range| discrete_range_to_string(&range.item))
+                .collect::<Result<Vec<_>>>()?;
+            let mut rendered = format!("({})", ranges.join(", "));
+            if let Some(element) = element {
+                rendered
+                    .push_str(&subtype_constraint_to_string(&element.item)?);
+            }
+            Ok(rendered)
+        }
+        _ => Err(unsupported("a record constraint")),
+    }
+}
+
+/// Render a range such as `7 downto 0`, `0 to N - 1`, or `sig'range`.
+pub fn range_to_string(range: &Range) -> Result<String> {
+    match range {
+        Range::Range(constraint) => range_constraint_to_string(constraint),
+        Range::Attribute(attr) => attribute_name_to_string(attr),
+    }
+}
+
+/// Render an explicit `left to/downto right` range.
+pub fn range_constraint_to_string(
+    constraint: &RangeConstraint,
+) -> Result<String> {
+    let direction = match constraint.direction {
+        Direction::Ascending => "to",
+        Direction::Descending => "downto",
+    };
+    Ok(format!(
+        "{} {direction} {}",
+        expr_to_string(&constraint.left_expr.item)?,
+        expr_to_string(&constraint.right_expr.item)?
+    ))
+}
+
+/// Render a discrete range as used in slices and index constraints.
+pub fn discrete_range_to_string(range: &DiscreteRange) -> Result<String> {
+    match range {
+        DiscreteRange::Range(range) => range_to_string(range),
+        DiscreteRange::Discrete(..) => {
+            Err(unsupported("a subtype discrete range"))
+        }
+    }
+}
+
+/// Render an expression.
+pub fn expr_to_string(expr: &Expression) -> Result<String> {
+    match expr {
+        Expression::Literal(literal) => literal_to_string(literal),
+        Expression::Name(name) => name_to_string(name),
+        Expression::Binary(op, left, right) => Ok(format!(
+            "{} {} {}",
+            expr_to_string(&left.item)?,
+            operator_to_string(&op.item.item)?,
+            expr_to_string(&right.item)?
+        )),
+        Expression::Unary(op, operand) => {
+            let operand = expr_to_string(&operand.item)?;
+            match &op.item.item {
+                Operator::Plus => Ok(format!("+{operand}")),
+                Operator::Minus => Ok(format!("-{operand}")),
+                other => {
+                    Ok(format!("{} {operand}", operator_to_string(other)?))
+                }
+            }
+        }
+        Expression::Parenthesized(inner) => {
+            Ok(format!("({})", expr_to_string(&inner.item)?))
+        }
+        Expression::Aggregate(associations) => {
+            let associations = associations
+                .iter()
+                .map(|association| {
+                    element_association_to_string(&association.item)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!("({})", associations.join(", ")))
+        }
+        Expression::Qualified(qualified) => {
+            let type_mark = name_to_string(&qualified.type_mark.item)?;
+            let operand = expr_to_string(&qualified.expr.item)?;
+            // The operand of a qualified expression is always parenthesized,
+            // which aggregates and parenthesized expressions already are
+            match &qualified.expr.item {
+                Expression::Aggregate(_) | Expression::Parenthesized(_) => {
+                    Ok(format!("{type_mark}'{operand}"))
+                }
+                _ => Ok(format!("{type_mark}'({operand})")),
+            }
+        }
+        _ => Err(unsupported("this kind of expression")),
+    }
+}
+
+/// Render one element of an aggregate, such as `x"00"`, `3 => '1'`, or
+/// `others => '0'`.
+fn element_association_to_string(
+    association: &ElementAssociation,
+) -> Result<String> {
+    match association {
+        ElementAssociation::Positional(expr) => expr_to_string(&expr.item),
+        ElementAssociation::Named(choices, expr) => {
+            let choices = choices
+                .iter()
+                .map(|choice| match &choice.item {
+                    Choice::Expression(expr) => expr_to_string(expr),
+                    Choice::DiscreteRange(range) => {
+                        discrete_range_to_string(range)
+                    }
+                    Choice::Others => Ok("others".to_string()),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!(
+                "{} => {}",
+                choices.join(" | "),
+                expr_to_string(&expr.item)?
+            ))
+        }
+    }
+}
+
+/// Render a literal value.
+pub fn literal_to_string(literal: &Literal) -> Result<String> {
+    match literal {
+        Literal::AbstractLiteral(value) => {
+            Ok(abstract_literal_to_string(value))
+        }
+        Literal::Character(c) => Ok(format!("'{}'", *c as char)),
+        Literal::String(s) => {
+            let text: String = s.bytes.iter().map(|&b| b as char).collect();
+            Ok(format!("\"{}\"", text.replace('"', "\"\"")))
+        }
+        Literal::BitString(bit_string) => {
+            let length = bit_string
+                .length
+                .map(|length| length.to_string())
+                .unwrap_or_default();
+            let base = match bit_string.base {
+                BaseSpecifier::B => "b",
+                BaseSpecifier::O => "o",
+                BaseSpecifier::X => "x",
+                BaseSpecifier::UB => "ub",
+                BaseSpecifier::UO => "uo",
+                BaseSpecifier::UX => "ux",
+                BaseSpecifier::SB => "sb",
+                BaseSpecifier::SO => "so",
+                BaseSpecifier::SX => "sx",
+                BaseSpecifier::D => "d",
+            };
+            let digits: String =
+                bit_string.value.bytes.iter().map(|&b| b as char).collect();
+            Ok(format!("{length}{base}\"{digits}\""))
+        }
+        Literal::Physical(physical) => Ok(format!(
+            "{} {}",
+            abstract_literal_to_string(&physical.value),
+            physical.unit.item.item.name_utf8()
+        )),
+        Literal::Null => Ok("null".to_string()),
+    }
+}
+
+fn abstract_literal_to_string(value: &AbstractLiteral) -> String {
+    match value {
+        AbstractLiteral::Integer(value) => value.to_string(),
+        // VHDL real literals need a decimal point
+        AbstractLiteral::Real(value) if value.fract() == 0.0 => {
+            format!("{value:.1}")
+        }
+        AbstractLiteral::Real(value) => value.to_string(),
+    }
+}
+
+/// Render a (possibly selected, sliced, or attributed) name.
+pub fn name_to_string(name: &Name) -> Result<String> {
+    match name {
+        Name::Designator(designator) => designator_to_string(&designator.item),
+        Name::Selected(prefix, suffix) => Ok(format!(
+            "{}.{}",
+            name_to_string(&prefix.item)?,
+            designator_to_string(&suffix.item.item)?
+        )),
+        Name::Slice(prefix, range) => Ok(format!(
+            "{}({})",
+            name_to_string(&prefix.item)?,
+            discrete_range_to_string(range)?
+        )),
+        Name::Attribute(attr) => attribute_name_to_string(attr),
+        _ => Err(unsupported("this kind of name")),
+    }
+}
+
+fn designator_to_string(designator: &Designator) -> Result<String> {
+    match designator {
+        Designator::Identifier(symbol) => Ok(symbol.name_utf8()),
+        Designator::Character(c) => Ok(format!("'{}'", *c as char)),
+        _ => Err(unsupported("an operator or anonymous designator")),
+    }
+}
+
+fn attribute_name_to_string(attr: &AttributeName) -> Result<String> {
+    let designator = match &attr.attr.item {
+        AttributeDesignator::Range(RangeAttribute::Range) => {
+            "range".to_string()
+        }
+        AttributeDesignator::Range(RangeAttribute::ReverseRange) => {
+            "reverse_range".to_string()
+        }
+        AttributeDesignator::Left => "left".to_string(),
+        AttributeDesignator::Right => "right".to_string(),
+        AttributeDesignator::High => "high".to_string(),
+        AttributeDesignator::Low => "low".to_string(),
+        AttributeDesignator::Length => "length".to_string(),
+        AttributeDesignator::Ident(symbol) => symbol.item.name_utf8(),
+        _ => return Err(unsupported("this attribute")),
+    };
+    let mut rendered =
+        format!("{}'{designator}", name_to_string(&attr.name.item)?);
+    if let Some(expr) = &attr.expr {
+        rendered.push_str(&format!("({})", expr_to_string(&expr.item)?));
+    }
+    Ok(rendered)
+}
+
+fn operator_to_string(op: &Operator) -> Result<&'static str> {
+    Ok(match op {
+        Operator::Plus => "+",
+        Operator::Minus => "-",
+        Operator::Times => "*",
+        Operator::Div => "/",
+        Operator::Pow => "**",
+        Operator::Mod => "mod",
+        Operator::Rem => "rem",
+        Operator::Abs => "abs",
+        Operator::Concat => "&",
+        Operator::And => "and",
+        Operator::Or => "or",
+        Operator::Xor => "xor",
+        Operator::Not => "not",
+        Operator::EQ => "=",
+        Operator::NE => "/=",
+        Operator::LT => "<",
+        Operator::LTE => "<=",
+        Operator::GT => ">",
+        Operator::GTE => ">=",
+        _ => return Err(unsupported("this operator")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use vhdl_lang::VHDLParser;
+
+    use super::*;
+    use crate::visitor::{walk_design_file, Visitor, VisitorResult};
+    use crate::VhdlStandard;
+
+    #[derive(Default)]
+    struct EntityRenderer {
+        rendered: Vec<String>,
+    }
+
+    impl Visitor for EntityRenderer {
+        fn visit_entity(
+            &mut self,
+            entity: &EntityDeclaration,
+        ) -> VisitorResult {
+            self.rendered.push(entity_to_string(entity));
+            VisitorResult::Continue
+        }
+    }
+
+    /// Parse `source` and render the first entity it declares.
+    fn render_entity(source: &str) -> String {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("design.vhd");
+        std::fs::write(&path, source).unwrap();
+        let parser = VHDLParser::new(VhdlStandard::Vhdl2008.into());
+        let mut diagnostics = Vec::new();
+        let (_, design_file) =
+            parser.parse_design_file(&path, &mut diagnostics).unwrap();
+        let mut renderer = EntityRenderer::default();
+        walk_design_file(&mut renderer, &design_file);
+        renderer.rendered.remove(0)
+    }
+
+    #[test]
+    fn renders_descending_ascending_and_attribute_ranges() {
+        let rendered = render_entity(
+            "entity ranges is
+                port (
+                    a : in std_logic_vector(7 downto 0);
+                    b : out std_logic_vector(0 to 7);
+                    c : out std_logic_vector(a'range)
+                );
+            end entity ranges;",
+        );
+        assert!(
+            rendered.contains("a : in std_logic_vector(7 downto 0);"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("b : out std_logic_vector(0 to 7);"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("c : out std_logic_vector(a'range)\n"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn renders_physical_literals_with_their_unit() {
+        let rendered = render_entity(
+            "entity delayed is
+                generic (
+                    delay : time := 10 ns
+                );
+            end entity delayed;",
+        );
+        assert!(rendered.contains("delay : time := 10 ns\n"), "{rendered}");
+    }
+
+    #[test]
+    fn renders_hex_bit_strings() {
+        let rendered = render_entity(
+            "entity flagged is
+                generic (
+                    mask : bit_vector(7 downto 0) := x\"80\"
+                );
+            end entity flagged;",
+        );
+        assert!(
+            rendered.contains("mask : bit_vector(7 downto 0) := x\"80\"\n"),
+            "{rendered}"
+        );
+    }
+}
@@ -26,10 +26,13 @@
 use vhdl_lang::ast::{
     AnyDesignUnit, AnyPrimaryUnit, AnySecondaryUnit, ArchitectureBody,
     Attribute, AttributeDeclaration, AttributeSpecification,
-    ComponentDeclaration, ConfigurationDeclaration, ContextDeclaration,
-    Declaration, DesignFile, EntityDeclaration, ObjectDeclaration, PackageBody,
-    PackageDeclaration, PackageInstantiation, SubprogramBody,
-    SubprogramDeclaration, SubprogramInstantiation, TypeDeclaration,
+    ComponentDeclaration, ConcurrentStatement, ConfigurationDeclaration,
+    ContextDeclaration, Declaration, DesignFile, EntityDeclaration,
+    GenerateBody, InstantiationStatement, InterfaceDeclaration, InterfaceList,
+    InterfaceObjectDeclaration, LabeledConcurrentStatement, Mode,
+    ModeIndication, ObjectDeclaration, PackageBody, PackageDeclaration,
+    PackageInstantiation, SubprogramBody, SubprogramDeclaration,
+    SubprogramInstantiation, SubtypeIndication, TypeDeclaration,
 };
 
 /// Controls whether AST traversal should continue or stop.
@@ -48,6 +51,22 @@ impl VisitorResult {
     }
 }
 
+/// One object (constant or signal) in an entity's generic or port list.
+///
+/// A declaration naming several objects (`a, b : in bit`) is reported once
+/// per name.
+#[derive(Debug, Clone)]
+pub struct InterfaceObject<'a> {
+    pub name: String,
+    /// The explicit mode, or `None` if the declaration leaves it out (which
+    /// means `in`) or uses a VHDL-2019 mode view.
+    pub mode: Option<Mode>,
+    /// The declared subtype, or `None` for a port with a mode view.
+    pub subtype: Option<&'a SubtypeIndication>,
+    /// The full declaration, for anything else (default value, `bus`).
+    pub decl: &'a InterfaceObjectDeclaration,
+}
+
 /// A trait for visiting nodes in a vhdl_lang AST.
 ///
 /// All methods have default implementations that return `Continue`,
@@ -79,6 +98,25 @@ pub trait Visitor {
         VisitorResult::Continue
     }
 
+    /// Called for each object in an entity's generic list, after
+    /// `visit_entity`. Generic types, subprograms and packages are skipped.
+    fn visit_entity_generic(
+        &mut self,
+        entity: &EntityDeclaration,
+        generic: &InterfaceObject,
+    ) -> VisitorResult {
+        VisitorResult::Continue
+    }
+
+    /// Called for each port of an entity, after its generics
+    fn visit_entity_port(
+        &mut self,
+        entity: &EntityDeclaration,
+        port: &InterfaceObject,
+    ) -> VisitorResult {
+        VisitorResult::Continue
+    }
+
     /// Called for package declarations
     fn visit_package(&mut self, package: &PackageDeclaration) -> VisitorResult {
         VisitorResult::Continue
@@ -141,6 +179,10 @@ pub trait Visitor {
         VisitorResult::Continue
     }
 
+    /// Called for object declarations (constants, signals, variables and
+    /// shared variables, files); `decl.class` says which, and
+    /// `decl.subtype_indication` and `decl.expression` give the subtype
+    /// and the optional default value
     fn visit_object_declaration(
         &mut self,
         decl: &ObjectDeclaration,
@@ -206,6 +248,30 @@ pub trait Visitor {
     ) -> VisitorResult {
         VisitorResult::Continue
     }
+
+    // ========================================================================
+    // Concurrent Statements
+    // ========================================================================
+
+    /// Called for each concurrent statement in an architecture, including
+    /// those nested in blocks and generate statements (before dispatching
+    /// to specific type)
+    fn visit_concurrent_statement(
+        &mut self,
+        stmt: &LabeledConcurrentStatement,
+        unit: &AnyDesignUnit,
+    ) -> VisitorResult {
+        VisitorResult::Continue
+    }
+
+    /// Called for component, entity and configuration instantiations
+    fn visit_instantiation(
+        &mut self,
+        inst: &InstantiationStatement,
+        unit: &AnyDesignUnit,
+    ) -> VisitorResult {
+        VisitorResult::Continue
+    }
 }
 
 /// Walk a design file, calling visitor methods for each node.
@@ -256,6 +322,9 @@ fn walk_primary_unit<V: Visitor>(
             if !visitor.visit_entity(entity).should_continue() {
                 return VisitorResult::Stop;
             }
+            if !walk_entity_interface(visitor, entity).should_continue() {
+                return VisitorResult::Stop;
+            }
             walk_declarations(visitor, &entity.decl, design_unit)
         }
         AnyPrimaryUnit::Package(package) => {
@@ -274,6 +343,61 @@ fn walk_primary_unit<V: Visitor>(
     }
 }
 
+/// Walk an entity's generic and port lists.
+fn walk_entity_interface<V: Visitor>(
+    visitor: &mut V,
+    entity: &EntityDeclaration,
+) -> VisitorResult {
+    for generic in interface_objects(entity.generic_clause.as_ref()) {
+        if !visitor
+            .visit_entity_generic(entity, &generic)
+            .should_continue()
+        {
+            return VisitorResult::Stop;
+        }
+    }
+    for port in interface_objects(entity.port_clause.as_ref()) {
+        if !visitor.visit_entity_port(entity, &port).should_continue() {
+            return VisitorResult::Stop;
+        }
+    }
+    VisitorResult::Continue
+}
+
+/// The objects declared in an interface list, one per name.
+fn interface_objects(list: Option<&InterfaceList>) -> Vec<InterfaceObject<'_>> {
+    list.into_iter()
+        .flat_map(|list| &list.items)
+        .filter_map(|item| match item {
+            InterfaceDeclaration::Object(decl) => Some(declared_objects(decl)),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// The objects named by one interface object declaration.
+pub(crate) fn declared_objects(
+    decl: &InterfaceObjectDeclaration,
+) -> Vec<InterfaceObject<'_>> {
+    let (mode, subtype) = match &decl.mode {
+        ModeIndication::Simple(simple) => (
+            simple.mode.as_ref().map(|mode| mode.item),
+            Some(&simple.subtype_indication),
+        ),
+        ModeIndication::View(_) => (None, None),
+    };
+    decl.idents
+        .iter()
+        .map(|ident| InterfaceObject {
+            name: ident.tree.item.name_utf8(),
+            mode,
+            subtype,
+            decl,
+        })
+        .collect()
+}
+
 /// Walk a secondary unit.
 fn walk_secondary_unit<V: Visitor>(
     visitor: &mut V,
@@ -285,7 +409,12 @@ fn walk_secondary_unit<V: Visitor>(
             if !visitor.visit_architecture(arch).should_continue() {
                 return VisitorResult::Stop;
             }
-            walk_declarations(visitor, &arch.decl, design_unit)
+            if !walk_declarations(visitor, &arch.decl, design_unit)
+                .should_continue()
+            {
+                return VisitorResult::Stop;
+            }
+            walk_concurrent_statements(visitor, &arch.statements, design_unit)
         }
         AnySecondaryUnit::PackageBody(body) => {
             if !visitor.visit_package_body(body).should_continue() {
@@ -310,6 +439,96 @@ fn walk_declarations<V: Visitor>(
     VisitorResult::Continue
 }
 
+/// Walk a list of concurrent statements, descending into blocks, processes
+/// (declarations only) and generate statements.
+fn walk_concurrent_statements<V: Visitor>(
+    visitor: &mut V,
+    stmts: &[LabeledConcurrentStatement],
+    unit: &AnyDesignUnit,
+) -> VisitorResult {
+    for stmt in stmts {
+        if !walk_concurrent_statement(visitor, stmt, unit).should_continue() {
+            return VisitorResult::Stop;
+        }
+    }
+    VisitorResult::Continue
+}
+
+/// Walk a single concurrent statement.
+fn walk_concurrent_statement<V: Visitor>(
+    visitor: &mut V,
+    stmt: &LabeledConcurrentStatement,
+    unit: &AnyDesignUnit,
+) -> VisitorResult {
+    // First call the generic statement visitor
+    if !visitor
+        .visit_concurrent_statement(stmt, unit)
+        .should_continue()
+    {
+        return VisitorResult::Stop;
+    }
+
+    // Then dispatch to specific visitors and recurse into nested statements
+    match &stmt.statement.item {
+        ConcurrentStatement::Instance(inst) => {
+            visitor.visit_instantiation(inst, unit)
+        }
+        ConcurrentStatement::Block(block) => {
+            if !walk_declarations(visitor, &block.decl, unit).should_continue()
+            {
+                return VisitorResult::Stop;
+            }
+            walk_concurrent_statements(visitor, &block.statements, unit)
+        }
+        ConcurrentStatement::Process(process) => {
+            walk_declarations(visitor, &process.decl, unit)
+        }
+        ConcurrentStatement::ForGenerate(generate) => {
+            walk_generate_body(visitor, &generate.body, unit)
+        }
+        ConcurrentStatement::IfGenerate(generate) => {
+            let bodies = generate
+                .conds
+                .conditionals
+                .iter()
+                .map(|cond| &cond.item)
+                .chain(generate.conds.else_item.as_ref().map(|(body, _)| body));
+            for body in bodies {
+                if !walk_generate_body(visitor, body, unit).should_continue() {
+                    return VisitorResult::Stop;
+                }
+            }
+            VisitorResult::Continue
+        }
+        ConcurrentStatement::CaseGenerate(generate) => {
+            for alternative in &generate.sels.alternatives {
+                if !walk_generate_body(visitor, &alternative.item, unit)
+                    .should_continue()
+                {
+                    return VisitorResult::Stop;
+                }
+            }
+            VisitorResult::Continue
+        }
+        // For other statement types, just continue
+        _ => VisitorResult::Continue,
+    }
+}
+
+/// Walk the declarations and statements of one generate alternative.
+fn walk_generate_body<V: Visitor>(
+    visitor: &mut V,
+    body: &GenerateBody,
+    unit: &AnyDesignUnit,
+) -> VisitorResult {
+    if let Some((decls, _)) = &body.decl {
+        if !walk_declarations(visitor, decls, unit).should_continue() {
+            return VisitorResult::Stop;
+        }
+    }
+    walk_concurrent_statements(visitor, &body.statements, unit)
+}
+
 /// Walk a single declaration.
 fn walk_declaration<V: Visitor>(
     visitor: &mut V,
@@ -353,3 +572,79 @@ fn walk_declaration<V: Visitor>(
         _ => VisitorResult::Continue,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use vhdl_lang::VHDLParser;
+
+    use super::*;
+    use crate::VhdlStandard;
+
+    #[derive(Default)]
+    struct InterfaceCollector {
+        generics: Vec<String>,
+        ports: Vec<(String, Option<Mode>)>,
+    }
+
+    impl Visitor for InterfaceCollector {
+        fn visit_entity_generic(
+            &mut self,
+            _entity: &EntityDeclaration,
+            generic: &InterfaceObject,
+        ) -> VisitorResult {
+            self.generics.push(generic.name.clone());
+            VisitorResult::Continue
+        }
+
+        fn visit_entity_port(
+            &mut self,
+            _entity: &EntityDeclaration,
+            port: &InterfaceObject,
+        ) -> VisitorResult {
+            self.ports.push((port.name.clone(), port.mode));
+            VisitorResult::Continue
+        }
+    }
+
+    #[test]
+    fn reports_each_port_and_generic_of_an_entity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fifo.vhd");
+        std::fs::write(
+            &path,
+            "entity fifo is
+                generic (
+                    width : natural := 8;
+                    depth : natural := 16
+                );
+                port (
+                    clk, rst : in bit;
+                    din : bit_vector(width - 1 downto 0);
+                    dout : out bit_vector(width - 1 downto 0);
+                    level : buffer natural
+                );
+            end entity;",
+        )
+        .unwrap();
+        let parser = VHDLParser::new(VhdlStandard::Vhdl2008.into());
+        let mut diagnostics = Vec::new();
+        let (_, design_file) =
+            parser.parse_design_file(&path, &mut diagnostics).unwrap();
+
+        let mut collector = InterfaceCollector::default();
+        walk_design_file(&mut collector, &design_file);
+
+        assert_eq!(collector.generics, ["width", "depth"]);
+        assert_eq!(collector.ports.len(), 5);
+        assert_eq!(
+            collector.ports,
+            [
+                ("clk".to_string(), Some(Mode::In)),
+                ("rst".to_string(), Some(Mode::In)),
+                ("din".to_string(), None),
+                ("dout".to_string(), Some(Mode::Out)),
+                ("level".to_string(), Some(Mode::Buffer)),
+            ]
+        );
+    }
+}